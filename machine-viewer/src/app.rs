@@ -1,5 +1,6 @@
 use std::error;
 use risc_v::RV32;
+use risc_v::bus::DeviceHandle;
 use crate::widgets::RegisterViewState;
 
 /// Application result type.
@@ -10,13 +11,23 @@ pub struct App {
     pub running: bool,
     pub cpu: RV32,
     pub register_view_state: RegisterViewState,
+    /// Input device keys are forwarded to
+    pub input: Option<DeviceHandle>,
+    /// Keys go to the guest instead of controlling the viewer
+    pub forwarding_keys: bool,
 }
 
 impl App {
     /// Constructs a new instance of [`App`].
     pub fn new(cpu: RV32) -> Self {
         let register_view_state = RegisterViewState::new(&cpu);
-        Self { running: true, cpu, register_view_state }
+        Self { running: true, cpu, register_view_state, input: None, forwarding_keys: false }
+    }
+
+    /// Forward keys to the input device connected as `input`
+    pub fn with_input(mut self, input: DeviceHandle) -> Self {
+        self.input = Some(input);
+        self
     }
 
     /// Handles the tick event of the terminal.
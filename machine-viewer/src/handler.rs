@@ -1,8 +1,24 @@
 use crate::app::{App, AppResult};
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use risc_v::devices::input::Input;
+use risc_v::devices::terminal;
+
+/// Key switching between controlling the viewer and typing to the guest
+pub const FORWARD_TOGGLE: KeyCode = KeyCode::F(12);
 
 /// Handles the key events and updates the state of [`App`].
 pub fn handle_key_events(key_event: KeyEvent, app: &mut App) -> AppResult<()> {
+    if key_event.code == FORWARD_TOGGLE {
+        app.forwarding_keys = !app.forwarding_keys && app.input.is_some();
+        return Ok(());
+    }
+    if app.forwarding_keys {
+        if let Some(input) = app.input.and_then(|handle| app.cpu.bus.device::<Input>(handle)) {
+            terminal::forward(&key_event, input, false);
+        }
+        return Ok(());
+    }
+
     match key_event.code {
         // Exit application on `ESC` or `q`
         KeyCode::Esc | KeyCode::Char('q') => {
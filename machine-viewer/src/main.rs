@@ -3,18 +3,24 @@ use machine_viewer::event::{Event, EventHandler};
 use machine_viewer::handler::handle_key_events;
 use machine_viewer::tui::Tui;
 use ratatui::backend::CrosstermBackend;
-use risc_v::{RV32, Word};
+use risc_v::{RV32, Word, MemoryRange};
+use risc_v::devices::{Device, rom::Rom64KiB, input::Input};
 use ratatui::Terminal;
 use std::io;
 
 const PROGRAM_ROM: &[u8] = include_bytes!("rom.bin");
 
+/// Address of the input device, keys typed after F12 reach it
+const INPUT_BASE: Word = Word(0x0001_0000);
+
 fn main() -> AppResult<()> {
-    let cpu = RV32::new();
-    cpu.bus.load(Word(0), PROGRAM_ROM);
+    let mut cpu = RV32::new();
+    let rom = Rom64KiB::from_bytes(PROGRAM_ROM);
+    cpu.bus.connect(Device::new(MemoryRange::new(Word(0), Word(0x10000)), Box::new(rom))).unwrap();
+    let input = cpu.bus.connect(Device::new(MemoryRange::new(INPUT_BASE, Word(0x20)), Box::new(Input::new()))).unwrap();
 
     // Create an application.
-    let mut app = App::new(cpu);
+    let mut app = App::new(cpu).with_input(input);
 
     // Initialize the terminal user interface.
     let backend = CrosstermBackend::new(io::stderr());
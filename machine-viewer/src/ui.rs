@@ -21,8 +21,12 @@ pub fn render(app: &mut App, frame: &mut Frame) {
 
     let (instruction_view_block, memory_register_blocks) = (layout[0], layout[1]);
 
+    let title = match app.forwarding_keys {
+        true => " Instructions - keys go to the guest, F12 to return ",
+        false => " Instructions ",
+    };
     let block = Block::default()
-        .title(" Instructions ")
+        .title(title)
         .border_type(BorderType::Plain)
         .borders(Borders::all());
 
@@ -58,7 +58,7 @@ impl<'a> Widget for MemoryView<'a> {
 
             for byte_offset in 0..mem_per_line {
                 let address = (offset + byte_offset).into();
-                let byte = self.cpu.bus.read(address).0 as u8;
+                let byte = self.cpu.bus.read_byte(address);
 
                 let byte_style = match (address / Word(4)) == (self.cpu.reg.read("pc").unwrap() / Word(4)) {
                     true    => Style::default().fg(Color::LightCyan),
@@ -0,0 +1,125 @@
+//! Audio output of sound devices
+//!
+//! Devices produce signed 16-bit mono samples at their sample rate and hand
+//! them to an [`AudioSink`]. Without SDL they can be written to a WAV file
+//! or collected in memory.
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Size of the RIFF and format headers, sample data follows
+const WAV_HEADER_SIZE: u32 = 44;
+
+pub trait AudioSink {
+    /// Play or store `samples`, they follow the previous ones without a gap
+    fn samples(&mut self, samples: &[i16]);
+}
+
+/// Samples kept in memory, clones share them
+#[derive(Clone, Default)]
+pub struct SampleBuffer {
+    samples: Rc<RefCell<Vec<i16>>>,
+}
+impl SampleBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything received so far
+    pub fn samples(&self) -> Vec<i16> {
+        self.samples.borrow().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.borrow().is_empty()
+    }
+}
+impl AudioSink for SampleBuffer {
+    fn samples(&mut self, samples: &[i16]) {
+        self.samples.borrow_mut().extend(samples);
+    }
+}
+
+/// 16-bit mono PCM WAV file, the header is kept up to date after every
+/// write so the file is valid even if the machine never stops cleanly
+pub struct WavWriter {
+    file: File,
+    sample_rate: u32,
+    data_size: u32,
+}
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        let mut writer = Self { file: File::create(path)?, sample_rate, data_size: 0 };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let byte_rate = self.sample_rate * 2;
+
+        let mut header = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+        header.extend(b"RIFF");
+        header.extend((WAV_HEADER_SIZE - 8 + self.data_size).to_le_bytes());
+        header.extend(b"WAVEfmt ");
+        header.extend(16u32.to_le_bytes());
+        // PCM, one channel
+        header.extend(1u16.to_le_bytes());
+        header.extend(1u16.to_le_bytes());
+        header.extend(self.sample_rate.to_le_bytes());
+        header.extend(byte_rate.to_le_bytes());
+        // Bytes per frame and bits per sample
+        header.extend(2u16.to_le_bytes());
+        header.extend(16u16.to_le_bytes());
+        header.extend(b"data");
+        header.extend(self.data_size.to_le_bytes());
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    fn append(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        self.file.write_all(&bytes)?;
+        self.data_size += bytes.len() as u32;
+        self.write_header()
+    }
+}
+impl AudioSink for WavWriter {
+    fn samples(&mut self, samples: &[i16]) {
+        self.append(samples).expect("Failed to write WAV file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wav_header_follows_written_samples() {
+        let path = std::env::temp_dir().join(format!("wav-writer-{}.wav", std::process::id()));
+        let mut writer = WavWriter::create(&path, 8000).unwrap();
+        writer.samples(&[1, -1]);
+        writer.samples(&[0x1234]);
+
+        let wav = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(wav.len(), WAV_HEADER_SIZE as usize + 6);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(wav[4..8], (36u32 + 6).to_le_bytes());
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(wav[24..28], 8000u32.to_le_bytes());
+        assert_eq!(wav[28..32], 16000u32.to_le_bytes());
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(wav[40..44], 6u32.to_le_bytes());
+        assert_eq!(&wav[44..], &[1, 0, 0xff, 0xff, 0x34, 0x12]);
+    }
+}
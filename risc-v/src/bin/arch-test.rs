@@ -0,0 +1,81 @@
+//! Run riscv-arch-test / riscv-tests binaries and report pass/fail per test
+//!
+//! Usage: arch-test [--references DIR] [--signatures DIR] [--max-steps N] TEST.elf...
+//!
+//! Reference signature of `name.elf` is looked up as `name.reference_output`
+//! in the references directory, or next to the binary when none is given.
+use risc_v::compliance::{ArchTest, SignatureCheck, TestReport};
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let mut references: Option<PathBuf> = None;
+    let mut signatures: Option<PathBuf> = None;
+    let mut max_steps = 10_000_000;
+    let mut binaries = vec![];
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--references" => references = args.next().map(PathBuf::from),
+            "--signatures" => signatures = args.next().map(PathBuf::from),
+            "--max-steps" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => max_steps = n,
+                None => {
+                    eprintln!("--max-steps expects a number");
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ => binaries.push(PathBuf::from(arg)),
+        }
+    }
+
+    if binaries.is_empty() {
+        eprintln!("usage: arch-test [--references DIR] [--signatures DIR] [--max-steps N] TEST.elf...");
+        return ExitCode::FAILURE;
+    }
+
+    let mut failed = 0;
+    for binary in binaries.iter() {
+        let mut test = match ArchTest::load(binary) {
+            Ok(test) => test,
+            Err(err) => {
+                println!("FAIL {}: {err}", binary.display());
+                failed += 1;
+                continue;
+            }
+        };
+
+        let outcome = test.run(max_steps);
+
+        if let Some(dir) = signatures.as_ref() {
+            let path = dir.join(format!("{}.signature", test.name));
+            if let Err(err) = std::fs::write(&path, test.signature_text()) {
+                eprintln!("failed to write {}: {err}", path.display());
+            }
+        }
+
+        let reference = match references.as_ref() {
+            Some(dir) => dir.join(format!("{}.reference_output", test.name)),
+            None => binary.with_extension("reference_output"),
+        };
+        let signature = match std::fs::read_to_string(&reference) {
+            Ok(text) => test.check_signature(&text),
+            Err(_) => SignatureCheck::Skipped,
+        };
+
+        let report = TestReport { name: test.name.clone(), outcome, signature, steps: test.steps() };
+        if !report.passed() {
+            failed += 1;
+        }
+        println!("{report}");
+    }
+
+    println!("{} passed, {failed} failed", binaries.len() - failed);
+
+    match failed {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
@@ -1,69 +1,846 @@
-use crate::Word;
-use crate::devices::Device;
+use crate::{Word, MemoryRange, Halt};
+use crate::devices::{AccessPolicy, Device, DeviceTrait, Properties};
+use crate::devices::ram::{Page, PAGE_SIZE};
+use crate::interrupts::{Interrupts, IrqLine};
+use crate::scheduler::{Cycle, DeviceContext, Scheduler};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::fmt::{Display, Formatter};
+
+/// Direction of a bus transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// Size of a single bus transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessWidth {
+    Byte,
+    Halfword,
+    Word,
+}
+impl AccessWidth {
+    /// Number of bytes moved by the transfer
+    pub fn bytes(self) -> u32 {
+        match self {
+            AccessWidth::Byte => 1,
+            AccessWidth::Halfword => 2,
+            AccessWidth::Word => 4,
+        }
+    }
+
+    /// Mask selecting the bits carried by the transfer
+    pub fn mask(self) -> Word {
+        match self {
+            AccessWidth::Byte => Word(0xFF),
+            AccessWidth::Halfword => Word(0xFFFF),
+            AccessWidth::Word => Word(0xFFFF_FFFF),
+        }
+    }
+
+    /// Return `true` if `address` is a multiple of the transfer size
+    pub fn is_aligned(self, address: Word) -> bool {
+        address.0.is_multiple_of(self.bytes())
+    }
+}
+
+/// Transfer observed on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub address: Word,
+    pub width: AccessWidth,
+    pub value: Word,
+}
+
+/// Reason a bus transfer failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusError {
+    /// No device is mapped at the address
+    Unmapped { address: Word },
+    /// Device mapped at the address doesn't serve transfers of this width
+    UnsupportedWidth { device: String, address: Word, width: AccessWidth },
+    /// Device mapped at the address is read-only
+    ReadOnly { device: String, address: Word },
+}
+impl BusError {
+    /// Address of the failed transfer
+    pub fn address(&self) -> Word {
+        match self {
+            BusError::Unmapped { address }
+            | BusError::UnsupportedWidth { address, .. }
+            | BusError::ReadOnly { address, .. } => *address,
+        }
+    }
+}
+impl Display for BusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BusError::Unmapped { address } => {
+                write!(f, "no device mapped at 0x{:08x}", address.0)
+            }
+            BusError::UnsupportedWidth { device, address, width } => {
+                write!(f, "{device} doesn't support {width:?} access at 0x{:08x}", address.0)
+            }
+            BusError::ReadOnly { device, address } => {
+                write!(f, "{device} is read-only, write at 0x{:08x}", address.0)
+            }
+        }
+    }
+}
+impl std::error::Error for BusError {}
+
+/// Reason a device couldn't be connected, moved or removed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// Range of the device overlaps a device already connected
+    Overlap { device: String, range: MemoryRange, existing: String, existing_range: MemoryRange },
+    /// Range of the device runs past the end of address space
+    OutOfAddressSpace { device: String, range: MemoryRange },
+    /// Handle doesn't refer to a connected device
+    NotConnected(DeviceHandle),
+    /// No more devices can be connected
+    TooManyDevices,
+}
+impl Display for MapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MapError::Overlap { device, range, existing, existing_range } => {
+                write!(f, "{device} at {} overlaps {existing} at {}", Span(*range), Span(*existing_range))
+            }
+            MapError::OutOfAddressSpace { device, range } => {
+                write!(f, "{device} at 0x{:08x} with size 0x{:x} runs past the end of address space", range.base().0, range.size().0)
+            }
+            MapError::NotConnected(handle) => write!(f, "no device connected with handle {handle:?}"),
+            MapError::TooManyDevices => write!(f, "too many devices connected"),
+        }
+    }
+}
+impl std::error::Error for MapError {}
+
+/// Formats range as `0x00001000-0x00001fff`
+struct Span(MemoryRange);
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let end = (self.0.base().0 as u64 + self.0.size().0 as u64).saturating_sub(1);
+        write!(f, "0x{:08x}-0x{end:08x}", self.0.base().0)
+    }
+}
+
+/// Stable reference to a connected device, returned by [`Bus::connect`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceHandle {
+    slot: usize,
+    generation: u32,
+}
+
+/// Device connected to the bus, as listed by [`Bus::memory_map`]
+#[derive(Debug, Clone)]
+pub struct MapEntry {
+    pub handle: DeviceHandle,
+    pub name: String,
+    pub range: MemoryRange,
+    pub policy: AccessPolicy,
+    pub properties: Properties,
+}
+impl Display for MapEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", Span(self.range), self.name)?;
+
+        match self.policy {
+            AccessPolicy::ReadWrite => Ok(()),
+            AccessPolicy::ReadOnly => write!(f, " (read-only)"),
+            AccessPolicy::WriteIgnored => write!(f, " (writes ignored)"),
+        }
+    }
+}
+
+/// Number of pages in the address space
+const PAGE_COUNT: usize = 1 << 20;
+
+/// Route of a page no device is mapped in
+const UNMAPPED: u16 = 0;
+
+/// Route of a page several devices are mapped in
+const SHARED: u16 = u16::MAX;
+
+/// Pages in one block of the direct access table
+const FAST_BLOCK: usize = 1024;
+
+/// Directly accessed pages of [`FAST_BLOCK`] consecutive pages
+type FastBlock = Box<[Option<Page>]>;
+
+/// Place for a device, reused after the device is disconnected
+struct Slot {
+    /// Incremented every time the slot is reused, so old handles don't match
+    generation: u32,
+    device: Option<Device>,
+}
 
 pub struct Bus {
-    devices: Vec<Device>,
+    devices: Vec<Slot>,
+
+    /// Slot of the device mapped in each page plus one,
+    /// or [`UNMAPPED`] or [`SHARED`]
+    routes: Vec<u16>,
+
+    /// Slots of devices sorted by base address,
+    /// searched when a page is shared by several devices
+    sorted: Vec<usize>,
+
+    /// RAM pages accessed directly instead of through their device,
+    /// in blocks of [`FAST_BLOCK`] pages allocated on first use
+    fast: RefCell<Vec<Option<FastBlock>>>,
+
+    /// Events devices scheduled for future cycles
+    scheduler: Rc<Scheduler>,
+
+    /// Lines devices raise interrupts on
+    interrupts: Rc<Interrupts>,
+
+    /// Transfers collected while recording
+    access_log: RefCell<Option<Vec<Access>>>,
+
+    /// Reason the machine was stopped, by the hart or by a device
+    halt: Cell<Option<Halt>>,
 }
 impl Bus {
     pub fn new() -> Self {
-        Self { devices: vec![] }
+        Self {
+            devices: vec![],
+            routes: vec![UNMAPPED; PAGE_COUNT],
+            sorted: vec![],
+            fast: RefCell::new(vec![None; PAGE_COUNT / FAST_BLOCK]),
+            scheduler: Rc::new(Scheduler::new()),
+            interrupts: Rc::new(Interrupts::new()),
+            access_log: RefCell::new(None),
+            halt: Cell::new(None),
+        }
+    }
+
+    /// Map `device` into its range, return handle to reach it later
+    pub fn connect(&mut self, device: Device) -> Result<DeviceHandle, MapError> {
+        check_address_space(&device.name(), device.range)?;
+        self.check_overlap(&device.name(), device.range, None)?;
+
+        let slot = match self.devices.iter().position(|s| s.device.is_none()) {
+            Some(slot) => slot,
+            None if self.devices.len() + 1 < SHARED as usize => {
+                self.devices.push(Slot { generation: 0, device: None });
+                self.devices.len() - 1
+            }
+            None => return Err(MapError::TooManyDevices),
+        };
+
+        for page in pages(device.range) {
+            self.routes[page] = match self.routes[page] {
+                UNMAPPED => slot as u16 + 1,
+                _ => SHARED,
+            };
+        }
+
+        let position = self.sorted.partition_point(|&i| self.slot(i).range.base < device.range.base);
+        self.sorted.insert(position, slot);
+
+        let entry = &mut self.devices[slot];
+        entry.generation += 1;
+        let handle = DeviceHandle { slot, generation: entry.generation };
+
+        device.device.attach(DeviceContext { handle, scheduler: self.scheduler.clone() });
+        entry.device = Some(device);
+        Ok(handle)
+    }
+
+    /// Unmap device and give it back
+    pub fn disconnect(&mut self, handle: DeviceHandle) -> Result<Device, MapError> {
+        self.get(handle).ok_or(MapError::NotConnected(handle))?;
+
+        let device = self.devices[handle.slot].device.take().unwrap();
+        self.rebuild();
+        Ok(device)
+    }
+
+    /// Move device to `range`, it keeps its state and handle
+    pub fn remap(&mut self, handle: DeviceHandle, range: MemoryRange) -> Result<(), MapError> {
+        let name = self.get(handle).ok_or(MapError::NotConnected(handle))?.name();
+        check_address_space(&name, range)?;
+        self.check_overlap(&name, range, Some(handle.slot))?;
+
+        self.devices[handle.slot].device.as_mut().unwrap().range = range;
+        self.rebuild();
+        Ok(())
+    }
+
+    /// Connected device `handle` refers to
+    pub fn get(&self, handle: DeviceHandle) -> Option<&Device> {
+        self.devices.get(handle.slot)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.device.as_ref())
+    }
+
+    /// Connected device `handle` refers to as its concrete type,
+    /// `None` if it is of another type
+    pub fn device<T: DeviceTrait + 'static>(&self, handle: DeviceHandle) -> Option<&T> {
+        self.get(handle)?.device.as_ref().as_any().downcast_ref::<T>()
+    }
+
+    /// Connected devices ordered by base address
+    pub fn memory_map(&self) -> Vec<MapEntry> {
+        self.sorted.iter()
+            .map(|&slot| (slot, self.slot(slot)))
+            .map(|(slot, device)| MapEntry {
+                handle: DeviceHandle { slot, generation: self.devices[slot].generation },
+                name: device.name(),
+                range: device.range,
+                policy: device.policy,
+                properties: device.device.properties(),
+            })
+            .collect()
+    }
+
+    fn check_overlap(&self, name: &str, range: MemoryRange, ignored: Option<usize>) -> Result<(), MapError> {
+        let existing = self.devices.iter()
+            .enumerate()
+            .filter(|(slot, _)| Some(*slot) != ignored)
+            .filter_map(|(_, slot)| slot.device.as_ref())
+            .find(|device| device.range.intersects(range));
+
+        match existing {
+            None => Ok(()),
+            Some(existing) => Err(MapError::Overlap {
+                device: name.into(),
+                range,
+                existing: existing.name(),
+                existing_range: existing.range,
+            }),
+        }
     }
 
-    pub fn connect(&mut self, device: Device) -> Result<(), ()> {
-        for dev in self.devices.iter() {
-            if dev.range.intersects(device.range) {
-                return Err(());
+    /// Device in occupied `slot`
+    fn slot(&self, slot: usize) -> &Device {
+        self.devices[slot].device.as_ref().unwrap()
+    }
+
+    /// Recompute routes after a device was removed or moved
+    fn rebuild(&mut self) {
+        self.routes.fill(UNMAPPED);
+        self.sorted.clear();
+        self.fast.get_mut().fill(None);
+
+        for slot in 0..self.devices.len() {
+            let Some(device) = self.devices[slot].device.as_ref() else {
+                continue;
+            };
+
+            for page in pages(device.range) {
+                self.routes[page] = match self.routes[page] {
+                    UNMAPPED => slot as u16 + 1,
+                    _ => SHARED,
+                };
+            }
+            self.sorted.push(slot);
+        }
+
+        let devices = &self.devices;
+        self.sorted.sort_by_key(|&slot| devices[slot].device.as_ref().unwrap().range.base);
+    }
+
+    /// Read `width` bytes at `address`, little endian.
+    /// Misaligned transfers are split into byte transfers.
+    pub fn read(&self, address: Word, width: AccessWidth) -> Result<Word, BusError> {
+        let value = if width.is_aligned(address) {
+            self.read_aligned(address, width)?
+        } else {
+            let mut value = Word(0);
+            for i in 0..width.bytes() {
+                let byte = self.read_aligned(address + Word(i), AccessWidth::Byte)?;
+                value |= byte << Word(8 * i);
             }
+            value
+        };
+
+        self.log(AccessKind::Read, address, width, value);
+        Ok(value)
+    }
+
+    /// Write low `width` bytes of `word` at `address`, little endian.
+    /// Misaligned transfers are split into byte transfers.
+    pub fn write(&self, address: Word, word: Word, width: AccessWidth) -> Result<(), BusError> {
+        let word = word & width.mask();
+        self.log(AccessKind::Write, address, width, word);
+
+        if width.is_aligned(address) {
+            return self.write_aligned(address, word, width);
         }
 
-        self.devices.push(device);
+        for i in 0..width.bytes() {
+            let byte = (word >> Word(8 * i)) & Word(0xFF);
+            self.write_aligned(address + Word(i), byte, AccessWidth::Byte)?;
+        }
         Ok(())
-    } 
+    }
 
-    pub fn read(&self, address: Word) -> Word {
-        for device in self.devices.iter() {
-            if device.range.contains(address) {
-                return device.device.read(address);
+    fn read_aligned(&self, address: Word, width: AccessWidth) -> Result<Word, BusError> {
+        let direct = self.with_page(address, false, |page, offset| {
+            let mut value = 0;
+            for i in (0..width.bytes() as usize).rev() {
+                value = (value << 8) | page[offset + i].get() as u32;
             }
+            Word(value)
+        });
+        if let Some(value) = direct {
+            return Ok(value);
         }
 
-        // FIXME: What happens when CPU reads open bus?
-        Word(0)
+        let device = self.device_at(address)?;
+        Self::check_width(device, address, width)?;
+
+        Ok(device.read(address, width) & width.mask())
     }
 
-    pub fn write(&self, address: Word, word: Word) {
-        for device in self.devices.iter() {
-            if device.range.contains(address) {
-                device.device.write(address, word);
+    fn write_aligned(&self, address: Word, word: Word, width: AccessWidth) -> Result<(), BusError> {
+        let direct = self.with_page(address, true, |page, offset| {
+            for (i, byte) in word.0.to_le_bytes().iter().take(width.bytes() as usize).enumerate() {
+                page[offset + i].set(*byte);
             }
+        });
+        if direct.is_some() {
+            return Ok(());
         }
+
+        let device = self.device_at(address)?;
+        Self::check_width(device, address, width)?;
+
+        match device.policy {
+            AccessPolicy::ReadWrite => device.write(address, word, width),
+            AccessPolicy::WriteIgnored => (),
+            AccessPolicy::ReadOnly => {
+                return Err(BusError::ReadOnly { device: device.device.name(), address });
+            }
+        }
+        Ok(())
     }
 
-    pub fn load(&self, offset: Word, data: &[u8]) {
+    fn device_at(&self, address: Word) -> Result<&Device, BusError> {
+        let device = match self.routes[page_of(address)] {
+            UNMAPPED => None,
+            SHARED => {
+                // Last device starting at or below the address
+                let position = self.sorted.partition_point(|&i| self.slot(i).range.base <= address);
+                position.checked_sub(1).map(|p| self.slot(self.sorted[p]))
+            }
+            route => Some(self.slot(route as usize - 1)),
+        };
+
+        device
+            .filter(|device| device.range.contains(address))
+            .ok_or(BusError::Unmapped { address })
+    }
+
+    /// Run `f` with the RAM page holding `address` and offset within it,
+    /// `None` if the page can't be accessed directly
+    fn with_page<T, F>(&self, address: Word, allocate: bool, f: F) -> Option<T>
+    where
+        F: FnOnce(&[Cell<u8>; PAGE_SIZE], usize) -> T,
+    {
+        let page = page_of(address);
+        let offset = address.0 as usize % PAGE_SIZE;
+        let (block, index) = (page / FAST_BLOCK, page % FAST_BLOCK);
+
+        if let Some(cached) = self.fast.borrow()[block].as_ref().and_then(|b| b[index].as_ref()) {
+            return Some(f(cached, offset));
+        }
+
+        // Only whole pages of writable memory are accessed directly
+        let device = match self.routes[page] {
+            UNMAPPED | SHARED => return None,
+            route => self.slot(route as usize - 1),
+        };
+        let start = Word((page * PAGE_SIZE) as u32);
+        let end = device.range.base.0 as u64 + device.range.offset.0 as u64;
+        let whole = start >= device.range.base && start.0 as u64 + PAGE_SIZE as u64 <= end;
+        if device.policy != AccessPolicy::ReadWrite || !whole {
+            return None;
+        }
+
+        let backing = device.device.page(start - device.range.base, allocate)?;
+        let result = f(&backing, offset);
+
+        let mut fast = self.fast.borrow_mut();
+        let block = fast[block].get_or_insert_with(|| vec![None; FAST_BLOCK].into_boxed_slice());
+        block[index] = Some(backing);
+
+        Some(result)
+    }
+
+    fn check_width(device: &Device, address: Word, width: AccessWidth) -> Result<(), BusError> {
+        match device.device.supports(width) {
+            true => Ok(()),
+            false => Err(BusError::UnsupportedWidth { device: device.device.name(), address, width }),
+        }
+    }
+
+    /// Write `data` starting at `offset`, stopping at the first failed transfer
+    pub fn load(&self, offset: Word, data: &[u8]) -> Result<(), BusError> {
         for (i, byte) in data.iter().enumerate() {
-            self.write(offset + Word(i as u32), Word(*byte as u32));
+            self.write(offset + Word(i as u32), Word(*byte as u32), AccessWidth::Byte)?;
+        }
+        Ok(())
+    }
+
+    /// Read `length` consecutive bytes starting at `offset`
+    pub fn read_bytes(&self, offset: Word, length: usize) -> Result<Vec<u8>, BusError> {
+        (0..length)
+            .map(|i| self.read(offset + Word(i as u32), AccessWidth::Byte).map(|b| b.0 as u8))
+            .collect()
+    }
+
+    /// Read null terminated string starting at `offset`, without the terminator
+    pub fn read_cstr(&self, offset: Word) -> Result<Vec<u8>, BusError> {
+        let mut string = vec![];
+        let mut address = offset;
+
+        loop {
+            match self.read(address, AccessWidth::Byte)?.0 as u8 {
+                0 => return Ok(string),
+                byte => string.push(byte),
+            }
+            address += Word(1);
         }
     }
 
+    /// Read a byte for inspection, `0` if the transfer fails
+    pub fn read_byte(&self, offset: Word) -> u8 {
+        self.read(offset, AccessWidth::Byte).unwrap_or_default().0 as u8
+    }
+
+    /// Read a little endian word for inspection, `0` if the transfer fails
     pub fn read_le_word(&self, offset: Word) -> Word {
-        let (b1, b2, b3, b4) = (
-            self.read(offset + Word(0)),
-            self.read(offset + Word(1)),
-            self.read(offset + Word(2)),
-            self.read(offset + Word(3)),
-        );
-    
-        let le_word = Word::from_le_bytes([
-            b1.0 as u8, 
-            b2.0 as u8, 
-            b3.0 as u8, 
-            b4.0 as u8
+        self.read(offset, AccessWidth::Word).unwrap_or_default()
+    }
+
+    pub fn scheduler(&self) -> &Rc<Scheduler> {
+        &self.scheduler
+    }
+
+    pub fn interrupts(&self) -> &Rc<Interrupts> {
+        &self.interrupts
+    }
+
+    /// Output for a device raising interrupts on `line`
+    pub fn interrupt_line(&self, line: u32) -> IrqLine {
+        IrqLine::new(self.interrupts.clone(), line)
+    }
+
+    /// Stop the machine, the hart returns from `run` after its current instruction
+    pub fn halt(&self, reason: Halt) {
+        self.halt.set(Some(reason));
+    }
+
+    /// Reason the machine stopped, `None` if it is still running
+    pub fn halted(&self) -> Option<Halt> {
+        self.halt.get()
+    }
+
+    /// Forget the halt reason so the machine can run again
+    pub fn resume(&self) {
+        self.halt.set(None);
+    }
+
+    /// Reset every device and forget the halt reason, memory is kept
+    pub fn reset(&self) {
+        for slot in &self.devices {
+            if let Some(device) = &slot.device {
+                device.device.reset();
+            }
+        }
+        self.resume();
+    }
+
+    /// Current cycle
+    pub fn now(&self) -> Cycle {
+        self.scheduler.now()
+    }
+
+    /// Let `cycles` cycles pass, running events due in that time
+    pub fn advance(&self, cycles: Cycle) {
+        self.scheduler.advance_to(self.now() + cycles, self);
+    }
+
+    /// Skip to the next pending event and run it,
+    /// return `false` if there is none
+    pub fn skip_to_next_event(&self) -> bool {
+        match self.scheduler.next_event() {
+            Some(cycle) => {
+                self.scheduler.advance_to(cycle, self);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Start collecting bus transfers,
+    /// previously collected transfers are discarded
+    pub fn record_accesses(&self) {
+        *self.access_log.borrow_mut() = Some(vec![]);
+    }
+
+    /// Stop collecting bus transfers and return them in the order they happened
+    pub fn take_accesses(&self) -> Vec<Access> {
+        self.access_log.borrow_mut().take().unwrap_or_default()
+    }
+
+    fn log(&self, kind: AccessKind, address: Word, width: AccessWidth, value: Word) {
+        if let Some(log) = self.access_log.borrow_mut().as_mut() {
+            log.push(Access { kind, address, width, value });
+        }
+    }
+}
+
+/// Reject `range` if it doesn't end within the 32-bit address space
+fn check_address_space(name: &str, range: MemoryRange) -> Result<(), MapError> {
+    match range.base.0 as u64 + range.offset.0 as u64 > 1 << 32 {
+        true => Err(MapError::OutOfAddressSpace { device: name.into(), range }),
+        false => Ok(()),
+    }
+}
+
+fn page_of(address: Word) -> usize {
+    address.0 as usize / PAGE_SIZE
+}
+
+/// Indices of pages `range` overlaps
+fn pages(range: MemoryRange) -> std::ops::Range<usize> {
+    let start = range.base.0 as u64;
+    let end = start + range.offset.0 as u64;
+    let page = PAGE_SIZE as u64;
+
+    (start / page) as usize..end.div_ceil(page) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RV32;
+    use crate::devices::ram::Ram;
+    use crate::exception::Exception;
+
+    /// Device that logs transfers it sees, every byte reads as the low byte of its offset
+    struct Probe {
+        words_only: bool,
+        log: RefCell<Vec<(AccessKind, Word, AccessWidth, Word)>>,
+    }
+    impl Probe {
+        fn new(words_only: bool) -> Self {
+            Self { words_only, log: RefCell::new(vec![]) }
+        }
+    }
+    impl DeviceTrait for Probe {
+        fn name(&self) -> String {
+            "probe".into()
+        }
+        fn supports(&self, width: AccessWidth) -> bool {
+            !self.words_only || width == AccessWidth::Word
+        }
+        fn read(&self, address: Word, width: AccessWidth) -> Word {
+            let bytes = (0..4).map(|i| address.0.wrapping_add(i) as u8).collect::<Vec<_>>();
+            let value = Word::from_le_bytes(bytes.try_into().unwrap());
+            self.log.borrow_mut().push((AccessKind::Read, address, width, value));
+            value
+        }
+        fn write(&self, address: Word, word: Word, width: AccessWidth) {
+            self.log.borrow_mut().push((AccessKind::Write, address, width, word));
+        }
+    }
+
+    fn probe(bus: &mut Bus, base: u32, probe: Probe, policy: AccessPolicy) -> DeviceHandle {
+        let device = Device::new(MemoryRange::new(Word(base), Word(0x100)), Box::new(probe));
+        bus.connect(device.with_policy(policy)).unwrap()
+    }
+
+    fn log(bus: &Bus, handle: DeviceHandle) -> Vec<(AccessKind, Word, AccessWidth, Word)> {
+        bus.device::<Probe>(handle).unwrap().log.take()
+    }
+
+    #[test]
+    fn transfers_reach_the_device_with_their_width() {
+        let mut bus = Bus::new();
+        let handle = probe(&mut bus, 0x1000, Probe::new(false), AccessPolicy::ReadWrite);
+
+        assert_eq!(bus.read(Word(0x1004), AccessWidth::Word), Ok(Word(0x0706_0504)));
+        assert_eq!(bus.read(Word(0x1006), AccessWidth::Halfword), Ok(Word(0x0706)));
+        assert_eq!(bus.read(Word(0x1007), AccessWidth::Byte), Ok(Word(0x07)));
+        bus.write(Word(0x1008), Word(0x1234_5678), AccessWidth::Halfword).unwrap();
+
+        // Offsets within the device, values masked to the width
+        assert_eq!(log(&bus, handle), vec![
+            (AccessKind::Read, Word(4), AccessWidth::Word, Word(0x0706_0504)),
+            (AccessKind::Read, Word(6), AccessWidth::Halfword, Word(0x0908_0706)),
+            (AccessKind::Read, Word(7), AccessWidth::Byte, Word(0x0A09_0807)),
+            (AccessKind::Write, Word(8), AccessWidth::Halfword, Word(0x5678)),
         ]);
+        assert_eq!(bus.read(Word(0x1100), AccessWidth::Byte), Err(BusError::Unmapped { address: Word(0x1100) }));
+    }
+
+    #[test]
+    fn misaligned_transfers_are_split_into_bytes() {
+        let mut bus = Bus::new();
+        let handle = probe(&mut bus, 0x1000, Probe::new(false), AccessPolicy::ReadWrite);
 
-        le_word
+        assert_eq!(bus.read(Word(0x1003), AccessWidth::Word), Ok(Word(0x0605_0403)));
+        assert_eq!(log(&bus, handle).iter().map(|&(_, a, w, _)| (a, w)).collect::<Vec<_>>(),
+                   (3..7).map(|a| (Word(a), AccessWidth::Byte)).collect::<Vec<_>>());
+
+        bus.write(Word(0x1001), Word(0xAABB), AccessWidth::Halfword).unwrap();
+        assert_eq!(log(&bus, handle), vec![
+            (AccessKind::Write, Word(1), AccessWidth::Byte, Word(0xBB)),
+            (AccessKind::Write, Word(2), AccessWidth::Byte, Word(0xAA)),
+        ]);
+
+        // Bytes land the same way in RAM, across a page boundary
+        let mut bus = Bus::new();
+        bus.connect(Device::new(MemoryRange::new(Word(0), Word(0x2000)), Box::new(Ram::new(0x2000)))).unwrap();
+        bus.write(Word(0xFFE), Word(0x4433_2211), AccessWidth::Word).unwrap();
+        assert_eq!(bus.read_bytes(Word(0xFFE), 4), Ok(vec![0x11, 0x22, 0x33, 0x44]));
+        assert_eq!(bus.read(Word(0xFFF), AccessWidth::Halfword), Ok(Word(0x3322)));
     }
 
-    pub fn tick(&self) {
-        self.devices.iter().for_each(|d| d.device.tick());
+    #[test]
+    fn unsupported_widths_fault_without_reaching_the_device() {
+        let mut cpu = RV32::new();
+        let handle = probe(&mut cpu.bus, 0x1000, Probe::new(true), AccessPolicy::ReadWrite);
+
+        let error = BusError::UnsupportedWidth { device: "probe".into(), address: Word(0x1000), width: AccessWidth::Byte };
+        assert_eq!(cpu.bus.read(Word(0x1000), AccessWidth::Byte), Err(error));
+        assert_eq!(cpu.load(Word(0x1002), AccessWidth::Halfword), Err(Exception::LoadAccessFault(Word(0x1002))));
+        assert_eq!(cpu.store(Word(0x1001), Word(0), AccessWidth::Byte), Err(Exception::StoreAccessFault(Word(0x1001))));
+        // Split into bytes, which the device refuses as well
+        assert_eq!(cpu.load(Word(0x1001), AccessWidth::Word), Err(Exception::LoadAccessFault(Word(0x1001))));
+        assert!(log(&cpu.bus, handle).is_empty());
+
+        assert_eq!(cpu.load(Word(0x1004), AccessWidth::Word), Ok(Word(0x0706_0504)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn ranges_past_the_end_of_address_space_are_rejected() {
+        let mut bus = Bus::new();
+        let range = MemoryRange::new(Word(0xFFFF_F000), Word(0x2000));
+        let error = MapError::OutOfAddressSpace { device: "RAM 8 KiB".into(), range };
+        assert_eq!(bus.connect(Device::new(range, Box::new(Ram::new(0x2000)))).err(), Some(error));
+
+        // Last page of address space is fine
+        let last = MemoryRange::new(Word(0xFFFF_F000), Word(0x1000));
+        let handle = bus.connect(Device::new(last, Box::new(Ram::new(0x1000)))).unwrap();
+        bus.write(Word(0xFFFF_FFFC), Word(7), AccessWidth::Word).unwrap();
+        assert_eq!(bus.read(Word(0xFFFF_FFFC), AccessWidth::Word), Ok(Word(7)));
+
+        let moved = MemoryRange::new(Word(0xFFFF_F800), Word(0x1000));
+        assert!(matches!(bus.remap(handle, moved), Err(MapError::OutOfAddressSpace { .. })));
+        assert_eq!(bus.get(handle).unwrap().range, last);
+    }
+
+    #[test]
+    fn access_policy_decides_what_writes_do() {
+        let mut cpu = RV32::new();
+        let read_only = probe(&mut cpu.bus, 0x1000, Probe::new(false), AccessPolicy::ReadOnly);
+        let ignored = probe(&mut cpu.bus, 0x2000, Probe::new(false), AccessPolicy::WriteIgnored);
+
+        let error = BusError::ReadOnly { device: "probe".into(), address: Word(0x1000) };
+        assert_eq!(cpu.bus.write(Word(0x1000), Word(1), AccessWidth::Word), Err(error));
+        assert_eq!(cpu.store(Word(0x1004), Word(1), AccessWidth::Byte), Err(Exception::StoreAccessFault(Word(0x1004))));
+        assert_eq!(cpu.load(Word(0x1004), AccessWidth::Byte), Ok(Word(0x04)));
+        assert_eq!(log(&cpu.bus, read_only).len(), 1);
+
+        assert_eq!(cpu.store(Word(0x2000), Word(1), AccessWidth::Word), Ok(()));
+        assert_eq!(cpu.load(Word(0x2000), AccessWidth::Word), Ok(Word(0x0302_0100)));
+        assert_eq!(log(&cpu.bus, ignored).len(), 1);
+
+        // Read-only RAM is never written through the direct access path
+        let ram = Device::new(MemoryRange::new(Word(0x10000), Word(0x1000)), Box::new(Ram::new(0x1000)));
+        cpu.bus.connect(ram.with_policy(AccessPolicy::ReadOnly)).unwrap();
+        assert_eq!(cpu.store(Word(0x10000), Word(1), AccessWidth::Word), Err(Exception::StoreAccessFault(Word(0x10000))));
+        assert_eq!(cpu.load(Word(0x10000), AccessWidth::Word), Ok(Word(0)));
+    }
+
+    fn ram(base: u32, size: u32) -> Device {
+        Device::new(MemoryRange::new(Word(base), Word(size)), Box::new(Ram::new(size as usize)))
+    }
+
+    #[test]
+    fn stale_handles_miss_a_reused_slot() {
+        let mut bus = Bus::new();
+        let old = bus.connect(ram(0x1000, 0x1000)).unwrap();
+        bus.disconnect(old).unwrap();
+
+        let new = bus.connect(ram(0x4000, 0x2000)).unwrap();
+        assert_ne!(old, new);
+        assert!(bus.get(old).is_none());
+        assert!(bus.device::<Ram>(old).is_none());
+        assert_eq!(bus.disconnect(old).err(), Some(MapError::NotConnected(old)));
+        assert_eq!(bus.remap(old, MemoryRange::new(Word(0), Word(0x1000))), Err(MapError::NotConnected(old)));
+        assert_eq!(bus.device::<Ram>(new).unwrap().size(), 0x2000);
+    }
+
+    #[test]
+    fn overlap_names_both_devices() {
+        let mut bus = Bus::new();
+        bus.connect(ram(0x1000, 0x2000)).unwrap();
+        let other = probe(&mut bus, 0x4000, Probe::new(false), AccessPolicy::ReadWrite);
+
+        let error = bus.connect(ram(0x2800, 0x1000)).err().unwrap();
+        assert_eq!(error, MapError::Overlap {
+            device: "RAM 4 KiB".into(),
+            range: MemoryRange::new(Word(0x2800), Word(0x1000)),
+            existing: "RAM 8 KiB".into(),
+            existing_range: MemoryRange::new(Word(0x1000), Word(0x2000)),
+        });
+        assert_eq!(error.to_string(), "RAM 4 KiB at 0x00002800-0x000037ff overlaps RAM 8 KiB at 0x00001000-0x00002fff");
+
+        let error = bus.remap(other, MemoryRange::new(Word(0x2000), Word(0x100))).unwrap_err();
+        assert!(matches!(error, MapError::Overlap { device, existing, .. } if device == "probe" && existing == "RAM 8 KiB"));
+        // Moving onto its own range is fine
+        bus.remap(other, MemoryRange::new(Word(0x4080), Word(0x100))).unwrap();
+    }
+
+    #[test]
+    fn remap_and_disconnect_forget_cached_pages() {
+        let mut bus = Bus::new();
+        let handle = bus.connect(ram(0x10000, 0x2000)).unwrap();
+        bus.write(Word(0x10000), Word(0x1111_1111), AccessWidth::Word).unwrap();
+
+        // Old range misses after the move, the cached page can't serve it
+        bus.remap(handle, MemoryRange::new(Word(0x20000), Word(0x2000))).unwrap();
+        assert_eq!(bus.read(Word(0x10000), AccessWidth::Word), Err(BusError::Unmapped { address: Word(0x10000) }));
+        assert_eq!(bus.read(Word(0x20000), AccessWidth::Word), Ok(Word(0x1111_1111)));
+
+        // New device in the old range gets its own memory
+        let other = bus.connect(ram(0x10000, 0x1000)).unwrap();
+        assert_eq!(bus.read(Word(0x10000), AccessWidth::Word), Ok(Word(0)));
+        bus.disconnect(other).unwrap();
+        assert!(bus.read(Word(0x10000), AccessWidth::Word).is_err());
+    }
+
+    #[test]
+    fn shared_pages_follow_remap_and_disconnect() {
+        let mut bus = Bus::new();
+        // Both devices in the page at 0x1000
+        let first = probe(&mut bus, 0x1000, Probe::new(false), AccessPolicy::ReadWrite);
+        let second = probe(&mut bus, 0x1800, Probe::new(false), AccessPolicy::ReadWrite);
+        assert_eq!(bus.routes[1], SHARED);
+
+        assert_eq!(bus.read(Word(0x1804), AccessWidth::Byte), Ok(Word(4)));
+        assert_eq!(log(&bus, second).len(), 1);
+        assert!(bus.read(Word(0x1400), AccessWidth::Byte).is_err());
+
+        // Second device alone in its page, first one gone
+        bus.remap(second, MemoryRange::new(Word(0x3000), Word(0x100))).unwrap();
+        bus.disconnect(first).unwrap();
+        assert_eq!(bus.routes[1], UNMAPPED);
+        assert!(bus.read(Word(0x1000), AccessWidth::Byte).is_err());
+        assert!(bus.read(Word(0x1804), AccessWidth::Byte).is_err());
+        assert_eq!(bus.read(Word(0x3004), AccessWidth::Byte), Ok(Word(4)));
+        assert_eq!(bus.memory_map().iter().map(|e| e.range.base()).collect::<Vec<_>>(), vec![Word(0x3000)]);
+    }
+}
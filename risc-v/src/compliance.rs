@@ -0,0 +1,219 @@
+//! Harness for riscv-arch-test and riscv-tests style test binaries
+//!
+//! A test is an ELF executable linked at [`RAM_BASE`]. It runs until it writes
+//! a non-zero value to the `tohost` symbol. riscv-tests report the result in
+//! that value: `1` means pass, `(n << 1) | 1` means test case `n` failed.
+//! riscv-arch-test binaries write their results between the `begin_signature`
+//! and `end_signature` symbols instead, which are compared against
+//! a reference signature, one 32-bit hex word per line.
+use crate::{RV32, Word, MemoryRange};
+use crate::devices::{Device, ram::Ram};
+use crate::elf::{Elf, ElfError};
+use crate::exception::Exception;
+
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// Address the tests are linked at
+pub const RAM_BASE: Word = Word(0x8000_0000);
+
+/// Size of memory available to the tests
+pub const RAM_SIZE: Word = Word(4 * 1024 * 1024);
+
+/// How test run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// `tohost` was written with `1`
+    Pass,
+    /// `tohost` was written with failed test case number
+    Fail(u32),
+    /// Machine raised an exception at given program counter
+    Exception(Exception, Word),
+    /// `tohost` wasn't written within step limit
+    Timeout,
+}
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail(case) => write!(f, "test case {case} failed"),
+            Outcome::Exception(exception, pc) => write!(f, "{exception:?} at 0x{:08x}", pc.0),
+            Outcome::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+/// Result of comparing signature with the reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureCheck {
+    /// No reference was given or binary has no signature region
+    Skipped,
+    Match,
+    /// Signatures differ first at given line (counting from 1)
+    Mismatch { line: usize, expected: String, actual: String },
+}
+
+/// Result of one test binary
+#[derive(Debug, Clone)]
+pub struct TestReport {
+    pub name: String,
+    pub outcome: Outcome,
+    pub signature: SignatureCheck,
+    pub steps: u64,
+}
+impl TestReport {
+    /// Test passes when it finished by writing `tohost` and its signature,
+    /// if checked, matches the reference
+    pub fn passed(&self) -> bool {
+        matches!(self.outcome, Outcome::Pass)
+            && !matches!(self.signature, SignatureCheck::Mismatch { .. })
+    }
+}
+impl Display for TestReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = if self.passed() { "PASS" } else { "FAIL" };
+        write!(f, "{status} {} ({}, {} steps)", self.name, self.outcome, self.steps)?;
+
+        match &self.signature {
+            SignatureCheck::Skipped => Ok(()),
+            SignatureCheck::Match => write!(f, ", signature matches"),
+            SignatureCheck::Mismatch { line, expected, actual } => {
+                write!(f, ", signature line {line} is `{actual}`, expected `{expected}`")
+            }
+        }
+    }
+}
+
+/// Test binary loaded into a fresh machine
+pub struct ArchTest {
+    pub name: String,
+    pub cpu: RV32,
+    tohost: Word,
+    signature: Option<(Word, Word)>,
+    steps: u64,
+}
+impl ArchTest {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ElfError> {
+        let name = path.as_ref()
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        Self::from_elf(name, &Elf::open(path)?)
+    }
+
+    pub fn from_elf(name: String, elf: &Elf) -> Result<Self, ElfError> {
+        let tohost = elf.symbol("tohost")
+            .ok_or(ElfError::Unsupported("missing `tohost` symbol"))?
+            .value;
+        let signature = elf.symbol("begin_signature")
+            .zip(elf.symbol("end_signature"))
+            .map(|(begin, end)| (begin.value, end.value));
+
+        let cpu = machine();
+        elf.load(&cpu.bus)?;
+        cpu.reg.write("pc", elf.entry).unwrap();
+
+        Ok(Self { name, cpu, tohost, signature, steps: 0 })
+    }
+
+    /// Run the test until it writes `tohost` or `max_steps` instructions execute
+    pub fn run(&mut self, max_steps: u64) -> Outcome {
+        while self.steps < max_steps {
+            let pc = self.cpu.reg.read("pc").unwrap();
+            if let Err(exception) = self.cpu.step() {
+                return Outcome::Exception(exception, pc);
+            }
+            self.steps += 1;
+
+            match self.cpu.bus.read_le_word(self.tohost) {
+                Word(0) => (),
+                Word(1) => return Outcome::Pass,
+                Word(code) => return Outcome::Fail(code >> 1),
+            }
+        }
+
+        Outcome::Timeout
+    }
+
+    /// Number of instructions executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Words of the signature region, empty if binary has none
+    pub fn signature(&self) -> Vec<Word> {
+        let Some((begin, end)) = self.signature else {
+            return vec![];
+        };
+
+        (begin.0..end.0)
+            .step_by(4)
+            .map(|address| self.cpu.bus.read_le_word(Word(address)))
+            .collect()
+    }
+
+    /// Signature in the reference format, one 32-bit hex word per line
+    pub fn signature_text(&self) -> String {
+        self.signature()
+            .iter()
+            .map(|word| format!("{:08x}\n", word.0))
+            .collect()
+    }
+
+    /// Compare signature with the contents of a reference file
+    pub fn check_signature(&self, reference: &str) -> SignatureCheck {
+        if self.signature.is_none() {
+            return SignatureCheck::Skipped;
+        }
+
+        let actual = self.signature_text();
+        let mut expected_lines = reference.lines().map(str::trim).filter(|l| !l.is_empty());
+        let mut actual_lines = actual.lines();
+
+        for line in 1.. {
+            match (expected_lines.next(), actual_lines.next()) {
+                (None, None) => break,
+                (e, a) if e.map(str::to_lowercase).as_deref() == a => continue,
+                (e, a) => {
+                    return SignatureCheck::Mismatch {
+                        line,
+                        expected: e.unwrap_or("<end>").into(),
+                        actual: a.unwrap_or("<end>").into(),
+                    };
+                }
+            }
+        }
+
+        SignatureCheck::Match
+    }
+}
+
+/// Create machine with memory the tests expect, exceptions trap to the
+/// handler the test installs
+pub fn machine() -> RV32 {
+    let mut cpu = RV32::new();
+    cpu.traps = true;
+
+    let range = MemoryRange::new(RAM_BASE, RAM_SIZE);
+    cpu.bus.connect(Device::new(range, Box::new(Ram::new(RAM_SIZE.0 as usize)))).unwrap();
+
+    cpu
+}
+
+/// Load and run a test binary, comparing its signature against
+/// the `reference` file when given
+pub fn run_test<P: AsRef<Path>>(elf: P, reference: Option<&Path>, max_steps: u64) -> Result<TestReport, ElfError> {
+    let mut test = ArchTest::load(elf)?;
+    let outcome = test.run(max_steps);
+
+    let signature = match reference {
+        None => SignatureCheck::Skipped,
+        Some(path) => {
+            let reference = std::fs::read_to_string(path).map_err(ElfError::Io)?;
+            test.check_signature(&reference)
+        }
+    };
+
+    Ok(TestReport { name: test.name.clone(), outcome, signature, steps: test.steps() })
+}
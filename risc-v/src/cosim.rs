@@ -0,0 +1,363 @@
+//! Lockstep co-simulation against a reference commit log
+//!
+//! The machine executes one instruction for every commit of the reference
+//! trace (for example produced by `spike --log-commits`) and compares program
+//! counter, instruction word, register writebacks and memory accesses.
+//! The first instruction that differs stops the run with a [`Divergence`].
+use crate::{RV32, Word};
+use crate::bus::AccessKind;
+use crate::exception::Exception;
+use crate::instructions::INSTRUCTION_SET;
+use crate::trace::{Commit, CommitReader, MemoryAccess, TraceError};
+
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// Traps taken in a row before an instruction retires, more means the
+/// handler itself keeps faulting
+const MAX_NESTED_TRAPS: usize = 4;
+
+/// Single difference between expected and executed instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Pc { expected: Word, actual: Word },
+    Instruction { expected: Word, actual: Word },
+    /// Register was written with a different value, or written by only one side
+    Writeback { register: Word, expected: Option<Word>, actual: Option<Word> },
+    /// Memory access differs, or was performed by only one side
+    Memory { expected: Option<MemoryAccess>, actual: Option<MemoryAccess> },
+    /// Machine raised an exception where the reference retired the instruction
+    Exception(Exception),
+}
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fn value(v: &Option<Word>) -> String {
+            v.map(|w| format!("0x{:08x}", w.0)).unwrap_or("nothing".into())
+        }
+        fn access(a: &Option<MemoryAccess>) -> String {
+            match a {
+                None => "nothing".into(),
+                Some(a) if a.kind == AccessKind::Read => format!("load from 0x{:08x}", a.address.0),
+                Some(a) => format!("{}-byte store of 0x{:x} to 0x{:08x}", a.size, a.value.0, a.address.0),
+            }
+        }
+
+        match self {
+            Mismatch::Pc { expected, actual } => {
+                write!(f, "pc is 0x{:08x}, expected 0x{:08x}", actual.0, expected.0)
+            }
+            Mismatch::Instruction { expected, actual } => {
+                write!(f, "fetched 0x{:08x}, expected 0x{:08x}", actual.0, expected.0)
+            }
+            Mismatch::Writeback { register, expected, actual } => {
+                write!(f, "x{} written with {}, expected {}", register.0, value(actual), value(expected))
+            }
+            Mismatch::Memory { expected, actual } => {
+                write!(f, "performed {}, expected {}", access(actual), access(expected))
+            }
+            Mismatch::Exception(exception) => {
+                write!(f, "raised {exception:?}")
+            }
+        }
+    }
+}
+
+/// Report of the first instruction whose effects differ from the reference
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Number of instructions that matched before this one
+    pub step: u64,
+    pub expected: Commit,
+    /// Effects of executed instruction, `None` if it raised an exception
+    pub actual: Option<Commit>,
+    pub mismatches: Vec<Mismatch>,
+    /// Register file right after the divergent instruction
+    pub registers: String,
+}
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "divergence at instruction {} (pc 0x{:08x})", self.step, self.expected.pc.0)?;
+
+        let disassembly = INSTRUCTION_SET.decode(self.expected.instruction)
+            .and_then(|inst| inst.disassemble(self.expected.instruction));
+        if let Ok(disassembly) = disassembly {
+            writeln!(f, "  instruction: {disassembly}")?;
+        }
+
+        writeln!(f, "  expected:    {}", self.expected)?;
+        match &self.actual {
+            Some(actual) => writeln!(f, "  actual:      {actual}")?,
+            None => writeln!(f, "  actual:      exception")?,
+        }
+
+        for mismatch in self.mismatches.iter() {
+            writeln!(f, "  - {mismatch}")?;
+        }
+
+        write!(f, "{}", self.registers)
+    }
+}
+
+/// Error that ended a lockstep run
+#[derive(Debug)]
+pub enum CosimError {
+    Trace(TraceError),
+    Divergence(Box<Divergence>),
+}
+impl Display for CosimError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CosimError::Trace(err) => write!(f, "{err}"),
+            CosimError::Divergence(divergence) => write!(f, "{divergence}"),
+        }
+    }
+}
+impl std::error::Error for CosimError {}
+
+/// Runs machine in lockstep with a reference trace
+pub struct Lockstep<'a> {
+    cpu: &'a RV32,
+    step: u64,
+}
+impl<'a> Lockstep<'a> {
+    pub fn new(cpu: &'a RV32) -> Self {
+        Self { cpu, step: 0 }
+    }
+
+    /// Number of instructions that matched the reference so far
+    pub fn steps(&self) -> u64 {
+        self.step
+    }
+
+    /// Execute one instruction and compare it against `expected`. With
+    /// `traps` set, an exception enters the handler like `step` does, and
+    /// the handler's first instruction is compared instead, as the reference
+    /// logs no commit for an instruction that traps.
+    pub fn check(&mut self, expected: &Commit) -> Result<(), Box<Divergence>> {
+        let mut result = self.cpu.step_traced();
+        for _ in 0..MAX_NESTED_TRAPS {
+            match result {
+                Err(exception) if self.cpu.traps => {
+                    self.cpu.trap(exception);
+                    result = self.cpu.step_traced();
+                }
+                _ => break,
+            }
+        }
+
+        let mismatches = match &result {
+            Ok(actual) => compare(expected, actual),
+            Err(exception) => vec![Mismatch::Exception(*exception)],
+        };
+
+        if !mismatches.is_empty() {
+            return Err(Box::new(Divergence {
+                step: self.step,
+                expected: expected.clone(),
+                actual: result.ok(),
+                mismatches,
+                registers: format!("{:?}", self.cpu.reg),
+            }));
+        }
+
+        self.step += 1;
+        Ok(())
+    }
+
+    /// Check every commit of the trace, return number of matched instructions
+    pub fn run<I>(&mut self, trace: I) -> Result<u64, CosimError>
+    where
+        I: IntoIterator<Item = Result<Commit, TraceError>>,
+    {
+        for commit in trace {
+            let commit = commit.map_err(CosimError::Trace)?;
+            self.check(&commit).map_err(CosimError::Divergence)?;
+        }
+
+        Ok(self.step)
+    }
+
+    /// Check every commit of a log file
+    pub fn run_file<P: AsRef<Path>>(&mut self, path: P) -> Result<u64, CosimError> {
+        let reader = CommitReader::open(path).map_err(|e| CosimError::Trace(TraceError::Io(e)))?;
+        self.run(reader)
+    }
+}
+
+fn compare(expected: &Commit, actual: &Commit) -> Vec<Mismatch> {
+    let mut mismatches = vec![];
+
+    if expected.pc != actual.pc {
+        mismatches.push(Mismatch::Pc { expected: expected.pc, actual: actual.pc });
+    }
+
+    if expected.instruction != actual.instruction {
+        mismatches.push(Mismatch::Instruction { expected: expected.instruction, actual: actual.instruction });
+    }
+
+    let writebacks = expected.writebacks.len().max(actual.writebacks.len());
+    for i in 0..writebacks {
+        let (exp, act) = (expected.writebacks.get(i), actual.writebacks.get(i));
+        if exp == act {
+            continue;
+        }
+
+        let register = exp.or(act).map(|(idx, _)| *idx).unwrap_or_default();
+        mismatches.push(Mismatch::Writeback {
+            register,
+            expected: exp.filter(|(idx, _)| *idx == register).map(|(_, v)| *v),
+            actual: act.filter(|(idx, _)| *idx == register).map(|(_, v)| *v),
+        });
+    }
+
+    let accesses = expected.memory.len().max(actual.memory.len());
+    for i in 0..accesses {
+        let (exp, act) = (expected.memory.get(i), actual.memory.get(i));
+
+        let same = match (exp, act) {
+            // Loads in the reference log carry only the address
+            (Some(e), Some(a)) if e.kind == AccessKind::Read => a.kind == e.kind && a.address == e.address,
+            (Some(e), Some(a)) => e == a,
+            _ => false,
+        };
+
+        if !same {
+            mismatches.push(Mismatch::Memory { expected: exp.copied(), actual: act.copied() });
+        }
+    }
+
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::csr::{MEPC, MTVEC};
+    use crate::devices::{Device, ram::Ram};
+
+    const HANDLER: u32 = 0x100;
+
+    /// Machine with `program` at address 0
+    fn machine(program: &[u32]) -> RV32 {
+        let mut cpu = RV32::new();
+        cpu.bus.connect(Device::new(MemoryRange::new(Word(0), Word(0x1000)), Box::new(Ram::new(0x1000)))).unwrap();
+        let bytes: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        cpu.bus.load(Word(0), &bytes).unwrap();
+        cpu
+    }
+
+    fn store(address: u32, size: u8, value: u32) -> MemoryAccess {
+        MemoryAccess { kind: AccessKind::Write, address: Word(address), size, value: Word(value) }
+    }
+
+    // addi x1, x0, 0x80; sw x1, 4(x0); lw x2, 4(x0)
+    const PROGRAM: [u32; 3] = [0x08000093, 0x00102223, 0x00402103];
+
+    fn reference() -> Vec<Commit> {
+        vec![
+            Commit::new(Word(0), Word(PROGRAM[0]), vec![(Word(1), Word(0x80))], vec![]),
+            Commit::new(Word(4), Word(PROGRAM[1]), vec![], vec![store(4, 4, 0x80)]),
+            Commit::new(Word(8), Word(PROGRAM[2]), vec![(Word(2), Word(0x80))],
+                vec![MemoryAccess { kind: AccessKind::Read, address: Word(4), size: 0, value: Word(0) }]),
+        ]
+    }
+
+    /// Mismatches of the first divergence running `trace`
+    fn mismatches(cpu: &RV32, trace: Vec<Commit>) -> (u64, Vec<Mismatch>) {
+        match Lockstep::new(cpu).run(trace.into_iter().map(Ok)) {
+            Err(CosimError::Divergence(divergence)) => (divergence.step, divergence.mismatches),
+            other => panic!("expected divergence, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn matching_trace_runs_to_the_end() {
+        let cpu = machine(&PROGRAM);
+        assert_eq!(Lockstep::new(&cpu).run(reference().into_iter().map(Ok)).unwrap(), 3);
+    }
+
+    #[test]
+    fn pc_and_instruction_mismatches() {
+        let mut trace = reference();
+        trace[1].pc = Word(0x40);
+        trace[1].instruction = Word(0x13);
+        assert_eq!(mismatches(&machine(&PROGRAM), trace), (1, vec![
+            Mismatch::Pc { expected: Word(0x40), actual: Word(4) },
+            Mismatch::Instruction { expected: Word(0x13), actual: Word(PROGRAM[1]) },
+        ]));
+    }
+
+    #[test]
+    fn writeback_mismatches() {
+        let mut trace = reference();
+        trace[0].writebacks = vec![(Word(1), Word(0x81))];
+        assert_eq!(mismatches(&machine(&PROGRAM), trace).1, vec![
+            Mismatch::Writeback { register: Word(1), expected: Some(Word(0x81)), actual: Some(Word(0x80)) },
+        ]);
+
+        let mut trace = reference();
+        trace[0].writebacks.clear();
+        assert_eq!(mismatches(&machine(&PROGRAM), trace).1, vec![
+            Mismatch::Writeback { register: Word(1), expected: None, actual: Some(Word(0x80)) },
+        ]);
+    }
+
+    #[test]
+    fn memory_mismatches() {
+        let mut trace = reference();
+        trace[1].memory = vec![store(4, 2, 0x80)];
+        assert_eq!(mismatches(&machine(&PROGRAM), trace).1, vec![
+            Mismatch::Memory { expected: Some(store(4, 2, 0x80)), actual: Some(store(4, 4, 0x80)) },
+        ]);
+
+        let mut trace = reference();
+        trace[0].memory = vec![store(8, 1, 1)];
+        assert_eq!(mismatches(&machine(&PROGRAM), trace).1, vec![
+            Mismatch::Memory { expected: Some(store(8, 1, 1)), actual: None },
+        ]);
+    }
+
+    #[test]
+    fn exception_without_traps_is_a_mismatch() {
+        // ecall
+        let cpu = machine(&[0x00000073]);
+        let trace = vec![Commit::new(Word(0), Word(0x00000073), vec![], vec![])];
+        let divergence = match Lockstep::new(&cpu).run(trace.into_iter().map(Ok)) {
+            Err(CosimError::Divergence(divergence)) => divergence,
+            other => panic!("expected divergence, got {other:?}"),
+        };
+        assert_eq!(divergence.mismatches, vec![Mismatch::Exception(Exception::EnvironmentCall)]);
+        assert!(divergence.actual.is_none());
+        assert!(divergence.to_string().contains("raised EnvironmentCall"));
+    }
+
+    #[test]
+    fn traps_are_followed_into_the_handler() {
+        // ecall, with the handler reading mcause: csrr x5, mcause
+        let mut program = vec![0u32; HANDLER as usize / 4 + 1];
+        program[0] = 0x00000073;
+        program[HANDLER as usize / 4] = 0x342022f3;
+        let mut cpu = machine(&program);
+        cpu.traps = true;
+        cpu.csr.write(Word(MTVEC), Word(HANDLER)).unwrap();
+
+        // Spike logs no commit for the instruction that traps
+        let trace = vec![Commit::new(Word(HANDLER), Word(0x342022f3), vec![(Word(5), Word(11))], vec![])];
+        assert_eq!(Lockstep::new(&cpu).run(trace.into_iter().map(Ok)).unwrap(), 1);
+        assert_eq!(cpu.csr.read(Word(MEPC)).unwrap(), Word(0));
+    }
+
+    #[test]
+    fn handler_that_keeps_faulting_diverges() {
+        // Illegal instruction at the handler, which traps to itself
+        let mut program = vec![0u32; HANDLER as usize / 4 + 1];
+        program[HANDLER as usize / 4] = 0xffffffff;
+        let mut cpu = machine(&program);
+        cpu.traps = true;
+        cpu.csr.write(Word(MTVEC), Word(HANDLER)).unwrap();
+
+        let trace = vec![Commit::new(Word(HANDLER), Word(0x13), vec![], vec![])];
+        assert_eq!(mismatches(&cpu, trace).1, vec![Mismatch::Exception(Exception::InvalidInstruction)]);
+    }
+}
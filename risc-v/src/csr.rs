@@ -0,0 +1,190 @@
+//! Machine mode control and status registers
+//!
+//! The hart only runs in machine mode, so only the registers needed to
+//! identify it and take traps are there. Accessing any other register,
+//! or writing a read-only one, is an illegal instruction.
+//!
+//! Every interrupt line of the bus is wired to machine external interrupt:
+//! `mip.MEIP` is set while any of them is pending.
+use crate::Word;
+use crate::exception::Exception;
+use crate::interrupts::Interrupts;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const MVENDORID: u32 = 0xf11;
+pub const MARCHID: u32 = 0xf12;
+pub const MIMPID: u32 = 0xf13;
+pub const MHARTID: u32 = 0xf14;
+
+/// Interrupts enabled
+pub const MSTATUS_MIE: u32 = 1 << 3;
+/// Interrupts enabled before the trap
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+/// Privilege before the trap, always machine mode
+pub const MSTATUS_MPP: u32 = 3 << 11;
+
+/// Machine external interrupt, in `mie` and `mip`
+pub const MEIP: u32 = 1 << 11;
+
+/// Cause number of machine external interrupt
+pub const MACHINE_EXTERNAL_INTERRUPT: u32 = 11;
+
+/// Set in `mcause` when the trap is an interrupt
+pub const MCAUSE_INTERRUPT: u32 = 1 << 31;
+
+/// 32-bit base integer ISA, no extensions
+const MISA_RV32I: u32 = (1 << 30) | (1 << (b'I' - b'A'));
+
+/// Software, timer and external interrupt enables of machine mode
+const MIE_MASK: u32 = (1 << 3) | (1 << 7) | (1 << 11);
+
+/// Name of register `csr`, `None` if the hart doesn't have it
+pub fn name(csr: Word) -> Option<&'static str> {
+    let name = match csr.0 {
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[derive(Debug, Default)]
+pub struct Csrs {
+    mstatus: Cell<u32>,
+    mie: Cell<u32>,
+    mtvec: Cell<u32>,
+    mscratch: Cell<u32>,
+    mepc: Cell<u32>,
+    mcause: Cell<u32>,
+    mtval: Cell<u32>,
+    /// Lines behind `mip.MEIP`, it reads as clear without them
+    interrupts: Option<Rc<Interrupts>>,
+}
+impl Csrs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Derive `mip.MEIP` from the pending `interrupts`
+    pub fn with_interrupts(mut self, interrupts: Rc<Interrupts>) -> Self {
+        self.interrupts = Some(interrupts);
+        self
+    }
+
+    /// Clear every register, as after power on. `mip` follows the lines.
+    pub fn reset(&self) {
+        for register in [&self.mstatus, &self.mie, &self.mtvec, &self.mscratch, &self.mepc, &self.mcause, &self.mtval] {
+            register.set(0);
+        }
+    }
+
+    /// Pending interrupts as bits of `mip`
+    fn mip(&self) -> u32 {
+        match self.interrupts.as_ref().map(|i| i.pending()) {
+            Some(pending) if pending != 0 => MEIP,
+            _ => 0,
+        }
+    }
+
+    /// Cause of the interrupt the hart should take before its next
+    /// instruction, `None` if none is both pending and enabled
+    pub fn interrupt(&self) -> Option<u32> {
+        let enabled = self.mstatus.get() & MSTATUS_MIE != 0;
+        match enabled && self.mie.get() & self.mip() != 0 {
+            true => Some(MCAUSE_INTERRUPT | MACHINE_EXTERNAL_INTERRUPT),
+            false => None,
+        }
+    }
+
+    pub fn read(&self, csr: Word) -> Result<Word, Exception> {
+        let value = match csr.0 {
+            MSTATUS => self.mstatus.get() | MSTATUS_MPP,
+            MISA => MISA_RV32I,
+            MIE => self.mie.get(),
+            MTVEC => self.mtvec.get(),
+            MSCRATCH => self.mscratch.get(),
+            MEPC => self.mepc.get(),
+            MCAUSE => self.mcause.get(),
+            MTVAL => self.mtval.get(),
+            MIP => self.mip(),
+            MVENDORID | MARCHID | MIMPID | MHARTID => 0,
+            _ => return Err(Exception::InvalidInstruction),
+        };
+        Ok(Word(value))
+    }
+
+    /// Write `value` to `csr`, bits the hart doesn't implement are dropped
+    pub fn write(&self, csr: Word, value: Word) -> Result<(), Exception> {
+        // Registers with both top address bits set are read-only
+        if csr.0 >> 10 == 0b11 {
+            return Err(Exception::InvalidInstruction);
+        }
+
+        let value = value.0;
+        match csr.0 {
+            MSTATUS => self.mstatus.set(value & (MSTATUS_MIE | MSTATUS_MPIE)),
+            // Only RV32I is supported, there is nothing to change
+            MISA => (),
+            MIE => self.mie.set(value & MIE_MASK),
+            // Only direct mode, handlers are 4 byte aligned
+            MTVEC => self.mtvec.set(value & !3),
+            MSCRATCH => self.mscratch.set(value),
+            MEPC => self.mepc.set(value & !3),
+            MCAUSE => self.mcause.set(value),
+            MTVAL => self.mtval.set(value),
+            // Only bits derived from the interrupt lines, writes are dropped
+            MIP => (),
+            _ => return Err(Exception::InvalidInstruction),
+        }
+        Ok(())
+    }
+
+    /// Record trap `cause` of instruction at `pc`, or of an interrupt taken
+    /// before it, return address of the handler
+    pub fn trap(&self, cause: u32, pc: Word, value: Word) -> Word {
+        let mstatus = self.mstatus.get();
+        let mpie = match mstatus & MSTATUS_MIE {
+            0 => 0,
+            _ => MSTATUS_MPIE,
+        };
+        self.mstatus.set(mpie);
+        self.mepc.set(pc.0 & !3);
+        self.mcause.set(cause);
+        self.mtval.set(value.0);
+
+        Word(self.mtvec.get())
+    }
+
+    /// Leave trap handler, return address to continue at
+    pub fn trap_return(&self) -> Word {
+        let mie = match self.mstatus.get() & MSTATUS_MPIE {
+            0 => 0,
+            _ => MSTATUS_MIE,
+        };
+        self.mstatus.set(mie | MSTATUS_MPIE);
+
+        Word(self.mepc.get())
+    }
+}
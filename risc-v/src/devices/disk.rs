@@ -0,0 +1,156 @@
+//! Disk images for storage devices
+//!
+//! A [`DiskImage`] is addressed in 512 byte sectors. With an overlay, written
+//! sectors are kept in memory and the image itself is never modified, so the
+//! same image can be booted again in a clean state or shared between machines.
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const SECTOR_SIZE: usize = 512;
+
+type Sector = Box<[u8; SECTOR_SIZE]>;
+
+/// Storage an image can be read from and written to
+trait Backing: Read + Write + Seek {}
+impl<T: Read + Write + Seek> Backing for T {}
+
+pub struct DiskImage {
+    backing: Box<dyn Backing>,
+    sectors: u64,
+    writable: bool,
+    /// Sectors written since the overlay was enabled, copy-on-write
+    overlay: Option<HashMap<u64, Sector>>,
+}
+impl DiskImage {
+    /// Open image file for reading and writing
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::from_file(file, true)
+    }
+
+    /// Open image file that can't be written, unless an overlay is added
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_file(File::open(path)?, false)
+    }
+
+    fn from_file(file: File, writable: bool) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        Ok(Self {
+            backing: Box::new(file),
+            sectors: size / SECTOR_SIZE as u64,
+            writable,
+            overlay: None,
+        })
+    }
+
+    /// Image held in memory, a partial last sector is dropped
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        let sectors = (data.len() / SECTOR_SIZE) as u64;
+        Self { backing: Box::new(Cursor::new(data)), sectors, writable: true, overlay: None }
+    }
+
+    /// Keep writes in memory instead of writing them to the image
+    pub fn with_overlay(mut self) -> Self {
+        self.overlay.get_or_insert_with(HashMap::new);
+        self
+    }
+
+    /// Size in sectors
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    /// Return `true` if writes fail
+    pub fn is_read_only(&self) -> bool {
+        !self.writable && self.overlay.is_none()
+    }
+
+    /// Number of sectors written to the overlay
+    pub fn overlay_sectors(&self) -> usize {
+        self.overlay.as_ref().map_or(0, HashMap::len)
+    }
+
+    /// Fail unless `length` bytes starting at `sector` are whole sectors
+    /// inside the image
+    pub fn check(&self, sector: u64, length: usize) -> io::Result<()> {
+        if !length.is_multiple_of(SECTOR_SIZE) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "length is not a multiple of the sector size"));
+        }
+        match sector.checked_add((length / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err(io::Error::new(ErrorKind::InvalidInput, "sectors past the end of the image")),
+        }
+    }
+
+    /// Read whole sectors starting at `sector` into `buffer`
+    pub fn read(&mut self, sector: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.check(sector, buffer.len())?;
+
+        for (i, chunk) in buffer.chunks_mut(SECTOR_SIZE).enumerate() {
+            let sector = sector + i as u64;
+            match self.overlay.as_ref().and_then(|overlay| overlay.get(&sector)) {
+                Some(data) => chunk.copy_from_slice(&data[..]),
+                None => {
+                    self.backing.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                    self.backing.read_exact(chunk)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write whole sectors of `data` starting at `sector`
+    pub fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.check(sector, data.len())?;
+
+        if let Some(overlay) = self.overlay.as_mut() {
+            for (i, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+                let mut copy = Box::new([0; SECTOR_SIZE]);
+                copy.copy_from_slice(chunk);
+                overlay.insert(sector + i as u64, copy);
+            }
+            return Ok(());
+        }
+
+        if !self.writable {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "image is read-only"));
+        }
+        self.backing.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.backing.write_all(data)
+    }
+
+    /// Make writes to the image durable
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.backing.flush()
+    }
+
+    /// Write sectors in the overlay to the image and empty the overlay
+    pub fn commit(&mut self) -> io::Result<()> {
+        if !self.writable {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "image is read-only"));
+        }
+        let Some(overlay) = self.overlay.as_mut() else {
+            return Ok(());
+        };
+
+        // Overlay is kept until everything is written, a failed commit can be retried
+        let mut sectors: Vec<&u64> = overlay.keys().collect();
+        sectors.sort();
+        for sector in sectors {
+            self.backing.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+            self.backing.write_all(&overlay[sector][..])?;
+        }
+        self.backing.flush()?;
+        overlay.clear();
+        Ok(())
+    }
+
+    /// Drop sectors in the overlay, the image reads as it was when opened
+    pub fn discard(&mut self) {
+        if let Some(overlay) = self.overlay.as_mut() {
+            overlay.clear();
+        }
+    }
+}
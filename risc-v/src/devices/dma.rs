@@ -0,0 +1,405 @@
+//! DMA controller
+//!
+//! Channels copy data over the bus on their own, in bursts of beats. A beat
+//! moves one byte, halfword or word and takes a number of bus cycles, a burst
+//! is carried out by a scheduled event once its cycles have passed. Fixed
+//! source or destination addresses let a channel feed or drain a peripheral
+//! register. Channels don't wait for peripherals, so bursts and beat cycles
+//! must suit what the peripheral can take. Channels take 32 bytes each, with
+//! 32-bit registers:
+//!
+//! | Offset | Register                                             |
+//! |--------|------------------------------------------------------|
+//! | `0x00` | source address                                       |
+//! | `0x04` | destination address                                  |
+//! | `0x08` | beats left to transfer                               |
+//! | `0x0c` | control, see below                                   |
+//! | `0x10` | status, bit 0 done and bit 1 bus error, write 1 to clear |
+//!
+//! Control bit 0 starts the channel and stays set while it is busy, clearing
+//! it aborts the transfer. Bit 1 enables the interrupt when the channel
+//! finishes or fails. Bits 2-3 are the beat width as 0 for bytes, 1 for
+//! halfwords and 2 for words. Bits 4 and 5 make the source and destination
+//! address advance after every beat. Bits 8-15 are the burst length minus one.
+use crate::Word;
+use crate::bus::{AccessWidth, Bus, BusError};
+use crate::interrupts::IrqLine;
+use crate::scheduler::{Cycle, DeviceContext, EventId};
+use super::{DeviceTrait, Properties, Property};
+
+use std::cell::RefCell;
+
+/// Most channels a DMA controller can have
+pub const MAX_CHANNELS: usize = 8;
+
+const CHANNEL_SIZE: u32 = 0x20;
+
+const SOURCE: u32 = 0x00;
+const DESTINATION: u32 = 0x04;
+const COUNT: u32 = 0x08;
+const CONTROL: u32 = 0x0c;
+const STATUS: u32 = 0x10;
+
+const CONTROL_START: u32 = 1 << 0;
+const CONTROL_INTERRUPT: u32 = 1 << 1;
+const CONTROL_WIDTH_SHIFT: u32 = 2;
+const CONTROL_SOURCE_INCREMENT: u32 = 1 << 4;
+const CONTROL_DESTINATION_INCREMENT: u32 = 1 << 5;
+const CONTROL_BURST_SHIFT: u32 = 8;
+const CONTROL_MASK: u32 = 0xff00 | 0x3f;
+
+const STATUS_DONE: u32 = 1 << 0;
+const STATUS_ERROR: u32 = 1 << 1;
+
+#[derive(Default)]
+struct Channel {
+    source: u32,
+    destination: u32,
+    count: u32,
+    control: u32,
+    status: u32,
+    /// Event finishing the burst in flight
+    burst: Option<EventId>,
+    /// Reason the last transfer failed
+    error: Option<BusError>,
+}
+impl Channel {
+    fn busy(&self) -> bool {
+        self.control & CONTROL_START != 0
+    }
+
+    fn width(&self) -> AccessWidth {
+        match (self.control >> CONTROL_WIDTH_SHIFT) & 3 {
+            0 => AccessWidth::Byte,
+            1 => AccessWidth::Halfword,
+            _ => AccessWidth::Word,
+        }
+    }
+
+    /// Beats of the next burst
+    fn burst_length(&self) -> u32 {
+        (((self.control >> CONTROL_BURST_SHIFT) & 0xff) + 1).min(self.count)
+    }
+
+    fn pending(&self) -> bool {
+        self.status != 0 && self.control & CONTROL_INTERRUPT != 0
+    }
+}
+
+pub struct Dma {
+    channels: RefCell<Vec<Channel>>,
+    cycles_per_beat: Cycle,
+    context: RefCell<Option<DeviceContext>>,
+    irq: Option<IrqLine>,
+}
+impl Dma {
+    pub fn new(channels: usize) -> Self {
+        assert!((1..=MAX_CHANNELS).contains(&channels), "DMA controller must have 1 to {MAX_CHANNELS} channels, got {channels}");
+        Self {
+            channels: RefCell::new((0..channels).map(|_| Channel::default()).collect()),
+            cycles_per_beat: 1,
+            context: RefCell::new(None),
+            irq: None,
+        }
+    }
+
+    /// Drive `irq` while a channel with its interrupt enabled finished or failed
+    pub fn with_interrupt(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    /// Bus cycles every beat takes, 1 by default
+    pub fn with_cycles_per_beat(mut self, cycles: Cycle) -> Self {
+        assert!(cycles > 0, "DMA beat must take at least one cycle");
+        self.cycles_per_beat = cycles;
+        self
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.borrow().len()
+    }
+
+    /// Bytes of bus address space the controller needs
+    pub fn size(&self) -> u32 {
+        self.channels() as u32 * CHANNEL_SIZE
+    }
+
+    /// `true` while `channel` is transferring
+    pub fn is_busy(&self, channel: usize) -> bool {
+        self.channels.borrow()[channel].busy()
+    }
+
+    /// Bus error that stopped the last transfer of `channel`
+    pub fn error(&self, channel: usize) -> Option<BusError> {
+        self.channels.borrow()[channel].error.clone()
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.channels.borrow().iter().any(Channel::pending));
+        }
+    }
+
+    /// Schedule the next burst of `index`, or finish it when nothing is left
+    fn schedule_burst(&self, index: usize) {
+        let mut channels = self.channels.borrow_mut();
+        let channel = &mut channels[index];
+        if channel.count == 0 {
+            channel.control &= !CONTROL_START;
+            channel.status |= STATUS_DONE;
+            drop(channels);
+            self.update_interrupt();
+            return;
+        }
+
+        let Some(context) = self.context.borrow().clone() else {
+            // Not on a bus, there is no memory to transfer
+            return;
+        };
+        let handle = context.handle;
+        let cycles = channel.burst_length() as Cycle * self.cycles_per_beat;
+        channel.burst = Some(context.scheduler.schedule(cycles, move |bus| {
+            if let Some(dma) = bus.device::<Dma>(handle) {
+                dma.burst(index, bus);
+            }
+        }));
+    }
+
+    /// Carry out the beats of a burst whose time has passed
+    fn burst(&self, index: usize, bus: &Bus) {
+        let mut channels = self.channels.borrow_mut();
+        let channel = &mut channels[index];
+        channel.burst = None;
+        let width = channel.width();
+        let beats = channel.burst_length();
+        let (mut source, mut destination) = (channel.source, channel.destination);
+        let control = channel.control;
+        // Beats may reach the controller's own registers
+        drop(channels);
+
+        let mut moved = 0;
+        let mut error = None;
+        for _ in 0..beats {
+            let result = bus.read(Word(source), width)
+                .and_then(|word| bus.write(Word(destination), word, width));
+            if let Err(err) = result {
+                error = Some(err);
+                break;
+            }
+            moved += 1;
+            if control & CONTROL_SOURCE_INCREMENT != 0 {
+                source = source.wrapping_add(width.bytes());
+            }
+            if control & CONTROL_DESTINATION_INCREMENT != 0 {
+                destination = destination.wrapping_add(width.bytes());
+            }
+        }
+
+        let mut channels = self.channels.borrow_mut();
+        let channel = &mut channels[index];
+        // Aborted while the burst was in flight
+        if !channel.busy() {
+            return;
+        }
+        channel.source = source;
+        channel.destination = destination;
+        channel.count -= moved;
+        match error {
+            Some(err) => {
+                channel.control &= !CONTROL_START;
+                channel.status |= STATUS_ERROR;
+                channel.error = Some(err);
+                drop(channels);
+                self.update_interrupt();
+            }
+            None => {
+                drop(channels);
+                self.schedule_burst(index);
+            }
+        }
+    }
+
+    fn read_register(&self, index: usize, offset: u32) -> u32 {
+        let channels = self.channels.borrow();
+        let channel = &channels[index];
+        match offset {
+            SOURCE => channel.source,
+            DESTINATION => channel.destination,
+            COUNT => channel.count,
+            CONTROL => channel.control,
+            STATUS => channel.status,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&self, index: usize, offset: u32, value: u32) {
+        let mut channels = self.channels.borrow_mut();
+        let channel = &mut channels[index];
+        let mut start = false;
+        // Addresses and count can't change under a running transfer
+        match offset {
+            SOURCE if !channel.busy() => channel.source = value,
+            DESTINATION if !channel.busy() => channel.destination = value,
+            COUNT if !channel.busy() => channel.count = value,
+            CONTROL => {
+                start = !channel.busy() && value & CONTROL_START != 0;
+                let abort = channel.busy() && value & CONTROL_START == 0;
+                channel.control = value & CONTROL_MASK;
+                if abort {
+                    if let (Some(event), Some(context)) = (channel.burst.take(), self.context.borrow().as_ref()) {
+                        context.scheduler.cancel(event);
+                    }
+                }
+                if start {
+                    channel.status = 0;
+                    channel.error = None;
+                }
+            }
+            STATUS => channel.status &= !value,
+            _ => return,
+        }
+        drop(channels);
+
+        if start {
+            self.schedule_burst(index);
+        }
+        self.update_interrupt();
+    }
+}
+impl DeviceTrait for Dma {
+    fn name(&self) -> String {
+        format!("DMA {} channels", self.channels())
+    }
+    fn attach(&self, context: DeviceContext) {
+        *self.context.borrow_mut() = Some(context);
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("dma-controller")
+            .with("#dma-cells", Property::Cells(vec![1]))
+            .with("dma-channels", Property::Cells(vec![self.channels() as u32]));
+        match &self.irq {
+            Some(irq) => properties.with("interrupts", Property::Cells(vec![irq.line()])),
+            None => properties,
+        }
+    }
+    fn supports(&self, width: AccessWidth) -> bool {
+        width == AccessWidth::Word
+    }
+    fn read(&self, address: Word, _: AccessWidth) -> Word {
+        let index = (address.0 / CHANNEL_SIZE) as usize;
+        match index < self.channels() {
+            true => Word(self.read_register(index, address.0 % CHANNEL_SIZE)),
+            false => Word(0),
+        }
+    }
+    fn write(&self, address: Word, word: Word, _: AccessWidth) {
+        let index = (address.0 / CHANNEL_SIZE) as usize;
+        if index < self.channels() {
+            self.write_register(index, address.0 % CHANNEL_SIZE, word.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::bus::DeviceHandle;
+    use crate::devices::{Device, ram::Ram};
+
+    const BASE: Word = Word(0x4000_0000);
+
+    /// Bus with RAM at 0 and the controller made by `dma`, which gets interrupt line 4
+    fn machine(dma: impl FnOnce(IrqLine) -> Dma) -> (Bus, DeviceHandle) {
+        let mut bus = Bus::new();
+        bus.connect(Device::new(MemoryRange::new(Word(0), Word(0x1000)), Box::new(Ram::new(0x1000)))).unwrap();
+        let dma = dma(bus.interrupt_line(4));
+        let size = Word(dma.size());
+        let handle = bus.connect(Device::new(MemoryRange::new(BASE, size), Box::new(dma))).unwrap();
+        (bus, handle)
+    }
+
+    fn write(bus: &Bus, channel: u32, register: u32, value: u32) {
+        bus.write(BASE + Word(channel * CHANNEL_SIZE + register), Word(value), AccessWidth::Word).unwrap();
+    }
+
+    fn read(bus: &Bus, channel: u32, register: u32) -> u32 {
+        bus.read(BASE + Word(channel * CHANNEL_SIZE + register), AccessWidth::Word).unwrap().0
+    }
+
+    fn start(bus: &Bus, channel: u32, source: u32, destination: u32, count: u32, control: u32) {
+        write(bus, channel, SOURCE, source);
+        write(bus, channel, DESTINATION, destination);
+        write(bus, channel, COUNT, count);
+        write(bus, channel, CONTROL, control | CONTROL_START);
+    }
+
+    #[test]
+    fn copy_takes_its_beats_in_bursts() {
+        let (bus, _) = machine(|irq| Dma::new(2).with_interrupt(irq).with_cycles_per_beat(2));
+        bus.load(Word(0x100), &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]).unwrap();
+
+        // Halfwords in bursts of 2 beats, 4 cycles per burst
+        let control = CONTROL_INTERRUPT | 1 << CONTROL_WIDTH_SHIFT | CONTROL_SOURCE_INCREMENT
+            | CONTROL_DESTINATION_INCREMENT | 1 << CONTROL_BURST_SHIFT;
+        start(&bus, 1, 0x100, 0x200, 5, control);
+
+        bus.advance(3);
+        assert_eq!(bus.read_bytes(Word(0x200), 4).unwrap(), [0; 4]);
+        bus.advance(1);
+        assert_eq!(bus.read_bytes(Word(0x200), 4).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(read(&bus, 1, COUNT), 3);
+        assert_eq!(read(&bus, 1, SOURCE), 0x104);
+
+        // Last burst has a single beat, ending at cycle 10
+        bus.advance(5);
+        assert_eq!(read(&bus, 1, CONTROL) & CONTROL_START, CONTROL_START);
+        assert!(!bus.interrupts().is_pending(4));
+        bus.advance(1);
+        assert_eq!(bus.read_bytes(Word(0x200), 10).unwrap(), [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(read(&bus, 1, CONTROL) & CONTROL_START, 0);
+        assert_eq!(read(&bus, 1, STATUS), STATUS_DONE);
+        assert!(bus.interrupts().is_pending(4));
+
+        write(&bus, 1, STATUS, STATUS_DONE);
+        assert!(!bus.interrupts().is_pending(4));
+    }
+
+    #[test]
+    fn fixed_destination_receives_every_beat() {
+        let (bus, _) = machine(|_| Dma::new(1));
+        bus.load(Word(0x100), &[1, 2, 3]).unwrap();
+
+        start(&bus, 0, 0x100, 0x300, 3, CONTROL_SOURCE_INCREMENT | 7 << CONTROL_BURST_SHIFT);
+        bus.advance(3);
+        assert_eq!(bus.read_byte(Word(0x300)), 3);
+        assert_eq!(bus.read_byte(Word(0x301)), 0);
+        assert_eq!(read(&bus, 0, DESTINATION), 0x300);
+    }
+
+    #[test]
+    fn bus_error_stops_the_channel() {
+        let (bus, handle) = machine(|_| Dma::new(1));
+
+        start(&bus, 0, 0xffe, 0x100, 4, CONTROL_SOURCE_INCREMENT | CONTROL_DESTINATION_INCREMENT | 3 << CONTROL_BURST_SHIFT);
+        bus.advance(4);
+        assert_eq!(read(&bus, 0, STATUS), STATUS_ERROR);
+        assert_eq!(read(&bus, 0, CONTROL) & CONTROL_START, 0);
+        // Beats before the failing one were moved
+        assert_eq!(read(&bus, 0, COUNT), 2);
+        assert!(bus.device::<Dma>(handle).unwrap().error(0).is_some());
+    }
+
+    #[test]
+    fn abort_cancels_the_burst_in_flight() {
+        let (bus, _) = machine(|_| Dma::new(1));
+        bus.load(Word(0x100), &[0xAA]).unwrap();
+
+        start(&bus, 0, 0x100, 0x200, 1, 0);
+        write(&bus, 0, CONTROL, 0);
+        assert!(!bus.skip_to_next_event());
+        assert_eq!(bus.read_byte(Word(0x200)), 0);
+        assert_eq!(read(&bus, 0, STATUS), 0);
+    }
+}
@@ -0,0 +1,320 @@
+//! Memory mapped framebuffer
+//!
+//! Pixel memory starts at offset 0, rows are `stride` bytes apart. Registers
+//! describing the mode and the palette follow it at the next page boundary:
+//!
+//! | Offset        | Register                                  |
+//! |---------------|-------------------------------------------|
+//! | `0x000`       | width in pixels, read-only                |
+//! | `0x004`       | height in pixels, read-only               |
+//! | `0x008`       | format, see [`PixelFormat`], read-only    |
+//! | `0x00c`       | stride in bytes, read-only                |
+//! | `0x400-0x7ff` | 256 palette entries, bytes R, G, B, unused |
+use crate::Word;
+use crate::bus::AccessWidth;
+use crate::image::Image;
+use super::{DeviceTrait, Properties, Property};
+use super::ram::{Page, PAGE_SIZE};
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+const WIDTH: u32 = 0x000;
+const HEIGHT: u32 = 0x004;
+const FORMAT: u32 = 0x008;
+const STRIDE: u32 = 0x00c;
+const PALETTE: u32 = 0x400;
+
+const PALETTE_ENTRIES: usize = 256;
+
+/// Layout of a pixel in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// One byte indexing the palette
+    Indexed8 = 0,
+    /// Little endian halfword, red in the top 5 bits and blue in the bottom 5
+    Rgb565 = 1,
+    /// Bytes R, G, B, A, alpha is ignored
+    Rgba8888 = 2,
+}
+impl PixelFormat {
+    pub fn bytes(self) -> u32 {
+        match self {
+            PixelFormat::Indexed8 => 1,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Rgba8888 => 4,
+        }
+    }
+
+    /// Name of the format used by `simple-framebuffer` device tree nodes
+    fn simple_framebuffer(self) -> Option<&'static str> {
+        match self {
+            PixelFormat::Indexed8 => None,
+            PixelFormat::Rgb565 => Some("r5g6b5"),
+            PixelFormat::Rgba8888 => Some("a8b8g8r8"),
+        }
+    }
+}
+
+/// Grayscale ramp the palette starts with
+fn default_palette() -> Vec<[u8; 3]> {
+    (0..PALETTE_ENTRIES).map(|i| [i as u8; 3]).collect()
+}
+
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    stride: u32,
+    pixels: Vec<Page>,
+    palette: RefCell<Vec<[u8; 3]>>,
+}
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Self {
+        assert!(width > 0 && height > 0, "Framebuffer must be at least 1x1 pixels, got {width}x{height}");
+
+        let stride = width * format.bytes();
+        let pages = (stride as usize * height as usize).div_ceil(PAGE_SIZE);
+        Self {
+            width,
+            height,
+            format,
+            stride,
+            pixels: (0..pages).map(|_| Rc::new(std::array::from_fn(|_| Cell::new(0)))).collect(),
+            palette: RefCell::new(default_palette()),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Bytes between the start of two rows
+    pub fn stride(&self) -> u32 {
+        self.stride
+    }
+
+    /// Offset of the registers, the end of pixel memory
+    pub fn registers(&self) -> u32 {
+        (self.pixels.len() * PAGE_SIZE) as u32
+    }
+
+    /// Bytes of bus address space the framebuffer needs
+    pub fn size(&self) -> u32 {
+        self.registers() + PAGE_SIZE as u32
+    }
+
+    /// Set palette entry `index` to `rgb`
+    pub fn set_palette(&self, index: u8, rgb: [u8; 3]) {
+        self.palette.borrow_mut()[index as usize] = rgb;
+    }
+
+    fn byte(&self, offset: usize) -> u8 {
+        self.pixels[offset / PAGE_SIZE][offset % PAGE_SIZE].get()
+    }
+
+    fn set_byte(&self, offset: usize, byte: u8) {
+        self.pixels[offset / PAGE_SIZE][offset % PAGE_SIZE].set(byte);
+    }
+
+    /// Palette entry closest to `rgb`
+    fn nearest(&self, [r, g, b]: [u8; 3]) -> u8 {
+        let distance = |[pr, pg, pb]: [u8; 3]| {
+            [(r, pr), (g, pg), (b, pb)].iter().map(|&(a, b)| (a as i32 - b as i32).pow(2)).sum::<i32>()
+        };
+        let palette = self.palette.borrow();
+        (0..PALETTE_ENTRIES).min_by_key(|&i| distance(palette[i])).unwrap_or(0) as u8
+    }
+
+    /// Draw `image` with its top left corner at `x`, `y`, clipped to the
+    /// framebuffer. Indexed pixels get the closest palette entry.
+    pub fn blit(&self, x: u32, y: u32, image: &Image) {
+        for row in 0..image.height().min(self.height.saturating_sub(y)) {
+            let line = ((y + row) * self.stride) as usize;
+            for column in 0..image.width().min(self.width.saturating_sub(x)) {
+                let offset = line + ((x + column) * self.format.bytes()) as usize;
+                let [r, g, b, a] = image.get(column, row);
+                match self.format {
+                    PixelFormat::Indexed8 => self.set_byte(offset, self.nearest([r, g, b])),
+                    PixelFormat::Rgb565 => {
+                        let pixel = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+                        for (i, byte) in pixel.to_le_bytes().into_iter().enumerate() {
+                            self.set_byte(offset + i, byte);
+                        }
+                    }
+                    PixelFormat::Rgba8888 => {
+                        for (i, byte) in [r, g, b, a].into_iter().enumerate() {
+                            self.set_byte(offset + i, byte);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Render pixel memory into an image
+    pub fn render(&self) -> Image {
+        let mut image = Image::new(self.width, self.height);
+        let palette = self.palette.borrow();
+
+        for y in 0..self.height {
+            let row = (y * self.stride) as usize;
+            for x in 0..self.width {
+                let offset = row + (x * self.format.bytes()) as usize;
+                let [r, g, b] = match self.format {
+                    PixelFormat::Indexed8 => palette[self.byte(offset) as usize],
+                    PixelFormat::Rgb565 => {
+                        let pixel = u16::from_le_bytes([self.byte(offset), self.byte(offset + 1)]);
+                        let (r, g, b) = (pixel >> 11, (pixel >> 5) & 0x3f, pixel & 0x1f);
+                        // Repeat top bits, so full intensity becomes 0xff
+                        [(r << 3 | r >> 2) as u8, (g << 2 | g >> 4) as u8, (b << 3 | b >> 2) as u8]
+                    }
+                    PixelFormat::Rgba8888 => [self.byte(offset), self.byte(offset + 1), self.byte(offset + 2)],
+                };
+                image.set(x, y, [r, g, b, 0xff]);
+            }
+        }
+        image
+    }
+
+    fn read_register(&self, offset: u32) -> u8 {
+        let word = match offset & !3 {
+            WIDTH => self.width,
+            HEIGHT => self.height,
+            FORMAT => self.format as u32,
+            STRIDE => self.stride,
+            offset if (PALETTE..PALETTE + 4 * PALETTE_ENTRIES as u32).contains(&offset) => {
+                let [r, g, b] = self.palette.borrow()[((offset - PALETTE) / 4) as usize];
+                u32::from_le_bytes([r, g, b, 0])
+            }
+            _ => 0,
+        };
+        word.to_le_bytes()[(offset & 3) as usize]
+    }
+
+    fn write_register(&self, offset: u32, byte: u8) {
+        if (PALETTE..PALETTE + 4 * PALETTE_ENTRIES as u32).contains(&offset) {
+            let channel = ((offset - PALETTE) % 4) as usize;
+            if channel < 3 {
+                self.palette.borrow_mut()[((offset - PALETTE) / 4) as usize][channel] = byte;
+            }
+        }
+    }
+}
+impl DeviceTrait for Framebuffer {
+    fn name(&self) -> String {
+        format!("framebuffer {}x{} {:?}", self.width, self.height, self.format)
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("framebuffer");
+        match self.format.simple_framebuffer() {
+            Some(format) => properties
+                .with_compatible("simple-framebuffer")
+                .with("width", Property::Cells(vec![self.width]))
+                .with("height", Property::Cells(vec![self.height]))
+                .with("stride", Property::Cells(vec![self.stride]))
+                .with("format", Property::String(format.into())),
+            None => properties,
+        }
+    }
+    fn read(&self, address: Word, width: AccessWidth) -> Word {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().take(width.bytes() as usize).enumerate() {
+            let offset = address.0 + i as u32;
+            *byte = match offset < self.registers() {
+                true => self.byte(offset as usize),
+                false => self.read_register(offset - self.registers()),
+            };
+        }
+        Word::from_le_bytes(bytes)
+    }
+    fn write(&self, address: Word, word: Word, width: AccessWidth) {
+        for (i, byte) in word.0.to_le_bytes().into_iter().take(width.bytes() as usize).enumerate() {
+            let offset = address.0 + i as u32;
+            match offset < self.registers() {
+                true => self.set_byte(offset as usize, byte),
+                false => self.write_register(offset - self.registers(), byte),
+            }
+        }
+    }
+    fn page(&self, offset: Word, _: bool) -> Option<Page> {
+        self.pixels.get(offset.0 as usize / PAGE_SIZE).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::bus::{Bus, DeviceHandle};
+    use crate::devices::Device;
+
+    const BASE: Word = Word(0x2000_0000);
+
+    /// Framebuffer on a bus, so pixels are written the way the guest does
+    fn machine(width: u32, height: u32, format: PixelFormat) -> (Bus, DeviceHandle) {
+        let mut bus = Bus::new();
+        let framebuffer = Framebuffer::new(width, height, format);
+        let size = framebuffer.size();
+        let handle = bus.connect(Device::new(MemoryRange::new(BASE, Word(size)), Box::new(framebuffer))).unwrap();
+        (bus, handle)
+    }
+
+    #[test]
+    fn registers_describe_the_mode() {
+        let (bus, handle) = machine(320, 200, PixelFormat::Rgb565);
+        let framebuffer = bus.device::<Framebuffer>(handle).unwrap();
+        let registers = BASE + Word(framebuffer.registers());
+
+        assert_eq!(framebuffer.registers(), 32 * PAGE_SIZE as u32);
+        assert_eq!(bus.read(registers + Word(WIDTH), AccessWidth::Word), Ok(Word(320)));
+        assert_eq!(bus.read(registers + Word(HEIGHT), AccessWidth::Word), Ok(Word(200)));
+        assert_eq!(bus.read(registers + Word(FORMAT), AccessWidth::Word), Ok(Word(1)));
+        assert_eq!(bus.read(registers + Word(STRIDE), AccessWidth::Word), Ok(Word(640)));
+    }
+
+    #[test]
+    fn rgb565_pixels_render_at_full_intensity() {
+        let (bus, handle) = machine(2, 2, PixelFormat::Rgb565);
+        // Second row, second pixel: pure red
+        bus.write(BASE + Word(4 + 2), Word(0xf800), AccessWidth::Halfword).unwrap();
+
+        let image = bus.device::<Framebuffer>(handle).unwrap().render();
+        assert_eq!(image.get(1, 1), [0xff, 0, 0, 0xff]);
+        assert_eq!(image.get(0, 0), [0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn indexed_pixels_use_the_palette() {
+        let (bus, handle) = machine(4, 1, PixelFormat::Indexed8);
+        let framebuffer = bus.device::<Framebuffer>(handle).unwrap();
+        let palette = BASE + Word(framebuffer.registers() + PALETTE);
+
+        bus.write(palette + Word(4 * 7), Word(0x00_30_20_10), AccessWidth::Word).unwrap();
+        bus.write(BASE + Word(2), Word(7), AccessWidth::Byte).unwrap();
+
+        let image = framebuffer.render();
+        assert_eq!(image.get(2, 0), [0x10, 0x20, 0x30, 0xff]);
+        // Default palette is a grayscale ramp
+        assert_eq!(image.get(0, 0), [0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn blit_clips_and_matches_the_palette() {
+        let framebuffer = Framebuffer::new(2, 2, PixelFormat::Indexed8);
+        let mut image = Image::new(3, 3);
+        image.set(0, 0, [0x80, 0x80, 0x81, 0xff]);
+
+        framebuffer.blit(1, 1, &image);
+        assert_eq!(framebuffer.byte(3), 0x80);
+        assert_eq!(framebuffer.render().get(1, 1), [0x80, 0x80, 0x80, 0xff]);
+    }
+}
@@ -0,0 +1,342 @@
+//! General purpose I/O pins
+//!
+//! A block of 32 pins, bit `n` of every register belongs to pin `n`:
+//!
+//! | Offset | Register                                                  |
+//! |--------|-----------------------------------------------------------|
+//! | `0x00` | direction, 1 for output                                   |
+//! | `0x04` | output level of output pins                               |
+//! | `0x08` | level of every pin, read-only                             |
+//! | `0x0c` | pull resistor enable                                      |
+//! | `0x10` | pull direction, 1 pulls up and 0 pulls down               |
+//! | `0x14` | interrupt on rising edge enable                           |
+//! | `0x18` | interrupt on falling edge enable                          |
+//! | `0x1c` | edges seen on enabled pins, write 1 to clear              |
+//!
+//! An output pin has its output level. Input pins have the level the host
+//! drives them to with [`Gpio::drive`], otherwise their pull level, and
+//! read low when floating. The host follows pins with [`Gpio::on_change`].
+use crate::Word;
+use crate::bus::AccessWidth;
+use crate::interrupts::IrqLine;
+use crate::scheduler::{Cycle, DeviceContext};
+use super::{DeviceTrait, Properties, Property};
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+/// Number of pins of a GPIO block
+pub const PINS: u32 = 32;
+
+const DIRECTION: u32 = 0x00;
+const OUTPUT: u32 = 0x04;
+const INPUT: u32 = 0x08;
+const PULL_ENABLE: u32 = 0x0c;
+const PULL_UP: u32 = 0x10;
+const RISE_ENABLE: u32 = 0x14;
+const FALL_ENABLE: u32 = 0x18;
+const INTERRUPT_STATUS: u32 = 0x1c;
+
+/// Level change of a pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinChange {
+    pub pin: u32,
+    /// `true` for high
+    pub level: bool,
+    /// Cycle the change happened at
+    pub cycle: Cycle,
+}
+
+/// Function called on every pin change
+pub type PinListener = Box<dyn FnMut(PinChange)>;
+
+pub struct Gpio {
+    direction: Cell<u32>,
+    output: Cell<u32>,
+    pull_enable: Cell<u32>,
+    pull_up: Cell<u32>,
+    rise_enable: Cell<u32>,
+    fall_enable: Cell<u32>,
+    interrupt_status: Cell<u32>,
+
+    /// Pins the host drives and their levels
+    driven: Cell<u32>,
+    driven_high: Cell<u32>,
+    /// Pin levels after the last change
+    levels: Cell<u32>,
+
+    listeners: RefCell<Vec<PinListener>>,
+    /// Changes waiting to be delivered to listeners
+    changes: RefCell<VecDeque<PinChange>>,
+    delivering: Cell<bool>,
+    context: RefCell<Option<DeviceContext>>,
+    irq: Option<IrqLine>,
+}
+impl Gpio {
+    pub fn new() -> Self {
+        Self {
+            direction: Cell::new(0),
+            output: Cell::new(0),
+            pull_enable: Cell::new(0),
+            pull_up: Cell::new(0),
+            rise_enable: Cell::new(0),
+            fall_enable: Cell::new(0),
+            interrupt_status: Cell::new(0),
+            driven: Cell::new(0),
+            driven_high: Cell::new(0),
+            levels: Cell::new(0),
+            listeners: RefCell::new(vec![]),
+            changes: RefCell::new(VecDeque::new()),
+            delivering: Cell::new(false),
+            context: RefCell::new(None),
+            irq: None,
+        }
+    }
+
+    /// Drive `irq` while an enabled edge is waiting to be cleared
+    pub fn with_interrupt(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    /// Level of every pin
+    pub fn levels(&self) -> u32 {
+        self.levels.get()
+    }
+
+    /// Level of `pin`, `true` for high
+    pub fn level(&self, pin: u32) -> bool {
+        self.levels() & Self::bit(pin) != 0
+    }
+
+    /// Drive input `pin` to `level` until it is released, output pins keep
+    /// their output level
+    pub fn drive(&self, pin: u32, level: bool) {
+        let bit = Self::bit(pin);
+        self.driven.set(self.driven.get() | bit);
+        match level {
+            true => self.driven_high.set(self.driven_high.get() | bit),
+            false => self.driven_high.set(self.driven_high.get() & !bit),
+        }
+        self.update();
+    }
+
+    /// Stop driving `pin`, it falls back to its pull level
+    pub fn release(&self, pin: u32) {
+        self.driven.set(self.driven.get() & !Self::bit(pin));
+        self.update();
+    }
+
+    /// Call `listener` whenever a pin changes level, for whatever reason
+    pub fn on_change<F: FnMut(PinChange) + 'static>(&self, listener: F) {
+        self.listeners.borrow_mut().push(Box::new(listener));
+    }
+
+    fn bit(pin: u32) -> u32 {
+        assert!(pin < PINS, "GPIO has {PINS} pins, got pin {pin}");
+        1 << pin
+    }
+
+    fn now(&self) -> Cycle {
+        self.context.borrow().as_ref().map_or(0, |context| context.scheduler.now())
+    }
+
+    /// Levels from the registers and host drivers
+    fn compute_levels(&self) -> u32 {
+        let direction = self.direction.get();
+        let driven = self.driven.get() & !direction;
+        let pulled = self.pull_enable.get() & !direction & !driven;
+        (self.output.get() & direction) | (self.driven_high.get() & driven) | (self.pull_up.get() & pulled)
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.interrupt_status.get() != 0);
+        }
+    }
+
+    /// Recompute levels, latch edges and tell listeners what changed
+    fn update(&self) {
+        let old = self.levels.get();
+        let new = self.compute_levels();
+        self.levels.set(new);
+
+        let edges = (!old & new & self.rise_enable.get()) | (old & !new & self.fall_enable.get());
+        self.interrupt_status.set(self.interrupt_status.get() | edges);
+        self.update_interrupt();
+
+        let changed = old ^ new;
+        let cycle = self.now();
+        self.changes.borrow_mut().extend((0..PINS)
+            .filter(|pin| changed & 1 << pin != 0)
+            .map(|pin| PinChange { pin, level: new & 1 << pin != 0, cycle }));
+        self.deliver();
+    }
+
+    /// Tell listeners about queued changes, until they stop making new ones
+    fn deliver(&self) {
+        // Changes made by listeners are queued and delivered by the outer call
+        if self.delivering.replace(true) {
+            return;
+        }
+        // Listeners may drive pins themselves, so they can't stay borrowed
+        let mut listeners = std::mem::take(&mut *self.listeners.borrow_mut());
+        loop {
+            let Some(change) = self.changes.borrow_mut().pop_front() else {
+                break;
+            };
+            for listener in listeners.iter_mut() {
+                listener(change);
+            }
+        }
+        let mut current = self.listeners.borrow_mut();
+        listeners.append(&mut current);
+        *current = listeners;
+        self.delivering.set(false);
+    }
+}
+impl Default for Gpio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl DeviceTrait for Gpio {
+    fn name(&self) -> String {
+        "GPIO".into()
+    }
+    fn attach(&self, context: DeviceContext) {
+        *self.context.borrow_mut() = Some(context);
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("gpio")
+            .with("gpio-controller", Property::Empty)
+            .with("#gpio-cells", Property::Cells(vec![2]))
+            .with("ngpios", Property::Cells(vec![PINS]));
+        match &self.irq {
+            Some(irq) => properties.with("interrupts", Property::Cells(vec![irq.line()])),
+            None => properties,
+        }
+    }
+    fn supports(&self, width: AccessWidth) -> bool {
+        width == AccessWidth::Word
+    }
+    fn read(&self, address: Word, _: AccessWidth) -> Word {
+        let value = match address.0 {
+            DIRECTION => self.direction.get(),
+            OUTPUT => self.output.get(),
+            INPUT => self.levels.get(),
+            PULL_ENABLE => self.pull_enable.get(),
+            PULL_UP => self.pull_up.get(),
+            RISE_ENABLE => self.rise_enable.get(),
+            FALL_ENABLE => self.fall_enable.get(),
+            INTERRUPT_STATUS => self.interrupt_status.get(),
+            _ => 0,
+        };
+        Word(value)
+    }
+    fn write(&self, address: Word, word: Word, _: AccessWidth) {
+        let register = match address.0 {
+            DIRECTION => &self.direction,
+            OUTPUT => &self.output,
+            PULL_ENABLE => &self.pull_enable,
+            PULL_UP => &self.pull_up,
+            RISE_ENABLE => &self.rise_enable,
+            FALL_ENABLE => &self.fall_enable,
+            INTERRUPT_STATUS => {
+                self.interrupt_status.set(self.interrupt_status.get() & !word.0);
+                self.update_interrupt();
+                return;
+            }
+            _ => return,
+        };
+        register.set(word.0);
+        self.update();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::bus::Bus;
+    use crate::devices::Device;
+
+    use std::rc::Rc;
+
+    const BASE: Word = Word(0x4000_0000);
+    const BUTTON: u32 = 0;
+    const LED: u32 = 5;
+    const LAMP: u32 = 9;
+
+    #[test]
+    fn changes_made_by_listeners_reach_every_listener() {
+        let mut bus = Bus::new();
+        let handle = bus.connect(Device::new(MemoryRange::new(BASE, Word(0x20)), Box::new(Gpio::new()))).unwrap();
+        let bus = Rc::new(bus);
+        let gpio = || bus.device::<Gpio>(handle).unwrap();
+
+        // Guest firmware: the LED follows the button
+        let firmware = Rc::downgrade(&bus);
+        gpio().on_change(move |change| {
+            if change.pin == BUTTON {
+                let bus = firmware.upgrade().unwrap();
+                let output = (change.level as u32) << LED;
+                bus.write(BASE + Word(OUTPUT), Word(output), AccessWidth::Word).unwrap();
+            }
+        });
+        // Board wiring: a lamp input is wired to the LED
+        let wiring = Rc::downgrade(&bus);
+        gpio().on_change(move |change| {
+            if change.pin == LED {
+                let bus = wiring.upgrade().unwrap();
+                bus.device::<Gpio>(handle).unwrap().drive(LAMP, change.level);
+            }
+        });
+        let seen = Rc::new(RefCell::new(vec![]));
+        let log = seen.clone();
+        gpio().on_change(move |change| log.borrow_mut().push((change.pin, change.level)));
+
+        bus.write(BASE + Word(DIRECTION), Word(1 << LED), AccessWidth::Word).unwrap();
+        gpio().drive(BUTTON, true);
+        assert!(gpio().level(LED));
+        assert!(gpio().level(LAMP));
+        gpio().drive(BUTTON, false);
+        assert!(!gpio().level(LAMP));
+
+        assert_eq!(*seen.borrow(), vec![
+            (BUTTON, true), (LED, true), (LAMP, true),
+            (BUTTON, false), (LED, false), (LAMP, false),
+        ]);
+    }
+
+    #[test]
+    fn enabled_edges_raise_the_interrupt() {
+        let mut bus = Bus::new();
+        let irq = bus.interrupt_line(2);
+        let handle = bus.connect(Device::new(MemoryRange::new(BASE, Word(0x20)),
+            Box::new(Gpio::new().with_interrupt(irq)))).unwrap();
+        let gpio = || bus.device::<Gpio>(handle).unwrap();
+
+        bus.write(BASE + Word(FALL_ENABLE), Word(1 << BUTTON), AccessWidth::Word).unwrap();
+        gpio().drive(BUTTON, true);
+        assert!(!bus.interrupts().is_pending(2));
+        gpio().drive(BUTTON, false);
+        assert!(bus.interrupts().is_pending(2));
+
+        bus.write(BASE + Word(INTERRUPT_STATUS), Word(1 << BUTTON), AccessWidth::Word).unwrap();
+        assert!(!bus.interrupts().is_pending(2));
+    }
+
+    #[test]
+    fn released_pins_fall_back_to_their_pull() {
+        let gpio = Gpio::new();
+        gpio.write(Word(PULL_ENABLE), Word(1 << BUTTON), AccessWidth::Word);
+        gpio.write(Word(PULL_UP), Word(1 << BUTTON), AccessWidth::Word);
+        assert!(gpio.level(BUTTON));
+
+        gpio.drive(BUTTON, false);
+        assert!(!gpio.level(BUTTON));
+        gpio.release(BUTTON);
+        assert!(gpio.level(BUTTON));
+    }
+}
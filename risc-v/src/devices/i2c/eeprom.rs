@@ -0,0 +1,268 @@
+//! 24Cxx serial EEPROM
+//!
+//! Writes start with the word address, one byte for parts up to 24C16 and
+//! two bytes from 24C32 on. Small parts with more than 256 bytes take the
+//! high address bits from the low bits of the device address, so they
+//! answer to several addresses. Data bytes of a write wrap around within
+//! their page and are stored on the stop condition, a repeated start drops
+//! them. Reads continue from the current address and wrap around the whole
+//! memory.
+use super::I2cDevice;
+
+/// Address of the first part on a bus, the low 3 bits select the part
+pub const BASE_ADDRESS: u8 = 0x50;
+
+/// Size variant of the 24Cxx family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    C01,
+    C02,
+    C04,
+    C08,
+    C16,
+    C32,
+    C64,
+    C128,
+    C256,
+    C512,
+}
+impl Model {
+    /// Size in bytes
+    pub fn size(self) -> usize {
+        match self {
+            Model::C01 => 128,
+            Model::C02 => 256,
+            Model::C04 => 512,
+            Model::C08 => 1024,
+            Model::C16 => 2048,
+            Model::C32 => 4096,
+            Model::C64 => 8192,
+            Model::C128 => 16384,
+            Model::C256 => 32768,
+            Model::C512 => 65536,
+        }
+    }
+
+    /// Bytes a single write can store
+    pub fn page_size(self) -> usize {
+        match self {
+            Model::C01 | Model::C02 => 8,
+            Model::C04 | Model::C08 | Model::C16 => 16,
+            Model::C32 | Model::C64 => 32,
+            Model::C128 | Model::C256 => 64,
+            Model::C512 => 128,
+        }
+    }
+
+    /// Bytes of word address at the start of a write
+    fn address_bytes(self) -> usize {
+        match self.size() > 2048 {
+            true => 2,
+            false => 1,
+        }
+    }
+
+    /// Device address bits used as high bits of the word address
+    fn block_bits(self) -> u32 {
+        match self {
+            Model::C04 => 1,
+            Model::C08 => 2,
+            Model::C16 => 3,
+            _ => 0,
+        }
+    }
+}
+
+pub struct Eeprom {
+    model: Model,
+    address: u8,
+    data: Vec<u8>,
+
+    /// Word address of the next read or write
+    pointer: usize,
+    /// Addressed for a write and bytes of word address received so far
+    writing: bool,
+    address_received: usize,
+    /// Bytes written to the current page by offset, stored at the stop
+    page: Vec<Option<u8>>,
+    /// Address of the current page
+    page_base: usize,
+}
+impl Eeprom {
+    /// Erased part at [`BASE_ADDRESS`]
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            address: BASE_ADDRESS,
+            data: vec![0xff; model.size()],
+            pointer: 0,
+            writing: false,
+            address_received: 0,
+            page: vec![None; model.page_size()],
+            page_base: 0,
+        }
+    }
+
+    /// Answer to 7-bit `address`, plus the following ones used for blocks
+    pub fn with_address(mut self, address: u8) -> Self {
+        assert!(address < 0x80, "I2C addresses are 7 bits, got {address:#x}");
+        self.address = address;
+        self
+    }
+
+    /// Start with `data` at the beginning of the memory
+    pub fn with_contents(mut self, data: &[u8]) -> Self {
+        assert!(data.len() <= self.data.len(), "EEPROM contents of {} bytes don't fit in {} bytes", data.len(), self.data.len());
+        self.data[..data.len()].copy_from_slice(data);
+        self
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Block selected by the device address, `None` if it isn't ours
+    fn block(&self, address: u8) -> Option<usize> {
+        let mask = (1 << self.model.block_bits()) - 1;
+        match address & !mask == self.address & !mask {
+            true => Some((address & mask) as usize),
+            false => None,
+        }
+    }
+}
+impl I2cDevice for Eeprom {
+    fn name(&self) -> String {
+        format!("24{:?} EEPROM at {:#x}", self.model, self.address)
+    }
+    fn start(&mut self, address: u8, read: bool) -> bool {
+        let Some(block) = self.block(address) else {
+            self.writing = false;
+            return false;
+        };
+        if self.model.block_bits() > 0 {
+            self.pointer = (block << 8) | (self.pointer & 0xff);
+        }
+        // No stop since the data bytes, their write is abandoned
+        self.page.fill(None);
+        self.writing = !read;
+        self.address_received = 0;
+        true
+    }
+    fn write(&mut self, byte: u8) -> bool {
+        if !self.writing {
+            return false;
+        }
+        let size = self.data.len();
+        if self.address_received < self.model.address_bytes() {
+            self.pointer = match self.model.address_bytes() {
+                // Block bits from the device address stay
+                1 => (self.pointer & !0xff) | byte as usize,
+                _ => (self.pointer << 8 | byte as usize) & 0xffff,
+            } % size;
+            self.address_received += 1;
+            return true;
+        }
+
+        // Later bytes for the same offset replace earlier ones
+        let page = self.model.page_size();
+        let offset = self.pointer & (page - 1);
+        self.page_base = self.pointer & !(page - 1);
+        self.page[offset] = Some(byte);
+        self.pointer = self.page_base | ((offset + 1) & (page - 1));
+        true
+    }
+    fn read(&mut self, _: bool) -> u8 {
+        let byte = self.data[self.pointer];
+        self.pointer = (self.pointer + 1) % self.data.len();
+        byte
+    }
+    fn stop(&mut self) {
+        for (offset, byte) in self.page.iter_mut().enumerate() {
+            if let Some(byte) = byte.take() {
+                self.data[self.page_base + offset] = byte;
+            }
+        }
+        self.writing = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `data` at word `address` in one transaction
+    fn write(eeprom: &mut Eeprom, device: u8, address: &[u8], data: &[u8]) {
+        assert!(eeprom.start(device, false));
+        for byte in address.iter().chain(data) {
+            assert!(eeprom.write(*byte));
+        }
+        eeprom.stop();
+    }
+
+    #[test]
+    fn writes_wrap_around_their_page() {
+        let mut eeprom = Eeprom::new(Model::C02);
+
+        write(&mut eeprom, BASE_ADDRESS, &[0x0e], &[1, 2, 3, 4]);
+        assert_eq!(eeprom.contents()[0x08..0x10], [3, 4, 0xff, 0xff, 0xff, 0xff, 1, 2]);
+        assert_eq!(eeprom.contents()[0x10], 0xff);
+    }
+
+    #[test]
+    fn long_writes_keep_the_last_page_worth() {
+        let mut eeprom = Eeprom::new(Model::C32);
+        let data: Vec<u8> = (0..1000u32).map(|i| (i / 32) as u8).collect();
+
+        write(&mut eeprom, BASE_ADDRESS, &[0x01, 0x00], &data);
+        assert_eq!(eeprom.page.len(), 32);
+        // Each offset keeps the last of the 32 byte rounds that reached it
+        let mut expected = [30; 32];
+        expected[..8].fill(31);
+        assert_eq!(eeprom.contents()[0x100..0x120], expected);
+        assert_eq!(eeprom.contents()[0x120], 0xff);
+    }
+
+    #[test]
+    fn nothing_is_stored_before_the_stop() {
+        let mut eeprom = Eeprom::new(Model::C02);
+
+        assert!(eeprom.start(BASE_ADDRESS, false));
+        eeprom.write(0x20);
+        eeprom.write(0xAA);
+        assert_eq!(eeprom.contents()[0x20], 0xff);
+        eeprom.stop();
+        assert_eq!(eeprom.contents()[0x20], 0xAA);
+    }
+
+    #[test]
+    fn small_parts_take_blocks_from_the_device_address() {
+        let mut eeprom = Eeprom::new(Model::C04).with_contents(&[0; 512]);
+
+        write(&mut eeprom, BASE_ADDRESS + 1, &[0x10], &[0x55]);
+        assert_eq!(eeprom.contents()[0x110], 0x55);
+        assert!(!eeprom.start(BASE_ADDRESS + 2, false));
+
+        // Random read: set the address with a write, then read on
+        write(&mut eeprom, BASE_ADDRESS + 1, &[0x0f], &[]);
+        assert!(eeprom.start(BASE_ADDRESS + 1, true));
+        assert_eq!([eeprom.read(true), eeprom.read(false)], [0, 0x55]);
+    }
+
+    #[test]
+    fn repeated_start_drops_unstored_bytes() {
+        let mut eeprom = Eeprom::new(Model::C02);
+
+        assert!(eeprom.start(BASE_ADDRESS, false));
+        for byte in [0x00, 0xAA, 0xBB] {
+            eeprom.write(byte);
+        }
+        // Repeated start into another page, only its bytes are stored
+        write(&mut eeprom, BASE_ADDRESS, &[0x10], &[0xCC]);
+        assert_eq!(eeprom.contents()[0x00..0x02], [0xff, 0xff]);
+        assert_eq!(eeprom.contents()[0x10..0x12], [0xCC, 0xff]);
+    }
+}
@@ -0,0 +1,369 @@
+//! I2C controller and simulated peripherals
+//!
+//! [`I2cController`] is a byte level master: the guest puts a byte in the
+//! data register and issues a command, the controller clocks it out and
+//! flags completion. Peripherals implement [`I2cDevice`]. Registers are 32
+//! bits wide:
+//!
+//! | Offset | Register                                                   |
+//! |--------|------------------------------------------------------------|
+//! | `0x00` | byte to send, or the byte received by a read               |
+//! | `0x04` | command, see below, ignored while busy                     |
+//! | `0x08` | status, see below                                          |
+//! | `0x0c` | cycles per bit, at least 1                                 |
+//! | `0x10` | interrupt enable, bit 0 interrupts when a command is done  |
+//!
+//! Command bit 0 sends a start condition followed by the data register as
+//! address byte, bit 2 sends the data register, bit 3 reads a byte and bit 4
+//! answers it with NACK rather than ACK. Bit 1 sends a stop condition after
+//! the rest of the command, or on its own.
+//!
+//! Status bit 0 is set while a command runs, bit 1 when the last byte sent
+//! wasn't acknowledged, bit 2 between a start and a stop condition and bit 3
+//! when a command finished, write 1 to bit 3 to clear it.
+pub mod eeprom;
+
+pub use eeprom::Eeprom;
+
+use crate::Word;
+use crate::bus::AccessWidth;
+use crate::interrupts::IrqLine;
+use crate::scheduler::{Cycle, DeviceContext};
+use super::{AsAny, DeviceTrait, Properties, Property};
+
+use std::cell::{Ref, RefCell, RefMut};
+
+const DATA: u32 = 0x00;
+const COMMAND: u32 = 0x04;
+const STATUS: u32 = 0x08;
+const DIVIDER: u32 = 0x0c;
+const INTERRUPT_ENABLE: u32 = 0x10;
+
+const COMMAND_START: u32 = 1 << 0;
+const COMMAND_STOP: u32 = 1 << 1;
+const COMMAND_WRITE: u32 = 1 << 2;
+const COMMAND_READ: u32 = 1 << 3;
+const COMMAND_NACK: u32 = 1 << 4;
+
+const STATUS_BUSY: u32 = 1 << 0;
+const STATUS_NACK: u32 = 1 << 1;
+const STATUS_BUS_ACTIVE: u32 = 1 << 2;
+const STATUS_DONE: u32 = 1 << 3;
+
+const INTERRUPT_DONE: u32 = 1 << 0;
+
+/// Peripheral on an I2C bus
+pub trait I2cDevice: AsAny {
+    fn name(&self) -> String;
+    /// Start condition with 7-bit `address`, return `true` to acknowledge
+    /// it. Every peripheral sees every start, also repeated ones.
+    fn start(&mut self, address: u8, read: bool) -> bool;
+    /// Byte written to the peripheral after it acknowledged its address,
+    /// return `true` to acknowledge it
+    fn write(&mut self, byte: u8) -> bool;
+    /// Byte read from the peripheral after it acknowledged its address,
+    /// `ack` is `false` when the controller answers with NACK to end the read
+    fn read(&mut self, ack: bool) -> u8;
+    /// Stop condition ended the transaction
+    fn stop(&mut self) {}
+}
+
+struct State {
+    data: u8,
+    /// Command being carried out
+    command: Option<u32>,
+    status: u32,
+    divider: u32,
+    interrupt_enable: u32,
+    /// Peripheral that acknowledged the last start
+    target: Option<usize>,
+}
+impl State {
+    fn set_nack(&mut self, nack: bool) {
+        match nack {
+            true => self.status |= STATUS_NACK,
+            false => self.status &= !STATUS_NACK,
+        }
+    }
+}
+
+pub struct I2cController {
+    devices: RefCell<Vec<Box<dyn I2cDevice>>>,
+    state: RefCell<State>,
+    context: RefCell<Option<DeviceContext>>,
+    irq: Option<IrqLine>,
+}
+impl I2cController {
+    pub fn new() -> Self {
+        Self {
+            devices: RefCell::new(vec![]),
+            state: RefCell::new(State {
+                data: 0,
+                command: None,
+                status: 0,
+                divider: 1,
+                interrupt_enable: 0,
+                target: None,
+            }),
+            context: RefCell::new(None),
+            irq: None,
+        }
+    }
+
+    /// Connect `device` to the bus, it picks the addresses it answers to
+    pub fn with_peripheral<D: I2cDevice + 'static>(self, device: D) -> Self {
+        self.devices.borrow_mut().push(Box::new(device));
+        self
+    }
+
+    /// Drive `irq` while a finished command waits to be acknowledged
+    pub fn with_interrupt(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    /// Peripheral `index` in connection order, `None` if there is none or it isn't a `T`
+    pub fn peripheral<T: I2cDevice + 'static>(&self, index: usize) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.devices.borrow(), |devices| {
+            devices.get(index)?.as_ref().as_any().downcast_ref::<T>()
+        }).ok()
+    }
+
+    /// Mutable access to peripheral `index`
+    pub fn peripheral_mut<T: I2cDevice + 'static>(&self, index: usize) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.devices.borrow_mut(), |devices| {
+            devices.get_mut(index)?.as_mut().as_any_mut().downcast_mut::<T>()
+        }).ok()
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(irq) = &self.irq {
+            let state = self.state.borrow();
+            irq.set(state.interrupt_enable & INTERRUPT_DONE != 0 && state.status & STATUS_DONE != 0);
+        }
+    }
+
+    fn start_command(&self, command: u32) {
+        let mut state = self.state.borrow_mut();
+        state.command = Some(command);
+        state.status = (state.status | STATUS_BUSY) & !STATUS_DONE;
+
+        // A byte takes 9 clocks with its acknowledge, conditions one each
+        let mut bits = 0;
+        if command & (COMMAND_START | COMMAND_WRITE | COMMAND_READ) != 0 {
+            bits += 9;
+        }
+        if command & COMMAND_START != 0 {
+            bits += 1;
+        }
+        if command & COMMAND_STOP != 0 {
+            bits += 1;
+        }
+        let delay = bits * state.divider as Cycle;
+        drop(state);
+        self.update_interrupt();
+
+        match self.context.borrow().clone() {
+            Some(context) => {
+                let handle = context.handle;
+                context.scheduler.schedule(delay, move |bus| {
+                    if let Some(i2c) = bus.device::<I2cController>(handle) {
+                        i2c.finish_command();
+                    }
+                });
+            }
+            // Not on a bus, nothing keeps time
+            None => self.finish_command(),
+        }
+    }
+
+    /// Clocks of the command are over, exchange data with the peripherals
+    fn finish_command(&self) {
+        let mut state = self.state.borrow_mut();
+        let Some(command) = state.command.take() else {
+            return;
+        };
+        let mut devices = self.devices.borrow_mut();
+
+        if command & COMMAND_START != 0 {
+            let (address, read) = (state.data >> 1, state.data & 1 != 0);
+            // Every peripheral sees the start, the first to answer gets the bus
+            let mut target = None;
+            for (index, device) in devices.iter_mut().enumerate() {
+                if device.start(address, read) && target.is_none() {
+                    target = Some(index);
+                }
+            }
+            state.target = target;
+            state.status |= STATUS_BUS_ACTIVE;
+            state.set_nack(target.is_none());
+        } else if command & COMMAND_WRITE != 0 {
+            let data = state.data;
+            let ack = state.target.is_some_and(|index| devices[index].write(data));
+            state.set_nack(!ack);
+        } else if command & COMMAND_READ != 0 {
+            // Nobody drives the bus, it reads high
+            state.data = match state.target {
+                Some(index) => devices[index].read(command & COMMAND_NACK == 0),
+                None => 0xff,
+            };
+        }
+
+        if command & COMMAND_STOP != 0 {
+            for device in devices.iter_mut() {
+                device.stop();
+            }
+            state.target = None;
+            state.status &= !STATUS_BUS_ACTIVE;
+        }
+
+        state.status = (state.status & !STATUS_BUSY) | STATUS_DONE;
+        drop(devices);
+        drop(state);
+        self.update_interrupt();
+    }
+}
+impl Default for I2cController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceTrait for I2cController {
+    fn name(&self) -> String {
+        let devices = self.devices.borrow();
+        let names: Vec<String> = devices.iter().map(|device| device.name()).collect();
+        format!("I2C controller [{}]", names.join(", "))
+    }
+    fn attach(&self, context: DeviceContext) {
+        *self.context.borrow_mut() = Some(context);
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("i2c")
+            .with("#address-cells", Property::Cells(vec![1]))
+            .with("#size-cells", Property::Cells(vec![0]));
+        match &self.irq {
+            Some(irq) => properties.with("interrupts", Property::Cells(vec![irq.line()])),
+            None => properties,
+        }
+    }
+    fn supports(&self, width: AccessWidth) -> bool {
+        width == AccessWidth::Word
+    }
+    fn read(&self, address: Word, _: AccessWidth) -> Word {
+        let state = self.state.borrow();
+        let value = match address.0 {
+            DATA => state.data as u32,
+            STATUS => state.status,
+            DIVIDER => state.divider,
+            INTERRUPT_ENABLE => state.interrupt_enable,
+            _ => 0,
+        };
+        Word(value)
+    }
+    fn write(&self, address: Word, word: Word, _: AccessWidth) {
+        let mut state = self.state.borrow_mut();
+        let busy = state.command.is_some();
+        match address.0 {
+            DATA if !busy => state.data = word.0 as u8,
+            COMMAND if !busy => {
+                drop(state);
+                self.start_command(word.0 & (COMMAND_START | COMMAND_STOP | COMMAND_WRITE | COMMAND_READ | COMMAND_NACK));
+                return;
+            }
+            STATUS => state.status &= !(word.0 & STATUS_DONE),
+            DIVIDER => state.divider = word.0.max(1),
+            INTERRUPT_ENABLE => state.interrupt_enable = word.0 & INTERRUPT_DONE,
+            _ => return,
+        }
+        drop(state);
+        self.update_interrupt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::bus::{Bus, DeviceHandle};
+    use crate::devices::Device;
+    use eeprom::{BASE_ADDRESS, Model};
+
+    const BASE: Word = Word(0x4000_0000);
+
+    fn read(bus: &Bus, register: u32) -> u32 {
+        bus.read(BASE + Word(register), AccessWidth::Word).unwrap().0
+    }
+
+    fn write(bus: &Bus, register: u32, value: u32) {
+        bus.write(BASE + Word(register), Word(value), AccessWidth::Word).unwrap();
+    }
+
+    /// Run `command` with `data` to completion, return the status
+    fn command(bus: &Bus, command: u32, data: u8) -> u32 {
+        write(bus, DATA, data as u32);
+        write(bus, COMMAND, command);
+        while bus.skip_to_next_event() {}
+        let status = read(bus, STATUS);
+        write(bus, STATUS, STATUS_DONE);
+        status
+    }
+
+    fn machine(i2c: I2cController) -> (Bus, DeviceHandle) {
+        let mut bus = Bus::new();
+        let handle = bus.connect(Device::new(MemoryRange::new(BASE, Word(0x14)), Box::new(i2c))).unwrap();
+        (bus, handle)
+    }
+
+    #[test]
+    fn eeprom_is_written_and_read_back_through_the_registers() {
+        let (bus, handle) = machine(I2cController::new().with_peripheral(Eeprom::new(Model::C02)));
+        let address = BASE_ADDRESS << 1;
+
+        assert_eq!(command(&bus, COMMAND_START, address) & STATUS_NACK, 0);
+        for byte in [0x40, 0x12, 0x34] {
+            assert_eq!(command(&bus, COMMAND_WRITE, byte) & STATUS_NACK, 0);
+        }
+        assert_eq!(command(&bus, COMMAND_STOP, 0) & STATUS_BUS_ACTIVE, 0);
+        let i2c = bus.device::<I2cController>(handle).unwrap();
+        assert_eq!(i2c.peripheral::<Eeprom>(0).unwrap().contents()[0x40..0x42], [0x12, 0x34]);
+
+        command(&bus, COMMAND_START, address);
+        command(&bus, COMMAND_WRITE, 0x40);
+        assert_ne!(command(&bus, COMMAND_START, address | 1) & STATUS_BUS_ACTIVE, 0);
+        command(&bus, COMMAND_READ, 0);
+        assert_eq!(read(&bus, DATA), 0x12);
+        command(&bus, COMMAND_READ | COMMAND_NACK | COMMAND_STOP, 0);
+        assert_eq!(read(&bus, DATA), 0x34);
+    }
+
+    #[test]
+    fn absent_address_is_not_acknowledged_and_reads_high() {
+        let (bus, _) = machine(I2cController::new().with_peripheral(Eeprom::new(Model::C02)));
+
+        assert_ne!(command(&bus, COMMAND_START, 0x20 << 1 | 1) & STATUS_NACK, 0);
+        command(&bus, COMMAND_READ | COMMAND_STOP, 0);
+        assert_eq!(read(&bus, DATA), 0xff);
+    }
+
+    #[test]
+    fn commands_take_their_clocks_and_interrupt_when_done() {
+        let mut bus = Bus::new();
+        let irq = bus.interrupt_line(6);
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(0x14)), Box::new(I2cController::new().with_interrupt(irq)))).unwrap();
+
+        write(&bus, DIVIDER, 10);
+        write(&bus, INTERRUPT_ENABLE, INTERRUPT_DONE);
+        // Start, address byte with its acknowledge and stop
+        write(&bus, COMMAND, COMMAND_START | COMMAND_STOP);
+        bus.advance(109);
+        assert_eq!(read(&bus, STATUS) & STATUS_BUSY, STATUS_BUSY);
+        assert!(!bus.interrupts().is_pending(6));
+        bus.advance(1);
+        assert_eq!(read(&bus, STATUS) & (STATUS_BUSY | STATUS_DONE), STATUS_DONE);
+        assert!(bus.interrupts().is_pending(6));
+
+        write(&bus, STATUS, STATUS_DONE);
+        assert!(!bus.interrupts().is_pending(6));
+    }
+}
@@ -0,0 +1,486 @@
+//! Host input forwarded to the guest
+//!
+//! Frontends such as the SDL display translate their events into calls on an
+//! [`InputSink`]. Keys are USB HID usage codes, which SDL scancodes already are.
+//!
+//! [`Input`] is the device guests read, with 32-bit registers:
+//!
+//! | Offset        | Register                                                    |
+//! |---------------|-------------------------------------------------------------|
+//! | `0x00`-`0x0c` | button mask of gamepad 0-3, see [`Button`]                  |
+//! | `0x10`        | key events waiting in bits 0-7, bit 8 set if events were lost, cleared by reading |
+//! | `0x14`        | next key event, HID usage in bits 0-15, bit 16 set for a release, 0 if none |
+//! | `0x18`        | interrupt enable, bit 0 for key events, bit 1 for gamepad changes |
+//! | `0x1c`        | interrupt status, same bits, write 1 to bit 1 to acknowledge |
+//!
+//! An [`InputScript`] replays input at fixed cycles, for reproducible tests.
+use crate::Word;
+use crate::bus::{AccessWidth, Bus, DeviceHandle};
+use crate::interrupts::IrqLine;
+use crate::scheduler::Cycle;
+use super::{DeviceTrait, Properties, Property};
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// Number of gamepads input devices serve
+pub const GAMEPADS: usize = 4;
+
+/// Key events the FIFO holds
+pub const KEY_FIFO_SIZE: usize = 16;
+
+const KEY_STATUS: u32 = 0x10;
+const KEY_DATA: u32 = 0x14;
+const INTERRUPT_ENABLE: u32 = 0x18;
+const INTERRUPT_STATUS: u32 = 0x1c;
+
+const KEY_RELEASED: u32 = 1 << 16;
+const KEY_OVERFLOW: u32 = 1 << 8;
+
+const INTERRUPT_KEYS: u32 = 1 << 0;
+const INTERRUPT_PADS: u32 = 1 << 1;
+
+/// Gamepad button, the value is its bit in a button mask
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Button {
+    Up = 0,
+    Down = 1,
+    Left = 2,
+    Right = 3,
+    A = 4,
+    B = 5,
+    X = 6,
+    Y = 7,
+    L = 8,
+    R = 9,
+    Start = 10,
+    Select = 11,
+}
+impl Button {
+    pub fn mask(self) -> u16 {
+        1 << self as u16
+    }
+
+    /// Button called `name`, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        let button = match name.to_lowercase().as_str() {
+            "up" => Button::Up,
+            "down" => Button::Down,
+            "left" => Button::Left,
+            "right" => Button::Right,
+            "a" => Button::A,
+            "b" => Button::B,
+            "x" => Button::X,
+            "y" => Button::Y,
+            "l" => Button::L,
+            "r" => Button::R,
+            "start" => Button::Start,
+            "select" => Button::Select,
+            _ => return None,
+        };
+        Some(button)
+    }
+}
+
+/// Receiver of keyboard and gamepad events
+pub trait InputSink {
+    /// Key with HID usage `scancode` was pressed or released
+    fn key(&self, scancode: u16, pressed: bool);
+    /// `button` of gamepad `pad` was pressed or released
+    fn button(&self, pad: usize, button: Button, pressed: bool);
+}
+
+/// HID usage of the key typing `c` on a US layout, without modifiers
+pub fn char_usage(c: char) -> Option<u16> {
+    let usage = match c.to_ascii_lowercase() {
+        c @ 'a'..='z' => 4 + (c as u16 - 'a' as u16),
+        '0' => 39,
+        c @ '1'..='9' => 30 + (c as u16 - '1' as u16),
+        '\n' | '\r' => 40,
+        '\x1b' => 41,
+        '\x08' => 42,
+        '\t' => 43,
+        ' ' => 44,
+        '-' => 45,
+        '=' => 46,
+        '[' => 47,
+        ']' => 48,
+        '\\' => 49,
+        ';' => 51,
+        '\'' => 52,
+        '`' => 53,
+        ',' => 54,
+        '.' => 55,
+        '/' => 56,
+        _ => return None,
+    };
+    Some(usage)
+}
+
+/// HID usage of the key called `name`, such as `a`, `enter` or `f1`, ignoring case
+pub fn key_usage(name: &str) -> Option<u16> {
+    let name = name.to_lowercase();
+    let usage = match name.as_str() {
+        "enter" | "return" => 40,
+        "escape" | "esc" => 41,
+        "backspace" => 42,
+        "tab" => 43,
+        "space" => 44,
+        "insert" => 73,
+        "home" => 74,
+        "pageup" => 75,
+        "delete" => 76,
+        "end" => 77,
+        "pagedown" => 78,
+        "right" => 79,
+        "left" => 80,
+        "down" => 81,
+        "up" => 82,
+        "lctrl" | "ctrl" => 224,
+        "lshift" | "shift" => 225,
+        "lalt" | "alt" => 226,
+        "rctrl" => 228,
+        "rshift" => 229,
+        "ralt" => 230,
+        _ => {
+            let mut chars = name.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(c), None) => char_usage(c),
+                (Some('f'), Some(_)) => match name[1..].parse::<u16>() {
+                    Ok(n @ 1..=12) => Some(57 + n),
+                    _ => None,
+                },
+                _ => None,
+            };
+        }
+    };
+    Some(usage)
+}
+
+/// Gamepads and keyboard as seen by the guest
+pub struct Input {
+    pads: [Cell<u16>; GAMEPADS],
+    keys: RefCell<VecDeque<u32>>,
+    overflow: Cell<bool>,
+    pads_changed: Cell<bool>,
+    interrupt_enable: Cell<u32>,
+    irq: Option<IrqLine>,
+}
+impl Input {
+    pub fn new() -> Self {
+        Self {
+            pads: Default::default(),
+            keys: RefCell::new(VecDeque::new()),
+            overflow: Cell::new(false),
+            pads_changed: Cell::new(false),
+            interrupt_enable: Cell::new(0),
+            irq: None,
+        }
+    }
+
+    /// Drive `irq` while an enabled interrupt is pending
+    pub fn with_interrupt(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    /// Buttons held on gamepad `pad`
+    pub fn pad(&self, pad: usize) -> u16 {
+        self.pads[pad].get()
+    }
+
+    fn interrupt_status(&self) -> u32 {
+        let mut status = 0;
+        if !self.keys.borrow().is_empty() {
+            status |= INTERRUPT_KEYS;
+        }
+        if self.pads_changed.get() {
+            status |= INTERRUPT_PADS;
+        }
+        status
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.interrupt_status() & self.interrupt_enable.get() != 0);
+        }
+    }
+}
+impl Default for Input {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl InputSink for Input {
+    fn key(&self, scancode: u16, pressed: bool) {
+        let event = match pressed {
+            true => scancode as u32,
+            false => scancode as u32 | KEY_RELEASED,
+        };
+
+        let mut keys = self.keys.borrow_mut();
+        match keys.len() < KEY_FIFO_SIZE {
+            true => keys.push_back(event),
+            false => self.overflow.set(true),
+        }
+        drop(keys);
+
+        self.update_interrupt();
+    }
+
+    fn button(&self, pad: usize, button: Button, pressed: bool) {
+        let Some(buttons) = self.pads.get(pad) else {
+            return;
+        };
+        let held = match pressed {
+            true => buttons.get() | button.mask(),
+            false => buttons.get() & !button.mask(),
+        };
+        if held != buttons.get() {
+            buttons.set(held);
+            self.pads_changed.set(true);
+            self.update_interrupt();
+        }
+    }
+}
+impl DeviceTrait for Input {
+    fn name(&self) -> String {
+        "input".into()
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("input");
+        match &self.irq {
+            Some(irq) => properties.with("interrupts", Property::Cells(vec![irq.line()])),
+            None => properties,
+        }
+    }
+    fn supports(&self, width: AccessWidth) -> bool {
+        width == AccessWidth::Word
+    }
+    fn read(&self, address: Word, _: AccessWidth) -> Word {
+        let value = match address.0 {
+            pad @ 0x00..=0x0c if pad % 4 == 0 => self.pads[pad as usize / 4].get() as u32,
+            KEY_STATUS => {
+                let overflow = match self.overflow.replace(false) {
+                    true => KEY_OVERFLOW,
+                    false => 0,
+                };
+                self.keys.borrow().len() as u32 | overflow
+            }
+            KEY_DATA => {
+                let event = self.keys.borrow_mut().pop_front().unwrap_or(0);
+                self.update_interrupt();
+                event
+            }
+            INTERRUPT_ENABLE => self.interrupt_enable.get(),
+            INTERRUPT_STATUS => self.interrupt_status(),
+            _ => 0,
+        };
+        Word(value)
+    }
+    fn write(&self, address: Word, word: Word, _: AccessWidth) {
+        match address.0 {
+            INTERRUPT_ENABLE => self.interrupt_enable.set(word.0 & (INTERRUPT_KEYS | INTERRUPT_PADS)),
+            INTERRUPT_STATUS if word.0 & INTERRUPT_PADS != 0 => self.pads_changed.set(false),
+            _ => return,
+        }
+        self.update_interrupt();
+    }
+}
+
+/// Reason an input script couldn't be read
+#[derive(Debug)]
+pub enum ScriptError {
+    Io(std::io::Error),
+    /// Line `line`, counted from 1, isn't a valid event
+    Syntax { line: usize, message: String },
+}
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScriptError::Io(err) => write!(f, "{err}"),
+            ScriptError::Syntax { line, message } => write!(f, "line {line}: {message}"),
+        }
+    }
+}
+impl std::error::Error for ScriptError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScriptEvent {
+    Key { scancode: u16, pressed: bool },
+    Button { pad: usize, button: Button, pressed: bool },
+}
+
+/// Input events at fixed cycles. Every line of a script is an event:
+///
+/// ```text
+/// # comments and empty lines are ignored
+/// 1000 key a press
+/// 1500 key a release
+/// 2000 key 0x28 press
+/// 3000 pad 0 start press
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct InputScript {
+    events: Vec<(Cycle, ScriptEvent)>,
+}
+impl InputScript {
+    pub fn parse(script: &str) -> Result<Self, ScriptError> {
+        let mut events = vec![];
+        for (index, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+
+            let error = |message: &str| ScriptError::Syntax { line: index + 1, message: message.into() };
+            let cycle = words[0].parse::<Cycle>().map_err(|_| error("expected cycle"))?;
+            let pressed = match words.last() {
+                Some(&"press") => true,
+                Some(&"release") => false,
+                _ => return Err(error("expected press or release at the end")),
+            };
+
+            let event = match &words[1..] {
+                ["key", key, _] => {
+                    let number = match key.strip_prefix("0x") {
+                        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+                        None => key.parse().ok(),
+                    };
+                    let scancode = key_usage(key).or(number).ok_or_else(|| error("unknown key"))?;
+                    ScriptEvent::Key { scancode, pressed }
+                }
+                ["pad", pad, button, _] => {
+                    let pad = pad.parse().ok().filter(|pad| *pad < GAMEPADS)
+                        .ok_or_else(|| error("expected gamepad 0 to 3"))?;
+                    let button = Button::from_name(button).ok_or_else(|| error("unknown button"))?;
+                    ScriptEvent::Button { pad, button, pressed }
+                }
+                _ => return Err(error("expected key or pad event")),
+            };
+            events.push((cycle, event));
+        }
+        Ok(Self { events })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ScriptError> {
+        Self::parse(&std::fs::read_to_string(path).map_err(ScriptError::Io)?)
+    }
+
+    /// Number of events
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Schedule every event for the [`Input`] device connected as `handle`,
+    /// events at cycles already past happen on the next cycle
+    pub fn install(&self, bus: &Bus, handle: DeviceHandle) {
+        for (cycle, event) in self.events.iter().copied() {
+            bus.scheduler().schedule_at(cycle, move |bus| {
+                if let Some(input) = bus.device::<Input>(handle) {
+                    match event {
+                        ScriptEvent::Key { scancode, pressed } => input.key(scancode, pressed),
+                        ScriptEvent::Button { pad, button, pressed } => input.button(pad, button, pressed),
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &Input, register: u32) -> u32 {
+        input.read(Word(register), AccessWidth::Word).0
+    }
+
+    #[test]
+    fn button_masks_are_distinct_bits() {
+        let buttons = ["up", "down", "left", "right", "a", "b", "x", "y", "l", "r", "start", "select"];
+        let masks: Vec<u16> = buttons.iter().map(|name| Button::from_name(name).unwrap().mask()).collect();
+
+        assert_eq!(masks.iter().fold(0, |all, mask| all | mask), 0x0fff);
+        assert_eq!(Button::from_name("START"), Some(Button::Start));
+        assert_eq!(Button::from_name("turbo"), None);
+    }
+
+    #[test]
+    fn sink_events_reach_the_registers() {
+        let input = Input::new();
+        input.button(1, Button::A, true);
+        input.button(1, Button::Start, true);
+        input.button(1, Button::A, false);
+        input.key(4, true);
+        input.key(4, false);
+
+        assert_eq!(read(&input, 0x04), Button::Start.mask() as u32);
+        assert_eq!(read(&input, INTERRUPT_STATUS), INTERRUPT_KEYS | INTERRUPT_PADS);
+        assert_eq!(read(&input, KEY_STATUS), 2);
+        assert_eq!(read(&input, KEY_DATA), 4);
+        assert_eq!(read(&input, KEY_DATA), 4 | KEY_RELEASED);
+        assert_eq!(read(&input, KEY_DATA), 0);
+    }
+
+    #[test]
+    fn full_key_fifo_reports_lost_events() {
+        let input = Input::new();
+        for _ in 0..KEY_FIFO_SIZE + 1 {
+            input.key(44, true);
+        }
+
+        assert_eq!(read(&input, KEY_STATUS), KEY_FIFO_SIZE as u32 | KEY_OVERFLOW);
+        assert_eq!(read(&input, KEY_STATUS), KEY_FIFO_SIZE as u32);
+    }
+
+    #[test]
+    fn script_replays_events_at_their_cycles() {
+        use crate::MemoryRange;
+        use crate::devices::Device;
+
+        let mut bus = Bus::new();
+        let handle = bus.connect(Device::new(MemoryRange::new(Word(0x4000_0000), Word(0x20)), Box::new(Input::new()))).unwrap();
+        let script = InputScript::parse("
+            # type a, then press start
+            10 key a press
+            20 key A release   # names ignore case
+            30 pad 2 start press
+            40 key 0x28 press
+        ").unwrap();
+        assert_eq!(script.len(), 4);
+        script.install(&bus, handle);
+
+        let input = || bus.device::<Input>(handle).unwrap();
+        bus.advance(9);
+        assert_eq!(read(input(), KEY_STATUS), 0);
+        bus.advance(1);
+        assert_eq!(read(input(), KEY_DATA), 4);
+        bus.advance(20);
+        assert_eq!(read(input(), KEY_DATA), 4 | KEY_RELEASED);
+        assert_eq!(input().pad(2), Button::Start.mask());
+        bus.advance(10);
+        assert_eq!(read(input(), KEY_DATA), 40);
+    }
+
+    #[test]
+    fn script_errors_name_the_line() {
+        let error = |script| match InputScript::parse(script) {
+            Err(ScriptError::Syntax { line, message }) => (line, message),
+            other => panic!("expected syntax error, got {other:?}"),
+        };
+
+        assert_eq!(error("10 key a press\nsoon key a press"), (2, "expected cycle".into()));
+        assert_eq!(error("10 key a hold"), (1, "expected press or release at the end".into()));
+        assert_eq!(error("10 pad 4 a press"), (1, "expected gamepad 0 to 3".into()));
+        assert_eq!(error("10 key nokey press"), (1, "unknown key".into()));
+        assert!(InputScript::parse("# nothing\n\n").unwrap().is_empty());
+    }
+}
@@ -1,30 +1,168 @@
 pub mod rom;
 pub mod ram;
+pub mod serial;
+pub mod uart;
+pub mod disk;
+pub mod virtio;
+pub mod framebuffer;
+pub mod font;
+pub mod text;
+pub mod input;
+pub mod psg;
+pub mod rtc;
+pub mod timer;
+pub mod gpio;
+pub mod dma;
+pub mod spi;
+pub mod i2c;
+pub mod watchdog;
+pub mod syscon;
 
 #[cfg(feature = "multimedia")]
 pub mod multimedia;
+#[cfg(feature = "terminal")]
+pub mod terminal;
 
 use crate::{Word, MemoryRange};
+use crate::bus::AccessWidth;
+use crate::scheduler::DeviceContext;
+use ram::Page;
+use std::any::Any;
+
+/// How the bus treats writes to a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessPolicy {
+    /// Writes reach the device
+    ReadWrite,
+    /// Writes raise a store access fault
+    ReadOnly,
+    /// Writes are dropped without a fault
+    WriteIgnored,
+}
+
+/// Value of a device tree property
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Property {
+    /// Property without a value, used as a flag
+    Empty,
+    /// List of 32-bit cells
+    Cells(Vec<u32>),
+    String(String),
+    /// List of strings
+    Strings(Vec<String>),
+}
+
+/// How a device describes itself in the memory map and the device tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Properties {
+    /// Generic node name, such as `memory` or `serial`
+    pub node: String,
+    /// `compatible` strings, most specific first
+    pub compatible: Vec<String>,
+    /// `device_type` property, `memory` for system RAM
+    pub device_type: Option<String>,
+    /// Properties specific to the device
+    pub extra: Vec<(String, Property)>,
+}
+impl Properties {
+    pub fn new(node: &str) -> Self {
+        Self { node: node.into(), compatible: vec![], device_type: None, extra: vec![] }
+    }
+
+    pub fn with_compatible(mut self, compatible: &str) -> Self {
+        self.compatible.push(compatible.into());
+        self
+    }
+
+    pub fn with_device_type(mut self, device_type: &str) -> Self {
+        self.device_type = Some(device_type.into());
+        self
+    }
+
+    pub fn with(mut self, name: &str, value: Property) -> Self {
+        self.extra.push((name.into(), value));
+        self
+    }
+}
 
 pub struct Device {
     pub range: MemoryRange,
     pub device: Box<dyn DeviceTrait>,
+    pub policy: AccessPolicy,
 }
 impl Device {
     pub fn new(range: MemoryRange, device: Box<dyn DeviceTrait>) -> Self {
-        Self { range, device }
+        let policy = device.policy();
+        Self { range, device, policy }
     }
-    pub fn read(&self, address: Word) -> Word {
-        self.device.read(address)
+
+    /// Override the access policy the device declares
+    pub fn with_policy(mut self, policy: AccessPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+    /// Read at absolute `address`, translated to an offset within the device
+    pub fn read(&self, address: Word, width: AccessWidth) -> Word {
+        self.device.read(address - self.range.base, width)
+    }
+    /// Write at absolute `address`, translated to an offset within the device
+    pub fn write(&self, address: Word, word: Word, width: AccessWidth) {
+        self.device.write(address - self.range.base, word, width);
+    }
+    pub fn name(&self) -> String {
+        self.device.name()
+    }
+}
+
+/// Access to a device as [`Any`], so it can be downcast to its concrete type
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
     }
-    pub fn write(&self, address: Word, word: Word) {
-        self.device.write(address, word);
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
     }
 }
 
-pub trait DeviceTrait {
+pub trait DeviceTrait: AsAny {
     fn name(&self) -> String;
-    fn read(&self, address: Word) -> Word;
-    fn write(&self, address: Word, word: Word);
-    fn tick(&self);
-}
\ No newline at end of file
+    /// Called once when the device is connected to the bus,
+    /// devices that need time keep the scheduler from `context`
+    fn attach(&self, context: DeviceContext) {
+        let _ = context;
+    }
+    /// Go back to the registers after power on, for a machine reset.
+    /// Memory keeps its contents, devices without such state do nothing.
+    fn reset(&self) {}
+    /// Description used by the memory map and the device tree
+    fn properties(&self) -> Properties {
+        Properties::new("device")
+    }
+    /// Return `true` if the device serves transfers of `width`,
+    /// other widths raise an access fault without reaching the device
+    fn supports(&self, width: AccessWidth) -> bool {
+        let _ = width;
+        true
+    }
+    /// Access policy the device is mapped with by default
+    fn policy(&self) -> AccessPolicy {
+        AccessPolicy::ReadWrite
+    }
+    /// Read `width` bytes at `address`, little endian.
+    /// `address` is an offset from the base of the device range.
+    fn read(&self, address: Word, width: AccessWidth) -> Word;
+    /// Write low `width` bytes of `word` at `address`.
+    /// `address` is an offset from the base of the device range.
+    fn write(&self, address: Word, word: Word, width: AccessWidth);
+    /// Host memory backing the page at page aligned `offset`, for devices
+    /// that are plain memory, so the bus can access it directly.
+    /// Pages not backed yet are only created when `allocate` is set.
+    fn page(&self, offset: Word, allocate: bool) -> Option<Page> {
+        let _ = (offset, allocate);
+        None
+    }
+}
@@ -0,0 +1,29 @@
+use sdl2::AudioSubsystem;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+use crate::audio::AudioSink;
+
+/// Plays samples on the default audio device
+pub struct Audio {
+    queue: AudioQueue<i16>,
+}
+impl Audio {
+    pub fn new(subsystem: &AudioSubsystem, sample_rate: u32) -> Result<Self, String> {
+        let spec = AudioSpecDesired { freq: Some(sample_rate as i32), channels: Some(1), samples: None };
+        let queue = subsystem.open_queue::<i16, _>(None, &spec)?;
+        queue.resume();
+
+        Ok(Self { queue })
+    }
+
+    /// Samples waiting to be played, a growing queue means the machine runs ahead
+    pub fn queued(&self) -> usize {
+        self.queue.size() as usize / 2
+    }
+}
+impl AudioSink for Audio {
+    fn samples(&mut self, samples: &[i16]) {
+        // Nothing useful can be done about a device that went away
+        let _ = self.queue.queue_audio(samples);
+    }
+}
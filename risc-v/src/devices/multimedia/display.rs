@@ -1,12 +1,191 @@
 use sdl2;
+use sdl2::controller::{self, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
+use sdl2::{EventPump, GameControllerSubsystem};
+
+use crate::devices::framebuffer::Framebuffer;
+use crate::devices::input::{Button, InputSink, GAMEPADS};
+use crate::image::Image;
+
+/// How the display window is created
+#[derive(Debug, Clone)]
+pub struct WindowOptions {
+    pub(super) title: String,
+    pub(super) width: u32,
+    pub(super) height: u32,
+    pub(super) vsync: bool,
+}
+impl WindowOptions {
+    pub fn new() -> Self {
+        Self { title: "Fantasy Console".into(), width: 800, height: 600, vsync: true }
+    }
+
+    pub fn with_title(mut self, title: &str) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    /// Initial size of the window, it can be resized
+    pub fn with_size(mut self, width: u32, height: u32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// Wait for the vertical blank when presenting, which paces the frame rate
+    pub fn with_vsync(mut self, vsync: bool) -> Self {
+        self.vsync = vsync;
+        self
+    }
+}
+impl Default for WindowOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Gamepad button of a controller button, others aren't forwarded
+fn button(button: controller::Button) -> Option<Button> {
+    match button {
+        controller::Button::DPadUp => Some(Button::Up),
+        controller::Button::DPadDown => Some(Button::Down),
+        controller::Button::DPadLeft => Some(Button::Left),
+        controller::Button::DPadRight => Some(Button::Right),
+        controller::Button::A => Some(Button::A),
+        controller::Button::B => Some(Button::B),
+        controller::Button::X => Some(Button::X),
+        controller::Button::Y => Some(Button::Y),
+        controller::Button::LeftShoulder => Some(Button::L),
+        controller::Button::RightShoulder => Some(Button::R),
+        controller::Button::Start => Some(Button::Start),
+        controller::Button::Back => Some(Button::Select),
+        _ => None,
+    }
+}
 
 pub struct Display {
     canvas: Canvas<Window>,
+    events: EventPump,
+    controller_subsystem: Option<GameControllerSubsystem>,
+    /// Open controllers, the index is the gamepad number
+    gamepads: [Option<GameController>; GAMEPADS],
+    /// Last presented frame, drawn again when the window changes
+    frame: Option<Image>,
+    open: bool,
 }
 impl Display {
-    pub fn new(canvas: Canvas<Window>) -> Self {
-        Self { canvas }
+    pub fn new(canvas: Canvas<Window>, events: EventPump, controller_subsystem: Option<GameControllerSubsystem>) -> Self {
+        Self {
+            canvas,
+            events,
+            controller_subsystem,
+            gamepads: Default::default(),
+            frame: None,
+            open: true,
+        }
     }
-}
\ No newline at end of file
+
+    /// Return `false` once the window was closed
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Show `image` at the largest integer scale fitting the window, centered
+    pub fn present(&mut self, image: &Image) -> Result<(), String> {
+        self.frame = Some(image.clone());
+        self.draw()
+    }
+
+    /// Render `framebuffer` and show it
+    pub fn present_framebuffer(&mut self, framebuffer: &Framebuffer) -> Result<(), String> {
+        self.present(&framebuffer.render())
+    }
+
+    fn draw(&mut self) -> Result<(), String> {
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+
+        if let Some(image) = &self.frame {
+            let creator = self.canvas.texture_creator();
+            // RGBA32 is bytes R, G, B, A in memory whatever the endianness
+            let mut texture = creator
+                .create_texture_streaming(PixelFormatEnum::RGBA32, image.width(), image.height())
+                .map_err(|e| e.to_string())?;
+            texture
+                .update(None, image.pixels(), image.width() as usize * 4)
+                .map_err(|e| e.to_string())?;
+
+            let (width, height) = self.canvas.output_size()?;
+            let scale = (width / image.width()).min(height / image.height()).max(1);
+            let (scaled_width, scaled_height) = (image.width() * scale, image.height() * scale);
+            let target = Rect::new(
+                (width as i32 - scaled_width as i32) / 2,
+                (height as i32 - scaled_height as i32) / 2,
+                scaled_width,
+                scaled_height,
+            );
+            self.canvas.copy(&texture, None, target)?;
+        }
+
+        self.canvas.present();
+        Ok(())
+    }
+
+    /// Handle pending window events, forwarding keyboard and gamepad input to `input`
+    pub fn poll_events(&mut self, input: Option<&dyn InputSink>) -> Result<(), String> {
+        let mut redraw = false;
+
+        for event in self.events.poll_iter() {
+            match event {
+                Event::Quit { .. } | Event::Window { win_event: WindowEvent::Close, .. } => self.open = false,
+                Event::Window {
+                    win_event: WindowEvent::Resized(..) | WindowEvent::SizeChanged(..) | WindowEvent::Exposed,
+                    ..
+                } => redraw = true,
+                // Guests see presses and releases, repeating is up to them
+                Event::KeyDown { scancode: Some(scancode), repeat: false, .. } => {
+                    if let Some(input) = input {
+                        input.key(scancode as i32 as u16, true);
+                    }
+                }
+                Event::KeyUp { scancode: Some(scancode), .. } => {
+                    if let Some(input) = input {
+                        input.key(scancode as i32 as u16, false);
+                    }
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    let free = self.gamepads.iter().position(Option::is_none);
+                    if let (Some(subsystem), Some(pad)) = (&self.controller_subsystem, free) {
+                        self.gamepads[pad] = subsystem.open(which).ok();
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    for gamepad in self.gamepads.iter_mut() {
+                        if gamepad.as_ref().is_some_and(|g| g.instance_id() == which) {
+                            *gamepad = None;
+                        }
+                    }
+                }
+                Event::ControllerButtonDown { which, button: pressed, .. }
+                | Event::ControllerButtonUp { which, button: pressed, .. } => {
+                    let down = matches!(event, Event::ControllerButtonDown { .. });
+                    let pad = self.gamepads.iter()
+                        .position(|g| g.as_ref().is_some_and(|g| g.instance_id() == which));
+                    if let (Some(input), Some(pad), Some(pressed)) = (input, pad, button(pressed)) {
+                        input.button(pad, pressed, down);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        match redraw {
+            true => self.draw(),
+            false => Ok(()),
+        }
+    }
+}
@@ -1,30 +1,74 @@
 pub mod display;
-pub use display::Display;
+pub mod audio;
+pub use display::{Display, WindowOptions};
+pub use audio::Audio;
 
-pub use sdl2::{Sdl, VideoSubsystem};
+pub use sdl2::{Sdl, VideoSubsystem, GameControllerSubsystem};
 
 #[allow(unused)]
 pub struct SdlDevice {
     context: Sdl,
     video_subsystem: VideoSubsystem,
+    /// Missing when SDL can't access joysticks, gamepads are ignored then
+    controller_subsystem: Option<GameControllerSubsystem>,
 }
 impl SdlDevice {
     pub fn new() -> Result<Self, String> {
         let context = sdl2::init()?;
         let video_subsystem = context.video()?;
+        let controller_subsystem = context.game_controller().ok();
 
-        Ok(Self { context, video_subsystem })
+        Ok(Self { context, video_subsystem, controller_subsystem })
+    }
+    /// SDL with the dummy video driver, windows work but are never shown.
+    /// Used for automated tests on machines without a screen.
+    pub fn headless() -> Result<Self, String> {
+        sdl2::hint::set("SDL_VIDEODRIVER", "dummy");
+        Self::new()
+    }
+    /// Audio output playing at `sample_rate`
+    pub fn audio(&self, sample_rate: u32) -> Result<Audio, String> {
+        Audio::new(&self.context.audio()?, sample_rate)
     }
     pub fn display(&self) -> Result<Display, String> {
-        let window = self.video_subsystem.window("Fantasy Console", 800, 600)
-            .borderless()
+        self.display_with(WindowOptions::new())
+    }
+    pub fn display_with(&self, options: WindowOptions) -> Result<Display, String> {
+        let window = self.video_subsystem.window(&options.title, options.width, options.height)
+            .resizable()
             .position_centered()
             .build()
             .map_err(|e| e.to_string())?;
 
-        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        let canvas = match options.vsync {
+            true => window.into_canvas().present_vsync().build(),
+            false => window.into_canvas().build(),
+        }.map_err(|e| e.to_string())?;
 
-        Ok(Display::new(canvas))
+        Ok(Display::new(canvas, self.context.event_pump()?, self.controller_subsystem.clone()))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::AccessWidth;
+    use crate::devices::DeviceTrait;
+    use crate::devices::framebuffer::{Framebuffer, PixelFormat};
+    use crate::Word;
+
+    #[test]
+    fn headless_display_presents_a_framebuffer() {
+        let sdl = SdlDevice::headless().unwrap();
+        let options = WindowOptions::new().with_size(64, 48).with_vsync(false);
+        let mut display = sdl.display_with(options).unwrap();
+
+        let framebuffer = Framebuffer::new(16, 12, PixelFormat::Rgba8888);
+        // Red top left pixel
+        framebuffer.write(Word(0), Word(0xFF00_00FF), AccessWidth::Word);
+        display.present_framebuffer(&framebuffer).unwrap();
+
+        display.poll_events(None).unwrap();
+        assert!(display.is_open());
+    }
+}
@@ -0,0 +1,513 @@
+//! Programmable sound generator
+//!
+//! Four voices, each playing a square, triangle or noise wave or an 8-bit
+//! sample, shaped by an ADSR envelope. Samples are generated for the cycles
+//! that passed whenever a register is written, so every change is heard at
+//! the exact sample it was made, and every 10 ms otherwise.
+//!
+//! Every voice has 32 bytes of registers at `voice * 0x20`:
+//!
+//! | Offset | Register                                                        |
+//! |--------|-----------------------------------------------------------------|
+//! | `0x00` | frequency in Hz, sample rate of the sample for sample playback  |
+//! | `0x04` | waveform in bits 0-1, square duty cycle in 1/256 in bits 8-15   |
+//! | `0x08` | volume, 0-255                                                   |
+//! | `0x0c` | attack time in ms in bits 0-15, decay time in bits 16-31        |
+//! | `0x10` | sustain level 0-255 in bits 0-7, release time in ms in 16-31    |
+//! | `0x14` | gate in bit 0, write 1 to start a note and 0 to release it; loop sample in bit 1 |
+//! | `0x18` | sample start, offset in sample memory                           |
+//! | `0x1c` | sample length in bytes                                          |
+//!
+//! `0x100` is the master volume, `0x104` has bit `n` set while voice `n`
+//! sounds and `0x108` is the loop start of the samples, relative to their start.
+//! Sample memory with signed 8-bit samples starts at `0x1000`.
+use crate::Word;
+use crate::audio::AudioSink;
+use crate::bus::AccessWidth;
+use crate::scheduler::DeviceContext;
+use super::{DeviceTrait, Properties};
+
+use std::cell::{Cell, RefCell};
+
+pub const VOICES: usize = 4;
+
+/// Offset of sample memory
+pub const SAMPLES: u32 = 0x1000;
+pub const SAMPLE_MEMORY: usize = 64 * 1024;
+
+const VOICE_SIZE: u32 = 0x20;
+
+const FREQUENCY: u32 = 0x00;
+const WAVE: u32 = 0x04;
+const VOLUME: u32 = 0x08;
+const ATTACK_DECAY: u32 = 0x0c;
+const SUSTAIN_RELEASE: u32 = 0x10;
+const CONTROL: u32 = 0x14;
+const SAMPLE_START: u32 = 0x18;
+const SAMPLE_LENGTH: u32 = 0x1c;
+
+const MASTER_VOLUME: u32 = 0x100;
+const STATUS: u32 = 0x104;
+const SAMPLE_LOOP: u32 = 0x108;
+
+const CONTROL_GATE: u32 = 1 << 0;
+const CONTROL_LOOP: u32 = 1 << 1;
+
+/// Largest output of one voice at full volume, four of them can't clip
+const VOICE_AMPLITUDE: f32 = 8191.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Square = 0,
+    Triangle = 1,
+    Noise = 2,
+    Sample = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Debug, Clone)]
+struct Voice {
+    frequency: u32,
+    wave: u32,
+    volume: u32,
+    attack_decay: u32,
+    sustain_release: u32,
+    control: u32,
+    sample_start: u32,
+    sample_length: u32,
+
+    /// Position in the wave period, a full period is 2^32
+    phase: u32,
+    /// Position in the sample, in 1/65536 of a sample
+    position: u64,
+    /// Noise shift register
+    lfsr: u16,
+    noise: f32,
+    stage: Stage,
+    level: f32,
+}
+impl Voice {
+    fn new() -> Self {
+        Self {
+            frequency: 440,
+            wave: 128 << 8,
+            volume: 255,
+            attack_decay: 0,
+            sustain_release: 255,
+            control: 0,
+            sample_start: 0,
+            sample_length: 0,
+            phase: 0,
+            position: 0,
+            lfsr: 1,
+            noise: 1.0,
+            stage: Stage::Idle,
+            level: 0.0,
+        }
+    }
+
+    fn register(&self, offset: u32) -> u32 {
+        match offset {
+            FREQUENCY => self.frequency,
+            WAVE => self.wave,
+            VOLUME => self.volume,
+            ATTACK_DECAY => self.attack_decay,
+            SUSTAIN_RELEASE => self.sustain_release,
+            CONTROL => self.control,
+            SAMPLE_START => self.sample_start,
+            SAMPLE_LENGTH => self.sample_length,
+            _ => 0,
+        }
+    }
+
+    fn set_register(&mut self, offset: u32, value: u32) {
+        match offset {
+            FREQUENCY => self.frequency = value,
+            WAVE => self.wave = value & 0xff03,
+            VOLUME => self.volume = value & 0xff,
+            ATTACK_DECAY => self.attack_decay = value,
+            SUSTAIN_RELEASE => self.sustain_release = value & 0xffff_00ff,
+            CONTROL => {
+                let gate = value & CONTROL_GATE != 0;
+                match (self.control & CONTROL_GATE != 0, gate) {
+                    (false, true) => {
+                        self.stage = Stage::Attack;
+                        self.phase = 0;
+                        self.position = 0;
+                    }
+                    (true, false) if self.stage != Stage::Idle => self.stage = Stage::Release,
+                    _ => (),
+                }
+                self.control = value & (CONTROL_GATE | CONTROL_LOOP);
+            }
+            SAMPLE_START => self.sample_start = value,
+            SAMPLE_LENGTH => self.sample_length = value,
+            _ => (),
+        }
+    }
+
+    fn waveform(&self) -> Waveform {
+        match self.wave & 3 {
+            0 => Waveform::Square,
+            1 => Waveform::Triangle,
+            2 => Waveform::Noise,
+            _ => Waveform::Sample,
+        }
+    }
+
+    /// Change of level per sample for a ramp over `ms` milliseconds
+    fn step(ms: u32, sample_rate: u32) -> f32 {
+        match ms {
+            0 => 1.0,
+            ms => 1000.0 / (ms as f32 * sample_rate as f32),
+        }
+    }
+
+    fn envelope(&mut self, sample_rate: u32) -> f32 {
+        let sustain = (self.sustain_release & 0xff) as f32 / 255.0;
+        match self.stage {
+            Stage::Idle => (),
+            Stage::Attack => {
+                self.level += Self::step(self.attack_decay & 0xffff, sample_rate);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= Self::step(self.attack_decay >> 16, sample_rate);
+                if self.level <= sustain {
+                    self.level = sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => self.level = sustain,
+            Stage::Release => {
+                self.level -= Self::step(self.sustain_release >> 16, sample_rate);
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = Stage::Idle;
+                }
+            }
+        }
+        self.level
+    }
+
+    /// Next value of the wave, between -1 and 1
+    fn oscillate(&mut self, sample_rate: u32, samples: &[u8], loop_start: u32) -> f32 {
+        if self.waveform() == Waveform::Sample {
+            return self.play_sample(sample_rate, samples, loop_start);
+        }
+
+        let increment = ((self.frequency as u64) << 32) / sample_rate as u64;
+        let (phase, wrapped) = self.phase.overflowing_add(increment as u32);
+        let value = match self.waveform() {
+            Waveform::Square => {
+                let duty = (self.wave >> 8) & 0xff;
+                match (self.phase >> 24) < duty {
+                    true => 1.0,
+                    false => -1.0,
+                }
+            }
+            Waveform::Triangle => {
+                let t = (self.phase >> 16) as f32 / 32768.0;
+                match t < 1.0 {
+                    true => 2.0 * t - 1.0,
+                    false => 3.0 - 2.0 * t,
+                }
+            }
+            _ => {
+                // 15-bit LFSR clocked once per period
+                if wrapped || increment >> 32 != 0 {
+                    let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+                    self.lfsr = (self.lfsr >> 1) | (bit << 14);
+                    self.noise = match self.lfsr & 1 {
+                        1 => 1.0,
+                        _ => -1.0,
+                    };
+                }
+                self.noise
+            }
+        };
+        self.phase = phase;
+        value
+    }
+
+    fn play_sample(&mut self, sample_rate: u32, samples: &[u8], loop_start: u32) -> f32 {
+        let length = self.sample_length as u64;
+        let mut index = self.position >> 16;
+        if index >= length {
+            match self.control & CONTROL_LOOP != 0 && (loop_start as u64) < length {
+                true => {
+                    let span = length - loop_start as u64;
+                    index = loop_start as u64 + (index - loop_start as u64) % span;
+                    self.position = index << 16 | (self.position & 0xffff);
+                }
+                false => return 0.0,
+            }
+        }
+
+        let byte = samples[(self.sample_start as u64 + index) as usize % samples.len()];
+        self.position += ((self.frequency as u64) << 16) / sample_rate as u64;
+        byte as i8 as f32 / 128.0
+    }
+}
+
+/// Registers and voices, changed by writes and by generating samples
+struct State {
+    voices: Vec<Voice>,
+    master_volume: u32,
+    sample_loop: u32,
+}
+
+pub struct Psg {
+    state: RefCell<State>,
+    samples: RefCell<Vec<u8>>,
+    sink: RefCell<Box<dyn AudioSink>>,
+    context: RefCell<Option<DeviceContext>>,
+    sample_rate: u32,
+    clock: u64,
+    /// Number of samples generated so far
+    generated: Cell<u64>,
+}
+impl Psg {
+    pub fn new<S: AudioSink + 'static>(sink: S) -> Self {
+        Self {
+            state: RefCell::new(State {
+                voices: vec![Voice::new(); VOICES],
+                master_volume: 255,
+                sample_loop: 0,
+            }),
+            samples: RefCell::new(vec![0; SAMPLE_MEMORY]),
+            sink: RefCell::new(Box::new(sink)),
+            context: RefCell::new(None),
+            sample_rate: 44_100,
+            clock: 10_000_000,
+            generated: Cell::new(0),
+        }
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        assert!(sample_rate > 0, "Sample rate of PSG must not be zero");
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Cycles per second, which ties emulated time to audio time
+    pub fn with_clock(mut self, cycles_per_second: u64) -> Self {
+        assert!(cycles_per_second > 0, "Clock of PSG must not be zero");
+        self.clock = cycles_per_second;
+        self
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Bytes of bus address space the PSG needs
+    pub fn size(&self) -> u32 {
+        SAMPLES + SAMPLE_MEMORY as u32
+    }
+
+    /// Generate samples for all cycles until now and pass them to the sink
+    pub fn flush(&self) {
+        let Some(now) = self.context.borrow().as_ref().map(|context| context.scheduler.now()) else {
+            return;
+        };
+        let due = (now as u128 * self.sample_rate as u128 / self.clock as u128) as u64;
+        let count = due.saturating_sub(self.generated.get());
+        if count == 0 {
+            return;
+        }
+
+        let mut state = self.state.borrow_mut();
+        let samples = self.samples.borrow();
+        let master = state.master_volume as f32 / 255.0;
+        let loop_start = state.sample_loop;
+
+        let output: Vec<i16> = (0..count)
+            .map(|_| {
+                let mix: f32 = state.voices.iter_mut()
+                    .map(|voice| {
+                        let level = voice.envelope(self.sample_rate);
+                        let wave = voice.oscillate(self.sample_rate, &samples, loop_start);
+                        wave * level * voice.volume as f32 / 255.0
+                    })
+                    .sum();
+                (mix * master * VOICE_AMPLITUDE) as i16
+            })
+            .collect();
+        self.generated.set(due);
+        drop(state);
+
+        self.sink.borrow_mut().samples(&output);
+    }
+
+    fn schedule_flush(&self) {
+        if let Some(context) = self.context.borrow().as_ref() {
+            let handle = context.handle;
+            context.scheduler.schedule(self.clock.div_ceil(100), move |bus| {
+                if let Some(psg) = bus.device::<Psg>(handle) {
+                    psg.flush();
+                    psg.schedule_flush();
+                }
+            });
+        }
+    }
+
+    fn register(&self, offset: u32) -> u32 {
+        let state = self.state.borrow();
+        match offset {
+            MASTER_VOLUME => state.master_volume,
+            STATUS => state.voices.iter().enumerate()
+                .filter(|(_, voice)| voice.stage != Stage::Idle)
+                .fold(0, |status, (i, _)| status | 1 << i),
+            SAMPLE_LOOP => state.sample_loop,
+            offset if offset < VOICE_SIZE * VOICES as u32 => {
+                state.voices[(offset / VOICE_SIZE) as usize].register(offset % VOICE_SIZE)
+            }
+            _ => 0,
+        }
+    }
+
+    fn set_register(&self, offset: u32, value: u32) {
+        // Everything before the write is played with the old settings
+        self.flush();
+
+        let mut state = self.state.borrow_mut();
+        match offset {
+            MASTER_VOLUME => state.master_volume = value & 0xff,
+            SAMPLE_LOOP => state.sample_loop = value,
+            offset if offset < VOICE_SIZE * VOICES as u32 => {
+                state.voices[(offset / VOICE_SIZE) as usize].set_register(offset % VOICE_SIZE, value);
+            }
+            _ => (),
+        }
+    }
+}
+impl DeviceTrait for Psg {
+    fn name(&self) -> String {
+        "PSG".into()
+    }
+    fn attach(&self, context: DeviceContext) {
+        self.generated.set((context.scheduler.now() as u128 * self.sample_rate as u128 / self.clock as u128) as u64);
+        *self.context.borrow_mut() = Some(context);
+        self.schedule_flush();
+    }
+    fn properties(&self) -> Properties {
+        Properties::new("sound")
+    }
+    fn read(&self, address: Word, width: AccessWidth) -> Word {
+        if address.0 >= SAMPLES {
+            let samples = self.samples.borrow();
+            let mut bytes = [0; 4];
+            for (i, byte) in bytes.iter_mut().take(width.bytes() as usize).enumerate() {
+                *byte = samples.get((address.0 - SAMPLES) as usize + i).copied().unwrap_or(0);
+            }
+            return Word::from_le_bytes(bytes);
+        }
+
+        let shift = (address.0 & 3) * 8;
+        Word((self.register(address.0 & !3) >> shift) & width.mask().0)
+    }
+    fn write(&self, address: Word, word: Word, width: AccessWidth) {
+        if address.0 >= SAMPLES {
+            // Samples already playing change from the next generated sample on
+            self.flush();
+            let mut samples = self.samples.borrow_mut();
+            for (i, byte) in word.0.to_le_bytes().into_iter().take(width.bytes() as usize).enumerate() {
+                if let Some(sample) = samples.get_mut((address.0 - SAMPLES) as usize + i) {
+                    *sample = byte;
+                }
+            }
+            return;
+        }
+
+        // Narrow writes change only their part of the register
+        let offset = address.0 & !3;
+        let shift = (address.0 & 3) * 8;
+        let mask = width.mask().0 << shift;
+        let value = (self.register(offset) & !mask) | ((word.0 << shift) & mask);
+        self.set_register(offset, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::audio::SampleBuffer;
+    use crate::bus::Bus;
+    use crate::devices::Device;
+
+    const BASE: Word = Word(0x3000_0000);
+
+    /// PSG generating one sample per cycle into a buffer
+    fn machine() -> (Bus, SampleBuffer) {
+        let mut bus = Bus::new();
+        let buffer = SampleBuffer::new();
+        let psg = Psg::new(buffer.clone()).with_sample_rate(1000).with_clock(1000);
+        let size = psg.size();
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(size)), Box::new(psg))).unwrap();
+        (bus, buffer)
+    }
+
+    fn write(bus: &Bus, register: u32, value: u32) {
+        bus.write(BASE + Word(register), Word(value), AccessWidth::Word).unwrap();
+    }
+
+    fn read(bus: &Bus, register: u32) -> u32 {
+        bus.read(BASE + Word(register), AccessWidth::Word).unwrap().0
+    }
+
+    #[test]
+    fn square_wave_is_generated_in_virtual_time() {
+        let (bus, buffer) = machine();
+        write(&bus, FREQUENCY, 125);
+        write(&bus, WAVE, 128 << 8 | Waveform::Square as u32);
+        write(&bus, CONTROL, CONTROL_GATE);
+        assert_eq!(read(&bus, STATUS), 1);
+
+        // 125 Hz at 1000 samples per second is 4 samples high, 4 low
+        bus.advance(20);
+        let high = VOICE_AMPLITUDE as i16;
+        let period = [[high; 4], [-high; 4]].concat();
+        assert_eq!(buffer.samples(), [&period[..], &period[..], &period[..4]].concat());
+    }
+
+    #[test]
+    fn released_voice_falls_silent() {
+        let (bus, buffer) = machine();
+        write(&bus, CONTROL, CONTROL_GATE);
+        bus.advance(10);
+
+        write(&bus, CONTROL, 0);
+        bus.advance(10);
+        assert_eq!(read(&bus, STATUS), 0);
+        assert!(buffer.samples()[11..].iter().all(|sample| *sample == 0));
+    }
+
+    #[test]
+    fn samples_play_from_sample_memory() {
+        let (bus, buffer) = machine();
+        bus.write(BASE + Word(SAMPLES), Word(0x80_c0_40_7f), AccessWidth::Word).unwrap();
+        write(&bus, FREQUENCY, 1000);
+        write(&bus, WAVE, Waveform::Sample as u32);
+        write(&bus, SAMPLE_LENGTH, 4);
+        write(&bus, CONTROL, CONTROL_GATE);
+
+        bus.advance(10);
+        let scale = |byte: u8| (byte as i8 as f32 / 128.0 * VOICE_AMPLITUDE) as i16;
+        let expected: Vec<i16> = [0x7f, 0x40, 0xc0, 0x80].into_iter().map(scale).collect();
+        assert_eq!(buffer.samples()[..4], expected);
+        assert!(buffer.samples()[4..].iter().all(|sample| *sample == 0));
+    }
+}
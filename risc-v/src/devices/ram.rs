@@ -1,6 +1,14 @@
 use crate::Word;
-use super::DeviceTrait;
-use std::cell::Cell;
+use crate::bus::AccessWidth;
+use super::{DeviceTrait, Properties};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+/// Granularity at which [`Ram`] allocates host memory
+pub const PAGE_SIZE: usize = 4096;
+
+/// Host memory backing one page of RAM, shared with the bus for direct access
+pub type Page = Rc<[Cell<u8>; PAGE_SIZE]>;
 
 pub struct Ram64KiB(Vec<Cell<u8>>);
 impl Ram64KiB {
@@ -20,15 +28,175 @@ impl DeviceTrait for Ram64KiB {
     fn name(&self) -> String {
         "RAM 64 KiB".into()
     }
-    fn read(&self, address: Word) -> Word {
+    fn properties(&self) -> Properties {
+        Properties::new("memory").with_device_type("memory")
+    }
+    fn read(&self, address: Word, width: AccessWidth) -> Word {
         let ram_address = (address & Word(0xFFFF)).0 as usize;
-        return Word(self.0[ram_address].get() as u32);
+        let bytes = &self.0[ram_address..ram_address + width.bytes() as usize];
+
+        // RISC-V is little endian
+        let value = bytes.iter().rev().fold(0, |word, byte| (word << 8) | byte.get() as u32);
+        Word(value)
     }
-    fn write(&self, address: Word, word: Word) {
+    fn write(&self, address: Word, word: Word, width: AccessWidth) {
         let ram_address = (address & Word(0xFFFF)).0 as usize;
-        self.0[ram_address].set((word & Word(0xFF)).0 as u8);
+        let bytes = &self.0[ram_address..ram_address + width.bytes() as usize];
+
+        for (byte, value) in bytes.iter().zip(word.0.to_le_bytes()) {
+            byte.set(value);
+        }
+    }
+}
+
+/// RAM of any size, host memory is allocated lazily for each page written.
+/// Pages never written read as zero.
+pub struct Ram {
+    size: usize,
+    pages: RefCell<Vec<Option<Page>>>,
+}
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        Self { size, pages: RefCell::new(vec![None; size.div_ceil(PAGE_SIZE)]) }
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of pages backed by host memory
+    pub fn allocated_pages(&self) -> usize {
+        self.pages.borrow().iter().filter(|p| p.is_some()).count()
+    }
+
+    /// Copy `data` to `offset`, bytes past the end of RAM are dropped
+    pub fn load(&self, offset: usize, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            self.set(offset + i, *byte);
+        }
+    }
+
+    fn get(&self, offset: usize) -> u8 {
+        match self.pages.borrow().get(offset / PAGE_SIZE) {
+            Some(Some(page)) => page[offset % PAGE_SIZE].get(),
+            _ => 0,
+        }
+    }
+
+    fn set(&self, offset: usize, byte: u8) {
+        if let Some(page) = self.backing(offset, true) {
+            page[offset % PAGE_SIZE].set(byte);
+        }
+    }
+
+    /// Page holding `offset`, allocated when `allocate` is set
+    fn backing(&self, offset: usize, allocate: bool) -> Option<Page> {
+        if offset >= self.size {
+            return None;
+        }
+
+        let mut pages = self.pages.borrow_mut();
+        let page = &mut pages[offset / PAGE_SIZE];
+        if page.is_none() && allocate {
+            *page = Some(Rc::new(std::array::from_fn(|_| Cell::new(0))));
+        }
+        page.clone()
     }
-    fn tick(&self) {
-        // Do nothing
+}
+impl DeviceTrait for Ram {
+    fn name(&self) -> String {
+        format!("RAM {} KiB", self.size / 1024)
+    }
+    fn properties(&self) -> Properties {
+        Properties::new("memory").with_device_type("memory")
+    }
+    fn read(&self, address: Word, width: AccessWidth) -> Word {
+        let offset = address.0 as usize;
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().take(width.bytes() as usize).enumerate() {
+            *byte = self.get(offset + i);
+        }
+
+        // RISC-V is little endian
+        Word::from_le_bytes(bytes)
+    }
+    fn write(&self, address: Word, word: Word, width: AccessWidth) {
+        let offset = address.0 as usize;
+        for (i, byte) in word.0.to_le_bytes().iter().take(width.bytes() as usize).enumerate() {
+            self.set(offset + i, *byte);
+        }
+    }
+    fn page(&self, offset: Word, allocate: bool) -> Option<Page> {
+        // Last page is only partially backed when size isn't a multiple of a page
+        if offset.0 as usize + PAGE_SIZE > self.size {
+            return None;
+        }
+        self.backing(offset.0 as usize, allocate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::bus::Bus;
+    use crate::devices::Device;
+
+    fn connect(bus: &mut Bus, base: u32, size: usize) -> crate::bus::DeviceHandle {
+        let range = MemoryRange::new(Word(base), Word(size as u32));
+        bus.connect(Device::new(range, Box::new(Ram::new(size)))).unwrap()
+    }
+
+    #[test]
+    fn pages_are_allocated_on_first_write() {
+        let mut bus = Bus::new();
+        let handle = connect(&mut bus, 0, 0x10000);
+
+        assert_eq!(bus.read(Word(0x4321), AccessWidth::Word), Ok(Word(0)));
+        assert_eq!(bus.device::<Ram>(handle).unwrap().allocated_pages(), 0);
+
+        bus.write(Word(0x4321), Word(0xAABB_CCDD), AccessWidth::Word).unwrap();
+        bus.write(Word(0x4FFF), Word(0x11), AccessWidth::Byte).unwrap();
+        assert_eq!(bus.device::<Ram>(handle).unwrap().allocated_pages(), 1);
+        assert_eq!(bus.read(Word(0x4321), AccessWidth::Word), Ok(Word(0xAABB_CCDD)));
+
+        // Word crossing into the next page allocates it
+        bus.write(Word(0x4FFE), Word(0x2211), AccessWidth::Word).unwrap();
+        assert_eq!(bus.device::<Ram>(handle).unwrap().allocated_pages(), 2);
+    }
+
+    #[test]
+    fn offsets_are_relative_to_an_unaligned_base() {
+        let mut bus = Bus::new();
+        // Base not aligned to 64 KiB nor to a page, last page only partly backed
+        let handle = connect(&mut bus, 0x0001_2800, 0x1A00);
+
+        bus.write(Word(0x0001_2800), Word(0x0102_0304), AccessWidth::Word).unwrap();
+        bus.write(Word(0x0001_41FC), Word(0x0506_0708), AccessWidth::Word).unwrap();
+        assert_eq!(bus.read(Word(0x0001_41FC), AccessWidth::Word), Ok(Word(0x0506_0708)));
+        assert!(bus.read(Word(0x0001_4200), AccessWidth::Byte).is_err());
+
+        let ram = bus.device::<Ram>(handle).unwrap();
+        assert_eq!(ram.read(Word(0), AccessWidth::Word), Word(0x0102_0304));
+        assert_eq!(ram.read(Word(0x19FC), AccessWidth::Word), Word(0x0506_0708));
+        assert_eq!(ram.page(Word(0x1000), true), None);
+        assert_eq!(ram.allocated_pages(), 2);
+    }
+
+    #[test]
+    fn gigabyte_is_mapped_without_allocating_it() {
+        let mut bus = Bus::new();
+        let handle = connect(&mut bus, 0x8000_0000, 1 << 30);
+
+        bus.write(Word(0x8000_0000), Word(1), AccessWidth::Word).unwrap();
+        bus.write(Word(0xBFFF_FFFC), Word(2), AccessWidth::Word).unwrap();
+        assert_eq!(bus.read(Word(0xBFFF_FFFC), AccessWidth::Word), Ok(Word(2)));
+        assert_eq!(bus.read(Word(0xA000_0000), AccessWidth::Word), Ok(Word(0)));
+        assert!(bus.read(Word(0xC000_0000), AccessWidth::Word).is_err());
+
+        let ram = bus.device::<Ram>(handle).unwrap();
+        assert_eq!(ram.size(), 1 << 30);
+        assert_eq!(ram.allocated_pages(), 2);
     }
 }
@@ -1,5 +1,6 @@
 use crate::Word;
-use super::DeviceTrait;
+use crate::bus::AccessWidth;
+use super::{AccessPolicy, DeviceTrait, Properties};
 
 pub struct Rom64KiB([u8; 1024 * 64]);
 impl Rom64KiB {
@@ -25,14 +26,69 @@ impl DeviceTrait for Rom64KiB {
     fn name(&self) -> String {
         "ROM 64 KiB".into()
     }
-    fn read(&self, address: Word) -> Word {
+    fn properties(&self) -> Properties {
+        Properties::new("rom")
+    }
+    fn read(&self, address: Word, width: AccessWidth) -> Word {
         let rom_address = (address & Word(0xFFFF)).0 as usize;
-        return Word(self.0[rom_address] as u32);
+        let mut bytes = [0; 4];
+        bytes[..width.bytes() as usize].copy_from_slice(&self.0[rom_address..rom_address + width.bytes() as usize]);
+
+        // RISC-V is little endian
+        Word::from_le_bytes(bytes)
     }
-    fn write(&self, _: Word, _: Word) {
+    fn policy(&self) -> AccessPolicy {
+        AccessPolicy::ReadOnly
+    }
+    fn write(&self, _: Word, _: Word, _: AccessWidth) {
         // ROM is not writable
     }
-    fn tick(&self) {
-        // Do nothing
+}
+
+/// ROM of any size, reads past its contents return zero
+pub struct Rom(Vec<u8>);
+impl Rom {
+    pub fn new(size: usize) -> Self {
+        Self(vec![0; size])
     }
-}
\ No newline at end of file
+
+    pub fn load(&mut self, data: &[u8]) {
+        assert!(data.len() <= self.0.len(), "Failed to load ROM; data can have only {} bytes of size, data size: {} bytes", self.0.len(), data.len());
+
+        self.0[..data.len()].copy_from_slice(data);
+    }
+
+    /// ROM exactly as large as `data`
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Self(data.to_vec())
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        self.0.len()
+    }
+}
+impl DeviceTrait for Rom {
+    fn name(&self) -> String {
+        format!("ROM {} KiB", self.0.len().div_ceil(1024))
+    }
+    fn properties(&self) -> Properties {
+        Properties::new("rom")
+    }
+    fn read(&self, address: Word, width: AccessWidth) -> Word {
+        let offset = address.0 as usize;
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().take(width.bytes() as usize).enumerate() {
+            *byte = self.0.get(offset + i).copied().unwrap_or(0);
+        }
+
+        // RISC-V is little endian
+        Word::from_le_bytes(bytes)
+    }
+    fn policy(&self) -> AccessPolicy {
+        AccessPolicy::ReadOnly
+    }
+    fn write(&self, _: Word, _: Word, _: AccessWidth) {
+        // ROM is not writable
+    }
+}
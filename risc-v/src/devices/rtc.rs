@@ -0,0 +1,330 @@
+//! Real-time clock with an alarm
+//!
+//! The clock counts seconds since the Unix epoch, taken either from the host
+//! or from the cycle counter, so runs in virtual time are reproducible.
+//! Registers are 32 bits wide:
+//!
+//! | Offset | Register                                                      |
+//! |--------|---------------------------------------------------------------|
+//! | `0x00` | low half of the time, reading it latches the high half        |
+//! | `0x04` | high half of the time, as latched by the last low read        |
+//! | `0x08` | low half of the alarm time                                    |
+//! | `0x0c` | high half of the alarm time                                   |
+//! | `0x10` | control, bit 0 arms the alarm and is cleared when it fires    |
+//! | `0x14` | status, bit 0 is set when the alarm fired, write 1 to clear it |
+//!
+//! Writing either half of the time sets the clock, the host clock itself is
+//! never changed.
+use crate::Word;
+use crate::bus::{AccessWidth, Bus};
+use crate::interrupts::IrqLine;
+use crate::scheduler::{Cycle, DeviceContext, EventId};
+use super::{DeviceTrait, Properties, Property};
+
+use std::cell::{Cell, RefCell};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TIME_LOW: u32 = 0x00;
+const TIME_HIGH: u32 = 0x04;
+const ALARM_LOW: u32 = 0x08;
+const ALARM_HIGH: u32 = 0x0c;
+const CONTROL: u32 = 0x10;
+const STATUS: u32 = 0x14;
+
+const CONTROL_ALARM: u32 = 1 << 0;
+const STATUS_ALARM: u32 = 1 << 0;
+
+/// Where the clock takes its time from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSource {
+    /// Wall clock of the host
+    Host,
+    /// `epoch` seconds at cycle 0, then one second per `clock` cycles
+    Virtual { epoch: u64 },
+}
+
+pub struct Rtc {
+    source: TimeSource,
+    clock: u64,
+    /// Seconds the guest moved the clock by
+    offset: Cell<i64>,
+    /// High half of the time at the last read of the low half
+    latched: Cell<u32>,
+
+    alarm: Cell<u64>,
+    control: Cell<u32>,
+    fired: Cell<bool>,
+    alarm_event: Cell<Option<EventId>>,
+
+    context: RefCell<Option<DeviceContext>>,
+    irq: Option<IrqLine>,
+}
+impl Rtc {
+    fn new(source: TimeSource) -> Self {
+        Self {
+            source,
+            clock: 10_000_000,
+            offset: Cell::new(0),
+            latched: Cell::new(0),
+            alarm: Cell::new(0),
+            control: Cell::new(0),
+            fired: Cell::new(false),
+            alarm_event: Cell::new(None),
+            context: RefCell::new(None),
+            irq: None,
+        }
+    }
+
+    /// Clock following the host's wall clock
+    pub fn host() -> Self {
+        Self::new(TimeSource::Host)
+    }
+
+    /// Clock starting at `epoch` seconds and advancing with the cycle counter
+    pub fn virtual_time(epoch: u64) -> Self {
+        Self::new(TimeSource::Virtual { epoch })
+    }
+
+    /// Cycles per second of virtual time, 10 MHz by default
+    pub fn with_clock(mut self, clock: u64) -> Self {
+        assert!(clock > 0, "Clock of RTC must be at least 1 Hz");
+        self.clock = clock;
+        self
+    }
+
+    /// Drive `irq` while the alarm has fired
+    pub fn with_interrupt(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    pub fn source(&self) -> TimeSource {
+        self.source
+    }
+
+    fn now(&self) -> Cycle {
+        self.context.borrow().as_ref().map_or(0, |context| context.scheduler.now())
+    }
+
+    /// Seconds of the source, before the guest's adjustments
+    fn source_time(&self) -> u64 {
+        match self.source {
+            TimeSource::Host => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()),
+            TimeSource::Virtual { epoch } => epoch + self.now() / self.clock,
+        }
+    }
+
+    /// Current time in seconds since the Unix epoch
+    pub fn time(&self) -> u64 {
+        self.source_time().saturating_add_signed(self.offset.get())
+    }
+
+    /// Set the clock to `time` seconds since the Unix epoch
+    pub fn set_time(&self, time: u64) {
+        self.offset.set(time.wrapping_sub(self.source_time()) as i64);
+        self.arm();
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.fired.get());
+        }
+    }
+
+    fn fire(&self) {
+        self.control.set(self.control.get() & !CONTROL_ALARM);
+        self.fired.set(true);
+        self.alarm_event.set(None);
+        self.update_interrupt();
+    }
+
+    /// Fire the alarm if it is due, otherwise schedule the check for when it is
+    fn arm(&self) {
+        let context = self.context.borrow().clone();
+        if let (Some(event), Some(context)) = (self.alarm_event.take(), &context) {
+            context.scheduler.cancel(event);
+        }
+        if self.control.get() & CONTROL_ALARM == 0 {
+            return;
+        }
+        if self.time() >= self.alarm.get() {
+            self.fire();
+            return;
+        }
+
+        // Not on a bus, time doesn't pass
+        let Some(context) = context else {
+            return;
+        };
+        let handle = context.handle;
+        let check = move |bus: &Bus| {
+            if let Some(rtc) = bus.device::<Rtc>(handle) {
+                rtc.alarm_event.set(None);
+                rtc.arm();
+            }
+        };
+        let event = match self.source {
+            // The host clock can't be predicted, check ten times a second
+            TimeSource::Host => context.scheduler.schedule(self.clock.div_ceil(10), check),
+            TimeSource::Virtual { .. } => {
+                let seconds = self.alarm.get() - self.time();
+                let cycle = (self.now() / self.clock).saturating_add(seconds).saturating_mul(self.clock);
+                context.scheduler.schedule_at(cycle, check)
+            }
+        };
+        self.alarm_event.set(Some(event));
+    }
+
+    fn read_register(&self, offset: u32) -> u32 {
+        match offset {
+            TIME_LOW => {
+                let time = self.time();
+                self.latched.set((time >> 32) as u32);
+                time as u32
+            }
+            TIME_HIGH => self.latched.get(),
+            ALARM_LOW => self.alarm.get() as u32,
+            ALARM_HIGH => (self.alarm.get() >> 32) as u32,
+            CONTROL => self.control.get(),
+            STATUS => self.fired.get() as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&self, offset: u32, value: u32) {
+        let low = |old: u64| old & !0xffff_ffff | value as u64;
+        let high = |old: u64| old & 0xffff_ffff | (value as u64) << 32;
+        match offset {
+            TIME_LOW => self.set_time(low(self.time())),
+            TIME_HIGH => self.set_time(high(self.time())),
+            ALARM_LOW => {
+                self.alarm.set(low(self.alarm.get()));
+                self.arm();
+            }
+            ALARM_HIGH => {
+                self.alarm.set(high(self.alarm.get()));
+                self.arm();
+            }
+            CONTROL => {
+                self.control.set(value & CONTROL_ALARM);
+                self.arm();
+            }
+            STATUS if value & STATUS_ALARM != 0 => {
+                self.fired.set(false);
+                self.update_interrupt();
+            }
+            _ => {}
+        }
+    }
+}
+impl DeviceTrait for Rtc {
+    fn name(&self) -> String {
+        match self.source {
+            TimeSource::Host => "RTC host time".into(),
+            TimeSource::Virtual { epoch } => format!("RTC virtual time from {epoch}"),
+        }
+    }
+    fn attach(&self, context: DeviceContext) {
+        *self.context.borrow_mut() = Some(context);
+        self.arm();
+    }
+    fn reset(&self) {
+        // The time is kept, as by the battery of a real clock
+        self.alarm.set(0);
+        self.control.set(0);
+        self.fired.set(false);
+        self.arm();
+        self.update_interrupt();
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("rtc");
+        match &self.irq {
+            Some(irq) => properties.with("interrupts", Property::Cells(vec![irq.line()])),
+            None => properties,
+        }
+    }
+    fn supports(&self, width: AccessWidth) -> bool {
+        width == AccessWidth::Word
+    }
+    fn read(&self, address: Word, _: AccessWidth) -> Word {
+        Word(self.read_register(address.0))
+    }
+    fn write(&self, address: Word, word: Word, _: AccessWidth) {
+        self.write_register(address.0, word.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::devices::Device;
+
+    const BASE: Word = Word(0x4000_0000);
+
+    fn machine(rtc: Rtc) -> Bus {
+        let mut bus = Bus::new();
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(0x18)), Box::new(rtc))).unwrap();
+        bus
+    }
+
+    fn read(bus: &Bus, register: u32) -> u32 {
+        bus.read(BASE + Word(register), AccessWidth::Word).unwrap().0
+    }
+
+    fn write(bus: &Bus, register: u32, value: u32) {
+        bus.write(BASE + Word(register), Word(value), AccessWidth::Word).unwrap();
+    }
+
+    #[test]
+    fn virtual_time_follows_cycles_and_latches_the_high_half() {
+        let bus = machine(Rtc::virtual_time(0x1_ffff_fffe).with_clock(100));
+
+        assert_eq!(read(&bus, TIME_LOW), 0xffff_fffe);
+        bus.advance(250);
+        // High half stays as latched until the low half is read again
+        assert_eq!(read(&bus, TIME_HIGH), 1);
+        assert_eq!(read(&bus, TIME_LOW), 0);
+        assert_eq!(read(&bus, TIME_HIGH), 2);
+    }
+
+    #[test]
+    fn set_time_moves_the_clock_from_now_on() {
+        let bus = machine(Rtc::virtual_time(1000).with_clock(100));
+
+        bus.advance(150);
+        write(&bus, TIME_LOW, 5000);
+        assert_eq!(read(&bus, TIME_LOW), 5000);
+        bus.advance(100);
+        assert_eq!(read(&bus, TIME_LOW), 5001);
+    }
+
+    #[test]
+    fn alarm_fires_at_the_second_it_is_set_to() {
+        let mut bus = Bus::new();
+        let irq = bus.interrupt_line(3);
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(0x18)),
+            Box::new(Rtc::virtual_time(1000).with_clock(100).with_interrupt(irq)))).unwrap();
+
+        write(&bus, ALARM_LOW, 1003);
+        write(&bus, CONTROL, CONTROL_ALARM);
+        assert!(bus.skip_to_next_event());
+        assert_eq!(bus.now(), 300);
+        assert_eq!(read(&bus, STATUS), STATUS_ALARM);
+        assert_eq!(read(&bus, CONTROL), 0);
+        assert!(bus.interrupts().is_pending(3));
+
+        write(&bus, STATUS, STATUS_ALARM);
+        assert!(!bus.interrupts().is_pending(3));
+        assert!(!bus.skip_to_next_event());
+    }
+
+    #[test]
+    fn alarm_in_the_past_fires_when_armed() {
+        let bus = machine(Rtc::virtual_time(1000).with_clock(100));
+
+        write(&bus, ALARM_LOW, 999);
+        write(&bus, CONTROL, CONTROL_ALARM);
+        assert_eq!(read(&bus, STATUS), STATUS_ALARM);
+    }
+}
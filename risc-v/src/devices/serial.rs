@@ -0,0 +1,181 @@
+//! Host side of serial devices
+//!
+//! A [`SerialBackend`] receives bytes the guest transmits and provides bytes
+//! for the guest to receive. Reads never block, devices poll for input.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+
+pub trait SerialBackend {
+    /// Send byte transmitted by the guest
+    fn write(&mut self, byte: u8);
+
+    /// Byte sent to the guest, `None` if nothing is waiting
+    fn read(&mut self) -> Option<u8>;
+}
+
+/// Host standard output and input
+pub struct Stdio {
+    input: Receiver<u8>,
+}
+impl Stdio {
+    pub fn new() -> Self {
+        // Stdin can't be read without blocking, so a thread waits for it
+        let (sender, input) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buffer = [0; 256];
+            while let Ok(read @ 1..) = io::stdin().read(&mut buffer) {
+                if buffer[..read].iter().any(|byte| sender.send(*byte).is_err()) {
+                    break;
+                }
+            }
+        });
+
+        Self { input }
+    }
+}
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl SerialBackend for Stdio {
+    fn write(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]).and_then(|_| stdout.flush());
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+/// In-memory buffers, clones share them so the host can keep one
+/// to check output and queue input
+#[derive(Clone, Default)]
+pub struct Buffer {
+    output: Rc<RefCell<Vec<u8>>>,
+    input: Rc<RefCell<VecDeque<u8>>>,
+}
+impl Buffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything the guest transmitted so far
+    pub fn output(&self) -> Vec<u8> {
+        self.output.borrow().clone()
+    }
+
+    /// Return and clear transmitted bytes
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.output.borrow_mut())
+    }
+
+    /// Queue bytes for the guest to receive
+    pub fn push_input(&self, data: &[u8]) {
+        self.input.borrow_mut().extend(data);
+    }
+}
+impl SerialBackend for Buffer {
+    fn write(&mut self, byte: u8) {
+        self.output.borrow_mut().push(byte);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.borrow_mut().pop_front()
+    }
+}
+
+/// Log transmitted bytes to a file, the guest receives nothing
+pub struct FileLog {
+    file: File,
+}
+impl FileLog {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self { file: File::create(path)? })
+    }
+}
+impl SerialBackend for FileLog {
+    fn write(&mut self, byte: u8) {
+        let _ = self.file.write_all(&[byte]);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+/// Unix pseudo-terminal, a terminal program such as `screen` can be
+/// attached to the path of its slave side
+#[cfg(unix)]
+pub struct Pty {
+    master: File,
+    path: std::path::PathBuf,
+}
+#[cfg(unix)]
+impl Pty {
+    pub fn open() -> io::Result<Self> {
+        use std::ffi::CStr;
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: plain libc calls, the descriptor is owned by `master` right after
+        // it is opened and `ptsname` result is copied before any other call
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let path = CStr::from_ptr(name).to_string_lossy().into_owned().into();
+
+            // Guest input is polled, reading must not block
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Pass bytes through unchanged, like a serial line
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                libc::tcsetattr(fd, libc::TCSANOW, &termios);
+            }
+
+            Ok(Self { master, path })
+        }
+    }
+
+    /// Path of the terminal to attach to, such as `/dev/pts/3`
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+#[cfg(unix)]
+impl SerialBackend for Pty {
+    fn write(&mut self, byte: u8) {
+        // Nothing may be attached yet, output is dropped then
+        let _ = self.master.write_all(&[byte]);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        match self.master.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! SPI NOR flash
+//!
+//! Follows the common 25-series command set with 3-byte addresses: JEDEC ID,
+//! status, read, fast read, write enable, page program and sector, block
+//! and chip erase. Programming only clears bits, erasing sets whole areas
+//! to `0xff`. Programs and erases finish when chip select is released,
+//! so the busy bit of the status never shows.
+use super::SpiDevice;
+
+const PAGE_PROGRAM: u8 = 0x02;
+const READ: u8 = 0x03;
+const WRITE_DISABLE: u8 = 0x04;
+const READ_STATUS: u8 = 0x05;
+const WRITE_ENABLE: u8 = 0x06;
+const FAST_READ: u8 = 0x0b;
+const SECTOR_ERASE: u8 = 0x20;
+const CHIP_ERASE: u8 = 0xc7;
+const CHIP_ERASE_ALT: u8 = 0x60;
+const READ_ID: u8 = 0x9f;
+const BLOCK_ERASE: u8 = 0xd8;
+
+const STATUS_WRITE_ENABLED: u8 = 1 << 1;
+
+pub const PAGE_SIZE: usize = 256;
+pub const SECTOR_SIZE: usize = 4096;
+pub const BLOCK_SIZE: usize = 65536;
+
+/// Bytes of address following a command
+const ADDRESS_BYTES: usize = 3;
+
+pub struct SpiFlash {
+    data: Vec<u8>,
+    jedec_id: [u8; 3],
+    write_enabled: bool,
+
+    /// Command of the current transaction and bytes received so far
+    command: Option<u8>,
+    received: usize,
+    address: u32,
+    /// Bytes to program when the transaction ends
+    program: Vec<u8>,
+}
+impl SpiFlash {
+    /// Erased flash of `size` bytes, a power of two up to 16 MiB
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two() && (SECTOR_SIZE..=1 << 24).contains(&size),
+            "Flash size must be a power of two from 4 KiB to 16 MiB, got {size}");
+        Self {
+            data: vec![0xff; size],
+            // Winbond W25Q series, the last byte is log2 of the size
+            jedec_id: [0xef, 0x40, size.trailing_zeros() as u8],
+            write_enabled: false,
+            command: None,
+            received: 0,
+            address: 0,
+            program: vec![],
+        }
+    }
+
+    /// Start with `data` at the beginning of the flash
+    pub fn with_contents(mut self, data: &[u8]) -> Self {
+        assert!(data.len() <= self.data.len(), "Flash contents of {} bytes don't fit in {} bytes", data.len(), self.data.len());
+        self.data[..data.len()].copy_from_slice(data);
+        self
+    }
+
+    /// Manufacturer, memory type and capacity bytes reported by `READ_ID`
+    pub fn with_jedec_id(mut self, id: [u8; 3]) -> Self {
+        self.jedec_id = id;
+        self
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn byte(&self, address: u32) -> u8 {
+        self.data[address as usize & (self.data.len() - 1)]
+    }
+
+    fn status(&self) -> u8 {
+        match self.write_enabled {
+            true => STATUS_WRITE_ENABLED,
+            false => 0,
+        }
+    }
+
+    /// Byte sent back while byte `index` of the transaction comes in
+    fn output(&self, index: usize) -> u8 {
+        match self.command {
+            Some(READ_ID) => self.jedec_id.get(index - 1).copied().unwrap_or(0),
+            Some(READ_STATUS) => self.status(),
+            Some(READ) if index > ADDRESS_BYTES => self.byte(self.address + (index - ADDRESS_BYTES - 1) as u32),
+            // One dummy byte after the address
+            Some(FAST_READ) if index > ADDRESS_BYTES + 1 => self.byte(self.address + (index - ADDRESS_BYTES - 2) as u32),
+            _ => 0xff,
+        }
+    }
+
+    fn input(&mut self, index: usize, byte: u8) {
+        let addressed = matches!(self.command, Some(READ | FAST_READ | PAGE_PROGRAM | SECTOR_ERASE | BLOCK_ERASE));
+        match index {
+            0 => self.command = Some(byte),
+            1..=ADDRESS_BYTES if addressed => self.address = self.address << 8 | byte as u32,
+            // Only the last page worth of data is programmed
+            _ if self.command == Some(PAGE_PROGRAM) => {
+                if self.program.len() == PAGE_SIZE {
+                    self.program.remove(0);
+                }
+                self.program.push(byte);
+            }
+            _ => {}
+        }
+    }
+
+    fn erase(&mut self, size: usize) {
+        let start = self.address as usize & (self.data.len() - 1) & !(size - 1);
+        self.data[start..start + size].fill(0xff);
+    }
+
+    /// Carry out the command of the finished transaction
+    fn execute(&mut self) {
+        let complete = self.received > ADDRESS_BYTES;
+        match self.command {
+            Some(WRITE_ENABLE) => self.write_enabled = true,
+            Some(WRITE_DISABLE) => self.write_enabled = false,
+            Some(PAGE_PROGRAM) if self.write_enabled && complete => {
+                // Addresses wrap around within the page
+                let page = self.address as usize & (self.data.len() - 1) & !(PAGE_SIZE - 1);
+                let skipped = (self.received - ADDRESS_BYTES - 1).saturating_sub(PAGE_SIZE);
+                for (i, byte) in self.program.iter().enumerate() {
+                    let offset = (self.address as usize + skipped + i) % PAGE_SIZE;
+                    self.data[page + offset] &= byte;
+                }
+            }
+            Some(SECTOR_ERASE) if self.write_enabled && complete => self.erase(SECTOR_SIZE),
+            Some(BLOCK_ERASE) if self.write_enabled && complete => self.erase(BLOCK_SIZE.min(self.data.len())),
+            Some(CHIP_ERASE | CHIP_ERASE_ALT) if self.write_enabled => self.data.fill(0xff),
+            _ => return,
+        }
+        // Modifying commands leave the flash write protected again
+        if self.command != Some(WRITE_ENABLE) {
+            self.write_enabled = false;
+        }
+    }
+
+    fn reset_transaction(&mut self) {
+        self.command = None;
+        self.received = 0;
+        self.address = 0;
+        self.program.clear();
+    }
+}
+impl SpiDevice for SpiFlash {
+    fn name(&self) -> String {
+        format!("SPI flash {} KiB", self.data.len() / 1024)
+    }
+    fn select(&mut self) {
+        self.reset_transaction();
+    }
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        let index = self.received;
+        self.received += 1;
+        let miso = self.output(index);
+        self.input(index, mosi);
+        miso
+    }
+    fn deselect(&mut self) {
+        self.execute();
+        self.reset_transaction();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(flash: &mut SpiFlash, data: &[u8]) -> Vec<u8> {
+        flash.select();
+        let received = data.iter().map(|byte| flash.transfer(*byte)).collect();
+        flash.deselect();
+        received
+    }
+
+    #[test]
+    fn programming_needs_write_enable_and_only_clears_bits() {
+        let mut flash = SpiFlash::new(SECTOR_SIZE).with_contents(&[0xf0]);
+
+        transaction(&mut flash, &[PAGE_PROGRAM, 0, 0, 0, 0x0f]);
+        assert_eq!(flash.contents()[0], 0xf0);
+
+        transaction(&mut flash, &[WRITE_ENABLE]);
+        assert_eq!(transaction(&mut flash, &[READ_STATUS, 0]), [0xff, STATUS_WRITE_ENABLED]);
+        transaction(&mut flash, &[PAGE_PROGRAM, 0, 0, 0, 0x3c]);
+        assert_eq!(flash.contents()[0], 0x30);
+        // Write enable is used up by the program
+        assert_eq!(transaction(&mut flash, &[READ_STATUS, 0]), [0xff, 0]);
+    }
+
+    #[test]
+    fn program_wraps_around_its_page() {
+        let mut flash = SpiFlash::new(SECTOR_SIZE);
+
+        transaction(&mut flash, &[WRITE_ENABLE]);
+        transaction(&mut flash, &[PAGE_PROGRAM, 0, 0x01, 0xff, 1, 2]);
+        assert_eq!(flash.contents()[0x1ff], 1);
+        assert_eq!(flash.contents()[0x100], 2);
+        assert_eq!(flash.contents()[0x200], 0xff);
+    }
+
+    #[test]
+    fn sector_erase_sets_the_whole_sector() {
+        let mut flash = SpiFlash::new(2 * SECTOR_SIZE).with_contents(&[0; 2 * SECTOR_SIZE]);
+
+        transaction(&mut flash, &[WRITE_ENABLE]);
+        transaction(&mut flash, &[SECTOR_ERASE, 0, 0x10, 0x20]);
+        assert!(flash.contents()[SECTOR_SIZE..].iter().all(|byte| *byte == 0xff));
+        assert!(flash.contents()[..SECTOR_SIZE].iter().all(|byte| *byte == 0));
+    }
+}
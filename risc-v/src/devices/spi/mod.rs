@@ -0,0 +1,383 @@
+//! SPI controller and simulated peripherals
+//!
+//! [`SpiController`] shifts bytes out to the peripherals whose chip select
+//! is asserted and collects what they shift back. Peripherals implement
+//! [`SpiDevice`]. Registers are 32 bits wide:
+//!
+//! | Offset | Register                                                       |
+//! |--------|----------------------------------------------------------------|
+//! | `0x00` | writes queue a byte to send, reads take a received byte, 0 if none |
+//! | `0x04` | status, see below                                              |
+//! | `0x08` | chip select, bit `n` asserts the line of peripheral `n`        |
+//! | `0x0c` | cycles per bit, at least 1                                     |
+//! | `0x10` | interrupt enable, bit 0 for idle and bit 1 for received bytes  |
+//!
+//! Status bit 0 is set while bytes are waiting or shifting, bit 1 while
+//! received bytes are waiting, bit 2 while the transmit FIFO is full and
+//! bit 3 when received bytes were lost, which reading the status clears.
+//! With no peripheral selected the controller receives `0xff`, as MISO is
+//! pulled up.
+pub mod flash;
+pub mod sdcard;
+
+pub use flash::SpiFlash;
+pub use sdcard::SdCard;
+
+use crate::Word;
+use crate::bus::AccessWidth;
+use crate::interrupts::IrqLine;
+use crate::scheduler::DeviceContext;
+use super::{AsAny, DeviceTrait, Properties, Property};
+
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::VecDeque;
+
+/// Chip select lines of a controller
+pub const CHIP_SELECTS: usize = 4;
+
+const FIFO_SIZE: usize = 8;
+
+const DATA: u32 = 0x00;
+const STATUS: u32 = 0x04;
+const SELECT: u32 = 0x08;
+const DIVIDER: u32 = 0x0c;
+const INTERRUPT_ENABLE: u32 = 0x10;
+
+const STATUS_BUSY: u32 = 1 << 0;
+const STATUS_RX_READY: u32 = 1 << 1;
+const STATUS_TX_FULL: u32 = 1 << 2;
+const STATUS_OVERRUN: u32 = 1 << 3;
+
+const INTERRUPT_IDLE: u32 = 1 << 0;
+const INTERRUPT_RX: u32 = 1 << 1;
+
+/// Peripheral on an SPI bus, mode 0 with bytes sent most significant bit first
+pub trait SpiDevice: AsAny {
+    fn name(&self) -> String;
+    /// Chip select was asserted, a transaction starts
+    fn select(&mut self) {}
+    /// Exchange a byte, `mosi` comes from the controller and the result goes back
+    fn transfer(&mut self, mosi: u8) -> u8;
+    /// Chip select was released, the transaction ends
+    fn deselect(&mut self) {}
+}
+
+struct State {
+    tx: VecDeque<u8>,
+    rx: VecDeque<u8>,
+    /// Byte being shifted out
+    shifting: Option<u8>,
+    select: u32,
+    divider: u32,
+    interrupt_enable: u32,
+    overrun: bool,
+}
+impl State {
+    fn busy(&self) -> bool {
+        self.shifting.is_some() || !self.tx.is_empty()
+    }
+
+    fn status(&self) -> u32 {
+        let mut status = 0;
+        if self.busy() {
+            status |= STATUS_BUSY;
+        }
+        if !self.rx.is_empty() {
+            status |= STATUS_RX_READY;
+        }
+        if self.tx.len() >= FIFO_SIZE {
+            status |= STATUS_TX_FULL;
+        }
+        if self.overrun {
+            status |= STATUS_OVERRUN;
+        }
+        status
+    }
+
+    fn pending(&self) -> bool {
+        (self.interrupt_enable & INTERRUPT_IDLE != 0 && !self.busy())
+            || (self.interrupt_enable & INTERRUPT_RX != 0 && !self.rx.is_empty())
+    }
+}
+
+pub struct SpiController {
+    devices: RefCell<Vec<Option<Box<dyn SpiDevice>>>>,
+    state: RefCell<State>,
+    context: RefCell<Option<DeviceContext>>,
+    irq: Option<IrqLine>,
+}
+impl SpiController {
+    pub fn new() -> Self {
+        Self {
+            devices: RefCell::new((0..CHIP_SELECTS).map(|_| None).collect()),
+            state: RefCell::new(State {
+                tx: VecDeque::new(),
+                rx: VecDeque::new(),
+                shifting: None,
+                select: 0,
+                divider: 1,
+                interrupt_enable: 0,
+                overrun: false,
+            }),
+            context: RefCell::new(None),
+            irq: None,
+        }
+    }
+
+    /// Connect `device` to chip select line `select`
+    pub fn with_peripheral<D: SpiDevice + 'static>(self, select: usize, device: D) -> Self {
+        assert!(select < CHIP_SELECTS, "SPI controller has {CHIP_SELECTS} chip selects, got {select}");
+        self.devices.borrow_mut()[select] = Some(Box::new(device));
+        self
+    }
+
+    /// Drive `irq` while an enabled interrupt is pending
+    pub fn with_interrupt(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    /// Peripheral on line `select`, `None` if there is none or it isn't a `T`
+    pub fn peripheral<T: SpiDevice + 'static>(&self, select: usize) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.devices.borrow(), |devices| {
+            devices.get(select)?.as_ref()?.as_ref().as_any().downcast_ref::<T>()
+        }).ok()
+    }
+
+    /// Mutable access to the peripheral on line `select`
+    pub fn peripheral_mut<T: SpiDevice + 'static>(&self, select: usize) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.devices.borrow_mut(), |devices| {
+            devices.get_mut(select)?.as_mut()?.as_mut().as_any_mut().downcast_mut::<T>()
+        }).ok()
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.state.borrow().pending());
+        }
+    }
+
+    /// Assert and release chip selects, telling peripherals whose line changed
+    fn set_select(&self, select: u32) {
+        let mut state = self.state.borrow_mut();
+        let old = state.select;
+        state.select = select & ((1 << CHIP_SELECTS) - 1);
+        let changed = old ^ state.select;
+        drop(state);
+
+        for (line, device) in self.devices.borrow_mut().iter_mut().enumerate() {
+            if let Some(device) = device.as_mut().filter(|_| changed & 1 << line != 0) {
+                match select & 1 << line != 0 {
+                    true => device.select(),
+                    false => device.deselect(),
+                }
+            }
+        }
+    }
+
+    /// Start shifting the next queued byte if the shift register is idle
+    fn start_transfer(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.shifting.is_some() {
+            return;
+        }
+        let Some(byte) = state.tx.pop_front() else {
+            return;
+        };
+        state.shifting = Some(byte);
+        let delay = 8 * state.divider as u64;
+        drop(state);
+
+        match self.context.borrow().clone() {
+            Some(context) => {
+                let handle = context.handle;
+                context.scheduler.schedule(delay, move |bus| {
+                    if let Some(spi) = bus.device::<SpiController>(handle) {
+                        spi.finish_transfer();
+                    }
+                });
+            }
+            // Not on a bus, nothing keeps time
+            None => self.finish_transfer(),
+        }
+    }
+
+    /// All bits of the byte were shifted, exchange it with the selected peripherals
+    fn finish_transfer(&self) {
+        let mut state = self.state.borrow_mut();
+        let Some(mosi) = state.shifting.take() else {
+            return;
+        };
+        let select = state.select;
+        drop(state);
+
+        // Selected peripherals pull MISO low, bits left alone read high
+        let mut miso = 0xff;
+        for (line, device) in self.devices.borrow_mut().iter_mut().enumerate() {
+            if let Some(device) = device.as_mut().filter(|_| select & 1 << line != 0) {
+                miso &= device.transfer(mosi);
+            }
+        }
+
+        let mut state = self.state.borrow_mut();
+        match state.rx.len() < FIFO_SIZE {
+            true => state.rx.push_back(miso),
+            false => state.overrun = true,
+        }
+        drop(state);
+
+        self.start_transfer();
+        self.update_interrupt();
+    }
+}
+impl Default for SpiController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl DeviceTrait for SpiController {
+    fn name(&self) -> String {
+        let devices = self.devices.borrow();
+        let names: Vec<String> = devices.iter().flatten().map(|device| device.name()).collect();
+        format!("SPI controller [{}]", names.join(", "))
+    }
+    fn attach(&self, context: DeviceContext) {
+        *self.context.borrow_mut() = Some(context);
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("spi")
+            .with("#address-cells", Property::Cells(vec![1]))
+            .with("#size-cells", Property::Cells(vec![0]));
+        match &self.irq {
+            Some(irq) => properties.with("interrupts", Property::Cells(vec![irq.line()])),
+            None => properties,
+        }
+    }
+    fn supports(&self, width: AccessWidth) -> bool {
+        width == AccessWidth::Word
+    }
+    fn read(&self, address: Word, _: AccessWidth) -> Word {
+        let mut state = self.state.borrow_mut();
+        let value = match address.0 {
+            DATA => state.rx.pop_front().unwrap_or(0) as u32,
+            STATUS => {
+                let status = state.status();
+                state.overrun = false;
+                status
+            }
+            SELECT => state.select,
+            DIVIDER => state.divider,
+            INTERRUPT_ENABLE => state.interrupt_enable,
+            _ => 0,
+        };
+        drop(state);
+
+        self.update_interrupt();
+        Word(value)
+    }
+    fn write(&self, address: Word, word: Word, _: AccessWidth) {
+        let mut state = self.state.borrow_mut();
+        match address.0 {
+            // Bytes written to a full FIFO are lost
+            DATA if state.tx.len() < FIFO_SIZE => state.tx.push_back(word.0 as u8),
+            SELECT => {
+                drop(state);
+                self.set_select(word.0);
+                return;
+            }
+            DIVIDER => state.divider = word.0.max(1),
+            INTERRUPT_ENABLE => state.interrupt_enable = word.0 & (INTERRUPT_IDLE | INTERRUPT_RX),
+            _ => return,
+        }
+        drop(state);
+
+        self.start_transfer();
+        self.update_interrupt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::bus::Bus;
+    use crate::devices::Device;
+
+    const BASE: Word = Word(0x4000_0000);
+
+    fn read(bus: &Bus, register: u32) -> u32 {
+        bus.read(BASE + Word(register), AccessWidth::Word).unwrap().0
+    }
+
+    fn write(bus: &Bus, register: u32, value: u32) {
+        bus.write(BASE + Word(register), Word(value), AccessWidth::Word).unwrap();
+    }
+
+    fn machine(spi: SpiController) -> Bus {
+        let mut bus = Bus::new();
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(0x14)), Box::new(spi))).unwrap();
+        bus
+    }
+
+    /// Exchange `data` with chip select `select` asserted, return what came back
+    fn transaction(bus: &Bus, select: u32, data: &[u8]) -> Vec<u8> {
+        write(bus, SELECT, select);
+        let mut received = vec![];
+        for chunk in data.chunks(FIFO_SIZE) {
+            for byte in chunk {
+                write(bus, DATA, *byte as u32);
+            }
+            while bus.skip_to_next_event() {}
+            received.extend(chunk.iter().map(|_| read(bus, DATA) as u8));
+        }
+        write(bus, SELECT, 0);
+        received
+    }
+
+    #[test]
+    fn flash_is_identified_programmed_and_read() {
+        let bus = machine(SpiController::new().with_peripheral(1, SpiFlash::new(1 << 16)));
+
+        assert_eq!(transaction(&bus, 1 << 1, &[0x9f, 0, 0, 0]), [0xff, 0xef, 0x40, 16]);
+        transaction(&bus, 1 << 1, &[0x06]);
+        transaction(&bus, 1 << 1, &[0x02, 0x00, 0x12, 0x34, 0xAA, 0x55]);
+        let read = transaction(&bus, 1 << 1, &[0x03, 0x00, 0x12, 0x33, 0, 0, 0, 0]);
+        assert_eq!(read[4..], [0xff, 0xAA, 0x55, 0xff]);
+    }
+
+    #[test]
+    fn nothing_selected_reads_high() {
+        let bus = machine(SpiController::new().with_peripheral(0, SpiFlash::new(1 << 16)));
+
+        assert_eq!(transaction(&bus, 0, &[0x9f, 0]), [0xff, 0xff]);
+    }
+
+    #[test]
+    fn bytes_take_eight_clocks_and_full_receive_fifo_overruns() {
+        let mut bus = Bus::new();
+        let irq = bus.interrupt_line(1);
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(0x14)), Box::new(SpiController::new().with_interrupt(irq)))).unwrap();
+
+        write(&bus, DIVIDER, 4);
+        write(&bus, INTERRUPT_ENABLE, INTERRUPT_RX);
+        write(&bus, DATA, 0);
+        bus.advance(31);
+        assert_eq!(read(&bus, STATUS), STATUS_BUSY);
+        bus.advance(1);
+        assert_eq!(read(&bus, STATUS), STATUS_RX_READY);
+        assert!(bus.interrupts().is_pending(1));
+
+        // First byte goes straight to the shift register
+        for _ in 0..=FIFO_SIZE {
+            write(&bus, DATA, 0);
+        }
+        assert_ne!(read(&bus, STATUS) & STATUS_TX_FULL, 0);
+        while bus.skip_to_next_event() {}
+        assert_eq!(read(&bus, STATUS), STATUS_RX_READY | STATUS_OVERRUN);
+        assert_eq!(read(&bus, STATUS), STATUS_RX_READY);
+        for _ in 0..FIFO_SIZE {
+            assert_eq!(read(&bus, DATA), 0xff);
+        }
+        assert!(!bus.interrupts().is_pending(1));
+    }
+}
@@ -0,0 +1,413 @@
+//! SD card in SPI mode
+//!
+//! Behaves like an SDHC card backed by a [`DiskImage`], so blocks are
+//! addressed by sector and are always 512 bytes. Supports the commands SPI
+//! hosts use to bring up a card and move data: reset, interface condition,
+//! OCR, CSD and CID, single and multiple block reads and writes, status and
+//! `ACMD41`. CRCs from the host are ignored, data blocks carry valid ones.
+use crate::devices::disk::{DiskImage, SECTOR_SIZE};
+use super::SpiDevice;
+
+use std::collections::VecDeque;
+
+const GO_IDLE_STATE: u8 = 0;
+const SEND_OP_COND: u8 = 1;
+const SEND_IF_COND: u8 = 8;
+const SEND_CSD: u8 = 9;
+const SEND_CID: u8 = 10;
+const STOP_TRANSMISSION: u8 = 12;
+const SEND_STATUS: u8 = 13;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const READ_MULTIPLE_BLOCK: u8 = 18;
+const WRITE_BLOCK: u8 = 24;
+const WRITE_MULTIPLE_BLOCK: u8 = 25;
+const APP_CMD: u8 = 55;
+const READ_OCR: u8 = 58;
+const CRC_ON_OFF: u8 = 59;
+/// `ACMD41`, after `APP_CMD`
+const SD_SEND_OP_COND: u8 = 41;
+
+const R1_IDLE: u8 = 1 << 0;
+const R1_ILLEGAL_COMMAND: u8 = 1 << 2;
+const R1_ADDRESS_ERROR: u8 = 1 << 5;
+const R1_PARAMETER_ERROR: u8 = 1 << 6;
+
+const START_BLOCK: u8 = 0xfe;
+const START_MULTIPLE_BLOCK: u8 = 0xfc;
+const STOP_TRAN: u8 = 0xfd;
+
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_WRITE_ERROR: u8 = 0x0d;
+/// Error token for reads past the end of the card
+const ERROR_OUT_OF_RANGE: u8 = 0x08;
+
+/// Powered up, SDHC, 2.7 to 3.6 V
+const OCR: u32 = 0xc0ff_8000;
+
+const COMMAND_SIZE: usize = 6;
+
+/// What the card does with incoming bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Looking for the start of a command
+    Command,
+    /// Sending blocks from `next` on until `STOP_TRANSMISSION`
+    ReadMultiple { next: u64 },
+    /// Waiting for a data token to write at `sector`
+    WriteToken { sector: u64, multiple: bool },
+    /// Collecting a block and its CRC to write at `sector`
+    WriteData { sector: u64, multiple: bool },
+}
+
+pub struct SdCard {
+    image: DiskImage,
+    idle: bool,
+    /// Next command is an application specific one
+    application: bool,
+    mode: Mode,
+    /// Command or data block being received
+    incoming: Vec<u8>,
+    /// Bytes to send, `0xff` is sent when it is empty
+    outgoing: VecDeque<u8>,
+}
+impl SdCard {
+    pub fn new(image: DiskImage) -> Self {
+        Self {
+            image,
+            idle: true,
+            application: false,
+            mode: Mode::Command,
+            incoming: vec![],
+            outgoing: VecDeque::new(),
+        }
+    }
+
+    pub fn image(&self) -> &DiskImage {
+        &self.image
+    }
+
+    pub fn image_mut(&mut self) -> &mut DiskImage {
+        &mut self.image
+    }
+
+    fn r1(&self) -> u8 {
+        match self.idle {
+            true => R1_IDLE,
+            false => 0,
+        }
+    }
+
+    /// Queue a response, after the byte of delay cards take before answering
+    fn respond(&mut self, response: &[u8]) {
+        self.outgoing.push_back(0xff);
+        self.outgoing.extend(response);
+    }
+
+    /// Queue a data block with its start token and CRC
+    fn send_block(&mut self, data: &[u8]) {
+        self.outgoing.push_back(0xff);
+        self.outgoing.push_back(START_BLOCK);
+        self.outgoing.extend(data);
+        self.outgoing.extend(crc16(data).to_be_bytes());
+    }
+
+    /// Queue sector `sector`, or an error token if it can't be read
+    fn send_sector(&mut self, sector: u64) -> bool {
+        let mut data = [0; SECTOR_SIZE];
+        match self.image.read(sector, &mut data) {
+            Ok(()) => {
+                self.send_block(&data);
+                true
+            }
+            Err(_) => {
+                self.outgoing.extend([0xff, ERROR_OUT_OF_RANGE]);
+                false
+            }
+        }
+    }
+
+    /// Card specific data, version 2 as used by SDHC cards
+    fn csd(&self) -> [u8; 16] {
+        // Capacity is (C_SIZE + 1) * 512 KiB
+        let c_size = (self.image.sectors() / 1024).saturating_sub(1) as u32;
+        let mut csd = [
+            0x40, 0x0e, 0x00, 0x32, 0x5b, 0x59, 0x00, 0x00,
+            0x00, 0x00, 0x7f, 0x80, 0x0a, 0x40, 0x00, 0x00,
+        ];
+        csd[7] = (c_size >> 16) as u8 & 0x3f;
+        csd[8] = (c_size >> 8) as u8;
+        csd[9] = c_size as u8;
+        csd[15] = crc7(&csd[..15]) << 1 | 1;
+        csd
+    }
+
+    /// Card identification, with made up manufacturer and product
+    fn cid(&self) -> [u8; 16] {
+        let mut cid = [0; 16];
+        cid[1..3].copy_from_slice(b"RV");
+        cid[3..8].copy_from_slice(b"RVSD0");
+        cid[8] = 0x10;
+        cid[15] = crc7(&cid[..15]) << 1 | 1;
+        cid
+    }
+
+    fn command(&mut self, command: [u8; COMMAND_SIZE]) {
+        let index = command[0] & 0x3f;
+        let argument = u32::from_be_bytes([command[1], command[2], command[3], command[4]]);
+        let application = std::mem::take(&mut self.application);
+
+        match (index, application) {
+            (GO_IDLE_STATE, _) => {
+                self.idle = true;
+                self.mode = Mode::Command;
+                self.respond(&[R1_IDLE]);
+            }
+            (SEND_OP_COND, _) | (SD_SEND_OP_COND, true) => {
+                // Initialization is instant
+                self.idle = false;
+                self.respond(&[0]);
+            }
+            (SEND_IF_COND, _) => {
+                let r1 = self.r1();
+                // Echo voltage and check pattern
+                self.respond(&[r1, 0, 0, (argument >> 8) as u8 & 0xf, argument as u8]);
+            }
+            (SEND_CSD, _) => {
+                let (r1, csd) = (self.r1(), self.csd());
+                self.respond(&[r1]);
+                self.send_block(&csd);
+            }
+            (SEND_CID, _) => {
+                let (r1, cid) = (self.r1(), self.cid());
+                self.respond(&[r1]);
+                self.send_block(&cid);
+            }
+            (STOP_TRANSMISSION, _) => {
+                self.mode = Mode::Command;
+                // Stuff byte, then the response
+                self.outgoing.clear();
+                self.outgoing.push_back(0xff);
+                let r1 = self.r1();
+                self.respond(&[r1]);
+            }
+            (SEND_STATUS, _) => {
+                let r1 = self.r1();
+                self.respond(&[r1, 0]);
+            }
+            (SET_BLOCKLEN, _) => {
+                let r1 = match argument as usize == SECTOR_SIZE {
+                    true => self.r1(),
+                    false => self.r1() | R1_PARAMETER_ERROR,
+                };
+                self.respond(&[r1]);
+            }
+            (READ_SINGLE_BLOCK | READ_MULTIPLE_BLOCK, _) if self.idle => self.respond(&[R1_IDLE | R1_ILLEGAL_COMMAND]),
+            (READ_SINGLE_BLOCK | READ_MULTIPLE_BLOCK, _) if argument as u64 >= self.image.sectors() => {
+                self.respond(&[R1_ADDRESS_ERROR]);
+            }
+            (READ_SINGLE_BLOCK, _) => {
+                self.respond(&[0]);
+                self.send_sector(argument as u64);
+            }
+            (READ_MULTIPLE_BLOCK, _) => {
+                self.respond(&[0]);
+                self.mode = Mode::ReadMultiple { next: argument as u64 };
+            }
+            (WRITE_BLOCK | WRITE_MULTIPLE_BLOCK, _) if self.idle => self.respond(&[R1_IDLE | R1_ILLEGAL_COMMAND]),
+            (WRITE_BLOCK | WRITE_MULTIPLE_BLOCK, _) if argument as u64 >= self.image.sectors() => {
+                self.respond(&[R1_ADDRESS_ERROR]);
+            }
+            (WRITE_BLOCK | WRITE_MULTIPLE_BLOCK, _) => {
+                self.respond(&[0]);
+                self.mode = Mode::WriteToken { sector: argument as u64, multiple: index == WRITE_MULTIPLE_BLOCK };
+            }
+            (APP_CMD, _) => {
+                self.application = true;
+                let r1 = self.r1();
+                self.respond(&[r1]);
+            }
+            (READ_OCR, _) => {
+                let r1 = self.r1();
+                self.respond(&[r1]);
+                self.outgoing.extend(OCR.to_be_bytes());
+            }
+            (CRC_ON_OFF, _) => {
+                let r1 = self.r1();
+                self.respond(&[r1]);
+            }
+            _ => {
+                let r1 = self.r1() | R1_ILLEGAL_COMMAND;
+                self.respond(&[r1]);
+            }
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match self.mode {
+            Mode::Command | Mode::ReadMultiple { .. } => {
+                // Commands start with bits 0 and 1, idle lines are all ones
+                if self.incoming.is_empty() && byte & 0xc0 != 0x40 {
+                    return;
+                }
+                self.incoming.push(byte);
+                if self.incoming.len() == COMMAND_SIZE {
+                    let command = std::mem::take(&mut self.incoming).try_into().unwrap();
+                    self.command(command);
+                }
+            }
+            Mode::WriteToken { sector, multiple } => match byte {
+                START_BLOCK if !multiple => self.mode = Mode::WriteData { sector, multiple },
+                START_MULTIPLE_BLOCK if multiple => self.mode = Mode::WriteData { sector, multiple },
+                STOP_TRAN if multiple => {
+                    // Busy for a byte while programming finishes
+                    self.outgoing.extend([0xff, 0x00]);
+                    self.mode = Mode::Command;
+                }
+                _ => {}
+            },
+            Mode::WriteData { sector, multiple } => {
+                self.incoming.push(byte);
+                // Data is followed by two CRC bytes
+                if self.incoming.len() < SECTOR_SIZE + 2 {
+                    return;
+                }
+                let data = std::mem::take(&mut self.incoming);
+                let response = match self.image.write(sector, &data[..SECTOR_SIZE]) {
+                    Ok(()) => DATA_ACCEPTED,
+                    Err(_) => DATA_WRITE_ERROR,
+                };
+                self.outgoing.extend([response, 0x00]);
+                self.mode = match (multiple, response) {
+                    (true, DATA_ACCEPTED) => Mode::WriteToken { sector: sector + 1, multiple },
+                    _ => Mode::Command,
+                };
+            }
+        }
+    }
+}
+impl SpiDevice for SdCard {
+    fn name(&self) -> String {
+        format!("SD card {} MiB", self.image.sectors() * SECTOR_SIZE as u64 / (1024 * 1024))
+    }
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        // Keep streaming blocks until the host stops the transfer
+        if let Mode::ReadMultiple { next } = self.mode {
+            if self.outgoing.is_empty() {
+                self.mode = match next < self.image.sectors() && self.send_sector(next) {
+                    true => Mode::ReadMultiple { next: next + 1 },
+                    false => Mode::Command,
+                };
+            }
+        }
+        let miso = self.outgoing.pop_front().unwrap_or(0xff);
+        self.receive(mosi);
+        miso
+    }
+    fn deselect(&mut self) {
+        self.incoming.clear();
+        self.outgoing.clear();
+        if matches!(self.mode, Mode::ReadMultiple { .. }) {
+            self.mode = Mode::Command;
+        }
+    }
+}
+
+/// CRC-7 of commands and registers
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        for bit in (0..8).rev() {
+            let feedback = ((crc >> 6) ^ (byte >> bit)) & 1;
+            crc = (crc << 1) & 0x7f;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// CRC-16-CCITT of data blocks
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send command `index` with `argument`, return the first response byte
+    /// and leave the rest queued
+    fn command(card: &mut SdCard, index: u8, argument: u32) -> u8 {
+        let mut command = vec![0x40 | index];
+        command.extend(argument.to_be_bytes());
+        command.push(crc7(&command) << 1 | 1);
+        for byte in command {
+            card.transfer(byte);
+        }
+        // Cards answer within a few bytes
+        (0..8).map(|_| card.transfer(0xff)).find(|byte| *byte != 0xff).unwrap()
+    }
+
+    fn initialized(sectors: usize) -> SdCard {
+        let mut card = SdCard::new(DiskImage::from_bytes(vec![0; sectors * SECTOR_SIZE]));
+        assert_eq!(command(&mut card, GO_IDLE_STATE, 0), R1_IDLE);
+        assert_eq!(command(&mut card, READ_SINGLE_BLOCK, 0), R1_IDLE | R1_ILLEGAL_COMMAND);
+        assert_eq!(command(&mut card, APP_CMD, 0), R1_IDLE);
+        assert_eq!(command(&mut card, SD_SEND_OP_COND, 0x4000_0000), 0);
+        card
+    }
+
+    #[test]
+    fn crcs_match_known_values() {
+        // CMD0 is always sent with CRC byte 0x95
+        assert_eq!(crc7(&[0x40, 0, 0, 0, 0]) << 1 | 1, 0x95);
+        assert_eq!(crc16(&[0xff; SECTOR_SIZE]), 0x7fa1);
+    }
+
+    #[test]
+    fn interface_condition_is_echoed() {
+        let mut card = SdCard::new(DiskImage::from_bytes(vec![0; SECTOR_SIZE]));
+
+        assert_eq!(command(&mut card, SEND_IF_COND, 0x1aa), R1_IDLE);
+        assert_eq!([0; 4].map(|_| card.transfer(0xff)), [0, 0, 0x01, 0xaa]);
+    }
+
+    #[test]
+    fn block_is_written_and_read_back() {
+        let mut card = initialized(4);
+        let data: Vec<u8> = (0..SECTOR_SIZE).map(|i| i as u8).collect();
+
+        assert_eq!(command(&mut card, WRITE_BLOCK, 2), 0);
+        card.transfer(0xff);
+        card.transfer(START_BLOCK);
+        for byte in data.iter().chain(&[0, 0]) {
+            card.transfer(*byte);
+        }
+        assert_eq!(card.transfer(0xff) & 0x1f, DATA_ACCEPTED);
+
+        assert_eq!(command(&mut card, READ_SINGLE_BLOCK, 2), 0);
+        while card.transfer(0xff) != START_BLOCK {}
+        let block: Vec<u8> = (0..SECTOR_SIZE + 2).map(|_| card.transfer(0xff)).collect();
+        assert_eq!(block[..SECTOR_SIZE], data);
+        assert_eq!(block[SECTOR_SIZE..], crc16(&data).to_be_bytes());
+    }
+
+    #[test]
+    fn reads_past_the_card_fail() {
+        let mut card = initialized(4);
+
+        assert_eq!(command(&mut card, READ_SINGLE_BLOCK, 4), R1_ADDRESS_ERROR);
+    }
+}
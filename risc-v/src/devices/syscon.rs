@@ -0,0 +1,160 @@
+//! System controller for power off and reset
+//!
+//! The guest stops the machine by writing a command to offset `0x0`, the
+//! hart then stops with [`Halt::PowerOff`] or [`Halt::Reset`] carrying the
+//! code from bits 16-31. A reset only halts, the host then resets the
+//! machine with [`RV32::reset`](crate::RV32::reset).
+//! The command is in bits 0-15, with the values of the SiFive test device
+//! QEMU uses:
+//!
+//! | Command  | Action            |
+//! |----------|-------------------|
+//! | `0x5555` | power off         |
+//! | `0x3333` | power off, failed |
+//! | `0x7777` | reset             |
+//!
+//! A failed power off carries code 1 when bits 16-31 are zero, so it can be
+//! told apart from a clean one. Other commands are ignored, reads return 0.
+use crate::{Halt, Word};
+use crate::bus::{AccessWidth, Bus};
+use crate::scheduler::DeviceContext;
+use super::{DeviceTrait, Properties};
+
+use std::cell::{Cell, RefCell};
+
+const COMMAND_PASS: u32 = 0x5555;
+const COMMAND_FAIL: u32 = 0x3333;
+const COMMAND_RESET: u32 = 0x7777;
+
+/// Bytes of bus address space the controller needs
+pub const SIZE: u32 = 0x1000;
+
+pub struct SystemController {
+    /// Last request of the guest
+    request: Cell<Option<Halt>>,
+    context: RefCell<Option<DeviceContext>>,
+}
+impl SystemController {
+    pub fn new() -> Self {
+        Self {
+            request: Cell::new(None),
+            context: RefCell::new(None),
+        }
+    }
+
+    /// Power off or reset the guest asked for last, also when not on a bus
+    pub fn request(&self) -> Option<Halt> {
+        self.request.get()
+    }
+
+    fn command(&self, word: u32) {
+        let code = word >> 16;
+        let reason = match word & 0xffff {
+            COMMAND_PASS => Halt::PowerOff(code),
+            COMMAND_FAIL => Halt::PowerOff(code.max(1)),
+            COMMAND_RESET => Halt::Reset(code),
+            _ => return,
+        };
+        self.request.set(Some(reason));
+
+        // Writes can't reach the bus, halt it from an event right away
+        if let Some(context) = self.context.borrow().as_ref() {
+            context.scheduler.schedule(0, move |bus: &Bus| bus.halt(reason));
+        }
+    }
+}
+impl Default for SystemController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceTrait for SystemController {
+    fn name(&self) -> String {
+        "system controller".to_string()
+    }
+    fn attach(&self, context: DeviceContext) {
+        *self.context.borrow_mut() = Some(context);
+    }
+    fn reset(&self) {
+        self.request.set(None);
+    }
+    fn properties(&self) -> Properties {
+        Properties::new("syscon").with_compatible("syscon")
+    }
+    fn supports(&self, width: AccessWidth) -> bool {
+        width == AccessWidth::Word
+    }
+    fn read(&self, _: Word, _: AccessWidth) -> Word {
+        Word(0)
+    }
+    fn write(&self, address: Word, word: Word, _: AccessWidth) {
+        if address.0 == 0 {
+            self.command(word.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryRange, RV32};
+    use crate::devices::{Device, ram::Ram};
+
+    const BASE: Word = Word(0x1000_0000);
+
+    #[test]
+    fn commands_halt_with_their_code() {
+        for (command, reason) in [
+            (0x0000_5555, Halt::PowerOff(0)),
+            (0x0000_3333, Halt::PowerOff(1)),
+            (0x0002_3333, Halt::PowerOff(2)),
+            (0x0005_7777, Halt::Reset(5)),
+        ] {
+            let mut bus = Bus::new();
+            let handle = bus.connect(Device::new(MemoryRange::new(BASE, Word(SIZE)), Box::new(SystemController::new()))).unwrap();
+
+            bus.write(BASE, Word(command), AccessWidth::Word).unwrap();
+            assert_eq!(bus.device::<SystemController>(handle).unwrap().request(), Some(reason));
+            assert_eq!(bus.halted(), None);
+            bus.advance(0);
+            assert_eq!(bus.halted(), Some(reason));
+        }
+    }
+
+    #[test]
+    fn other_commands_are_ignored() {
+        let controller = SystemController::new();
+        controller.write(Word(0), Word(0x1234), AccessWidth::Word);
+        controller.write(Word(4), Word(0x5555), AccessWidth::Word);
+        assert_eq!(controller.request(), None);
+    }
+
+    #[test]
+    fn reset_halts_run_and_the_host_starts_over() {
+        let mut cpu = RV32::new();
+        cpu.bus.connect(Device::new(MemoryRange::new(Word(0), Word(0x1000)), Box::new(Ram::new(0x1000)))).unwrap();
+        cpu.bus.connect(Device::new(MemoryRange::new(BASE, Word(SIZE)), Box::new(SystemController::new()))).unwrap();
+        let program: [u32; 4] = [
+            0x10000537, // lui a0, 0x10000
+            0x000075b7, // lui a1, 0x7
+            0x77758593, // addi a1, a1, 0x777
+            0x00b52023, // sw a1, 0(a0)
+        ];
+        let bytes: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        cpu.bus.load(Word(0), &bytes).unwrap();
+
+        assert_eq!(cpu.run(), Ok(Halt::Reset(0)));
+        assert_eq!(cpu.reg.read("pc").unwrap(), Word(16));
+
+        assert_eq!(cpu.reg.read("a1").unwrap(), Word(0x7777));
+
+        // Registers are cleared and the program runs again from the vector
+        cpu.reset();
+        assert_eq!(cpu.halted(), None);
+        assert_eq!(cpu.reg.read("pc").unwrap(), Word(0));
+        assert_eq!(cpu.reg.read("a1").unwrap(), Word(0));
+        assert_eq!(cpu.run(), Ok(Halt::Reset(0)));
+        assert_eq!(cpu.reg.read("pc").unwrap(), Word(16));
+    }
+}
@@ -0,0 +1,106 @@
+//! Crossterm key events for the input device
+//!
+//! Terminal front-ends pass their key events to [`forward`], which turns
+//! them into HID key presses and releases on an [`InputSink`].
+use super::input::{char_usage, key_usage, InputSink};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+
+/// HID usage of `code`, `None` for keys the guest keyboard doesn't have
+fn usage(code: KeyCode) -> Option<u16> {
+    let name = match code {
+        KeyCode::Char(c) => return char_usage(c),
+        KeyCode::F(n) => return key_usage(&format!("f{n}")),
+        KeyCode::Enter => "enter",
+        KeyCode::Esc => "escape",
+        KeyCode::Backspace => "backspace",
+        KeyCode::Tab | KeyCode::BackTab => "tab",
+        KeyCode::Insert => "insert",
+        KeyCode::Home => "home",
+        KeyCode::PageUp => "pageup",
+        KeyCode::Delete => "delete",
+        KeyCode::End => "end",
+        KeyCode::PageDown => "pagedown",
+        KeyCode::Right => "right",
+        KeyCode::Left => "left",
+        KeyCode::Down => "down",
+        KeyCode::Up => "up",
+        _ => return None,
+    };
+    key_usage(name)
+}
+
+/// Modifier keys held during `event`
+fn modifiers(event: &KeyEvent) -> Vec<u16> {
+    let mut held = vec![];
+    let shifted = matches!(event.code, KeyCode::Char(c) if c.is_ascii_uppercase()) || event.code == KeyCode::BackTab;
+    for (modifier, name) in [(KeyModifiers::CONTROL, "lctrl"), (KeyModifiers::SHIFT, "lshift"), (KeyModifiers::ALT, "lalt")] {
+        if event.modifiers.contains(modifier) || (name == "lshift" && shifted) {
+            held.extend(key_usage(name));
+        }
+    }
+    held
+}
+
+/// Forward a terminal key event to `sink`.
+///
+/// Most terminals only report presses, unless `releases` is set every press
+/// is followed by its release, with modifiers pressed around it.
+pub fn forward(event: &KeyEvent, sink: &dyn InputSink, releases: bool) {
+    let Some(scancode) = usage(event.code) else {
+        return;
+    };
+    match (event.kind, releases) {
+        (KeyEventKind::Press, true) => sink.key(scancode, true),
+        (KeyEventKind::Release, true) => sink.key(scancode, false),
+        (KeyEventKind::Press, false) => {
+            let modifiers = modifiers(event);
+            for modifier in &modifiers {
+                sink.key(*modifier, true);
+            }
+            sink.key(scancode, true);
+            sink.key(scancode, false);
+            for modifier in modifiers.iter().rev() {
+                sink.key(*modifier, false);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Sink remembering `(scancode, pressed)` of every key event
+    #[derive(Default)]
+    struct Keys(RefCell<Vec<(u16, bool)>>);
+    impl InputSink for Keys {
+        fn key(&self, scancode: u16, pressed: bool) {
+            self.0.borrow_mut().push((scancode, pressed));
+        }
+        fn button(&self, _: usize, _: crate::devices::input::Button, _: bool) {}
+    }
+
+    #[test]
+    fn press_is_followed_by_release_around_modifiers() {
+        let keys = Keys::default();
+        forward(&KeyEvent::new(KeyCode::Char('A'), KeyModifiers::NONE), &keys, false);
+
+        // Upper case letters are typed with shift held
+        assert_eq!(*keys.0.borrow(), vec![(225, true), (4, true), (4, false), (225, false)]);
+    }
+
+    #[test]
+    fn releases_are_forwarded_when_the_terminal_reports_them() {
+        let keys = Keys::default();
+        let mut event = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
+        forward(&event, &keys, true);
+        event.kind = KeyEventKind::Release;
+        forward(&event, &keys, true);
+        forward(&KeyEvent::new(KeyCode::Null, KeyModifiers::NONE), &keys, true);
+
+        assert_eq!(*keys.0.borrow(), vec![(40, true), (40, false)]);
+    }
+}
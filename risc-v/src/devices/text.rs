@@ -0,0 +1,398 @@
+//! Text mode display
+//!
+//! Screen memory starts at offset 0 with two bytes per cell, the character
+//! and its attribute, row after row. The low nibble of the attribute is the
+//! foreground color and the high nibble the background, out of the 16 CGA
+//! colors. Registers follow at the next page boundary, and the font ROM a
+//! page after them:
+//!
+//! | Offset          | Register                                             |
+//! |-----------------|------------------------------------------------------|
+//! | `0x000`         | columns, read-only                                   |
+//! | `0x004`         | rows, read-only                                      |
+//! | `0x008`         | cursor column                                        |
+//! | `0x00c`         | cursor row                                           |
+//! | `0x010`         | cursor control, bit 0 shows the cursor               |
+//! | `0x014`         | memory row shown at the top, for hardware scrolling  |
+//! | `0x018`         | character height, 8 or 16 pixels                     |
+//! | `0x01c`         | writing `n` scrolls the text up `n` rows, reads 0    |
+//! | `0x1000-0x17ff` | 8x8 font ROM, 8 bytes for each of 256 characters     |
+//! | `0x1800-0x27ff` | 8x16 font ROM, 16 bytes for each of 256 characters   |
+//!
+//! Rows scrolled in are blank with light gray on black.
+use crate::Word;
+use crate::bus::AccessWidth;
+use crate::image::Image;
+use super::{DeviceTrait, Properties};
+use super::font::{glyph8, glyph16};
+use super::framebuffer::Framebuffer;
+use super::ram::{Page, PAGE_SIZE};
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+const COLUMNS: u32 = 0x000;
+const ROWS: u32 = 0x004;
+const CURSOR_COLUMN: u32 = 0x008;
+const CURSOR_ROW: u32 = 0x00c;
+const CURSOR_CONTROL: u32 = 0x010;
+const START_ROW: u32 = 0x014;
+const CHARACTER_HEIGHT: u32 = 0x018;
+const SCROLL: u32 = 0x01c;
+const FONT_8X8: u32 = 0x1000;
+const FONT_8X16: u32 = 0x1800;
+const FONT_END: u32 = 0x2800;
+
+const CURSOR_VISIBLE: u32 = 1 << 0;
+
+/// Attribute of blank cells, light gray on black
+pub const DEFAULT_ATTRIBUTE: u8 = 0x07;
+
+/// CGA colors in attribute order
+pub const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00], [0x00, 0x00, 0xaa], [0x00, 0xaa, 0x00], [0x00, 0xaa, 0xaa],
+    [0xaa, 0x00, 0x00], [0xaa, 0x00, 0xaa], [0xaa, 0x55, 0x00], [0xaa, 0xaa, 0xaa],
+    [0x55, 0x55, 0x55], [0x55, 0x55, 0xff], [0x55, 0xff, 0x55], [0x55, 0xff, 0xff],
+    [0xff, 0x55, 0x55], [0xff, 0x55, 0xff], [0xff, 0xff, 0x55], [0xff, 0xff, 0xff],
+];
+
+pub struct TextDisplay {
+    columns: u32,
+    rows: u32,
+    cells: Vec<Page>,
+    cursor: Cell<(u32, u32)>,
+    cursor_visible: Cell<bool>,
+    start_row: Cell<u32>,
+    character_height: Cell<u32>,
+}
+impl TextDisplay {
+    /// Blank display of `columns` by `rows` characters, with the cursor shown
+    pub fn new(columns: u32, rows: u32) -> Self {
+        assert!(columns > 0 && rows > 0, "Text display must be at least 1x1 characters, got {columns}x{rows}");
+
+        let pages = (columns as usize * rows as usize * 2).div_ceil(PAGE_SIZE);
+        let display = Self {
+            columns,
+            rows,
+            cells: (0..pages).map(|_| Rc::new(std::array::from_fn(|_| Cell::new(0)))).collect(),
+            cursor: Cell::new((0, 0)),
+            cursor_visible: Cell::new(true),
+            start_row: Cell::new(0),
+            character_height: Cell::new(16),
+        };
+        display.clear_rows(0..rows);
+        display
+    }
+
+    /// Use 8 or 16 pixel high characters, 16 by default
+    pub fn with_character_height(self, height: u32) -> Self {
+        assert!(height == 8 || height == 16, "Character height must be 8 or 16, got {height}");
+        self.character_height.set(height);
+        self
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.columns
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    pub fn character_height(&self) -> u32 {
+        self.character_height.get()
+    }
+
+    /// Offset of the registers, the end of screen memory
+    pub fn registers(&self) -> u32 {
+        (self.cells.len() * PAGE_SIZE) as u32
+    }
+
+    /// Bytes of bus address space the display needs
+    pub fn size(&self) -> u32 {
+        self.registers() + FONT_END
+    }
+
+    /// Column and row of the cursor, `None` while it is hidden
+    pub fn cursor(&self) -> Option<(u32, u32)> {
+        self.cursor_visible.get().then(|| self.cursor.get())
+    }
+
+    /// Offset in screen memory of the cell shown at `column`, `row`
+    fn offset(&self, column: u32, row: u32) -> usize {
+        let row = (row + self.start_row.get()) % self.rows;
+        (row * self.columns + column) as usize * 2
+    }
+
+    fn byte(&self, offset: usize) -> u8 {
+        self.cells[offset / PAGE_SIZE][offset % PAGE_SIZE].get()
+    }
+
+    fn set_byte(&self, offset: usize, byte: u8) {
+        self.cells[offset / PAGE_SIZE][offset % PAGE_SIZE].set(byte);
+    }
+
+    /// Character and attribute shown at `column`, `row`
+    pub fn cell(&self, column: u32, row: u32) -> (u8, u8) {
+        let offset = self.offset(column, row);
+        (self.byte(offset), self.byte(offset + 1))
+    }
+
+    /// Put `character` with `attribute` at `column`, `row` of the screen
+    pub fn set_cell(&self, column: u32, row: u32, character: u8, attribute: u8) {
+        let offset = self.offset(column, row);
+        self.set_byte(offset, character);
+        self.set_byte(offset + 1, attribute);
+    }
+
+    fn clear_rows(&self, rows: std::ops::Range<u32>) {
+        for row in rows {
+            for column in 0..self.columns {
+                self.set_cell(column, row, b' ', DEFAULT_ATTRIBUTE);
+            }
+        }
+    }
+
+    /// Move the text up `count` rows, blanking the rows at the bottom
+    pub fn scroll(&self, count: u32) {
+        let count = count.min(self.rows);
+        for row in 0..self.rows - count {
+            for column in 0..self.columns {
+                let (character, attribute) = self.cell(column, row + count);
+                self.set_cell(column, row, character, attribute);
+            }
+        }
+        self.clear_rows(self.rows - count..self.rows);
+    }
+
+    /// Text of screen row `row`, without trailing spaces. Characters
+    /// outside printable ASCII show as spaces.
+    pub fn row_text(&self, row: u32) -> String {
+        let text: String = (0..self.columns)
+            .map(|column| match self.cell(column, row).0 {
+                c @ 0x20..=0x7e => c as char,
+                _ => ' ',
+            })
+            .collect();
+        text.trim_end().to_string()
+    }
+
+    /// Text of the whole screen, one line per row, without trailing spaces
+    pub fn snapshot(&self) -> String {
+        let rows: Vec<String> = (0..self.rows).map(|row| self.row_text(row)).collect();
+        rows.join("\n")
+    }
+
+    /// Render the screen with the current font and the cursor as an
+    /// underline in the color of its cell
+    pub fn render(&self) -> Image {
+        let height = self.character_height.get();
+        let mut image = Image::new(self.columns * 8, self.rows * height);
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                let (character, attribute) = self.cell(column, row);
+                let glyph = match height {
+                    8 => glyph8(character).to_vec(),
+                    _ => glyph16(character).to_vec(),
+                };
+                let [fr, fg, fb] = PALETTE[(attribute & 0xf) as usize];
+                let [br, bg, bb] = PALETTE[(attribute >> 4) as usize];
+                let cursor = self.cursor() == Some((column, row));
+
+                for (y, bits) in (0..height).zip(glyph) {
+                    let underline = cursor && y >= height - height / 8;
+                    for x in 0..8 {
+                        let rgba = match underline || bits & 0x80 >> x != 0 {
+                            true => [fr, fg, fb, 0xff],
+                            false => [br, bg, bb, 0xff],
+                        };
+                        image.set(column * 8 + x, row * height + y, rgba);
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    /// Render the screen into the top left corner of `framebuffer`
+    pub fn draw(&self, framebuffer: &Framebuffer) {
+        framebuffer.blit(0, 0, &self.render());
+    }
+
+    fn read_register(&self, offset: u32) -> u8 {
+        let word = match offset & !3 {
+            COLUMNS => self.columns,
+            ROWS => self.rows,
+            CURSOR_COLUMN => self.cursor.get().0,
+            CURSOR_ROW => self.cursor.get().1,
+            CURSOR_CONTROL => self.cursor_visible.get() as u32,
+            START_ROW => self.start_row.get(),
+            CHARACTER_HEIGHT => self.character_height.get(),
+            _ => 0,
+        };
+        let font = match offset {
+            FONT_8X8..FONT_8X16 => Some(glyph8(((offset - FONT_8X8) / 8) as u8)[(offset % 8) as usize]),
+            FONT_8X16..FONT_END => Some(glyph16(((offset - FONT_8X16) / 16) as u8)[(offset % 16) as usize]),
+            _ => None,
+        };
+        font.unwrap_or(word.to_le_bytes()[(offset & 3) as usize])
+    }
+
+    fn write_register(&self, offset: u32, word: u32) {
+        match offset {
+            CURSOR_COLUMN => self.cursor.set((word.min(self.columns - 1), self.cursor.get().1)),
+            CURSOR_ROW => self.cursor.set((self.cursor.get().0, word.min(self.rows - 1))),
+            CURSOR_CONTROL => self.cursor_visible.set(word & CURSOR_VISIBLE != 0),
+            START_ROW => self.start_row.set(word % self.rows),
+            CHARACTER_HEIGHT if word == 8 || word == 16 => self.character_height.set(word),
+            SCROLL => self.scroll(word),
+            _ => {}
+        }
+    }
+}
+impl DeviceTrait for TextDisplay {
+    fn name(&self) -> String {
+        format!("text display {}x{}", self.columns, self.rows)
+    }
+    fn properties(&self) -> Properties {
+        Properties::new("display")
+    }
+    fn read(&self, address: Word, width: AccessWidth) -> Word {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().take(width.bytes() as usize).enumerate() {
+            let offset = address.0 + i as u32;
+            *byte = match offset < self.registers() {
+                true => self.byte(offset as usize),
+                false => self.read_register(offset - self.registers()),
+            };
+        }
+        Word::from_le_bytes(bytes)
+    }
+    fn write(&self, address: Word, word: Word, width: AccessWidth) {
+        if address.0 >= self.registers() {
+            // Registers take whole words, the font is read-only
+            if width == AccessWidth::Word {
+                self.write_register(address.0 - self.registers(), word.0);
+            }
+            return;
+        }
+        for (i, byte) in word.0.to_le_bytes().into_iter().take(width.bytes() as usize).enumerate() {
+            let offset = address.0 as usize + i;
+            if offset < self.registers() as usize {
+                self.set_byte(offset, byte);
+            }
+        }
+    }
+    fn page(&self, offset: Word, _: bool) -> Option<Page> {
+        self.cells.get(offset.0 as usize / PAGE_SIZE).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::bus::Bus;
+    use crate::devices::Device;
+
+    const BASE: Word = Word(0x3000_0000);
+
+    fn machine(display: TextDisplay) -> (Bus, Word) {
+        let mut bus = Bus::new();
+        let registers = BASE + Word(display.registers());
+        let size = Word(display.size());
+        bus.connect(Device::new(MemoryRange::new(BASE, size), Box::new(display))).unwrap();
+        (bus, registers)
+    }
+
+    fn display(bus: &Bus) -> &TextDisplay {
+        bus.memory_map().first().and_then(|entry| bus.device::<TextDisplay>(entry.handle)).unwrap()
+    }
+
+    fn write(bus: &Bus, registers: Word, register: u32, value: u32) {
+        bus.write(registers + Word(register), Word(value), AccessWidth::Word).unwrap();
+    }
+
+    fn read(bus: &Bus, registers: Word, register: u32) -> u32 {
+        bus.read(registers + Word(register), AccessWidth::Word).unwrap().0
+    }
+
+    #[test]
+    fn snapshot_follows_screen_memory_and_scrolling() {
+        let (bus, registers) = machine(TextDisplay::new(10, 3));
+        // Cells are character then attribute
+        bus.load(BASE, b"h\x07i\x07").unwrap();
+        bus.load(BASE + Word(2 * 10 * 2), b"y\x1fo\x1f\x01\x07").unwrap();
+        assert_eq!(display(&bus).snapshot(), "hi\n\nyo");
+
+        write(&bus, registers, SCROLL, 1);
+        assert_eq!(display(&bus).snapshot(), "\nyo\n");
+        assert_eq!(display(&bus).cell(1, 1), (b'o', 0x1f));
+        assert_eq!(display(&bus).cell(0, 2), (b' ', DEFAULT_ATTRIBUTE));
+        assert_eq!(read(&bus, registers, SCROLL), 0);
+
+        // More rows than the screen has clears it
+        display(&bus).scroll(100);
+        assert_eq!(display(&bus).snapshot(), "\n\n");
+    }
+
+    #[test]
+    fn start_row_wraps_and_cursor_is_clamped() {
+        let (bus, registers) = machine(TextDisplay::new(4, 3));
+
+        write(&bus, registers, START_ROW, 4);
+        assert_eq!(read(&bus, registers, START_ROW), 1);
+        // Memory row 1 is shown at the top, row 0 at the bottom
+        bus.load(BASE + Word(4 * 2), b"A").unwrap();
+        bus.load(BASE, b"B").unwrap();
+        assert_eq!(display(&bus).cell(0, 0).0, b'A');
+        assert_eq!(display(&bus).cell(0, 2).0, b'B');
+
+        write(&bus, registers, CURSOR_COLUMN, 100);
+        write(&bus, registers, CURSOR_ROW, 100);
+        assert_eq!((read(&bus, registers, CURSOR_COLUMN), read(&bus, registers, CURSOR_ROW)), (3, 2));
+        assert_eq!(display(&bus).cursor(), Some((3, 2)));
+        write(&bus, registers, CURSOR_CONTROL, 0);
+        assert_eq!(display(&bus).cursor(), None);
+
+        // Registers other than the font are read-only or ignore bad values
+        write(&bus, registers, COLUMNS, 80);
+        write(&bus, registers, CHARACTER_HEIGHT, 12);
+        assert_eq!((read(&bus, registers, COLUMNS), read(&bus, registers, CHARACTER_HEIGHT)), (4, 16));
+    }
+
+    #[test]
+    fn font_rom_follows_the_registers() {
+        let (bus, registers) = machine(TextDisplay::new(80, 25));
+        let glyph = |font: u32, size: u32| bus.read_bytes(registers + Word(font + b'A' as u32 * size), size as usize).unwrap();
+
+        assert_eq!(glyph(FONT_8X8, 8), glyph8(b'A'));
+        assert_eq!(glyph(FONT_8X16, 16), glyph16(b'A'));
+        let word = bus.read(registers + Word(FONT_8X8 + b'A' as u32 * 8), AccessWidth::Word).unwrap();
+        assert_eq!(word.0.to_le_bytes(), glyph8(b'A')[..4]);
+
+        // Font can't be written
+        write(&bus, registers, FONT_8X8 + b'A' as u32 * 8, 0);
+        assert_eq!(glyph(FONT_8X8, 8), glyph8(b'A'));
+        assert_eq!(display(&bus).size(), display(&bus).registers() + FONT_END);
+    }
+
+    #[test]
+    fn render_uses_the_character_height() {
+        let display = TextDisplay::new(4, 2).with_character_height(8);
+        let image = display.render();
+        assert_eq!((image.width(), image.height()), (32, 16));
+
+        // Cursor at the top left is an underline on the last row of its cell
+        let [r, g, b] = PALETTE[DEFAULT_ATTRIBUTE as usize];
+        assert_eq!(image.get(0, 7), [r, g, b, 0xff]);
+        assert_eq!(image.get(0, 6), [0, 0, 0, 0xff]);
+        assert_eq!(image.get(8, 7), [0, 0, 0, 0xff]);
+
+        let display = TextDisplay::new(4, 2);
+        let image = display.render();
+        assert_eq!((image.width(), image.height()), (32, 32));
+        assert_eq!(image.get(0, 15), [r, g, b, 0xff]);
+        assert_eq!(image.get(0, 13), [0, 0, 0, 0xff]);
+    }
+}
@@ -0,0 +1,368 @@
+//! General purpose countdown timers
+//!
+//! Every channel counts down from its load value, one tick every
+//! `prescaler + 1` cycles. When it reaches zero it flags its expiry and
+//! either stops or starts over from the load value. Channels take 16 bytes
+//! each, with 32-bit registers:
+//!
+//! | Offset | Register                                                          |
+//! |--------|-------------------------------------------------------------------|
+//! | `0x0`  | load value, writing it also restarts the count from it            |
+//! | `0x4`  | current count                                                     |
+//! | `0x8`  | control, see below                                                |
+//! | `0xc`  | status, bit 0 is set when the count reached zero, write 1 to clear it |
+//!
+//! Control bit 0 enables counting, bit 1 selects periodic instead of one-shot
+//! mode and bit 2 enables the interrupt. Bits 16-31 are the prescaler. A
+//! one-shot channel clears its enable bit when it expires.
+use crate::Word;
+use crate::bus::{AccessWidth, Bus};
+use crate::interrupts::IrqLine;
+use crate::scheduler::{Cycle, DeviceContext, EventId};
+use super::{DeviceTrait, Properties, Property};
+
+use std::cell::RefCell;
+
+/// Most channels a timer block can have
+pub const MAX_CHANNELS: usize = 16;
+
+const CHANNEL_SIZE: u32 = 0x10;
+
+const LOAD: u32 = 0x0;
+const COUNT: u32 = 0x4;
+const CONTROL: u32 = 0x8;
+const STATUS: u32 = 0xc;
+
+const CONTROL_ENABLE: u32 = 1 << 0;
+const CONTROL_PERIODIC: u32 = 1 << 1;
+const CONTROL_INTERRUPT: u32 = 1 << 2;
+const CONTROL_PRESCALER_SHIFT: u32 = 16;
+const CONTROL_MASK: u32 = 0xffff_0000 | CONTROL_ENABLE | CONTROL_PERIODIC | CONTROL_INTERRUPT;
+
+const STATUS_EXPIRED: u32 = 1 << 0;
+
+#[derive(Default)]
+struct Channel {
+    load: u32,
+    control: u32,
+    /// Count at cycle `start`, it decreases from there while enabled
+    count: u32,
+    start: Cycle,
+    expired: bool,
+    /// Event for when the count reaches zero
+    expiry: Option<EventId>,
+}
+impl Channel {
+    fn enabled(&self) -> bool {
+        self.control & CONTROL_ENABLE != 0
+    }
+
+    /// Cycles per tick
+    fn tick(&self) -> Cycle {
+        (self.control >> CONTROL_PRESCALER_SHIFT) as Cycle + 1
+    }
+
+    fn count(&self, now: Cycle) -> u32 {
+        match self.enabled() {
+            true => {
+                let ticks = (now - self.start) / self.tick();
+                self.count.saturating_sub(ticks.min(u32::MAX as Cycle) as u32)
+            }
+            false => self.count,
+        }
+    }
+
+    /// Restart counting at `now` from `count`
+    fn restart(&mut self, now: Cycle, count: u32) {
+        self.count = count;
+        self.start = now;
+    }
+
+    fn pending(&self) -> bool {
+        self.expired && self.control & CONTROL_INTERRUPT != 0
+    }
+}
+
+pub struct Timer {
+    channels: RefCell<Vec<Channel>>,
+    context: RefCell<Option<DeviceContext>>,
+    irq: Option<IrqLine>,
+}
+impl Timer {
+    pub fn new(channels: usize) -> Self {
+        assert!((1..=MAX_CHANNELS).contains(&channels), "Timer must have 1 to {MAX_CHANNELS} channels, got {channels}");
+        Self {
+            channels: RefCell::new((0..channels).map(|_| Channel::default()).collect()),
+            context: RefCell::new(None),
+            irq: None,
+        }
+    }
+
+    /// Drive `irq` while any channel with its interrupt enabled expired
+    pub fn with_interrupt(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.borrow().len()
+    }
+
+    /// Bytes of bus address space the timer needs
+    pub fn size(&self) -> u32 {
+        self.channels() as u32 * CHANNEL_SIZE
+    }
+
+    /// Current count of `channel`
+    pub fn count(&self, channel: usize) -> u32 {
+        self.channels.borrow()[channel].count(self.now())
+    }
+
+    fn now(&self) -> Cycle {
+        self.context.borrow().as_ref().map_or(0, |context| context.scheduler.now())
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.channels.borrow().iter().any(Channel::pending));
+        }
+    }
+
+    /// Replace the expiry event of `index` after its count or control changed
+    fn schedule(&self, index: usize) {
+        let context = self.context.borrow().clone();
+        let mut channels = self.channels.borrow_mut();
+        let channel = &mut channels[index];
+        if let (Some(event), Some(context)) = (channel.expiry.take(), &context) {
+            context.scheduler.cancel(event);
+        }
+        if !channel.enabled() {
+            return;
+        }
+
+        let cycle = channel.start + channel.count as Cycle * channel.tick();
+        match context {
+            Some(context) => {
+                let handle = context.handle;
+                channel.expiry = Some(context.scheduler.schedule_at(cycle, move |bus: &Bus| {
+                    if let Some(timer) = bus.device::<Timer>(handle) {
+                        timer.expire(index);
+                    }
+                }));
+            }
+            // Not on a bus, time doesn't pass and only a zero count expires
+            None if cycle == channel.start => {
+                drop(channels);
+                self.expire(index);
+            }
+            None => {}
+        }
+    }
+
+    fn expire(&self, index: usize) {
+        let now = self.now();
+        let mut channels = self.channels.borrow_mut();
+        let channel = &mut channels[index];
+        channel.expiry = None;
+        channel.expired = true;
+        channel.restart(now, 0);
+
+        // A periodic channel with nothing to count would expire forever
+        let reload = channel.control & CONTROL_PERIODIC != 0 && channel.load > 0;
+        match reload {
+            true => channel.restart(now, channel.load),
+            false => channel.control &= !CONTROL_ENABLE,
+        }
+        drop(channels);
+
+        if reload {
+            self.schedule(index);
+        }
+        self.update_interrupt();
+    }
+
+    fn read_register(&self, index: usize, offset: u32) -> u32 {
+        let channels = self.channels.borrow();
+        let channel = &channels[index];
+        match offset {
+            LOAD => channel.load,
+            COUNT => channel.count(self.now()),
+            CONTROL => channel.control,
+            STATUS => channel.expired as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&self, index: usize, offset: u32, value: u32) {
+        let now = self.now();
+        let mut channels = self.channels.borrow_mut();
+        let channel = &mut channels[index];
+        match offset {
+            LOAD => {
+                channel.load = value;
+                channel.restart(now, value);
+            }
+            COUNT => channel.restart(now, value),
+            CONTROL => {
+                // Keep the count reached so far, then tick at the new rate
+                let count = channel.count(now);
+                channel.control = value & CONTROL_MASK;
+                channel.restart(now, count);
+            }
+            STATUS if value & STATUS_EXPIRED != 0 => channel.expired = false,
+            _ => return,
+        }
+        drop(channels);
+
+        if offset != STATUS {
+            self.schedule(index);
+        }
+        self.update_interrupt();
+    }
+}
+impl DeviceTrait for Timer {
+    fn name(&self) -> String {
+        format!("timer {} channels", self.channels())
+    }
+    fn attach(&self, context: DeviceContext) {
+        *self.context.borrow_mut() = Some(context);
+    }
+    fn reset(&self) {
+        let context = self.context.borrow().clone();
+        for channel in self.channels.borrow_mut().iter_mut() {
+            if let (Some(event), Some(context)) = (channel.expiry.take(), &context) {
+                context.scheduler.cancel(event);
+            }
+            *channel = Channel::default();
+        }
+        self.update_interrupt();
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("timer");
+        match &self.irq {
+            Some(irq) => properties.with("interrupts", Property::Cells(vec![irq.line()])),
+            None => properties,
+        }
+    }
+    fn supports(&self, width: AccessWidth) -> bool {
+        width == AccessWidth::Word
+    }
+    fn read(&self, address: Word, _: AccessWidth) -> Word {
+        let index = (address.0 / CHANNEL_SIZE) as usize;
+        match index < self.channels() {
+            true => Word(self.read_register(index, address.0 % CHANNEL_SIZE)),
+            false => Word(0),
+        }
+    }
+    fn write(&self, address: Word, word: Word, _: AccessWidth) {
+        let index = (address.0 / CHANNEL_SIZE) as usize;
+        if index < self.channels() {
+            self.write_register(index, address.0 % CHANNEL_SIZE, word.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::devices::Device;
+
+    const BASE: Word = Word(0x4000_0000);
+    const LINE: u32 = 4;
+
+    fn machine(channels: usize) -> Bus {
+        let mut bus = Bus::new();
+        let timer = Timer::new(channels).with_interrupt(bus.interrupt_line(LINE));
+        let size = Word(timer.size());
+        bus.connect(Device::new(MemoryRange::new(BASE, size), Box::new(timer))).unwrap();
+        bus
+    }
+
+    fn read(bus: &Bus, channel: u32, register: u32) -> u32 {
+        bus.read(BASE + Word(channel * CHANNEL_SIZE + register), AccessWidth::Word).unwrap().0
+    }
+
+    fn write(bus: &Bus, channel: u32, register: u32, value: u32) {
+        bus.write(BASE + Word(channel * CHANNEL_SIZE + register), Word(value), AccessWidth::Word).unwrap();
+    }
+
+    #[test]
+    fn one_shot_expires_once_and_raises_the_line() {
+        let bus = machine(1);
+        write(&bus, 0, LOAD, 10);
+        write(&bus, 0, CONTROL, CONTROL_ENABLE | CONTROL_INTERRUPT);
+
+        bus.advance(9);
+        assert_eq!((read(&bus, 0, COUNT), read(&bus, 0, STATUS)), (1, 0));
+        assert!(!bus.interrupts().is_pending(LINE));
+
+        bus.advance(1);
+        assert_eq!((read(&bus, 0, COUNT), read(&bus, 0, STATUS)), (0, STATUS_EXPIRED));
+        assert_eq!(read(&bus, 0, CONTROL), CONTROL_INTERRUPT);
+        assert!(bus.interrupts().is_pending(LINE));
+
+        write(&bus, 0, STATUS, STATUS_EXPIRED);
+        assert!(!bus.interrupts().is_pending(LINE));
+        assert!(!bus.skip_to_next_event());
+        assert_eq!(read(&bus, 0, STATUS), 0);
+    }
+
+    #[test]
+    fn periodic_channel_reloads_at_the_prescaled_rate() {
+        let bus = machine(2);
+        // Tick every 4 cycles, without interrupt
+        write(&bus, 1, LOAD, 5);
+        write(&bus, 1, CONTROL, 3 << CONTROL_PRESCALER_SHIFT | CONTROL_PERIODIC | CONTROL_ENABLE);
+
+        bus.advance(19);
+        assert_eq!((read(&bus, 1, COUNT), read(&bus, 1, STATUS)), (1, 0));
+        bus.advance(1);
+        assert_eq!((read(&bus, 1, COUNT), read(&bus, 1, STATUS)), (5, STATUS_EXPIRED));
+        assert!(!bus.interrupts().is_pending(LINE));
+
+        write(&bus, 1, STATUS, STATUS_EXPIRED);
+        bus.advance(20);
+        assert_eq!(read(&bus, 1, STATUS), STATUS_EXPIRED);
+        assert_ne!(read(&bus, 1, CONTROL) & CONTROL_ENABLE, 0);
+        // Other channel never started
+        assert_eq!(read(&bus, 0, STATUS), 0);
+    }
+
+    #[test]
+    fn control_writes_keep_the_count_reached() {
+        let bus = machine(1);
+        write(&bus, 0, LOAD, 100);
+        write(&bus, 0, CONTROL, CONTROL_ENABLE);
+
+        bus.advance(30);
+        assert_eq!(read(&bus, 0, COUNT), 70);
+
+        // Half the rate from here on
+        write(&bus, 0, CONTROL, 1 << CONTROL_PRESCALER_SHIFT | CONTROL_ENABLE);
+        bus.advance(10);
+        assert_eq!(read(&bus, 0, COUNT), 65);
+
+        // Stopped, then started again from the same count
+        write(&bus, 0, CONTROL, 0);
+        bus.advance(100);
+        assert_eq!(read(&bus, 0, COUNT), 65);
+        write(&bus, 0, CONTROL, CONTROL_ENABLE);
+        bus.advance(64);
+        assert_eq!(read(&bus, 0, STATUS), 0);
+        bus.advance(1);
+        assert_eq!(read(&bus, 0, STATUS), STATUS_EXPIRED);
+    }
+
+    #[test]
+    fn periodic_channel_without_load_expires_once() {
+        let bus = machine(1);
+        write(&bus, 0, CONTROL, CONTROL_PERIODIC | CONTROL_INTERRUPT | CONTROL_ENABLE);
+
+        bus.advance(1);
+        assert_eq!(read(&bus, 0, STATUS), STATUS_EXPIRED);
+        assert_eq!(read(&bus, 0, CONTROL) & CONTROL_ENABLE, 0);
+        assert!(bus.interrupts().is_pending(LINE));
+        assert!(!bus.skip_to_next_event());
+    }
+}
@@ -0,0 +1,465 @@
+//! 16550A compatible UART
+//!
+//! Registers are byte wide and packed, the device only needs 8 bytes of the
+//! bus. The UART is clocked by the cycle counter: a character takes
+//! `divisor * 16` cycles per bit. Input from the backend is polled by a
+//! periodic event, so the guest receives it even while waiting in `wfi`.
+use crate::Word;
+use crate::bus::AccessWidth;
+use crate::interrupts::IrqLine;
+use crate::scheduler::{Cycle, DeviceContext};
+use super::{DeviceTrait, Properties, Property};
+use super::serial::SerialBackend;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+/// Frequency reported in the device tree, the same as the default timebase
+pub const CLOCK_FREQUENCY: u32 = 10_000_000;
+
+const FIFO_SIZE: usize = 16;
+
+// Register offsets, some are different registers for reads and writes
+const RBR_THR_DLL: u32 = 0;
+const IER_DLM: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_RLS: u8 = 1 << 2;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_RLS: u8 = 0x06;
+const IIR_TIMEOUT: u8 = 0x0c;
+const IIR_FIFO: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_OE: u8 = 1 << 1;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+/// CTS, DSR and DCD asserted, the other end is always ready
+const MSR_READY: u8 = 0xb0;
+
+/// Register state, changed by the guest and by scheduled events
+struct State {
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    /// Character in the transmit shift register
+    shifting: Option<u8>,
+
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+
+    overrun: bool,
+    /// Transmitter became empty and the guest hasn't seen it in IIR yet
+    thre_pending: bool,
+    /// Characters are waiting below the trigger level and none came in a while
+    timeout: bool,
+}
+impl State {
+    fn new() -> Self {
+        Self {
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            shifting: None,
+            ier: 0,
+            fcr: 0,
+            lcr: 0x03,
+            mcr: 0,
+            scr: 0,
+            divisor: 1,
+            overrun: false,
+            thre_pending: false,
+            timeout: false,
+        }
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_ENABLE != 0
+    }
+
+    /// Characters the FIFOs, or the holding registers without them, can take
+    fn capacity(&self) -> usize {
+        match self.fifo_enabled() {
+            true => FIFO_SIZE,
+            false => 1,
+        }
+    }
+
+    /// Received characters needed to raise the data available interrupt
+    fn trigger_level(&self) -> usize {
+        match self.fifo_enabled() {
+            true => [1, 4, 8, 14][(self.fcr >> 6) as usize],
+            false => 1,
+        }
+    }
+
+    /// Start, data, parity and stop bits of a character
+    fn frame_bits(&self) -> Cycle {
+        let data = 5 + (self.lcr & 0x03) as Cycle;
+        let parity = ((self.lcr >> 3) & 1) as Cycle;
+        let stop = 1 + ((self.lcr >> 2) & 1) as Cycle;
+        1 + data + parity + stop
+    }
+
+    /// Cycles to send or receive one character
+    fn character_time(&self) -> Cycle {
+        self.divisor.max(1) as Cycle * 16 * self.frame_bits()
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match self.rx.len() < self.capacity() {
+            true => self.rx.push_back(byte),
+            false => self.overrun = true,
+        }
+    }
+
+    fn lsr(&self) -> u8 {
+        let mut lsr = 0;
+        if !self.rx.is_empty() {
+            lsr |= LSR_DR;
+        }
+        if self.overrun {
+            lsr |= LSR_OE;
+        }
+        if self.tx.is_empty() {
+            lsr |= LSR_THRE;
+            if self.shifting.is_none() {
+                lsr |= LSR_TEMT;
+            }
+        }
+        if self.fifo_enabled() && self.overrun {
+            // Error in the receiver FIFO
+            lsr |= 1 << 7;
+        }
+        lsr
+    }
+
+    /// Pending interrupt with the highest priority
+    fn interrupt(&self) -> u8 {
+        if self.ier & IER_RLS != 0 && self.overrun {
+            IIR_RLS
+        } else if self.ier & IER_RDA != 0 && self.rx.len() >= self.trigger_level() {
+            IIR_RDA
+        } else if self.ier & IER_RDA != 0 && self.timeout && !self.rx.is_empty() {
+            IIR_TIMEOUT
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            IIR_THRE
+        } else {
+            IIR_NONE
+        }
+    }
+}
+
+/// 16550A UART sending and receiving through a [`SerialBackend`]
+pub struct Uart16550 {
+    state: RefCell<State>,
+    backend: RefCell<Box<dyn SerialBackend>>,
+    context: RefCell<Option<DeviceContext>>,
+    irq: Option<IrqLine>,
+    poll_interval: Cycle,
+}
+impl Uart16550 {
+    pub fn new<B: SerialBackend + 'static>(backend: B) -> Self {
+        Self {
+            state: RefCell::new(State::new()),
+            backend: RefCell::new(Box::new(backend)),
+            context: RefCell::new(None),
+            irq: None,
+            poll_interval: 10_000,
+        }
+    }
+
+    /// Drive `irq` while an enabled interrupt is pending
+    pub fn with_interrupt(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    /// Cycles between checks for input from the backend
+    pub fn with_poll_interval(mut self, cycles: Cycle) -> Self {
+        assert!(cycles > 0, "Poll interval of UART must be at least one cycle");
+        self.poll_interval = cycles;
+        self
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.state.borrow().interrupt() != IIR_NONE);
+        }
+    }
+
+    /// Move the next character into the shift register if it is idle
+    fn start_transmit(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.shifting.is_some() {
+            return;
+        }
+        let Some(byte) = state.tx.pop_front() else {
+            return;
+        };
+        state.shifting = Some(byte);
+        if state.tx.is_empty() {
+            state.thre_pending = true;
+        }
+        let delay = state.character_time();
+        drop(state);
+
+        match self.context.borrow().clone() {
+            Some(context) => {
+                let handle = context.handle;
+                context.scheduler.schedule(delay, move |bus| {
+                    if let Some(uart) = bus.device::<Uart16550>(handle) {
+                        uart.finish_transmit();
+                    }
+                });
+            }
+            // Not on a bus, nothing keeps time
+            None => self.finish_transmit(),
+        }
+    }
+
+    /// Shift register is done sending its character
+    fn finish_transmit(&self) {
+        let mut state = self.state.borrow_mut();
+        if let Some(byte) = state.shifting.take() {
+            match state.mcr & MCR_LOOPBACK != 0 {
+                true => state.receive(byte),
+                false => self.backend.borrow_mut().write(byte),
+            }
+        }
+        drop(state);
+
+        self.start_transmit();
+        self.update_interrupt();
+    }
+
+    /// Take waiting input from the backend and schedule the next poll
+    fn poll(&self) {
+        let mut state = self.state.borrow_mut();
+        let mut received = false;
+        if state.mcr & MCR_LOOPBACK == 0 {
+            let mut backend = self.backend.borrow_mut();
+            while state.rx.len() < state.capacity() {
+                match backend.read() {
+                    Some(byte) => state.receive(byte),
+                    None => break,
+                }
+                received = true;
+            }
+        }
+        // Quiet line with characters below the trigger level
+        state.timeout = state.fifo_enabled() && !received && !state.rx.is_empty();
+        drop(state);
+
+        self.update_interrupt();
+        self.schedule_poll();
+    }
+
+    fn schedule_poll(&self) {
+        if let Some(context) = self.context.borrow().as_ref() {
+            let handle = context.handle;
+            context.scheduler.schedule(self.poll_interval, move |bus| {
+                if let Some(uart) = bus.device::<Uart16550>(handle) {
+                    uart.poll();
+                }
+            });
+        }
+    }
+
+    fn read_register(&self, offset: u32) -> u8 {
+        let mut state = self.state.borrow_mut();
+        let dlab = state.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => state.divisor as u8,
+            RBR_THR_DLL => {
+                state.timeout = false;
+                state.rx.pop_front().unwrap_or(0)
+            }
+            IER_DLM if dlab => (state.divisor >> 8) as u8,
+            IER_DLM => state.ier,
+            IIR_FCR => {
+                let interrupt = state.interrupt();
+                // Reading the THRE interrupt acknowledges it
+                if interrupt == IIR_THRE {
+                    state.thre_pending = false;
+                }
+                match state.fifo_enabled() {
+                    true => interrupt | IIR_FIFO,
+                    false => interrupt,
+                }
+            }
+            LCR => state.lcr,
+            MCR => state.mcr,
+            LSR => {
+                let lsr = state.lsr();
+                state.overrun = false;
+                lsr
+            }
+            MSR => match state.mcr & MCR_LOOPBACK != 0 {
+                // Modem outputs are looped back to the inputs
+                true => (state.mcr & 0x0f) << 4,
+                false => MSR_READY,
+            },
+            SCR => state.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&self, offset: u32, value: u8) {
+        let mut state = self.state.borrow_mut();
+        let dlab = state.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => state.divisor = (state.divisor & 0xff00) | value as u16,
+            RBR_THR_DLL => {
+                if state.tx.len() < state.capacity() {
+                    state.tx.push_back(value);
+                }
+                state.thre_pending = false;
+            }
+            IER_DLM if dlab => state.divisor = (state.divisor & 0x00ff) | (value as u16) << 8,
+            IER_DLM => {
+                let enabled = value & !state.ier;
+                state.ier = value & 0x0f;
+                // Enabling the interrupt while the transmitter is empty raises it
+                if enabled & IER_THRE != 0 && state.tx.is_empty() {
+                    state.thre_pending = true;
+                }
+            }
+            IIR_FCR => {
+                // Switching the FIFOs on or off clears them
+                if (value ^ state.fcr) & FCR_ENABLE != 0 || value & FCR_CLEAR_RX != 0 {
+                    state.rx.clear();
+                    state.timeout = false;
+                }
+                if (value ^ state.fcr) & FCR_ENABLE != 0 || value & FCR_CLEAR_TX != 0 {
+                    state.tx.clear();
+                }
+                state.fcr = value & 0xc1;
+            }
+            LCR => state.lcr = value,
+            MCR => state.mcr = value & 0x1f,
+            SCR => state.scr = value,
+            _ => (),
+        }
+        drop(state);
+
+        self.start_transmit();
+        self.update_interrupt();
+    }
+}
+impl DeviceTrait for Uart16550 {
+    fn name(&self) -> String {
+        "UART 16550A".into()
+    }
+    fn attach(&self, context: DeviceContext) {
+        *self.context.borrow_mut() = Some(context);
+        self.schedule_poll();
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("serial")
+            .with_compatible("ns16550a")
+            .with("clock-frequency", Property::Cells(vec![CLOCK_FREQUENCY]))
+            .with("reg-io-width", Property::Cells(vec![1]));
+
+        match &self.irq {
+            Some(irq) => properties.with("interrupts", Property::Cells(vec![irq.line()])),
+            None => properties,
+        }
+    }
+    fn supports(&self, width: AccessWidth) -> bool {
+        width == AccessWidth::Byte
+    }
+    fn read(&self, address: Word, _: AccessWidth) -> Word {
+        let value = self.read_register(address.0 & 0x7);
+        self.update_interrupt();
+        Word(value as u32)
+    }
+    fn write(&self, address: Word, word: Word, _: AccessWidth) {
+        self.write_register(address.0 & 0x7, word.0 as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::bus::Bus;
+    use crate::devices::{Device, serial::Buffer};
+
+    const BASE: Word = Word(0x1000_0000);
+
+    /// UART on a bus, polling its buffer every 100 cycles
+    fn machine() -> (Bus, Buffer) {
+        let mut bus = Bus::new();
+        let buffer = Buffer::new();
+        let uart = Uart16550::new(buffer.clone())
+            .with_interrupt(bus.interrupt_line(3))
+            .with_poll_interval(100);
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(8)), Box::new(uart))).unwrap();
+        (bus, buffer)
+    }
+
+    fn write(bus: &Bus, register: u32, value: u8) {
+        bus.write(BASE + Word(register), Word(value as u32), AccessWidth::Byte).unwrap();
+    }
+
+    fn read(bus: &Bus, register: u32) -> u8 {
+        bus.read(BASE + Word(register), AccessWidth::Byte).unwrap().0 as u8
+    }
+
+    #[test]
+    fn transmitted_characters_reach_the_buffer_in_time() {
+        let (bus, buffer) = machine();
+        write(&bus, IIR_FCR, FCR_ENABLE);
+        write(&bus, RBR_THR_DLL, b'h');
+        write(&bus, RBR_THR_DLL, b'i');
+        assert_eq!(read(&bus, LSR) & LSR_TEMT, 0);
+
+        // 8N1 frame is 10 bits of 16 cycles each
+        bus.advance(160);
+        assert_eq!(buffer.output(), b"h");
+        bus.advance(160);
+        assert_eq!(buffer.take_output(), b"hi");
+        assert_eq!(read(&bus, LSR) & (LSR_THRE | LSR_TEMT), LSR_THRE | LSR_TEMT);
+    }
+
+    #[test]
+    fn buffered_input_raises_data_available() {
+        let (bus, buffer) = machine();
+        write(&bus, IER_DLM, IER_RDA);
+        buffer.push_input(b"ok");
+
+        assert_eq!(read(&bus, LSR) & LSR_DR, 0);
+        bus.advance(100);
+        assert!(bus.interrupts().is_pending(3));
+        assert_eq!(read(&bus, IIR_FCR), IIR_RDA);
+
+        // Without FIFOs only one character is held, the other waits in the buffer
+        assert_eq!(read(&bus, RBR_THR_DLL), b'o');
+        assert!(!bus.interrupts().is_pending(3));
+        bus.advance(100);
+        assert_eq!(read(&bus, RBR_THR_DLL), b'k');
+        assert_eq!(read(&bus, LSR) & LSR_DR, 0);
+    }
+}
@@ -0,0 +1,160 @@
+//! Virtio block device
+//!
+//! Requests are served synchronously from a [`DiskImage`] when the queue is
+//! processed, the transport latency decides when they complete.
+use crate::bus::Bus;
+use crate::devices::disk::{DiskImage, SECTOR_SIZE};
+use super::{Chain, Queue, QueueError, VirtioDevice, CHUNK_SIZE};
+
+const DEVICE_ID: u32 = 2;
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_GET_ID: u32 = 8;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+/// Type, reserved field and sector of a request
+const HEADER_SIZE: usize = 16;
+/// Length of the id returned by `GET_ID`
+const ID_SIZE: usize = 20;
+
+/// Block device serving requests from a disk image
+pub struct Block {
+    image: DiskImage,
+    id: String,
+}
+impl Block {
+    pub fn new(image: DiskImage) -> Self {
+        Self { image, id: String::new() }
+    }
+
+    /// Serial number reported to the driver, at most 20 bytes are used
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = id.into();
+        self
+    }
+
+    pub fn image(&self) -> &DiskImage {
+        &self.image
+    }
+
+    /// Image, to commit or discard its overlay
+    pub fn image_mut(&mut self) -> &mut DiskImage {
+        &mut self.image
+    }
+
+    /// Serve the request in `chain`, return bytes written to the driver
+    /// without the status byte and the status
+    fn request(&mut self, chain: &Chain, bus: &Bus) -> Result<(usize, u8), QueueError> {
+        let header = chain.read_at(bus, 0, HEADER_SIZE)?;
+        if header.len() < HEADER_SIZE {
+            return Ok((0, S_IOERR));
+        }
+        let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        // Data follows the header, the last writable byte is the status
+        let length = chain.readable_len() - HEADER_SIZE;
+        let space = chain.writable_len().saturating_sub(1);
+
+        let status = match kind {
+            T_IN => match self.read_sectors(chain, bus, sector, space)? {
+                S_OK => return Ok((space, S_OK)),
+                status => status,
+            },
+            T_OUT => self.write_sectors(chain, bus, sector, length)?,
+            T_FLUSH => match self.image.flush() {
+                Ok(()) => S_OK,
+                Err(_) => S_IOERR,
+            },
+            T_GET_ID => {
+                let mut id = [0; ID_SIZE];
+                let length = self.id.len().min(ID_SIZE);
+                id[..length].copy_from_slice(&self.id.as_bytes()[..length]);
+
+                let length = space.min(ID_SIZE);
+                chain.write_at(bus, 0, &id[..length])?;
+                return Ok((length, S_OK));
+            }
+            _ => S_UNSUPP,
+        };
+        Ok((0, status))
+    }
+
+    /// Copy `length` bytes of the image at `sector` to the writable buffers,
+    /// a chunk at a time
+    fn read_sectors(&mut self, chain: &Chain, bus: &Bus, sector: u64, length: usize) -> Result<u8, QueueError> {
+        if self.image.check(sector, length).is_err() {
+            return Ok(S_IOERR);
+        }
+
+        let mut buffer = vec![0; length.min(CHUNK_SIZE)];
+        for offset in (0..length).step_by(CHUNK_SIZE) {
+            let buffer = &mut buffer[..(length - offset).min(CHUNK_SIZE)];
+            if self.image.read(sector + (offset / SECTOR_SIZE) as u64, buffer).is_err() {
+                return Ok(S_IOERR);
+            }
+            chain.write_at(bus, offset, buffer)?;
+        }
+        Ok(S_OK)
+    }
+
+    /// Copy `length` bytes following the header to the image at `sector`,
+    /// a chunk at a time
+    fn write_sectors(&mut self, chain: &Chain, bus: &Bus, sector: u64, length: usize) -> Result<u8, QueueError> {
+        if self.image.check(sector, length).is_err() {
+            return Ok(S_IOERR);
+        }
+
+        for offset in (0..length).step_by(CHUNK_SIZE) {
+            let data = chain.read_at(bus, HEADER_SIZE + offset, CHUNK_SIZE)?;
+            if self.image.write(sector + (offset / SECTOR_SIZE) as u64, &data).is_err() {
+                return Ok(S_IOERR);
+            }
+        }
+        Ok(S_OK)
+    }
+}
+impl VirtioDevice for Block {
+    fn name(&self) -> String {
+        format!("block {} KiB", self.image.sectors() * SECTOR_SIZE as u64 / 1024)
+    }
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+    fn features(&self) -> u64 {
+        match self.image.is_read_only() {
+            true => F_FLUSH | F_RO,
+            false => F_FLUSH,
+        }
+    }
+    fn queue_count(&self) -> usize {
+        1
+    }
+    fn config(&self) -> Vec<u8> {
+        // Capacity in 512 byte sectors, whatever the block size
+        self.image.sectors().to_le_bytes().to_vec()
+    }
+    fn process(&mut self, index: usize, queues: &mut [Queue], bus: &Bus) -> Result<(), QueueError> {
+        let queue = &mut queues[index];
+        while let Some(chain) = queue.pop(bus)? {
+            let written = match chain.writable_len() {
+                // No room for the status, the request can't be answered
+                0 => 0,
+                length => {
+                    let (written, status) = self.request(&chain, bus)?;
+                    chain.write_at(bus, length - 1, &[status])?;
+                    written + 1
+                }
+            };
+            queue.push(bus, &chain, written as u32)?;
+        }
+        Ok(())
+    }
+}
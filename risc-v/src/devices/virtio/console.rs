@@ -0,0 +1,272 @@
+//! Virtio console device
+//!
+//! Every port is connected to a host stream through a [`SerialBackend`].
+//! Port 0 is the console. With more than one port the multiport feature is
+//! offered, and ports are announced to the driver through control messages.
+use crate::bus::Bus;
+use crate::devices::serial::SerialBackend;
+use crate::scheduler::Cycle;
+use super::{Queue, QueueError, VirtioDevice, CHUNK_SIZE};
+
+use std::collections::VecDeque;
+
+const DEVICE_ID: u32 = 3;
+
+const F_SIZE: u64 = 1 << 0;
+const F_MULTIPORT: u64 = 1 << 1;
+const F_EMERG_WRITE: u64 = 1 << 2;
+
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+// Control message events
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+/// Port id, event and value
+const CONTROL_SIZE: usize = 8;
+
+/// Offset of `emerg_wr` in the configuration space
+const EMERGENCY_WRITE: usize = 8;
+
+/// Input buffered from a backend while the driver has no buffers for it
+const INPUT_LIMIT: usize = 4096;
+
+struct Port {
+    name: Option<String>,
+    backend: Box<dyn SerialBackend>,
+    input: VecDeque<u8>,
+    /// Guest has the port open
+    open: bool,
+}
+
+fn control(port: usize, event: u16, value: u16) -> Vec<u8> {
+    let mut message = (port as u32).to_le_bytes().to_vec();
+    message.extend(event.to_le_bytes());
+    message.extend(value.to_le_bytes());
+    message
+}
+
+/// Console with one or more ports
+pub struct Console {
+    ports: Vec<Port>,
+    size: Option<(u16, u16)>,
+    /// Control messages waiting for receive buffers of the driver
+    control: VecDeque<Vec<u8>>,
+    poll_interval: Cycle,
+}
+impl Console {
+    /// Console with port 0 connected to `backend`
+    pub fn new<B: SerialBackend + 'static>(backend: B) -> Self {
+        Self {
+            ports: vec![],
+            size: None,
+            control: VecDeque::new(),
+            poll_interval: 10_000,
+        }.with_port(None, backend)
+    }
+
+    /// Add port connected to `backend`, named ports show up
+    /// as `/dev/virtio-ports/<name>` in Linux guests
+    pub fn with_port<B: SerialBackend + 'static>(mut self, name: Option<&str>, backend: B) -> Self {
+        self.ports.push(Port {
+            name: name.map(String::from),
+            backend: Box::new(backend),
+            input: VecDeque::new(),
+            open: false,
+        });
+        self
+    }
+
+    /// Size of the console in columns and rows
+    pub fn with_size(mut self, columns: u16, rows: u16) -> Self {
+        self.size = Some((columns, rows));
+        self
+    }
+
+    /// Cycles between checks for input from the backends
+    pub fn with_poll_interval(mut self, cycles: Cycle) -> Self {
+        assert!(cycles > 0, "Poll interval of console must be at least one cycle");
+        self.poll_interval = cycles;
+        self
+    }
+
+    /// Return `true` if the guest opened `port`
+    pub fn is_open(&self, port: usize) -> bool {
+        self.ports.get(port).is_some_and(|port| port.open)
+    }
+
+    fn multiport(&self) -> bool {
+        self.ports.len() > 1
+    }
+
+    /// Receive queue of `port`, its transmit queue follows it
+    fn receive_queue(port: usize) -> usize {
+        match port {
+            0 => 0,
+            port => 2 + 2 * port,
+        }
+    }
+
+    /// Port of a data queue and whether it is the transmit queue
+    fn port_of(index: usize) -> Option<(usize, bool)> {
+        match index {
+            0 | 1 => Some((0, index == 1)),
+            CONTROL_RX | CONTROL_TX => None,
+            index => Some(((index - 2) / 2, index % 2 == 1)),
+        }
+    }
+
+    fn transmit(&mut self, port: usize, queue: &mut Queue, bus: &Bus) -> Result<(), QueueError> {
+        while let Some(chain) = queue.pop(bus)? {
+            let backend = &mut self.ports[port].backend;
+            for offset in (0..chain.readable_len()).step_by(CHUNK_SIZE) {
+                for byte in chain.read_at(bus, offset, CHUNK_SIZE)? {
+                    backend.write(byte);
+                }
+            }
+            queue.push(bus, &chain, 0)?;
+        }
+        Ok(())
+    }
+
+    fn handle_control(&mut self, queue: &mut Queue, bus: &Bus) -> Result<(), QueueError> {
+        while let Some(chain) = queue.pop(bus)? {
+            let message = chain.read_at(bus, 0, CONTROL_SIZE)?;
+            queue.push(bus, &chain, 0)?;
+            if message.len() < CONTROL_SIZE {
+                continue;
+            }
+
+            let port = u32::from_le_bytes(message[0..4].try_into().unwrap()) as usize;
+            let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+            let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+            match event {
+                DEVICE_READY if value == 1 => {
+                    for port in 0..self.ports.len() {
+                        self.control.push_back(control(port, DEVICE_ADD, 0));
+                    }
+                }
+                PORT_READY if value == 1 && port < self.ports.len() => {
+                    if port == 0 {
+                        self.control.push_back(control(port, CONSOLE_PORT, 1));
+                    }
+                    if let Some(name) = &self.ports[port].name {
+                        let mut message = control(port, PORT_NAME, 1);
+                        message.extend(name.as_bytes());
+                        self.control.push_back(message);
+                    }
+                    // Host side of every port is always open
+                    self.control.push_back(control(port, PORT_OPEN, 1));
+                }
+                PORT_OPEN if port < self.ports.len() => self.ports[port].open = value != 0,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill receive buffers with pending control messages and input
+    fn deliver(&mut self, queues: &mut [Queue], bus: &Bus) -> Result<(), QueueError> {
+        if self.multiport() {
+            while let Some(message) = self.control.front() {
+                let Some(chain) = queues[CONTROL_RX].pop(bus)? else {
+                    break;
+                };
+                chain.write_at(bus, 0, message)?;
+                queues[CONTROL_RX].push(bus, &chain, message.len() as u32)?;
+                self.control.pop_front();
+            }
+        }
+
+        for (index, port) in self.ports.iter_mut().enumerate() {
+            while port.input.len() < INPUT_LIMIT {
+                match port.backend.read() {
+                    Some(byte) => port.input.push_back(byte),
+                    None => break,
+                }
+            }
+
+            let queue = &mut queues[Self::receive_queue(index)];
+            while !port.input.is_empty() {
+                let Some(chain) = queue.pop(bus)? else {
+                    break;
+                };
+                let length = chain.writable_len().min(port.input.len());
+                let data: Vec<u8> = port.input.drain(..length).collect();
+                chain.write_at(bus, 0, &data)?;
+                queue.push(bus, &chain, length as u32)?;
+            }
+        }
+        Ok(())
+    }
+}
+impl VirtioDevice for Console {
+    fn name(&self) -> String {
+        match self.ports.len() {
+            1 => "console".into(),
+            ports => format!("console {ports} ports"),
+        }
+    }
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+    fn features(&self) -> u64 {
+        let mut features = F_EMERG_WRITE;
+        if self.size.is_some() {
+            features |= F_SIZE;
+        }
+        if self.multiport() {
+            features |= F_MULTIPORT;
+        }
+        features
+    }
+    fn queue_count(&self) -> usize {
+        match self.multiport() {
+            true => 2 + 2 * self.ports.len(),
+            false => 2,
+        }
+    }
+    fn config(&self) -> Vec<u8> {
+        let (columns, rows) = self.size.unwrap_or_default();
+        let mut config = columns.to_le_bytes().to_vec();
+        config.extend(rows.to_le_bytes());
+        config.extend((self.ports.len() as u32).to_le_bytes());
+        config.extend(0u32.to_le_bytes());
+        config
+    }
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        // Output that works before the queues are set up
+        if offset == EMERGENCY_WRITE {
+            if let Some(byte) = data.first() {
+                self.ports[0].backend.write(*byte);
+            }
+        }
+    }
+    fn process(&mut self, index: usize, queues: &mut [Queue], bus: &Bus) -> Result<(), QueueError> {
+        match Self::port_of(index) {
+            Some((port, true)) => self.transmit(port, &mut queues[index], bus)?,
+            // New receive buffers are filled below
+            Some((_, false)) => (),
+            None if index == CONTROL_TX => self.handle_control(&mut queues[index], bus)?,
+            None => (),
+        }
+        self.deliver(queues, bus)
+    }
+    fn poll_interval(&self) -> Option<Cycle> {
+        Some(self.poll_interval)
+    }
+    fn poll(&mut self, queues: &mut [Queue], bus: &Bus) -> Result<(), QueueError> {
+        self.deliver(queues, bus)
+    }
+    fn reset(&mut self) {
+        self.control.clear();
+        for port in self.ports.iter_mut() {
+            port.open = false;
+        }
+    }
+}
@@ -0,0 +1,545 @@
+//! Virtio devices on the memory mapped transport
+//!
+//! [`VirtioMmio`] implements the version 2 register layout of virtio-mmio
+//! and hands queue notifications to a [`VirtioDevice`]. Queues are processed
+//! in a scheduled event, after the write to `QueueNotify` completes, because
+//! only events get access to the bus and through it to guest memory.
+pub mod queue;
+pub mod blk;
+pub mod console;
+pub mod rng;
+
+pub use queue::{Queue, QueueError, Chain, Buffer};
+pub use blk::Block;
+pub use console::Console;
+pub use rng::Rng;
+
+use crate::Word;
+use crate::bus::{AccessWidth, Bus};
+use crate::interrupts::IrqLine;
+use crate::scheduler::{Cycle, DeviceContext};
+use super::{AsAny, DeviceTrait, Properties, Property};
+
+use std::cell::{Cell, Ref, RefCell, RefMut};
+
+const MAGIC: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+/// "RISC" as vendor id, read little endian
+const VENDOR_ID: u32 = 0x4353_4952;
+
+const MAGIC_VALUE: u32 = 0x000;
+const VERSION_REG: u32 = 0x004;
+const DEVICE_ID: u32 = 0x008;
+const VENDOR_ID_REG: u32 = 0x00c;
+const DEVICE_FEATURES: u32 = 0x010;
+const DEVICE_FEATURES_SEL: u32 = 0x014;
+const DRIVER_FEATURES: u32 = 0x020;
+const DRIVER_FEATURES_SEL: u32 = 0x024;
+const QUEUE_SEL: u32 = 0x030;
+const QUEUE_NUM_MAX: u32 = 0x034;
+const QUEUE_NUM: u32 = 0x038;
+const QUEUE_READY: u32 = 0x044;
+const QUEUE_NOTIFY: u32 = 0x050;
+const INTERRUPT_STATUS: u32 = 0x060;
+const INTERRUPT_ACK: u32 = 0x064;
+const STATUS: u32 = 0x070;
+const QUEUE_DESC_LOW: u32 = 0x080;
+const QUEUE_DESC_HIGH: u32 = 0x084;
+const QUEUE_DRIVER_LOW: u32 = 0x090;
+const QUEUE_DRIVER_HIGH: u32 = 0x094;
+const QUEUE_DEVICE_LOW: u32 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u32 = 0x0a4;
+const CONFIG_GENERATION: u32 = 0x0fc;
+const CONFIG: u32 = 0x100;
+
+/// Device follows the virtio 1.0 specification rather than legacy
+pub const F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_NEEDS_RESET: u32 = 64;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// Largest piece of guest memory a device copies at once, buffers can be
+/// as long as the driver likes
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Device specific part of a virtio device
+pub trait VirtioDevice: AsAny {
+    fn name(&self) -> String;
+    /// Virtio device type, such as 2 for block devices
+    fn device_id(&self) -> u32;
+    /// Feature bits the device offers, the transport adds [`F_VERSION_1`]
+    fn features(&self) -> u64 {
+        0
+    }
+    /// Number of virtqueues
+    fn queue_count(&self) -> usize;
+    /// Largest number of entries a queue can have
+    fn queue_size(&self) -> u16 {
+        128
+    }
+    /// Device configuration space, little endian
+    fn config(&self) -> Vec<u8> {
+        vec![]
+    }
+    /// Driver wrote `data` at `offset` of the configuration space
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        let _ = (offset, data);
+    }
+    /// Handle chains the driver made available in queue `index`
+    fn process(&mut self, index: usize, queues: &mut [Queue], bus: &Bus) -> Result<(), QueueError>;
+    /// Cycles between calls to [`poll`](VirtioDevice::poll),
+    /// `None` for devices that only act when notified
+    fn poll_interval(&self) -> Option<Cycle> {
+        None
+    }
+    /// Fill queues with data that arrived from the host
+    fn poll(&mut self, queues: &mut [Queue], bus: &Bus) -> Result<(), QueueError> {
+        let _ = (queues, bus);
+        Ok(())
+    }
+    /// Driver reset the device
+    fn reset(&mut self) {}
+}
+
+/// Transport registers set by the driver
+struct Transport {
+    status: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+}
+impl Transport {
+    fn new(queues: usize) -> Self {
+        Self {
+            status: 0,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues: vec![Queue::default(); queues],
+            interrupt_status: 0,
+        }
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+}
+
+/// Set low or high half of a 64-bit register
+fn set_half(register: &mut u64, high: bool, value: u32) {
+    *register = match high {
+        true => (*register & 0xffff_ffff) | (value as u64) << 32,
+        false => (*register & !0xffff_ffff) | value as u64,
+    };
+}
+
+/// Virtio-mmio transport of a [`VirtioDevice`]
+pub struct VirtioMmio {
+    device: RefCell<Box<dyn VirtioDevice>>,
+    transport: RefCell<Transport>,
+    context: RefCell<Option<DeviceContext>>,
+    irq: Option<IrqLine>,
+    latency: Cycle,
+    /// Device is working on its queues, its own transfers mustn't reach
+    /// the transport registers
+    working: Cell<bool>,
+    /// A transfer of the device reached the transport registers
+    looped_back: Cell<bool>,
+}
+impl VirtioMmio {
+    pub fn new<D: VirtioDevice + 'static>(device: D) -> Self {
+        let queues = device.queue_count();
+        Self {
+            device: RefCell::new(Box::new(device)),
+            transport: RefCell::new(Transport::new(queues)),
+            context: RefCell::new(None),
+            irq: None,
+            latency: 0,
+            working: Cell::new(false),
+            looped_back: Cell::new(false),
+        }
+    }
+
+    /// Drive `irq` while the interrupt status is not acknowledged
+    pub fn with_interrupt(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    /// Cycles between a queue notification and the device handling it
+    pub fn with_latency(mut self, cycles: Cycle) -> Self {
+        self.latency = cycles;
+        self
+    }
+
+    /// Device behind the transport, `None` if it isn't a `T`
+    pub fn inner<T: VirtioDevice + 'static>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.device.borrow(), |device| device.as_ref().as_any().downcast_ref::<T>()).ok()
+    }
+
+    /// Mutable access to the device behind the transport
+    pub fn inner_mut<T: VirtioDevice + 'static>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.device.borrow_mut(), |device| device.as_mut().as_any_mut().downcast_mut::<T>()).ok()
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.transport.borrow().interrupt_status != 0);
+        }
+    }
+
+    /// Let the device work on its queues, then interrupt the driver if it
+    /// returned buffers to a queue that wants interrupts. Run from events.
+    fn run<F>(&self, bus: &Bus, work: F)
+    where
+        F: FnOnce(&mut dyn VirtioDevice, &mut [Queue]) -> Result<(), QueueError>,
+    {
+        let mut queues = {
+            let mut transport = self.transport.borrow_mut();
+            if transport.status & STATUS_DRIVER_OK == 0 || transport.status & STATUS_NEEDS_RESET != 0 {
+                return;
+            }
+            std::mem::take(&mut transport.queues)
+        };
+
+        // Buffers can point at the transport itself, the registers
+        // aren't borrowed while the device goes through them
+        self.working.set(true);
+        let result = work(self.device.borrow_mut().as_mut(), &mut queues)
+            .and_then(|_| {
+                let mut interrupt = false;
+                for queue in queues.iter_mut() {
+                    interrupt |= queue.take_used() && queue.wants_interrupt(bus)?;
+                }
+                Ok(interrupt)
+            });
+        self.working.set(false);
+
+        let mut transport = self.transport.borrow_mut();
+        transport.queues = queues;
+        let result = match self.looped_back.take() {
+            true => Err(()),
+            false => result.map_err(|_| ()),
+        };
+        match result {
+            Ok(true) => transport.interrupt_status |= INTERRUPT_USED_BUFFER,
+            Ok(false) => (),
+            // Driver has to reset the device to recover
+            Err(_) => {
+                transport.status |= STATUS_NEEDS_RESET;
+                transport.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+            }
+        }
+        drop(transport);
+
+        self.update_interrupt();
+    }
+
+    fn notify(&self, index: usize) {
+        if let Some(context) = self.context.borrow().as_ref() {
+            let handle = context.handle;
+            context.scheduler.schedule(self.latency, move |bus| {
+                if let Some(virtio) = bus.device::<VirtioMmio>(handle) {
+                    virtio.run(bus, |device, queues| match index < queues.len() {
+                        true => device.process(index, queues, bus),
+                        false => Ok(()),
+                    });
+                }
+            });
+        }
+    }
+
+    fn schedule_poll(&self, interval: Cycle) {
+        if let Some(context) = self.context.borrow().as_ref() {
+            let handle = context.handle;
+            context.scheduler.schedule(interval, move |bus| {
+                if let Some(virtio) = bus.device::<VirtioMmio>(handle) {
+                    virtio.run(bus, |device, queues| device.poll(queues, bus));
+                    virtio.schedule_poll(interval);
+                }
+            });
+        }
+    }
+
+    fn reset(&self) {
+        let queues = self.device.borrow().queue_count();
+        *self.transport.borrow_mut() = Transport::new(queues);
+        self.device.borrow_mut().reset();
+        self.update_interrupt();
+    }
+
+    fn read_register(&self, offset: u32) -> u32 {
+        let mut transport = self.transport.borrow_mut();
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION_REG => VERSION,
+            DEVICE_ID => self.device.borrow().device_id(),
+            VENDOR_ID_REG => VENDOR_ID,
+            DEVICE_FEATURES => {
+                let features = self.device.borrow().features() | F_VERSION_1;
+                match transport.device_features_sel {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0,
+                }
+            }
+            QUEUE_NUM_MAX => match transport.queue() {
+                Some(_) => self.device.borrow().queue_size() as u32,
+                None => 0,
+            },
+            QUEUE_READY => transport.queue().is_some_and(|queue| queue.ready) as u32,
+            INTERRUPT_STATUS => transport.interrupt_status,
+            STATUS => transport.status,
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&self, offset: u32, value: u32) {
+        let mut transport = self.transport.borrow_mut();
+        let max = self.device.borrow().queue_size();
+        match offset {
+            DEVICE_FEATURES_SEL => transport.device_features_sel = value,
+            DRIVER_FEATURES => {
+                let high = match transport.driver_features_sel {
+                    0 => false,
+                    1 => true,
+                    _ => return,
+                };
+                set_half(&mut transport.driver_features, high, value);
+            }
+            DRIVER_FEATURES_SEL => transport.driver_features_sel = value,
+            QUEUE_SEL => transport.queue_sel = value,
+            QUEUE_NUM => if let Some(queue) = transport.queue() {
+                queue.size = value.min(max as u32) as u16;
+            },
+            QUEUE_READY => if let Some(queue) = transport.queue() {
+                queue.ready = value & 1 != 0;
+            },
+            QUEUE_NOTIFY => {
+                drop(transport);
+                self.notify(value as usize);
+            }
+            INTERRUPT_ACK => {
+                transport.interrupt_status &= !value;
+                drop(transport);
+                self.update_interrupt();
+            }
+            STATUS => match value {
+                0 => {
+                    drop(transport);
+                    self.reset();
+                }
+                _ => transport.status = value,
+            },
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH => if let Some(queue) = transport.queue() {
+                set_half(&mut queue.desc, offset == QUEUE_DESC_HIGH, value);
+            },
+            QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => if let Some(queue) = transport.queue() {
+                set_half(&mut queue.driver, offset == QUEUE_DRIVER_HIGH, value);
+            },
+            QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => if let Some(queue) = transport.queue() {
+                set_half(&mut queue.device, offset == QUEUE_DEVICE_HIGH, value);
+            },
+            _ => (),
+        }
+    }
+}
+impl DeviceTrait for VirtioMmio {
+    fn name(&self) -> String {
+        format!("virtio-mmio {}", self.device.borrow().name())
+    }
+    fn attach(&self, context: DeviceContext) {
+        *self.context.borrow_mut() = Some(context);
+        if let Some(interval) = self.device.borrow().poll_interval() {
+            self.schedule_poll(interval);
+        }
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("virtio_mmio")
+            .with_compatible("virtio,mmio")
+            .with("dma-coherent", Property::Empty);
+
+        match &self.irq {
+            Some(irq) => properties.with("interrupts", Property::Cells(vec![irq.line()])),
+            None => properties,
+        }
+    }
+    fn read(&self, address: Word, width: AccessWidth) -> Word {
+        if self.working.get() {
+            self.looped_back.set(true);
+            return Word(0);
+        }
+        if address.0 >= CONFIG {
+            let config = self.device.borrow().config();
+            let offset = (address.0 - CONFIG) as usize;
+            let mut bytes = [0; 4];
+            for (i, byte) in bytes.iter_mut().take(width.bytes() as usize).enumerate() {
+                *byte = config.get(offset + i).copied().unwrap_or(0);
+            }
+            return Word::from_le_bytes(bytes);
+        }
+
+        // Registers are only defined for 32-bit accesses
+        match width {
+            AccessWidth::Word => Word(self.read_register(address.0)),
+            _ => Word(0),
+        }
+    }
+    fn write(&self, address: Word, word: Word, width: AccessWidth) {
+        if self.working.get() {
+            self.looped_back.set(true);
+            return;
+        }
+        if address.0 >= CONFIG {
+            let bytes = word.0.to_le_bytes();
+            let offset = (address.0 - CONFIG) as usize;
+            self.device.borrow_mut().write_config(offset, &bytes[..width.bytes() as usize]);
+            return;
+        }
+
+        if width == AccessWidth::Word {
+            self.write_register(address.0, word.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::devices::{Device, ram::Ram, disk::{DiskImage, SECTOR_SIZE}};
+
+    pub(super) const BASE: Word = Word(0x1000_1000);
+    const RAM: Word = Word(0x8000_0000);
+    // Queue structures and buffers in RAM
+    const DESC: Word = Word(0x8000_0000);
+    const DRIVER: Word = Word(0x8000_0100);
+    const DEVICE: Word = Word(0x8000_0200);
+    pub(super) const DATA: Word = Word(0x8000_1000);
+
+    const DESC_F_NEXT: u32 = 1;
+    const DESC_F_WRITE: u32 = 2;
+
+    /// Bus with RAM and `device` behind a transport, set up by the driver
+    /// with one queue of 8 entries
+    pub(super) fn machine<D: VirtioDevice + 'static>(device: D) -> Bus {
+        let mut bus = Bus::new();
+        bus.connect(Device::new(MemoryRange::new(RAM, Word(0x10000)), Box::new(Ram::new(0x10000)))).unwrap();
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(0x200)), Box::new(VirtioMmio::new(device)))).unwrap();
+
+        let registers = [
+            (STATUS, 1 | 2 | 8),
+            (QUEUE_SEL, 0),
+            (QUEUE_NUM, 8),
+            (QUEUE_DESC_LOW, DESC.0),
+            (QUEUE_DRIVER_LOW, DRIVER.0),
+            (QUEUE_DEVICE_LOW, DEVICE.0),
+            (QUEUE_READY, 1),
+            (STATUS, 1 | 2 | 8 | STATUS_DRIVER_OK),
+        ];
+        for (register, value) in registers {
+            write_register(&bus, register, value);
+        }
+        bus
+    }
+
+    pub(super) fn write_register(bus: &Bus, register: u32, value: u32) {
+        bus.write(BASE + Word(register), Word(value), AccessWidth::Word).unwrap();
+    }
+
+    pub(super) fn read_register(bus: &Bus, register: u32) -> u32 {
+        bus.read(BASE + Word(register), AccessWidth::Word).unwrap().0
+    }
+
+    /// Place chain of `(address, length, writable)` buffers in the descriptor
+    /// table, make it available and notify the device
+    pub(super) fn submit(bus: &Bus, buffers: &[(Word, u32, bool)]) {
+        for (i, (address, length, writable)) in buffers.iter().enumerate() {
+            let mut flags = if *writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            let desc = DESC + Word(16 * i as u32);
+            bus.load(desc, &(address.0 as u64).to_le_bytes()).unwrap();
+            bus.load(desc + Word(8), &length.to_le_bytes()).unwrap();
+            bus.load(desc + Word(12), &(flags as u16).to_le_bytes()).unwrap();
+            bus.load(desc + Word(14), &(i as u16 + 1).to_le_bytes()).unwrap();
+        }
+
+        let avail = bus.read(DRIVER + Word(2), AccessWidth::Halfword).unwrap().0 as u16;
+        bus.load(DRIVER + Word(4 + 2 * (avail % 8) as u32), &0u16.to_le_bytes()).unwrap();
+        bus.load(DRIVER + Word(2), &avail.wrapping_add(1).to_le_bytes()).unwrap();
+
+        write_register(bus, QUEUE_NOTIFY, 0);
+        bus.advance(1);
+    }
+
+    /// Bytes written to the last chain returned in the used ring
+    pub(super) fn used_length(bus: &Bus) -> u32 {
+        let used = bus.read(DEVICE + Word(2), AccessWidth::Halfword).unwrap().0;
+        let slot = (used + 7) % 8;
+        bus.read(DEVICE + Word(4 + 8 * slot + 4), AccessWidth::Word).unwrap().0
+    }
+
+    /// `T_IN` request header for `sector` at `DATA`
+    fn read_request(bus: &Bus, sector: u64) {
+        bus.load(DATA, &[0u32.to_le_bytes(), 0u32.to_le_bytes()].concat()).unwrap();
+        bus.load(DATA + Word(8), &sector.to_le_bytes()).unwrap();
+    }
+
+    fn disk() -> DiskImage {
+        let data: Vec<u8> = (0..2 * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8 + 1).collect();
+        DiskImage::from_bytes(data)
+    }
+
+    #[test]
+    fn block_reads_sectors() {
+        let bus = machine(Block::new(disk()));
+        read_request(&bus, 1);
+
+        let (buffer, status) = (DATA + Word(0x100), DATA + Word(0x400));
+        submit(&bus, &[(DATA, 16, false), (buffer, SECTOR_SIZE as u32, true), (status, 1, true)]);
+
+        assert_eq!(bus.read_byte(status), 0);
+        assert_eq!(bus.read_bytes(buffer, SECTOR_SIZE).unwrap(), vec![2; SECTOR_SIZE]);
+        assert_eq!(used_length(&bus), SECTOR_SIZE as u32 + 1);
+        assert_eq!(read_register(&bus, INTERRUPT_STATUS), INTERRUPT_USED_BUFFER);
+    }
+
+    #[test]
+    fn block_read_past_the_image_fails_without_copying() {
+        let bus = machine(Block::new(disk()));
+        read_request(&bus, 0);
+
+        // Buffer claims nearly all of the address space
+        let status = DATA + Word(0x400);
+        submit(&bus, &[(DATA, 16, false), (Word(0x1000), 0xF000_0000, true), (status, 1, true)]);
+
+        assert_eq!(bus.read_byte(status), 1);
+        assert_eq!(used_length(&bus), 1);
+    }
+
+    #[test]
+    fn buffer_in_transport_registers_needs_reset() {
+        let bus = machine(Block::new(disk()));
+        read_request(&bus, 0);
+
+        submit(&bus, &[(DATA, 16, false), (BASE, SECTOR_SIZE as u32, true), (DATA + Word(0x400), 1, true)]);
+
+        assert_eq!(read_register(&bus, STATUS) & STATUS_NEEDS_RESET, STATUS_NEEDS_RESET);
+        assert_eq!(read_register(&bus, INTERRUPT_STATUS), INTERRUPT_CONFIG_CHANGE);
+        assert_eq!(read_register(&bus, MAGIC_VALUE), MAGIC);
+
+        // Driver recovers by resetting the device
+        write_register(&bus, STATUS, 0);
+        assert_eq!(read_register(&bus, STATUS), 0);
+    }
+}
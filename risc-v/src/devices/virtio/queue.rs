@@ -0,0 +1,240 @@
+//! Split virtqueues in guest memory
+//!
+//! The driver places descriptor chains in the descriptor table and their
+//! heads in the available ring, the device returns them through the used
+//! ring. Everything is accessed through the bus, like a bus master would.
+use crate::Word;
+use crate::bus::{AccessWidth, Bus, BusError};
+
+use std::fmt::{Display, Formatter};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const DESC_F_INDIRECT: u16 = 4;
+
+const DESC_SIZE: u64 = 16;
+
+/// Driver doesn't want an interrupt when buffers are used
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// Reason a queue couldn't be processed, the device then needs a reset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueError {
+    Bus(BusError),
+    /// Address doesn't fit the 32-bit bus
+    Address(u64),
+    /// Descriptor chain loops, is too long or points past the table
+    InvalidChain { head: u16 },
+}
+impl Display for QueueError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::Bus(err) => write!(f, "{err}"),
+            QueueError::Address(address) => write!(f, "address 0x{address:x} is beyond the bus"),
+            QueueError::InvalidChain { head } => write!(f, "invalid descriptor chain starting at {head}"),
+        }
+    }
+}
+impl std::error::Error for QueueError {}
+impl From<BusError> for QueueError {
+    fn from(err: BusError) -> Self {
+        QueueError::Bus(err)
+    }
+}
+
+fn bus_address(address: u64) -> Result<Word, QueueError> {
+    u32::try_from(address).map(Word).map_err(|_| QueueError::Address(address))
+}
+
+fn read(bus: &Bus, address: u64, width: AccessWidth) -> Result<u32, QueueError> {
+    Ok(bus.read(bus_address(address)?, width)?.0)
+}
+
+fn read_u64(bus: &Bus, address: u64) -> Result<u64, QueueError> {
+    let low = read(bus, address, AccessWidth::Word)? as u64;
+    let high = read(bus, address + 4, AccessWidth::Word)? as u64;
+    Ok(high << 32 | low)
+}
+
+/// Guest memory described by one descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub address: Word,
+    pub length: u32,
+}
+
+/// Descriptor chain taken from the available ring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain {
+    head: u16,
+    /// Buffers the device reads, they come first in a chain
+    pub readable: Vec<Buffer>,
+    /// Buffers the device writes
+    pub writable: Vec<Buffer>,
+}
+impl Chain {
+    /// Total size of readable buffers
+    pub fn readable_len(&self) -> usize {
+        self.readable.iter().map(|buffer| buffer.length as usize).sum()
+    }
+
+    /// Read up to `length` bytes at `offset` of the readable buffers, as if
+    /// they were one. Fewer bytes are returned past the end of the buffers.
+    pub fn read_at(&self, bus: &Bus, offset: usize, length: usize) -> Result<Vec<u8>, QueueError> {
+        let end = offset.saturating_add(length).min(self.readable_len());
+        let mut data = vec![];
+
+        let mut start = 0;
+        for buffer in self.readable.iter() {
+            let buffer_end = start + buffer.length as usize;
+            let from = offset.max(start);
+            let to = end.min(buffer_end);
+            if from < to {
+                let address = buffer.address + Word((from - start) as u32);
+                data.extend(bus.read_bytes(address, to - from)?);
+            }
+            start = buffer_end;
+        }
+        Ok(data)
+    }
+
+    /// Total size of writable buffers
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|buffer| buffer.length as usize).sum()
+    }
+
+    /// Write `data` at `offset` into the writable buffers, as if they were
+    /// one. Data past the end of the buffers is dropped.
+    pub fn write_at(&self, bus: &Bus, offset: usize, data: &[u8]) -> Result<(), QueueError> {
+        let mut start = 0;
+        for buffer in self.writable.iter() {
+            let end = start + buffer.length as usize;
+            let from = offset.max(start);
+            let to = (offset + data.len()).min(end);
+            if from < to {
+                let address = buffer.address + Word((from - start) as u32);
+                bus.load(address, &data[from - offset..to - offset])?;
+            }
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+/// State of one virtqueue, set up by the driver through the transport
+#[derive(Debug, Clone, Default)]
+pub struct Queue {
+    pub(super) size: u16,
+    pub(super) ready: bool,
+    /// Address of the descriptor table
+    pub(super) desc: u64,
+    /// Address of the available ring
+    pub(super) driver: u64,
+    /// Address of the used ring
+    pub(super) device: u64,
+    /// Next entry of the available ring the device will take
+    last_avail: u16,
+    /// Next entry of the used ring the device will fill
+    next_used: u16,
+    /// Chains were returned since the driver was last interrupted
+    used: bool,
+}
+impl Queue {
+    pub fn is_ready(&self) -> bool {
+        self.ready && self.size > 0
+    }
+
+    /// Take the next chain the driver made available
+    pub fn pop(&mut self, bus: &Bus) -> Result<Option<Chain>, QueueError> {
+        if !self.is_ready() {
+            return Ok(None);
+        }
+
+        let avail = read(bus, self.driver + 2, AccessWidth::Halfword)? as u16;
+        if avail == self.last_avail {
+            return Ok(None);
+        }
+
+        let slot = (self.last_avail % self.size) as u64;
+        let head = read(bus, self.driver + 4 + 2 * slot, AccessWidth::Halfword)? as u16;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        self.chain(bus, head).map(Some)
+    }
+
+    fn chain(&self, bus: &Bus, head: u16) -> Result<Chain, QueueError> {
+        let mut chain = Chain { head, readable: vec![], writable: vec![] };
+        let invalid = QueueError::InvalidChain { head };
+
+        let mut table = self.desc;
+        let mut table_size = self.size as u64;
+        let mut index = head as u64;
+        let mut indirect = false;
+        let mut remaining = table_size;
+
+        loop {
+            if index >= table_size || remaining == 0 {
+                return Err(invalid);
+            }
+            remaining -= 1;
+
+            let desc = table + index * DESC_SIZE;
+            let address = read_u64(bus, desc)?;
+            let length = read(bus, desc + 8, AccessWidth::Word)?;
+            let flags = read(bus, desc + 12, AccessWidth::Halfword)? as u16;
+            let next = read(bus, desc + 14, AccessWidth::Halfword)? as u64;
+
+            if flags & DESC_F_INDIRECT != 0 {
+                // Chain continues in a separate table, which can't nest
+                if indirect || !(length as u64).is_multiple_of(DESC_SIZE) {
+                    return Err(invalid);
+                }
+                indirect = true;
+                table = address;
+                table_size = length as u64 / DESC_SIZE;
+                remaining = table_size;
+                index = 0;
+                continue;
+            }
+
+            bus_address((address + length as u64).saturating_sub(1))?;
+            let buffer = Buffer { address: bus_address(address)?, length };
+            match flags & DESC_F_WRITE != 0 {
+                true => chain.writable.push(buffer),
+                // Readable buffers must not follow writable ones
+                false if !chain.writable.is_empty() => return Err(invalid),
+                false => chain.readable.push(buffer),
+            }
+
+            if flags & DESC_F_NEXT == 0 {
+                return Ok(chain);
+            }
+            index = next;
+        }
+    }
+
+    /// Return `chain` to the driver, `written` bytes of its writable buffers were filled
+    pub fn push(&mut self, bus: &Bus, chain: &Chain, written: u32) -> Result<(), QueueError> {
+        let used = self.next_used;
+        let element = self.device + 4 + 8 * (used % self.size) as u64;
+
+        bus.write(bus_address(element)?, Word(chain.head as u32), AccessWidth::Word)?;
+        bus.write(bus_address(element + 4)?, Word(written), AccessWidth::Word)?;
+        // Index is updated last, the driver may look at the ring any time
+        self.next_used = used.wrapping_add(1);
+        self.used = true;
+        bus.write(bus_address(self.device + 2)?, Word(self.next_used as u32), AccessWidth::Halfword)?;
+        Ok(())
+    }
+
+    /// Return `true` if chains were returned since the last call
+    pub(super) fn take_used(&mut self) -> bool {
+        std::mem::take(&mut self.used)
+    }
+
+    /// Return `true` unless the driver suppressed interrupts for this queue
+    pub fn wants_interrupt(&self, bus: &Bus) -> Result<bool, QueueError> {
+        let flags = read(bus, self.driver, AccessWidth::Halfword)? as u16;
+        Ok(flags & AVAIL_F_NO_INTERRUPT == 0)
+    }
+}
@@ -0,0 +1,142 @@
+//! Virtio entropy device
+//!
+//! Every writable buffer the driver makes available is filled completely
+//! from an [`EntropySource`]. A [`Seeded`] source produces the same bytes on
+//! every run, so machines using it stay reproducible.
+use crate::bus::Bus;
+use super::{Queue, QueueError, VirtioDevice};
+
+use std::fs::File;
+use std::io::{self, Read};
+
+const DEVICE_ID: u32 = 4;
+
+/// Bytes generated at a time, buffers are filled in pieces of this size
+const FILL_SIZE: usize = 4096;
+
+/// Where random bytes come from
+pub trait EntropySource {
+    fn fill(&mut self, buffer: &mut [u8]);
+}
+
+/// Deterministic SplitMix64 generator
+#[derive(Debug, Clone)]
+pub struct Seeded {
+    state: u64,
+}
+impl Seeded {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+impl EntropySource for Seeded {
+    fn fill(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// Randomness of the host, read from `/dev/urandom`
+pub struct Host {
+    urandom: File,
+}
+impl Host {
+    pub fn open() -> io::Result<Self> {
+        Ok(Self { urandom: File::open("/dev/urandom")? })
+    }
+}
+impl EntropySource for Host {
+    fn fill(&mut self, buffer: &mut [u8]) {
+        self.urandom.read_exact(buffer).expect("Failed to read /dev/urandom");
+    }
+}
+
+/// Entropy device handing out bytes of its source
+pub struct Rng {
+    source: Box<dyn EntropySource>,
+}
+impl Rng {
+    pub fn new<S: EntropySource + 'static>(source: S) -> Self {
+        Self { source: Box::new(source) }
+    }
+
+    /// Device producing the same bytes for the same `seed`
+    pub fn seeded(seed: u64) -> Self {
+        Self::new(Seeded::new(seed))
+    }
+}
+impl VirtioDevice for Rng {
+    fn name(&self) -> String {
+        "entropy".into()
+    }
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+    fn queue_count(&self) -> usize {
+        1
+    }
+    fn process(&mut self, index: usize, queues: &mut [Queue], bus: &Bus) -> Result<(), QueueError> {
+        let queue = &mut queues[index];
+        while let Some(chain) = queue.pop(bus)? {
+            let length = chain.writable_len();
+            let mut buffer = [0; FILL_SIZE];
+            for offset in (0..length).step_by(FILL_SIZE) {
+                let buffer = &mut buffer[..(length - offset).min(FILL_SIZE)];
+                self.source.fill(buffer);
+                chain.write_at(bus, offset, buffer)?;
+            }
+            queue.push(bus, &chain, length as u32)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Word;
+    use crate::devices::virtio::{STATUS, STATUS_NEEDS_RESET};
+    use crate::devices::virtio::tests::{machine, read_register, submit, used_length, DATA};
+
+    #[test]
+    fn seeded_device_is_reproducible() {
+        let length = 2 * FILL_SIZE as u32 + 100;
+        let fill = |seed| {
+            let bus = machine(Rng::seeded(seed));
+            submit(&bus, &[(DATA, length, true)]);
+            assert_eq!(used_length(&bus), length);
+            bus.read_bytes(DATA, length as usize).unwrap()
+        };
+
+        let bytes = fill(7);
+        assert_eq!(bytes, fill(7));
+        assert_ne!(bytes, fill(8));
+
+        // Pieces continue the stream of a single fill
+        let mut expected = vec![0; length as usize];
+        Seeded::new(7).fill(&mut expected);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn buffers_are_filled_in_pieces() {
+        let bus = machine(Rng::seeded(1));
+
+        // Only the start of the buffer is memory, filling stops at its end
+        // instead of generating the whole length up front
+        submit(&bus, &[(DATA, 0x1000_0000, true)]);
+        assert_ne!(bus.read_bytes(DATA, 16).unwrap(), vec![0; 16]);
+        assert_ne!(bus.read_le_word(Word(0x8000_fffc)), Word(0));
+        assert_eq!(read_register(&bus, STATUS) & STATUS_NEEDS_RESET, STATUS_NEEDS_RESET);
+    }
+}
@@ -0,0 +1,333 @@
+//! Watchdog timer
+//!
+//! Once enabled, the watchdog counts down the timeout in cycles and the guest
+//! has to kick it before the count runs out. When it does run out the hart
+//! stops with [`Halt::WatchdogReset`] and the host resets the machine with
+//! [`RV32::reset`](crate::RV32::reset). With the interrupt enabled, the first
+//! expiry only raises the interrupt and counts the timeout again, the halt
+//! comes if that one runs out as well. The hart takes the warning as a
+//! machine external interrupt, so its handler can still kick in time.
+//! Registers are 32 bits wide:
+//!
+//! | Offset | Register                                                        |
+//! |--------|-----------------------------------------------------------------|
+//! | `0x00` | timeout in cycles, at least 1                                   |
+//! | `0x04` | writing any value restarts the count, reads 0                   |
+//! | `0x08` | control, bit 0 enables the watchdog, bit 1 the interrupt        |
+//! | `0x0c` | status, bit 0 is set when the interrupt fired, write 1 to clear it |
+//! | `0x10` | cycles left before expiry, read-only                            |
+//!
+//! Writing the timeout or control also restarts the count. Kicking clears the
+//! status too, so the halt only comes when the guest stops kicking. After it
+//! the watchdog is disabled, as after power on and after a reset.
+use crate::{Halt, Word};
+use crate::bus::{AccessWidth, Bus};
+use crate::interrupts::IrqLine;
+use crate::scheduler::{Cycle, DeviceContext, EventId};
+use super::{DeviceTrait, Properties, Property};
+
+use std::cell::RefCell;
+
+const TIMEOUT: u32 = 0x00;
+const KICK: u32 = 0x04;
+const CONTROL: u32 = 0x08;
+const STATUS: u32 = 0x0c;
+const COUNT: u32 = 0x10;
+
+const CONTROL_ENABLE: u32 = 1 << 0;
+const CONTROL_INTERRUPT: u32 = 1 << 1;
+const STATUS_EXPIRED: u32 = 1 << 0;
+
+/// Timeout after power on, in cycles
+const DEFAULT_TIMEOUT: u32 = 1_000_000;
+
+struct State {
+    timeout: u32,
+    control: u32,
+    /// Interrupt fired, the next expiry halts unless the guest kicks
+    expired: bool,
+    /// Interrupt fired and wasn't acknowledged yet
+    status: bool,
+    /// Cycle the count started from the timeout
+    start: Cycle,
+    /// Event for when the count runs out
+    expiry: Option<EventId>,
+}
+
+pub struct Watchdog {
+    state: RefCell<State>,
+    /// Timeout after power on, restored by a reset
+    power_on_timeout: u32,
+    context: RefCell<Option<DeviceContext>>,
+    irq: Option<IrqLine>,
+}
+impl Watchdog {
+    /// Disabled watchdog
+    pub fn new() -> Self {
+        Self {
+            state: RefCell::new(State {
+                timeout: DEFAULT_TIMEOUT,
+                control: 0,
+                expired: false,
+                status: false,
+                start: 0,
+                expiry: None,
+            }),
+            power_on_timeout: DEFAULT_TIMEOUT,
+            context: RefCell::new(None),
+            irq: None,
+        }
+    }
+
+    /// Start with a timeout of `cycles` instead of a million
+    pub fn with_timeout(mut self, cycles: u32) -> Self {
+        self.power_on_timeout = cycles.max(1);
+        self.state.borrow_mut().timeout = self.power_on_timeout;
+        self
+    }
+
+    /// Drive `irq` while the first expiry waits to be acknowledged
+    pub fn with_interrupt(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.borrow().control & CONTROL_ENABLE != 0
+    }
+
+    /// Cycles left before the watchdog expires, the full timeout while disabled
+    pub fn remaining(&self) -> u32 {
+        let state = self.state.borrow();
+        match state.control & CONTROL_ENABLE != 0 {
+            true => {
+                let elapsed = self.now() - state.start;
+                (state.timeout as Cycle).saturating_sub(elapsed) as u32
+            }
+            false => state.timeout,
+        }
+    }
+
+    fn now(&self) -> Cycle {
+        self.context.borrow().as_ref().map_or(0, |context| context.scheduler.now())
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(irq) = &self.irq {
+            let state = self.state.borrow();
+            irq.set(state.status && state.control & CONTROL_INTERRUPT != 0);
+        }
+    }
+
+    /// Count the timeout again from now, or stop counting when disabled
+    fn restart(&self) {
+        let context = self.context.borrow().clone();
+        let mut state = self.state.borrow_mut();
+        if let (Some(event), Some(context)) = (state.expiry.take(), &context) {
+            context.scheduler.cancel(event);
+        }
+        state.start = self.now();
+        // Not on a bus, nothing keeps time and the watchdog never expires
+        let Some(context) = context else {
+            return;
+        };
+        if state.control & CONTROL_ENABLE == 0 {
+            return;
+        }
+
+        let handle = context.handle;
+        state.expiry = Some(context.scheduler.schedule(state.timeout as Cycle, move |bus: &Bus| {
+            if let Some(watchdog) = bus.device::<Watchdog>(handle) {
+                watchdog.expire(bus);
+            }
+        }));
+    }
+
+    fn expire(&self, bus: &Bus) {
+        let mut state = self.state.borrow_mut();
+        state.expiry = None;
+        let warn = state.control & CONTROL_INTERRUPT != 0 && !state.expired;
+        match warn {
+            true => {
+                state.expired = true;
+                state.status = true;
+            }
+            false => {
+                state.control = 0;
+                state.expired = false;
+                state.status = false;
+            }
+        }
+        drop(state);
+
+        if warn {
+            self.restart();
+        } else {
+            bus.halt(Halt::WatchdogReset);
+        }
+        self.update_interrupt();
+    }
+
+    fn write_register(&self, offset: u32, value: u32) {
+        let mut state = self.state.borrow_mut();
+        match offset {
+            TIMEOUT => state.timeout = value.max(1),
+            KICK => {
+                state.expired = false;
+                state.status = false;
+            }
+            CONTROL => state.control = value & (CONTROL_ENABLE | CONTROL_INTERRUPT),
+            STATUS if value & STATUS_EXPIRED != 0 => state.status = false,
+            _ => return,
+        }
+        drop(state);
+
+        // Acknowledging the interrupt doesn't count as a kick
+        if offset != STATUS {
+            self.restart();
+        }
+        self.update_interrupt();
+    }
+}
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceTrait for Watchdog {
+    fn name(&self) -> String {
+        "watchdog".to_string()
+    }
+    fn attach(&self, context: DeviceContext) {
+        *self.context.borrow_mut() = Some(context);
+        self.restart();
+    }
+    fn reset(&self) {
+        {
+            let mut state = self.state.borrow_mut();
+            state.timeout = self.power_on_timeout;
+            state.control = 0;
+            state.expired = false;
+            state.status = false;
+        }
+        // Disabled, this only cancels the expiry
+        self.restart();
+        self.update_interrupt();
+    }
+    fn properties(&self) -> Properties {
+        let properties = Properties::new("watchdog");
+        match &self.irq {
+            Some(irq) => properties.with("interrupts", Property::Cells(vec![irq.line()])),
+            None => properties,
+        }
+    }
+    fn supports(&self, width: AccessWidth) -> bool {
+        width == AccessWidth::Word
+    }
+    fn read(&self, address: Word, _: AccessWidth) -> Word {
+        if address.0 == COUNT {
+            return Word(self.remaining());
+        }
+        let state = self.state.borrow();
+        let value = match address.0 {
+            TIMEOUT => state.timeout,
+            CONTROL => state.control,
+            STATUS => state.status as u32,
+            _ => 0,
+        };
+        Word(value)
+    }
+    fn write(&self, address: Word, word: Word, _: AccessWidth) {
+        self.write_register(address.0, word.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::devices::Device;
+
+    const BASE: Word = Word(0x4000_0000);
+
+    fn write(bus: &Bus, register: u32, value: u32) {
+        bus.write(BASE + Word(register), Word(value), AccessWidth::Word).unwrap();
+    }
+
+    fn read(bus: &Bus, register: u32) -> u32 {
+        bus.read(BASE + Word(register), AccessWidth::Word).unwrap().0
+    }
+
+    #[test]
+    fn expiry_halts_unless_kicked() {
+        let mut bus = Bus::new();
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(0x14)), Box::new(Watchdog::new().with_timeout(100)))).unwrap();
+
+        write(&bus, CONTROL, CONTROL_ENABLE);
+        bus.advance(60);
+        assert_eq!(read(&bus, COUNT), 40);
+        write(&bus, KICK, 0);
+        bus.advance(99);
+        assert_eq!(bus.halted(), None);
+        bus.advance(1);
+        assert_eq!(bus.halted(), Some(Halt::WatchdogReset));
+        assert_eq!(read(&bus, CONTROL), 0);
+        assert!(!bus.skip_to_next_event());
+    }
+
+    #[test]
+    fn interrupt_warns_once_before_the_halt() {
+        let mut bus = Bus::new();
+        let irq = bus.interrupt_line(7);
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(0x14)),
+            Box::new(Watchdog::new().with_timeout(100).with_interrupt(irq)))).unwrap();
+
+        write(&bus, CONTROL, CONTROL_ENABLE | CONTROL_INTERRUPT);
+        bus.advance(100);
+        assert_eq!(bus.halted(), None);
+        assert_eq!(read(&bus, STATUS), STATUS_EXPIRED);
+        assert!(bus.interrupts().is_pending(7));
+
+        // Acknowledging isn't kicking, the second expiry halts
+        write(&bus, STATUS, STATUS_EXPIRED);
+        assert!(!bus.interrupts().is_pending(7));
+        bus.advance(100);
+        assert_eq!(bus.halted(), Some(Halt::WatchdogReset));
+    }
+
+    #[test]
+    fn kick_after_the_warning_starts_over() {
+        let mut bus = Bus::new();
+        let irq = bus.interrupt_line(7);
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(0x14)),
+            Box::new(Watchdog::new().with_timeout(100).with_interrupt(irq)))).unwrap();
+
+        write(&bus, CONTROL, CONTROL_ENABLE | CONTROL_INTERRUPT);
+        bus.advance(150);
+        write(&bus, KICK, 0);
+        assert!(!bus.interrupts().is_pending(7));
+        bus.advance(100);
+        assert_eq!(bus.halted(), None);
+        assert!(bus.interrupts().is_pending(7));
+    }
+
+    #[test]
+    fn reset_disables_it_and_lowers_the_line() {
+        let mut bus = Bus::new();
+        let irq = bus.interrupt_line(7);
+        bus.connect(Device::new(MemoryRange::new(BASE, Word(0x14)),
+            Box::new(Watchdog::new().with_timeout(100).with_interrupt(irq)))).unwrap();
+
+        write(&bus, TIMEOUT, 50);
+        write(&bus, CONTROL, CONTROL_ENABLE | CONTROL_INTERRUPT);
+        bus.advance(50);
+        assert!(bus.interrupts().is_pending(7));
+
+        bus.reset();
+        assert!(!bus.interrupts().is_pending(7));
+        assert_eq!([read(&bus, TIMEOUT), read(&bus, CONTROL), read(&bus, STATUS)], [100, 0, 0]);
+        assert!(!bus.skip_to_next_event());
+        assert_eq!(bus.halted(), None);
+    }
+}
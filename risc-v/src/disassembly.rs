@@ -1,5 +1,6 @@
 //! Helper structs for disassembling instructions
 use crate::Word;
+use crate::csr;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Operand {
@@ -14,6 +15,8 @@ pub enum Operand {
     RegisterUnsigned(Word),
     /// Offset to a memory relative to program counter
     Offset(Word),
+    /// Control and status register address
+    Csr(Word),
 }
 
 #[derive(Debug, Clone)]
@@ -25,4 +28,68 @@ impl Disassembly {
     pub fn new(mnemonic: &'static str, operands: Vec<Operand>) -> Self {
         Self { mnemonic, operands }
     }
-}
\ No newline at end of file
+}
+
+/// ABI names of general purpose registers, indexed by register number
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp",  "gp",  "tp", "t0", "t1", "t2",
+    "s0",   "s1", "a0",  "a1",  "a2", "a3", "a4", "a5",
+    "a6",   "a7", "s2",  "s3",  "s4", "s5", "s6", "s7",
+    "s8",   "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+fn abi_name(idx: Word) -> &'static str {
+    ABI_NAMES.get(idx.0 as usize).copied().unwrap_or("?")
+}
+
+/// Formats instruction the same way as Spike's disassembler does,
+/// e.g. `addi    sp, sp, -16`, `lw      a0, 8(sp)` or `beq     a0, a1, pc + 8`
+impl std::fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.operands.is_empty() {
+            return write!(f, "{}", self.mnemonic);
+        }
+
+        write!(f, "{:<7} ", self.mnemonic)?;
+
+        let mut operands = self.operands.iter().peekable();
+        let mut first = true;
+        while let Some(operand) = operands.next() {
+            if !first {
+                write!(f, ", ")?;
+            }
+            first = false;
+
+            match *operand {
+                Operand::Register(idx) | Operand::RegisterUnsigned(idx) => {
+                    write!(f, "{}", abi_name(idx))?;
+                }
+                // Upper immediates are shown without the 12 zeroed low bits
+                Operand::Immediate(imm) if matches!(self.mnemonic, "lui" | "auipc") => {
+                    write!(f, "0x{:x}", imm.0 >> 12)?;
+                }
+                Operand::Immediate(imm) => {
+                    write!(f, "{}", imm.signed())?;
+                }
+                // Offset followed by a base register is a memory operand
+                Operand::Offset(off) => match operands.peek() {
+                    Some(Operand::RegisterOffset(base)) => {
+                        write!(f, "{}({})", off.signed(), abi_name(*base))?;
+                        operands.next();
+                    }
+                    _ if off.signed() < 0 => write!(f, "pc - {}", off.signed().unsigned_abs())?,
+                    _ => write!(f, "pc + {}", off.signed())?,
+                },
+                Operand::RegisterOffset(idx) => {
+                    write!(f, "({})", abi_name(idx))?;
+                }
+                Operand::Csr(address) => match csr::name(address) {
+                    Some(name) => write!(f, "{name}")?,
+                    None => write!(f, "0x{:03x}", address.0)?,
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
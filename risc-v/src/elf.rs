@@ -0,0 +1,185 @@
+//! Minimal reader of 32-bit little endian RISC-V ELF executables
+//!
+//! Only the parts needed to load a program are read: the entry point,
+//! loadable segments and the symbol table.
+use crate::Word;
+use crate::bus::{Bus, BusError};
+
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+/// Error while reading an ELF file
+#[derive(Debug)]
+pub enum ElfError {
+    Io(std::io::Error),
+    /// File is not a 32-bit little endian RISC-V ELF
+    Unsupported(&'static str),
+    /// Header or table points outside of the file
+    Truncated,
+    /// Segment couldn't be written to memory
+    Load(BusError),
+}
+impl Display for ElfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ElfError::Io(err) => write!(f, "failed to read ELF file: {err}"),
+            ElfError::Unsupported(what) => write!(f, "unsupported ELF file: {what}"),
+            ElfError::Truncated => write!(f, "truncated ELF file"),
+            ElfError::Load(err) => write!(f, "failed to load ELF file: {err}"),
+        }
+    }
+}
+impl std::error::Error for ElfError {}
+
+/// Loadable segment of an executable
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// Physical address the segment is loaded at
+    pub address: Word,
+    /// Virtual address the program expects the segment at
+    pub virtual_address: Word,
+    /// Size of the segment in memory, the part not covered by `data` is zeroed
+    pub size: Word,
+    /// Segment permissions, `PF_X`, `PF_W` and `PF_R` bits
+    pub flags: u32,
+    pub data: Vec<u8>,
+}
+
+/// Entry of the symbol table
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: Word,
+    pub size: Word,
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub entry: Word,
+    /// Virtual address of the program header table, if a segment maps it
+    pub program_headers: Option<Word>,
+    pub program_header_count: u16,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+}
+impl Elf {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ElfError> {
+        let data = std::fs::read(path).map_err(ElfError::Io)?;
+        Self::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, ElfError> {
+        if data.get(0..4) != Some(b"\x7FELF") {
+            return Err(ElfError::Unsupported("missing ELF magic"));
+        }
+        if data.get(4) != Some(&ELFCLASS32) {
+            return Err(ElfError::Unsupported("not a 32-bit ELF"));
+        }
+        if data.get(5) != Some(&ELFDATA2LSB) {
+            return Err(ElfError::Unsupported("not a little endian ELF"));
+        }
+        if half(data, 18)? != EM_RISCV {
+            return Err(ElfError::Unsupported("not a RISC-V ELF"));
+        }
+
+        let entry = Word(word(data, 24)?);
+        let (phoff, shoff) = (word(data, 28)? as usize, word(data, 32)? as usize);
+        let (phentsize, phnum) = (half(data, 42)? as usize, half(data, 44)? as usize);
+        let (shentsize, shnum) = (half(data, 46)? as usize, half(data, 48)? as usize);
+
+        let mut segments = vec![];
+        let mut program_headers = None;
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if word(data, ph)? != PT_LOAD {
+                continue;
+            }
+
+            let (offset, vaddr, paddr) = (word(data, ph + 4)? as usize, word(data, ph + 8)?, word(data, ph + 12)?);
+            let (filesz, memsz) = (word(data, ph + 16)? as usize, word(data, ph + 20)?);
+            let flags = word(data, ph + 24)?;
+
+            if (offset..offset + filesz).contains(&phoff) {
+                program_headers = Some(Word(vaddr) + Word((phoff - offset) as u32));
+            }
+
+            let bytes = data.get(offset..offset + filesz).ok_or(ElfError::Truncated)?;
+            segments.push(Segment {
+                address: Word(paddr),
+                virtual_address: Word(vaddr),
+                size: Word(memsz),
+                flags,
+                data: bytes.to_vec(),
+            });
+        }
+
+        let mut symbols = vec![];
+        for i in 0..shnum {
+            let sh = shoff + i * shentsize;
+            if word(data, sh + 4)? != SHT_SYMTAB {
+                continue;
+            }
+
+            let (offset, size) = (word(data, sh + 16)? as usize, word(data, sh + 20)? as usize);
+            let (link, entsize) = (word(data, sh + 24)? as usize, word(data, sh + 36)? as usize);
+
+            // Names are stored in the string table linked to the symbol table
+            let strtab = shoff + link * shentsize;
+            let (str_offset, str_size) = (word(data, strtab + 16)? as usize, word(data, strtab + 20)? as usize);
+            let strings = data.get(str_offset..str_offset + str_size).ok_or(ElfError::Truncated)?;
+
+            for sym in (offset..offset + size).step_by(entsize.max(1)) {
+                let name_offset = word(data, sym)? as usize;
+                let name = strings.get(name_offset..)
+                    .and_then(|s| s.split(|b| *b == 0).next())
+                    .ok_or(ElfError::Truncated)?;
+
+                if name.is_empty() {
+                    continue;
+                }
+
+                symbols.push(Symbol {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    value: Word(word(data, sym + 4)?),
+                    size: Word(word(data, sym + 8)?),
+                });
+            }
+        }
+
+        Ok(Self { entry, program_headers, program_header_count: phnum as u16, segments, symbols })
+    }
+
+    /// Find symbol by its name
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    /// Write all loadable segments to the bus
+    pub fn load(&self, bus: &Bus) -> Result<(), ElfError> {
+        for segment in self.segments.iter() {
+            bus.load(segment.address, &segment.data).map_err(ElfError::Load)?;
+
+            let zeroed = segment.size.0.saturating_sub(segment.data.len() as u32);
+            bus.load(segment.address + Word(segment.data.len() as u32), &vec![0; zeroed as usize])
+                .map_err(ElfError::Load)?;
+        }
+        Ok(())
+    }
+}
+
+fn half(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn word(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
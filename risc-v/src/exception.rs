@@ -1,9 +1,42 @@
+use crate::Word;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Exception {
     InvalidInstruction,
     InvalidRegister,
     MisalignedAddress,
     EnvironmentCall,
     EnvironmentBreak,
-}
\ No newline at end of file
+    /// Instruction fetch from given address failed on the bus
+    InstructionAccessFault(Word),
+    /// Load from given address failed on the bus
+    LoadAccessFault(Word),
+    /// Store to given address failed on the bus
+    StoreAccessFault(Word),
+}
+impl Exception {
+    /// Exception code written to `mcause` when the exception traps
+    pub fn cause(&self) -> u32 {
+        match self {
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::InvalidInstruction | Exception::InvalidRegister => 2,
+            Exception::EnvironmentBreak => 3,
+            Exception::MisalignedAddress => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAccessFault(_) => 7,
+            // Call from machine mode, the only mode there is
+            Exception::EnvironmentCall => 11,
+        }
+    }
+
+    /// Value written to `mtval` when the exception traps, the faulting address
+    /// of access faults and zero otherwise
+    pub fn value(&self) -> Word {
+        match self {
+            Exception::InstructionAccessFault(address)
+            | Exception::LoadAccessFault(address)
+            | Exception::StoreAccessFault(address) => *address,
+            _ => Word(0),
+        }
+    }
+}
@@ -0,0 +1,358 @@
+//! Flattened device tree (DTB) generation
+//!
+//! The tree is built from the memory map of the bus: devices with
+//! `device_type = "memory"` become `memory@...` nodes at the root, every
+//! other device a node under `soc`. The bus interrupt lines have no
+//! controller a kernel has a driver for, so `interrupts` properties of
+//! devices are left out. Firmware and kernels expect the blob address in
+//! `a1` and the hart id in `a0`, see [`DeviceTree::install`].
+use crate::{RV32, Word};
+use crate::bus::{Bus, BusError, MapEntry};
+use crate::devices::Property;
+
+use std::collections::HashMap;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// Size of the header, the memory reservation map follows it
+const HEADER_SIZE: u32 = 40;
+
+/// Description of the machine that isn't part of the memory map
+#[derive(Debug, Clone)]
+pub struct DeviceTree {
+    model: String,
+    isa: String,
+    harts: u32,
+    timebase: u32,
+    bootargs: Option<String>,
+}
+impl DeviceTree {
+    pub fn new() -> Self {
+        Self {
+            model: "risc-v".into(),
+            isa: "rv32i".into(),
+            harts: 1,
+            timebase: 10_000_000,
+            bootargs: None,
+        }
+    }
+
+    /// Value of the root `model` property
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// ISA string of every hart, such as `rv32i` or `rv32imac_zicsr`
+    pub fn with_isa(mut self, isa: &str) -> Self {
+        self.isa = isa.to_lowercase();
+        self
+    }
+
+    pub fn with_harts(mut self, harts: u32) -> Self {
+        self.harts = harts;
+        self
+    }
+
+    /// Frequency of the `time` counter in Hz
+    pub fn with_timebase(mut self, timebase: u32) -> Self {
+        self.timebase = timebase;
+        self
+    }
+
+    /// Kernel command line passed in `/chosen`
+    pub fn with_bootargs(mut self, bootargs: &str) -> Self {
+        self.bootargs = Some(bootargs.into());
+        self
+    }
+
+    /// Build the blob describing devices connected to `bus`
+    pub fn build(&self, bus: &Bus) -> Vec<u8> {
+        let map = bus.memory_map();
+        let (memory, peripherals): (Vec<&MapEntry>, Vec<&MapEntry>) = map.iter()
+            .partition(|entry| entry.properties.device_type.as_deref() == Some("memory"));
+
+        let mut fdt = Writer::new();
+        fdt.begin_node("");
+        fdt.cells("#address-cells", &[1]);
+        fdt.cells("#size-cells", &[1]);
+        fdt.string("model", &self.model);
+        fdt.string("compatible", "riscv-virtio");
+
+        fdt.begin_node("chosen");
+        if let Some(bootargs) = &self.bootargs {
+            fdt.string("bootargs", bootargs);
+        }
+        if let Some(serial) = peripherals.iter().find(|e| e.properties.node == "serial") {
+            fdt.string("stdout-path", &format!("/soc/{}", node_name(serial)));
+        }
+        fdt.end_node();
+
+        fdt.begin_node("cpus");
+        fdt.cells("#address-cells", &[1]);
+        fdt.cells("#size-cells", &[0]);
+        fdt.cells("timebase-frequency", &[self.timebase]);
+        for hart in 0..self.harts {
+            self.hart(&mut fdt, hart);
+        }
+        fdt.end_node();
+
+        for entry in memory {
+            fdt.begin_node(&node_name(entry));
+            fdt.string("device_type", "memory");
+            fdt.cells("reg", &[entry.range.base().0, entry.range.size().0]);
+            fdt.end_node();
+        }
+
+        fdt.begin_node("soc");
+        fdt.cells("#address-cells", &[1]);
+        fdt.cells("#size-cells", &[1]);
+        fdt.strings("compatible", &["simple-bus".into()]);
+        fdt.empty("ranges");
+        for entry in peripherals {
+            fdt.begin_node(&node_name(entry));
+            if !entry.properties.compatible.is_empty() {
+                fdt.strings("compatible", &entry.properties.compatible);
+            }
+            if let Some(device_type) = &entry.properties.device_type {
+                fdt.string("device_type", device_type);
+            }
+            fdt.cells("reg", &[entry.range.base().0, entry.range.size().0]);
+            // Lines would need a parent, see the module documentation
+            for (name, value) in entry.properties.extra.iter().filter(|(name, _)| name != "interrupts") {
+                fdt.property(name, value);
+            }
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.finish()
+    }
+
+    /// Build the blob, write it to `address` and pass it to the hart
+    /// the way firmware does: hart id in `a0`, blob address in `a1`
+    pub fn install(&self, cpu: &RV32, address: Word) -> Result<(), BusError> {
+        cpu.bus.load(address, &self.build(&cpu.bus))?;
+        cpu.reg.write("a0", Word(0)).unwrap();
+        cpu.reg.write("a1", address).unwrap();
+        Ok(())
+    }
+
+    fn hart(&self, fdt: &mut Writer, hart: u32) {
+        // Base is `rv32i`, single letter extensions follow it,
+        // multi-letter ones are separated by underscores
+        let mut parts = self.isa.split('_');
+        let letters = parts.next().unwrap_or_default();
+        let base = letters.get(..5).unwrap_or(letters);
+        let extensions: Vec<String> = base.get(4..).unwrap_or_default().chars()
+            .chain(letters.get(5..).unwrap_or_default().chars())
+            .map(String::from)
+            .chain(parts.map(String::from))
+            .collect();
+
+        fdt.begin_node(&format!("cpu@{hart:x}"));
+        fdt.string("device_type", "cpu");
+        fdt.cells("reg", &[hart]);
+        fdt.string("status", "okay");
+        fdt.strings("compatible", &["riscv".into()]);
+        fdt.string("riscv,isa", &self.isa);
+        fdt.string("riscv,isa-base", base);
+        fdt.strings("riscv,isa-extensions", &extensions);
+        fdt.string("mmu-type", "riscv,none");
+
+        fdt.begin_node("interrupt-controller");
+        fdt.cells("#interrupt-cells", &[1]);
+        fdt.empty("interrupt-controller");
+        fdt.string("compatible", "riscv,cpu-intc");
+        fdt.cells("phandle", &[hart + 1]);
+        fdt.end_node();
+
+        fdt.end_node();
+    }
+}
+
+impl Default for DeviceTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `name@address` of the node describing `entry`
+fn node_name(entry: &MapEntry) -> String {
+    format!("{}@{:x}", entry.properties.node, entry.range.base().0)
+}
+
+/// Structure and strings blocks being built
+struct Writer {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    /// Offsets of names already in the strings block
+    offsets: HashMap<String, u32>,
+}
+impl Writer {
+    fn new() -> Self {
+        Self { structure: vec![], strings: vec![], offsets: HashMap::new() }
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend(token.to_be_bytes());
+    }
+
+    /// Append `bytes` to the structure block, padded to 4 bytes
+    fn padded(&mut self, bytes: &[u8]) {
+        self.structure.extend(bytes);
+        self.structure.resize(self.structure.len().next_multiple_of(4), 0);
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.padded(&[name.as_bytes(), &[0]].concat());
+    }
+
+    fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    fn raw(&mut self, name: &str, value: &[u8]) {
+        let offset = match self.offsets.get(name) {
+            Some(offset) => *offset,
+            None => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend(name.as_bytes());
+                self.strings.push(0);
+                self.offsets.insert(name.into(), offset);
+                offset
+            }
+        };
+
+        self.token(FDT_PROP);
+        self.structure.extend((value.len() as u32).to_be_bytes());
+        self.structure.extend(offset.to_be_bytes());
+        self.padded(value);
+    }
+
+    fn property(&mut self, name: &str, value: &Property) {
+        match value {
+            Property::Empty => self.empty(name),
+            Property::Cells(cells) => self.cells(name, cells),
+            Property::String(string) => self.string(name, string),
+            Property::Strings(strings) => self.strings(name, strings),
+        }
+    }
+
+    fn empty(&mut self, name: &str) {
+        self.raw(name, &[]);
+    }
+
+    fn cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.raw(name, &bytes);
+    }
+
+    fn string(&mut self, name: &str, string: &str) {
+        self.raw(name, &[string.as_bytes(), &[0]].concat());
+    }
+
+    fn strings(&mut self, name: &str, strings: &[String]) {
+        let bytes: Vec<u8> = strings.iter().flat_map(|s| s.bytes().chain([0])).collect();
+        self.raw(name, &bytes);
+    }
+
+    /// Terminate the structure block and assemble the blob
+    fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+
+        // Empty memory reservation map is a single zero entry
+        let reservations = HEADER_SIZE;
+        let structure = reservations + 16;
+        let strings = structure + self.structure.len() as u32;
+        let total = strings + self.strings.len() as u32;
+
+        let header = [
+            FDT_MAGIC,
+            total,
+            structure,
+            strings,
+            reservations,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob: Vec<u8> = header.iter().flat_map(|w| w.to_be_bytes()).collect();
+        blob.extend([0; 16]);
+        blob.extend(self.structure);
+        blob.extend(self.strings);
+        blob
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::devices::{Device, ram::Ram, timer::Timer};
+
+    fn be(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// `(node path, property name, value)` of every property in `blob`
+    fn properties(blob: &[u8]) -> Vec<(String, String, Vec<u8>)> {
+        let (structure, strings) = (be(blob, 8) as usize, be(blob, 12) as usize);
+        let mut path: Vec<String> = vec![];
+        let mut properties = vec![];
+        let mut offset = structure;
+
+        loop {
+            let token = be(blob, offset);
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let end = offset + blob[offset..].iter().position(|b| *b == 0).unwrap();
+                    path.push(String::from_utf8_lossy(&blob[offset..end]).into_owned());
+                    offset = (end + 1).next_multiple_of(4);
+                }
+                FDT_END_NODE => { path.pop(); }
+                FDT_PROP => {
+                    let (length, name) = (be(blob, offset) as usize, strings + be(blob, offset + 4) as usize);
+                    let name_end = name + blob[name..].iter().position(|b| *b == 0).unwrap();
+                    let value = blob[offset + 8..offset + 8 + length].to_vec();
+                    properties.push((path.join("/"), String::from_utf8_lossy(&blob[name..name_end]).into_owned(), value));
+                    offset = (offset + 8 + length).next_multiple_of(4);
+                }
+                _ => return properties,
+            }
+        }
+    }
+
+    fn find<'a>(properties: &'a [(String, String, Vec<u8>)], path: &str, name: &str) -> Option<&'a [u8]> {
+        properties.iter().find(|(p, n, _)| p == path && n == name).map(|(_, _, v)| v.as_slice())
+    }
+
+    #[test]
+    fn interrupt_lines_are_left_out() {
+        let mut bus = Bus::new();
+        let timer = Timer::new(1).with_interrupt(bus.interrupt_line(5));
+        bus.connect(Device::new(MemoryRange::new(Word(0x1000_0000), Word(0x100)), Box::new(timer))).unwrap();
+        bus.connect(Device::new(MemoryRange::new(Word(0x8000_0000), Word(0x1000)), Box::new(Ram::new(0x1000)))).unwrap();
+
+        let properties = properties(&DeviceTree::new().build(&bus));
+
+        assert_eq!(find(&properties, "/memory@80000000", "device_type"), Some(&b"memory\0"[..]));
+        assert!(find(&properties, "/soc/timer@10000000", "reg").is_some());
+        assert!(find(&properties, "/soc/timer@10000000", "interrupts").is_none());
+        assert!(find(&properties, "/soc", "interrupt-parent").is_none());
+        assert!(!properties.iter().any(|(path, _, _)| path == "/interrupt-controller"));
+    }
+}
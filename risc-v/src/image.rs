@@ -0,0 +1,176 @@
+//! Rendered images and PNG encoding
+//!
+//! Video devices render into an [`Image`] without needing a window, so
+//! output can be saved or compared in headless runs. The PNG encoder uses
+//! uncompressed deflate blocks, which every decoder reads.
+use std::io;
+use std::path::Path;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Largest deflate block without compression
+const STORED_BLOCK: usize = 65535;
+
+/// RGBA pixels, 8 bits per channel, rows from the top
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+impl Image {
+    /// Black image
+    pub fn new(width: u32, height: u32) -> Self {
+        let mut pixels = vec![0; width as usize * height as usize * 4];
+        for pixel in pixels.chunks_mut(4) {
+            pixel[3] = 0xff;
+        }
+        Self { width, height, pixels }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Pixel data, 4 bytes per pixel in R, G, B, A order
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = (y as usize * self.width as usize + x as usize) * 4;
+        self.pixels[i..i + 4].try_into().unwrap()
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, rgba: [u8; 4]) {
+        let i = (y as usize * self.width as usize + x as usize) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&rgba);
+    }
+
+    /// Encode as PNG
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut header = self.width.to_be_bytes().to_vec();
+        header.extend(self.height.to_be_bytes());
+        // 8 bits per channel, RGBA, deflate, adaptive filtering, no interlace
+        header.extend([8, 6, 0, 0, 0]);
+
+        // Every row starts with its filter type, none
+        let row = self.width as usize * 4;
+        let mut raw = Vec::with_capacity((row + 1) * self.height as usize);
+        for line in self.pixels.chunks(row.max(1)).take(self.height as usize) {
+            raw.push(0);
+            raw.extend(line);
+        }
+
+        let mut png = PNG_SIGNATURE.to_vec();
+        chunk(&mut png, b"IHDR", &header);
+        chunk(&mut png, b"IDAT", &zlib(&raw));
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(path, self.to_png())
+    }
+}
+
+/// Append chunk with its length and CRC
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(kind);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+/// Zlib stream of stored deflate blocks
+fn zlib(data: &[u8]) -> Vec<u8> {
+    // Deflate with 32 KiB window, no dictionary, header checksum makes it a multiple of 31
+    let mut stream = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        stream.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        stream.push(last as u8);
+        stream.extend(length.to_le_bytes());
+        stream.extend((!length).to_le_bytes());
+        stream.extend(block);
+    }
+
+    stream.extend(adler32(data).to_be_bytes());
+    stream
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (mut a, mut b) = (1, 0);
+    // Sums can't overflow within 5552 bytes
+    for block in data.chunks(5552) {
+        for byte in block {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= MODULUS;
+        b %= MODULUS;
+    }
+    b << 16 | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn png_stores_rows_uncompressed() {
+        let mut image = Image::new(2, 1);
+        image.set(1, 0, [0x10, 0x20, 0x30, 0xff]);
+        let png = image.to_png();
+
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        // IHDR: 13 bytes, 2x1, 8-bit RGBA
+        assert_eq!(&png[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+        assert_eq!(png[29..33], crc32(&png[12..29]).to_be_bytes());
+
+        // IDAT: zlib header, one final stored block of the filtered row, Adler-32
+        let raw = [0, 0, 0, 0, 0xff, 0x10, 0x20, 0x30, 0xff];
+        let idat = &png[33..];
+        let length = u32::from_be_bytes(idat[..4].try_into().unwrap()) as usize;
+        assert_eq!(&idat[4..8], b"IDAT");
+        assert_eq!(&idat[8..15], &[0x78, 0x01, 1, 9, 0, !9, 0xff]);
+        assert_eq!(&idat[15..24], &raw);
+        assert_eq!(idat[24..28], adler32(&raw).to_be_bytes());
+        assert_eq!(length, 2 + 5 + raw.len() + 4);
+
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+    }
+}
@@ -2,6 +2,8 @@
 
 use super::Instruction;
 use crate::{Exception, Word, RV32};
+use crate::bus::AccessWidth;
+use crate::semihosting::Semihosting;
 use crate::disassembly::{Disassembly, Operand::{Immediate, Register, Offset, RegisterOffset, RegisterUnsigned}};
 
 /// Add registers
@@ -656,7 +658,7 @@ impl Instruction for Lb {
         let rs1v = cpu.reg.read_gpr(rs1)?;
 
         let address = rs1v + imm;
-        let byte = cpu.bus.read(address);
+        let byte = cpu.load(address, AccessWidth::Byte)?;
 
         // sign extention
         let word = match byte & Word(0x80) {
@@ -695,13 +697,7 @@ impl Instruction for Lh {
         let rs1v = cpu.reg.read_gpr(rs1)?;
 
         let address = rs1v + imm;
-
-        let (byte1, byte2) = (
-            cpu.bus.read(address + Word(0)),
-            cpu.bus.read(address + Word(1))
-        );
-
-        let halfword = (byte1 << Word(8)) | byte2;
+        let halfword = cpu.load(address, AccessWidth::Halfword)?;
 
         // sign extention
         let word = match halfword & Word(0x8000) {
@@ -740,15 +736,8 @@ impl Instruction for Lw {
         let rs1v = cpu.reg.read_gpr(rs1)?;
 
         let address = rs1v + imm;
+        let word = cpu.load(address, AccessWidth::Word)?;
 
-        let (byte1, byte2, byte3, byte4) = (
-            cpu.bus.read(address + Word(0)),
-            cpu.bus.read(address + Word(1)),
-            cpu.bus.read(address + Word(2)),
-            cpu.bus.read(address + Word(3)),
-        );
-
-        let word = (byte1 << Word(24)) | (byte2 << Word(16)) | (byte3 << Word(8)) | byte4;
         cpu.reg.write_gpr(rd, word)?;
 
         Ok(true)
@@ -779,7 +768,7 @@ impl Instruction for Lbu {
         let rs1v = cpu.reg.read_gpr(rs1)?;
 
         let address = rs1v + imm;
-        let word = cpu.bus.read(address);
+        let word = cpu.load(address, AccessWidth::Byte)?;
 
         cpu.reg.write_gpr(rd, word)?;
 
@@ -811,13 +800,7 @@ impl Instruction for Lhu {
         let rs1v = cpu.reg.read_gpr(rs1)?;
 
         let address = rs1v + imm;
-
-        let (byte1, byte2) = (
-            cpu.bus.read(address + Word(0)),
-            cpu.bus.read(address + Word(1))
-        );
-
-        let word = (byte1 << Word(8)) | byte2;
+        let word = cpu.load(address, AccessWidth::Halfword)?;
 
         cpu.reg.write_gpr(rd, word)?;
 
@@ -850,9 +833,7 @@ impl Instruction for Sb {
         let rs2v = cpu.reg.read_gpr(rs2)?;
 
         let address = rs1v + imm;
-        let word = rs2v & Word(0xFF);
-        
-        cpu.bus.write(address, word);
+        cpu.store(address, rs2v, AccessWidth::Byte)?;
 
         Ok(true)
     }
@@ -883,13 +864,7 @@ impl Instruction for Sh {
         let rs2v = cpu.reg.read_gpr(rs2)?;
 
         let address = rs1v + imm;
-        let (byte1, byte2) = (
-            (rs2v & Word(0xFF00)) >> Word(8),
-            (rs2v & Word(0x00FF)),
-        );
-        
-        cpu.bus.write(address + Word(0), byte1);
-        cpu.bus.write(address + Word(1), byte2);
+        cpu.store(address, rs2v, AccessWidth::Halfword)?;
 
         Ok(true)
     }
@@ -920,17 +895,7 @@ impl Instruction for Sw {
         let rs2v = cpu.reg.read_gpr(rs2)?;
 
         let address = rs1v + imm;
-        let (byte1, byte2, byte3, byte4) = (
-            (rs2v & Word(0xFF000000)) >> Word(24),
-            (rs2v & Word(0x00FF0000)) >> Word(16),
-            (rs2v & Word(0x0000FF00)) >> Word(8),
-            (rs2v & Word(0x000000FF)),
-        );
-
-        cpu.bus.write(address + Word(0), byte1);
-        cpu.bus.write(address + Word(1), byte2);
-        cpu.bus.write(address + Word(2), byte3);
-        cpu.bus.write(address + Word(3), byte4);
+        cpu.store(address, rs2v, AccessWidth::Word)?;
 
         Ok(true)
     }
@@ -1289,6 +1254,47 @@ impl Instruction for Auipc {
     }
 }
 
+/// Fence memory and I/O, accesses are never reordered so it does nothing
+pub struct Fence;
+impl Instruction for Fence {
+    fn syntax(&self) -> &'static str { "fence" }
+
+    fn validate(&self, word: Word) -> Result<(), Exception> {
+        match (word.opcode(), word.funct3()) {
+            (Word(0b_0001111), Word(0x0)) => Ok(()),
+            _ => Err(Exception::InvalidInstruction)
+        }
+    }
+    fn disassemble(&self, word: Word) -> Result<Disassembly, Exception> {
+        self.validate(word)?;
+        Ok(Disassembly::new("fence", vec![]))
+    }
+    fn execute(&self, _word: Word, _cpu: &RV32) -> Result<bool, Exception> {
+        Ok(true)
+    }
+}
+
+/// Fence instruction fetches, instructions are always fetched from the bus
+/// so it does nothing
+pub struct FenceI;
+impl Instruction for FenceI {
+    fn syntax(&self) -> &'static str { "fence.i" }
+
+    fn validate(&self, word: Word) -> Result<(), Exception> {
+        match (word.opcode(), word.funct3()) {
+            (Word(0b_0001111), Word(0x1)) => Ok(()),
+            _ => Err(Exception::InvalidInstruction)
+        }
+    }
+    fn disassemble(&self, word: Word) -> Result<Disassembly, Exception> {
+        self.validate(word)?;
+        Ok(Disassembly::new("fence.i", vec![]))
+    }
+    fn execute(&self, _word: Word, _cpu: &RV32) -> Result<bool, Exception> {
+        Ok(true)
+    }
+}
+
 /// Environment call
 pub struct Ecall;
 impl Instruction for Ecall {
@@ -1304,7 +1310,12 @@ impl Instruction for Ecall {
         self.validate(word)?;
         Ok(Disassembly::new("ecall", vec![]))
     }
-    fn execute(&self, _word: Word, _cpu: &RV32) -> Result<bool, Exception> {
+    fn execute(&self, _word: Word, cpu: &RV32) -> Result<bool, Exception> {
+        if let Some(linux) = cpu.linux.as_ref() {
+            linux.syscall(cpu)?;
+            return Ok(true);
+        }
+
         Err(Exception::EnvironmentCall)
     }
 }
@@ -1324,7 +1335,68 @@ impl Instruction for Ebreak {
         self.validate(word)?;
         Ok(Disassembly::new("ebreak", vec![]))
     }
-    fn execute(&self, _word: Word, _cpu: &RV32) -> Result<bool, Exception> {
+    fn execute(&self, _word: Word, cpu: &RV32) -> Result<bool, Exception> {
+        if let Some(semihosting) = cpu.semihosting.as_ref() {
+            let pc = cpu.reg.read("pc")?;
+
+            if Semihosting::is_call(cpu, pc) {
+                semihosting.call(cpu)?;
+                return Ok(true);
+            }
+        }
+
         Err(Exception::EnvironmentBreak)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::devices::{Device, ram::Ram};
+
+    /// Machine with RAM at 0 and `ra` pointing at 0x100
+    fn machine() -> RV32 {
+        let mut cpu = RV32::new();
+        let ram = Ram::new(0x1000);
+        cpu.bus.connect(Device::new(MemoryRange::new(Word(0), Word(0x1000)), Box::new(ram))).unwrap();
+        cpu.reg.write("ra", Word(0x100)).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn stores_are_little_endian() {
+        let cpu = machine();
+        cpu.reg.write("sp", Word(0x11223344)).unwrap();
+
+        // sw sp, 0(ra)
+        Sw.execute(Word(0x0020a023), &cpu).unwrap();
+        assert_eq!(cpu.bus.read_bytes(Word(0x100), 4), Ok(vec![0x44, 0x33, 0x22, 0x11]));
+
+        // sh sp, 4(ra)
+        Sh.execute(Word(0x00209223), &cpu).unwrap();
+        assert_eq!(cpu.bus.read_bytes(Word(0x104), 2), Ok(vec![0x44, 0x33]));
+    }
+
+    #[test]
+    fn loads_are_little_endian() {
+        let cpu = machine();
+        cpu.bus.load(Word(0x100), &[0x44, 0x33, 0x22, 0x11, 0x80, 0xff]).unwrap();
+
+        // lw gp, 0(ra)
+        Lw.execute(Word(0x0000a183), &cpu).unwrap();
+        assert_eq!(cpu.reg.read("gp"), Ok(Word(0x11223344)));
+
+        // lbu gp, 0(ra)
+        Lbu.execute(Word(0x0000c183), &cpu).unwrap();
+        assert_eq!(cpu.reg.read("gp"), Ok(Word(0x44)));
+
+        // lh gp, 2(ra)
+        Lh.execute(Word(0x00209183), &cpu).unwrap();
+        assert_eq!(cpu.reg.read("gp"), Ok(Word(0x1122)));
+
+        // lhu gp, 4(ra)
+        Lhu.execute(Word(0x0040d183), &cpu).unwrap();
+        assert_eq!(cpu.reg.read("gp"), Ok(Word(0xff80)));
+    }
+}
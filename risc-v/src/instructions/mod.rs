@@ -4,7 +4,11 @@ use std::fmt::{Debug, Formatter};
 use crate::disassembly::Disassembly;
 
 pub mod i;
+pub mod privileged;
+pub mod zicsr;
 pub use i::*;
+pub use privileged::*;
+pub use zicsr::*;
 use lazy_static::lazy_static;
 
 /// A instruction that can be executed on a RISC-V machine.
@@ -72,7 +76,9 @@ lazy_static! {
             Slt, Sltu, Addi, Xori, Ori, Andi, Slli,
             Srli, Srai, Slti, Sltiu, Lb, Lh, Lw, Lbu, 
             Lhu, Sb, Sh, Sw, Beq, Bne, Blt, Bge, Bltu, 
-            Bgeu, Jal, Jalr, Lui, Auipc, Ecall, Ebreak
+            Bgeu, Jal, Jalr, Lui, Auipc, Fence, FenceI,
+            Ecall, Ebreak, Wfi, Mret, Csrrw, Csrrs, Csrrc,
+            Csrrwi, Csrrsi, Csrrci
         );
 
         instruction_set
@@ -0,0 +1,51 @@
+//! Privileged Instructions
+
+use super::Instruction;
+use crate::{Exception, Word, RV32};
+use crate::disassembly::Disassembly;
+
+/// Wait For Interrupt
+pub struct Wfi;
+impl Instruction for Wfi {
+    fn syntax(&self) -> &'static str { "wfi" }
+
+    fn validate(&self, word: Word) -> Result<(), Exception> {
+        match word {
+            Word(0x10500073) => Ok(()),
+            _ => Err(Exception::InvalidInstruction)
+        }
+    }
+    fn disassemble(&self, word: Word) -> Result<Disassembly, Exception> {
+        self.validate(word)?;
+        Ok(Disassembly::new("wfi", vec![]))
+    }
+    fn execute(&self, _word: Word, cpu: &RV32) -> Result<bool, Exception> {
+        // Idling starts once the instruction retired, so events run while
+        // waiting aren't part of it
+        cpu.request_wait();
+        Ok(true)
+    }
+}
+
+/// Return from machine mode trap handler
+pub struct Mret;
+impl Instruction for Mret {
+    fn syntax(&self) -> &'static str { "mret" }
+
+    fn validate(&self, word: Word) -> Result<(), Exception> {
+        match word {
+            Word(0x30200073) => Ok(()),
+            _ => Err(Exception::InvalidInstruction)
+        }
+    }
+    fn disassemble(&self, word: Word) -> Result<Disassembly, Exception> {
+        self.validate(word)?;
+        Ok(Disassembly::new("mret", vec![]))
+    }
+    fn execute(&self, _word: Word, cpu: &RV32) -> Result<bool, Exception> {
+        cpu.reg.write("pc", cpu.csr.trap_return())?;
+
+        // We changed program counter, don't increment it
+        Ok(false)
+    }
+}
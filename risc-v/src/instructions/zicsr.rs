@@ -0,0 +1,164 @@
+//! Control and Status Register Instructions
+
+use super::Instruction;
+use crate::{Exception, Word, RV32};
+use crate::disassembly::{Disassembly, Operand::{Immediate, Register, Csr}};
+
+/// Validate SYSTEM instruction with given `funct3`
+fn validate(word: Word, funct3: u32) -> Result<(), Exception> {
+    match (word.opcode(), word.funct3()) {
+        (Word(0b_1110011), Word(f)) if f == funct3 => Ok(()),
+        _ => Err(Exception::InvalidInstruction)
+    }
+}
+
+/// Write old value of the register to `rd` and replace it with `update` of
+/// the old value and `source`. The register is only read when `read` is set
+/// and only written when `write` is set, so accesses have no side effects
+/// the instruction doesn't ask for.
+fn access(cpu: &RV32, word: Word, source: Word, read: bool, write: bool, update: fn(Word, Word) -> Word) -> Result<bool, Exception> {
+    let (csr, rd) = (word.csr(), word.rd());
+
+    let old = match read {
+        true => cpu.csr.read(csr)?,
+        false => Word(0),
+    };
+    if write {
+        cpu.csr.write(csr, update(old, source))?;
+    }
+    cpu.reg.write_gpr(rd, old)?;
+
+    Ok(true)
+}
+
+/// Atomic Read/Write CSR
+pub struct Csrrw;
+impl Instruction for Csrrw {
+    fn syntax(&self) -> &'static str { "csrrw rd, csr, rs1" }
+
+    fn validate(&self, word: Word) -> Result<(), Exception> {
+        validate(word, 0x1)
+    }
+    fn disassemble(&self, word: Word) -> Result<Disassembly, Exception> {
+        self.validate(word)?;
+        Ok(Disassembly::new("csrrw", vec![
+            Register(word.rd()),
+            Csr(word.csr()),
+            Register(word.rs1()),
+        ]))
+    }
+    fn execute(&self, word: Word, cpu: &RV32) -> Result<bool, Exception> {
+        let rs1v = cpu.reg.read_gpr(word.rs1())?;
+        access(cpu, word, rs1v, word.rd().0 != 0, true, |_, value| value)
+    }
+}
+
+/// Atomic Read and Set Bits in CSR
+pub struct Csrrs;
+impl Instruction for Csrrs {
+    fn syntax(&self) -> &'static str { "csrrs rd, csr, rs1" }
+
+    fn validate(&self, word: Word) -> Result<(), Exception> {
+        validate(word, 0x2)
+    }
+    fn disassemble(&self, word: Word) -> Result<Disassembly, Exception> {
+        self.validate(word)?;
+        Ok(Disassembly::new("csrrs", vec![
+            Register(word.rd()),
+            Csr(word.csr()),
+            Register(word.rs1()),
+        ]))
+    }
+    fn execute(&self, word: Word, cpu: &RV32) -> Result<bool, Exception> {
+        let rs1v = cpu.reg.read_gpr(word.rs1())?;
+        access(cpu, word, rs1v, true, word.rs1().0 != 0, |old, bits| old | bits)
+    }
+}
+
+/// Atomic Read and Clear Bits in CSR
+pub struct Csrrc;
+impl Instruction for Csrrc {
+    fn syntax(&self) -> &'static str { "csrrc rd, csr, rs1" }
+
+    fn validate(&self, word: Word) -> Result<(), Exception> {
+        validate(word, 0x3)
+    }
+    fn disassemble(&self, word: Word) -> Result<Disassembly, Exception> {
+        self.validate(word)?;
+        Ok(Disassembly::new("csrrc", vec![
+            Register(word.rd()),
+            Csr(word.csr()),
+            Register(word.rs1()),
+        ]))
+    }
+    fn execute(&self, word: Word, cpu: &RV32) -> Result<bool, Exception> {
+        let rs1v = cpu.reg.read_gpr(word.rs1())?;
+        access(cpu, word, rs1v, true, word.rs1().0 != 0, |old, bits| old & !bits)
+    }
+}
+
+/// Atomic Read/Write CSR with 5-bit unsigned immediate
+pub struct Csrrwi;
+impl Instruction for Csrrwi {
+    fn syntax(&self) -> &'static str { "csrrwi rd, csr, uimm" }
+
+    fn validate(&self, word: Word) -> Result<(), Exception> {
+        validate(word, 0x5)
+    }
+    fn disassemble(&self, word: Word) -> Result<Disassembly, Exception> {
+        self.validate(word)?;
+        Ok(Disassembly::new("csrrwi", vec![
+            Register(word.rd()),
+            Csr(word.csr()),
+            Immediate(word.rs1()),
+        ]))
+    }
+    fn execute(&self, word: Word, cpu: &RV32) -> Result<bool, Exception> {
+        // The immediate is encoded in place of rs1
+        access(cpu, word, word.rs1(), word.rd().0 != 0, true, |_, value| value)
+    }
+}
+
+/// Atomic Read and Set Bits in CSR with 5-bit unsigned immediate
+pub struct Csrrsi;
+impl Instruction for Csrrsi {
+    fn syntax(&self) -> &'static str { "csrrsi rd, csr, uimm" }
+
+    fn validate(&self, word: Word) -> Result<(), Exception> {
+        validate(word, 0x6)
+    }
+    fn disassemble(&self, word: Word) -> Result<Disassembly, Exception> {
+        self.validate(word)?;
+        Ok(Disassembly::new("csrrsi", vec![
+            Register(word.rd()),
+            Csr(word.csr()),
+            Immediate(word.rs1()),
+        ]))
+    }
+    fn execute(&self, word: Word, cpu: &RV32) -> Result<bool, Exception> {
+        let uimm = word.rs1();
+        access(cpu, word, uimm, true, uimm.0 != 0, |old, bits| old | bits)
+    }
+}
+
+/// Atomic Read and Clear Bits in CSR with 5-bit unsigned immediate
+pub struct Csrrci;
+impl Instruction for Csrrci {
+    fn syntax(&self) -> &'static str { "csrrci rd, csr, uimm" }
+
+    fn validate(&self, word: Word) -> Result<(), Exception> {
+        validate(word, 0x7)
+    }
+    fn disassemble(&self, word: Word) -> Result<Disassembly, Exception> {
+        self.validate(word)?;
+        Ok(Disassembly::new("csrrci", vec![
+            Register(word.rd()),
+            Csr(word.csr()),
+            Immediate(word.rs1()),
+        ]))
+    }
+    fn execute(&self, word: Word, cpu: &RV32) -> Result<bool, Exception> {
+        let uimm = word.rs1();
+        access(cpu, word, uimm, true, uimm.0 != 0, |old, bits| old & !bits)
+    }
+}
@@ -0,0 +1,78 @@
+//! Interrupt lines from devices to the hart
+//!
+//! Devices drive numbered lines through an [`IrqLine`]. The hart only sees
+//! whether any line is pending, as machine external interrupt; `wfi` waits
+//! until one is.
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Number of interrupt lines
+pub const LINES: u32 = 64;
+
+/// Level of every interrupt line
+#[derive(Debug)]
+pub struct Interrupts {
+    pending: Cell<u64>,
+}
+impl Interrupts {
+    pub fn new() -> Self {
+        Self { pending: Cell::new(0) }
+    }
+
+    /// Bitmap of raised lines, bit `n` is line `n`
+    pub fn pending(&self) -> u64 {
+        self.pending.get()
+    }
+
+    pub fn is_pending(&self, line: u32) -> bool {
+        self.pending() & (1 << line) != 0
+    }
+
+    fn set(&self, line: u32, level: bool) {
+        let mask = 1 << line;
+        match level {
+            true => self.pending.set(self.pending() | mask),
+            false => self.pending.set(self.pending() & !mask),
+        }
+    }
+}
+impl Default for Interrupts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Output of a device wired to one interrupt line
+#[derive(Clone)]
+pub struct IrqLine {
+    interrupts: Rc<Interrupts>,
+    line: u32,
+}
+impl IrqLine {
+    pub fn new(interrupts: Rc<Interrupts>, line: u32) -> Self {
+        assert!(line < LINES, "Interrupt line {line} doesn't exist, there are only {LINES} lines");
+        Self { interrupts, line }
+    }
+
+    /// Number of the line, devices list it as their `interrupts` property
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn raise(&self) {
+        self.interrupts.set(self.line, true);
+    }
+
+    pub fn lower(&self) {
+        self.interrupts.set(self.line, false);
+    }
+
+    /// Drive the line to `level`
+    pub fn set(&self, level: bool) {
+        self.interrupts.set(self.line, level);
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.interrupts.is_pending(self.line)
+    }
+}
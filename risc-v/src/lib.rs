@@ -1,98 +1,294 @@
 pub mod exception;
+pub mod csr;
 pub mod register;
 pub mod disassembly;
 pub mod instructions;
 pub mod devices;
 pub mod word;
 pub mod bus;
+pub mod trace;
+pub mod cosim;
+pub mod elf;
+pub mod compliance;
+pub mod semihosting;
+pub mod linux_user;
+pub mod fdt;
+pub mod image;
+pub mod audio;
+pub mod scheduler;
+pub mod interrupts;
 
 use instructions::INSTRUCTION_SET;
 pub use word::Word;
 
-use bus::Bus;
+use bus::{Bus, AccessWidth};
+use scheduler::Cycle;
 use register::RV32IRegisters;
+use csr::Csrs;
 use exception::Exception;
+use trace::{Commit, CommitLog, MemoryAccess};
+use semihosting::Semihosting;
+use linux_user::LinuxUser;
 
-#[derive(Debug, Clone, Copy)]
+use std::cell::Cell;
+
+/// Longest idle stretch of a single `wfi`, devices polling on a schedule
+/// would otherwise keep it skipping forever
+pub const MAX_IDLE_CYCLES: Cycle = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MemoryRange {
     base: Word,
     offset: Word,
 }
 impl MemoryRange {
     pub fn new(base: Word, offset: Word) -> Self {
-        return Self { base, offset };
+        Self { base, offset }
     }
 
-    pub fn contains(&self, address: Word) -> bool {
-        let end = match self.base.checked_add(self.offset.0) {
-            None => Word::MAX,
-            Some(n) => Word(n),
-        };
+    /// First address of the range
+    pub fn base(&self) -> Word {
+        self.base
+    }
 
-        address < self.base && address > end
+    /// Number of bytes in the range
+    pub fn size(&self) -> Word {
+        self.offset
+    }
+
+    pub fn contains(&self, address: Word) -> bool {
+        address >= self.base && address - self.base < self.offset
     }
 
     pub fn intersects(&self, other: Self) -> bool {
-        use std::cmp::Ordering::{Greater, Less};
-
-        let self_end = match self.base.checked_add(self.offset.0) {
-            None => Word::MAX,
-            Some(n) => Word(n),
-        };
-
-        let other_end = match other.base.checked_add(self.offset.0) {
-            None => Word::MAX,
-            Some(n) => Word(n),
-        };
-
-        let (c1, c2, c3, c4) = (
-            self.base.cmp(&other.base),
-            self.base.cmp(&other_end),
-            self_end.cmp(&other.base),
-            self_end.cmp(&other_end),  
-        );
-
-        match (c1, c2, c3, c4) {
-            (Less, Less, Less, Less) |
-            (Greater, Greater, Greater, Greater) => {
-                return true;
-            }
-            _ => {
-                return false;
-            }
-        }
+        // Compare in 64 bits, so ranges ending at the top of the address space don't wrap
+        let self_end = self.base.0 as u64 + self.offset.0 as u64;
+        let other_end = other.base.0 as u64 + other.offset.0 as u64;
+
+        (self.base.0 as u64) < other_end && (other.base.0 as u64) < self_end
     }
 }
 
+/// Reason why the machine stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Halt {
+    /// Guest program exited with given code
+    Exit(i32),
+    /// Guest asked the system controller to power off, with given code
+    PowerOff(u32),
+    /// Guest asked the system controller to reset, with given code. The host
+    /// calls [`RV32::reset`] if it wants the machine to start over.
+    Reset(u32),
+    /// Watchdog expired without being kicked, the host handles it like `Reset`
+    WatchdogReset,
+}
+
 pub struct RV32 {
     /// RV32 registers
     pub reg: RV32IRegisters,
 
+    /// Machine mode control and status registers
+    pub csr: Csrs,
+
+    /// Take exceptions as traps to `mtvec` instead of returning them from `step`
+    pub traps: bool,
+
     /// Address bus with devices
     pub bus: Bus,
+
+    /// Log of retired instructions, written on every step when present
+    pub commit_log: Option<CommitLog>,
+
+    /// Host services for semihosting calls, `ebreak` traps when not present
+    pub semihosting: Option<Semihosting>,
+
+    /// Emulated Linux system calls, `ecall` traps when not present
+    pub linux: Option<LinuxUser>,
+
+    /// Address the hart starts from after `reset`
+    pub reset_vector: Word,
+
+    /// Set by `wfi`, the hart idles once the instruction retired
+    waiting: Cell<bool>,
 }
 impl RV32 {
     pub fn new() -> Self {
         let bus = Bus::new();
-        Self { reg: RV32IRegisters::new(), bus }
+        let csr = Csrs::new().with_interrupts(bus.interrupts().clone());
+        Self {
+            reg: RV32IRegisters::new(), csr, traps: false, bus,
+            commit_log: None, semihosting: None, linux: None, reset_vector: Word(0), waiting: Cell::new(false),
+        }
+    }
+
+    /// Stop the machine, `run` returns after current instruction
+    pub fn halt(&self, reason: Halt) {
+        self.bus.halt(reason);
+    }
+
+    /// Reason the machine stopped, `None` if it is still running
+    pub fn halted(&self) -> Option<Halt> {
+        self.bus.halted()
+    }
+
+    /// Clear the halt reason, `run` then goes on from the current pc.
+    /// Registers, CSRs and memory are left as they are, see `reset`.
+    pub fn resume(&self) {
+        self.bus.resume();
+    }
+
+    /// Reset the machine, for [`Halt::Reset`] and [`Halt::WatchdogReset`]:
+    /// clear registers and CSRs, reset the devices and clear the halt reason,
+    /// `run` then starts over from `reset_vector`. Memory keeps its contents.
+    pub fn reset(&self) {
+        self.reg.reset();
+        self.csr.reset();
+        self.waiting.set(false);
+        self.bus.reset();
+        self.reg.write("pc", self.reset_vector).unwrap();
+    }
+
+    /// Step until the machine halts or an exception occurs
+    pub fn run(&self) -> Result<Halt, Exception> {
+        loop {
+            if let Some(reason) = self.halted() {
+                return Ok(reason);
+            }
+
+            self.step()?;
+        }
     }
 
     /// fetch next word pointed by program counter
     pub fn fetch(&self) -> Result<Word, Exception> {
         let pc = self.reg.read("pc").unwrap();
-        let inst_word = self.bus.read_le_word(pc);
+        let inst_word = self.bus.read(pc, AccessWidth::Word)
+            .map_err(|err| Exception::InstructionAccessFault(err.address()))?;
         Ok(inst_word)
     }
 
+    /// Read `width` bytes of data memory for a load instruction
+    pub fn load(&self, address: Word, width: AccessWidth) -> Result<Word, Exception> {
+        self.bus.read(address, width)
+            .map_err(|err| Exception::LoadAccessFault(err.address()))
+    }
+
+    /// Write low `width` bytes of `word` to data memory for a store instruction
+    pub fn store(&self, address: Word, word: Word, width: AccessWidth) -> Result<(), Exception> {
+        self.bus.write(address, word, width)
+            .map_err(|err| Exception::StoreAccessFault(err.address()))
+    }
+
     /// Instrement Program Counter
     pub fn increment_pc(&self) {
         let pc = self.reg.read("pc").unwrap();
         self.reg.write("pc", pc + Word(4)).unwrap();
     }
 
-    /// Fetch and execute one instruction and clock the bus once
+    /// Fetch and execute one instruction, an exception traps to the handler
+    /// instead of being returned when `traps` is set. A pending and enabled
+    /// interrupt is taken first, the instruction is then the handler's first.
     pub fn step(&self) -> Result<(), Exception> {
+        match self.try_step() {
+            Err(exception) if self.traps => {
+                self.trap(exception);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn try_step(&self) -> Result<(), Exception> {
+        if let Some(log) = self.commit_log.as_ref() {
+            let commit = self.step_traced()?;
+            let disassembly = INSTRUCTION_SET.decode(commit.instruction)
+                .and_then(|inst| inst.disassemble(commit.instruction))
+                .ok();
+
+            log.log(&commit, disassembly.as_ref());
+            return Ok(());
+        }
+
+        self.take_interrupt();
+        let word = self.fetch()?;
+        self.execute(word)?;
+        self.retire();
+        Ok(())
+    }
+
+    /// Same as `step`, but also return the effects of executed instruction
+    pub fn step_traced(&self) -> Result<Commit, Exception> {
+        self.take_interrupt();
+        let pc = self.reg.read("pc")?;
         let word = self.fetch()?;
+
+        self.reg.record_writes();
+        self.bus.record_accesses();
+
+        let result = self.execute(word);
+        let writebacks = self.reg.take_writes();
+        let accesses = self.bus.take_accesses();
+        result?;
+
+        // Events due in this cycle run after recording stopped,
+        // so their transfers aren't attributed to the instruction
+        self.retire();
+        Ok(Commit::new(pc, word, writebacks, MemoryAccess::coalesce(&accesses)))
+    }
+
+    /// Enter the trap handler for `exception` raised by the instruction at pc,
+    /// the trap takes the cycle of the instruction
+    pub(crate) fn trap(&self, exception: Exception) {
+        let pc = self.reg.read("pc").unwrap();
+        let handler = self.csr.trap(exception.cause(), pc, exception.value());
+        self.reg.write("pc", handler).unwrap();
+        self.bus.advance(1);
+    }
+
+    /// Enter the handler of a pending and enabled interrupt, if there is one,
+    /// in place of the instruction at pc
+    fn take_interrupt(&self) {
+        if let Some(cause) = self.csr.interrupt() {
+            let pc = self.reg.read("pc").unwrap();
+            let handler = self.csr.trap(cause, pc, Word(0));
+            self.reg.write("pc", handler).unwrap();
+        }
+    }
+
+    /// Let the cycle of the retired instruction pass, then idle if it was `wfi`
+    fn retire(&self) {
+        self.bus.advance(1);
+        if self.waiting.take() {
+            self.wait_for_interrupt();
+        }
+    }
+
+    /// Idle after the current instruction retires, for `wfi`
+    pub(crate) fn request_wait(&self) {
+        self.waiting.set(true);
+    }
+
+    /// Idle until an interrupt is pending, skipping straight to scheduled
+    /// events, for at most [`MAX_IDLE_CYCLES`]. Returns at once when nothing
+    /// is scheduled, as nothing could raise an interrupt anymore.
+    pub fn wait_for_interrupt(&self) {
+        let deadline = self.bus.now() + MAX_IDLE_CYCLES;
+        while self.bus.interrupts().pending() == 0 && self.halted().is_none() {
+            match self.bus.scheduler().next_event() {
+                Some(cycle) if cycle <= deadline => {
+                    self.bus.skip_to_next_event();
+                }
+                Some(_) => {
+                    self.bus.advance(deadline - self.bus.now());
+                    break;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Execute fetched instruction, the caller lets its cycle pass
+    fn execute(&self, word: Word) -> Result<(), Exception> {
         let instruction = INSTRUCTION_SET.decode(word)?;
         let increment_pc = instruction.execute(word, self)?;
 
@@ -100,8 +296,129 @@ impl RV32 {
             self.increment_pc()
         }
 
-        self.bus.tick();
         Ok(())
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryRange, Word, RV32, MAX_IDLE_CYCLES};
+    use crate::bus::{AccessWidth, Bus};
+    use crate::csr::{MCAUSE, MEPC, MEIP, MIE, MIP, MSTATUS, MSTATUS_MIE, MSTATUS_MPIE, MTVEC};
+    use crate::devices::{Device, ram::Ram, timer::Timer};
+
+    const TIMER_BASE: Word = Word(0x1000_0000);
+
+    /// RAM at 0 with `program`, timer channel raising line 3 after `load` cycles
+    fn machine(program: &[u32], load: u32) -> RV32 {
+        let mut cpu = RV32::new();
+        cpu.bus.connect(Device::new(MemoryRange::new(Word(0), Word(0x1000)), Box::new(Ram::new(0x1000)))).unwrap();
+        let timer = Timer::new(1).with_interrupt(cpu.bus.interrupt_line(3));
+        cpu.bus.connect(Device::new(MemoryRange::new(TIMER_BASE, Word(0x10)), Box::new(timer))).unwrap();
+
+        let bytes: Vec<u8> = program.iter().flat_map(|w| w.to_le_bytes()).collect();
+        cpu.bus.load(Word(0), &bytes).unwrap();
+        // Load, then enable with interrupt
+        cpu.bus.write(TIMER_BASE, Word(load), AccessWidth::Word).unwrap();
+        cpu.bus.write(TIMER_BASE + Word(8), Word(0b101), AccessWidth::Word).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn intersects_only_overlapping_ranges() {
+        let range = MemoryRange::new(Word(0x1000), Word(0x1000));
+
+        assert!(!range.intersects(MemoryRange::new(Word(0x0000), Word(0x1000))));
+        assert!(!range.intersects(MemoryRange::new(Word(0x2000), Word(0x1000))));
+        assert!(range.intersects(MemoryRange::new(Word(0x1800), Word(0x1000))));
+        assert!(range.intersects(MemoryRange::new(Word(0x0000), Word(0x4000))));
+        assert!(MemoryRange::new(Word(0x1800), Word(0x10)).intersects(range));
+    }
+
+    #[test]
+    fn intersects_at_top_of_address_space() {
+        let top = MemoryRange::new(Word(0xffff_f000), Word(0x1000));
+
+        assert!(top.intersects(MemoryRange::new(Word(0xffff_fff0), Word(0x10))));
+        assert!(!top.intersects(MemoryRange::new(Word(0), Word(0x1000))));
+    }
+
+    #[test]
+    fn scheduled_events_run_after_the_instruction_and_outside_its_trace() {
+        let mut cpu = RV32::new();
+        cpu.bus.connect(Device::new(MemoryRange::new(Word(0), Word(0x1000)), Box::new(Ram::new(0x1000)))).unwrap();
+        // addi ra, zero, 1
+        cpu.bus.load(Word(0), &0x00100093u32.to_le_bytes()).unwrap();
+
+        cpu.bus.scheduler().schedule(1, |bus| {
+            bus.write(Word(0x100), Word(0xAA), AccessWidth::Byte).unwrap();
+        });
+
+        let commit = cpu.step_traced().unwrap();
+        assert!(commit.memory.is_empty());
+        assert_eq!(commit.writebacks, vec![(Word(1), Word(1))]);
+        assert_eq!(cpu.bus.now(), 1);
+        assert_eq!(cpu.bus.read_byte(Word(0x100)), 0xAA);
+    }
+
+    #[test]
+    fn timer_interrupt_enters_the_handler() {
+        // Loop at 0 with `j .`, handler at 0x100 sets ra and loops
+        let mut program = vec![0x0000006f; 0x41];
+        program[0x40] = 0x00100093; // addi ra, zero, 1
+        let cpu = machine(&program, 10);
+        cpu.csr.write(Word(MTVEC), Word(0x100)).unwrap();
+        cpu.csr.write(Word(MIE), Word(MEIP)).unwrap();
+
+        // Masked by mstatus, the line is pending but not taken
+        for _ in 0..20 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.csr.read(Word(MIP)), Ok(Word(MEIP)));
+        assert_eq!(cpu.reg.read("pc"), Ok(Word(0)));
+
+        cpu.csr.write(Word(MSTATUS), Word(MSTATUS_MIE)).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg.read("ra"), Ok(Word(1)));
+        assert_eq!(cpu.reg.read("pc"), Ok(Word(0x104)));
+        assert_eq!(cpu.csr.read(Word(MCAUSE)), Ok(Word(0x8000_000B)));
+        assert_eq!(cpu.csr.read(Word(MEPC)), Ok(Word(0)));
+        assert_eq!(cpu.csr.read(Word(MSTATUS)).unwrap().0 & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+
+        // Writes to mip are dropped, the bit follows the line
+        cpu.csr.write(Word(MIP), Word(0)).unwrap();
+        assert_eq!(cpu.csr.read(Word(MIP)), Ok(Word(MEIP)));
+        cpu.bus.write(TIMER_BASE + Word(0xc), Word(1), AccessWidth::Word).unwrap();
+        assert_eq!(cpu.csr.read(Word(MIP)), Ok(Word(0)));
+    }
+
+    #[test]
+    fn wfi_idles_until_the_interrupt_outside_its_trace() {
+        // wfi, then `j .`
+        let cpu = machine(&[0x10500073, 0x0000006f], 500);
+        cpu.bus.scheduler().schedule(100, |bus| {
+            bus.write(Word(0x200), Word(0xAA), AccessWidth::Byte).unwrap();
+        });
+
+        let commit = cpu.step_traced().unwrap();
+        assert!(commit.memory.is_empty());
+        assert_eq!(cpu.bus.now(), 500);
+        assert_eq!(cpu.bus.read_byte(Word(0x200)), 0xAA);
+        assert_eq!(cpu.reg.read("pc"), Ok(Word(4)));
+        assert!(cpu.bus.interrupts().is_pending(3));
+    }
+
+    #[test]
+    fn wfi_stops_idling_after_a_while() {
+        fn poll(bus: &Bus) {
+            bus.scheduler().schedule(1000, poll);
+        }
+        let cpu = machine(&[0x10500073, 0x0000006f], u32::MAX);
+        cpu.bus.scheduler().schedule(1000, poll);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.now(), 1 + MAX_IDLE_CYCLES);
+        assert_eq!(cpu.reg.read("pc"), Ok(Word(4)));
+    }
+}
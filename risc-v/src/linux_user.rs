@@ -0,0 +1,955 @@
+//! User-mode emulation of Linux system calls
+//!
+//! Runs statically linked rv32 Linux programs without a kernel, the way
+//! `qemu-riscv32` does. The program is loaded into [`MEMORY_SIZE`] bytes of
+//! memory at address `0`, and every `ecall` is dispatched on the syscall
+//! number in `a7`. Arguments are passed in `a0`-`a5`, result, or negated error
+//! number, is returned in `a0`.
+//!
+//! Memory layout of the process:
+//!
+//! ```text
+//! 0x0000_0000  program segments, followed by the heap growing up (brk)
+//! 0x0080_0000  anonymous and file mappings growing up (mmap)
+//! 0x00F0_0000  stack growing down from 0x0100_0000
+//! ```
+//!
+//! Ranges given back with `munmap` are reused by later mappings that fit
+//! in them, first fit from the lowest address.
+use crate::{RV32, Word, Halt, MemoryRange};
+use crate::bus::{AccessWidth, BusError};
+use crate::devices::{Device, ram::Ram};
+use crate::elf::{Elf, ElfError};
+use crate::exception::Exception;
+use crate::semihosting::contained;
+
+use std::cell::{Cell, RefCell};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Size of memory available to the process
+pub const MEMORY_SIZE: Word = Word(16 * 1024 * 1024);
+
+/// Lowest address used for `mmap`, also the upper limit of the heap
+pub const MMAP_BASE: Word = Word(0x0080_0000);
+
+/// Lowest address of the stack, also the upper limit of `mmap`
+pub const STACK_BASE: Word = Word(0x00F0_0000);
+
+/// Initial stack pointer is placed below this address
+pub const STACK_TOP: Word = Word(0x0100_0000);
+
+pub const PAGE_SIZE: u32 = 4096;
+
+/// Largest number of bytes moved by a single `read`, `write` or `getrandom`,
+/// longer requests return a short count as Linux is allowed to
+pub const MAX_TRANSFER: u32 = 64 * 1024;
+
+/// Largest number of buffers accepted by `readv` and `writev`
+pub const IOV_MAX: u32 = 1024;
+
+// Syscall numbers from the generic Linux syscall table used by RISC-V
+pub const SYS_IOCTL: u32 = 29;
+pub const SYS_FACCESSAT: u32 = 48;
+pub const SYS_OPENAT: u32 = 56;
+pub const SYS_CLOSE: u32 = 57;
+pub const SYS_LLSEEK: u32 = 62;
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_READV: u32 = 65;
+pub const SYS_WRITEV: u32 = 66;
+pub const SYS_READLINKAT: u32 = 78;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_EXIT_GROUP: u32 = 94;
+pub const SYS_SET_TID_ADDRESS: u32 = 96;
+pub const SYS_SET_ROBUST_LIST: u32 = 99;
+pub const SYS_CLOCK_GETTIME: u32 = 113;
+pub const SYS_RT_SIGACTION: u32 = 134;
+pub const SYS_RT_SIGPROCMASK: u32 = 135;
+pub const SYS_UNAME: u32 = 160;
+pub const SYS_GETTIMEOFDAY: u32 = 169;
+pub const SYS_GETPID: u32 = 172;
+pub const SYS_GETUID: u32 = 174;
+pub const SYS_GETEUID: u32 = 175;
+pub const SYS_GETGID: u32 = 176;
+pub const SYS_GETEGID: u32 = 177;
+pub const SYS_GETTID: u32 = 178;
+pub const SYS_BRK: u32 = 214;
+pub const SYS_MUNMAP: u32 = 215;
+pub const SYS_MMAP2: u32 = 222;
+pub const SYS_MPROTECT: u32 = 226;
+pub const SYS_MADVISE: u32 = 233;
+pub const SYS_PRLIMIT64: u32 = 261;
+pub const SYS_GETRANDOM: u32 = 278;
+pub const SYS_STATX: u32 = 291;
+pub const SYS_CLOCK_GETTIME64: u32 = 403;
+
+// Error numbers
+const ENOENT: i32 = 2;
+const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+const ENOTTY: i32 = 25;
+const ESPIPE: i32 = 29;
+const ENOSYS: i32 = 38;
+
+// Auxiliary vector entries
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_HWCAP: u32 = 16;
+const AT_SECURE: u32 = 23;
+const AT_RANDOM: u32 = 25;
+
+const AT_FDCWD: i32 = -100;
+const AT_EMPTY_PATH: u32 = 0x1000;
+
+const O_ACCMODE: u32 = 0o3;
+const O_CREAT: u32 = 0o100;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+const MAP_ANONYMOUS: u32 = 0x20;
+const TIOCGWINSZ: u32 = 0x5413;
+
+const S_IFCHR: u16 = 0o020000;
+const S_IFREG: u16 = 0o100000;
+
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Emulated kernel of a single user process
+pub struct LinuxUser {
+    root: PathBuf,
+    fds: RefCell<Vec<Option<Fd>>>,
+    /// Start of the heap, `brk` can't go below it
+    heap: Cell<Word>,
+    brk: Cell<Word>,
+    mmap: Cell<Word>,
+    /// Unmapped ranges below `mmap`, sorted and merged, as `(start, end)`
+    unmapped: RefCell<Vec<(Word, Word)>>,
+    /// State of the `getrandom` generator
+    random: Cell<u64>,
+}
+impl LinuxUser {
+    /// Absolute paths opened by the process are looked up relative to `root`
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            fds: RefCell::new(vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)]),
+            heap: Cell::new(Word(0)),
+            brk: Cell::new(Word(0)),
+            mmap: Cell::new(MMAP_BASE),
+            unmapped: RefCell::new(vec![]),
+            random: Cell::new(0x853c_49e6_748f_ea9b),
+        }
+    }
+
+    /// Seed of the generator behind `getrandom` and `AT_RANDOM`
+    pub fn with_seed(self, seed: u64) -> Self {
+        self.random.set(seed | 1);
+        self
+    }
+
+    /// Load program segments, build initial stack with `args`, `env`
+    /// and auxiliary vector, and point `pc` and `sp` at them
+    pub fn load(&self, cpu: &RV32, elf: &Elf, args: &[String], env: &[String]) -> Result<(), ElfError> {
+        let mut end = Word(0);
+
+        for segment in elf.segments.iter() {
+            let segment_end = segment.virtual_address.0 as u64 + segment.size.0 as u64;
+            if segment_end > MMAP_BASE.0 as u64 {
+                return Err(ElfError::Unsupported("segment outside of process memory"));
+            }
+
+            cpu.bus.load(segment.virtual_address, &segment.data).map_err(ElfError::Load)?;
+            end = end.max(Word(segment_end as u32));
+        }
+
+        // Segments end below MMAP_BASE, so aligning can't overflow
+        let heap = page_align(end).unwrap();
+        self.heap.set(heap);
+        self.brk.set(heap);
+
+        let sp = self.build_stack(cpu, elf, args, env).map_err(ElfError::Load)?;
+        cpu.reg.write("pc", elf.entry).unwrap();
+        cpu.reg.write("sp", sp).unwrap();
+
+        Ok(())
+    }
+
+    fn build_stack(&self, cpu: &RV32, elf: &Elf, args: &[String], env: &[String]) -> Result<Word, BusError> {
+        let mut top = STACK_TOP;
+
+        let mut push_bytes = |bytes: &[u8]| {
+            top -= Word(bytes.len() as u32);
+            cpu.bus.load(top, bytes).map(|_| top)
+        };
+
+        let random: Vec<u8> = (0..16).map(|_| self.next_random() as u8).collect();
+        let random = push_bytes(&random)?;
+
+        let mut push_strings = |strings: &[String]| -> Result<Vec<Word>, BusError> {
+            strings.iter()
+                .map(|s| push_bytes(&[s.as_bytes(), &[0]].concat()))
+                .collect()
+        };
+        let argv = push_strings(args)?;
+        let envp = push_strings(env)?;
+
+        let auxv = [
+            (AT_PHDR, elf.program_headers.unwrap_or_default().0),
+            (AT_PHENT, 32),
+            (AT_PHNUM, elf.program_header_count as u32),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, elf.entry.0),
+            (AT_UID, 0), (AT_EUID, 0), (AT_GID, 0), (AT_EGID, 0),
+            (AT_HWCAP, 0),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random.0),
+            (AT_NULL, 0),
+        ];
+
+        // argc, argv, NULL, envp, NULL, auxv
+        let mut words = vec![args.len() as u32];
+        words.extend(argv.iter().map(|w| w.0));
+        words.push(0);
+        words.extend(envp.iter().map(|w| w.0));
+        words.push(0);
+        words.extend(auxv.iter().flat_map(|(key, value)| [*key, *value]));
+
+        let size = words.len() as u32 * 4;
+        let sp = Word((top.0 - size) & !0xF);
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        cpu.bus.load(sp, &bytes)?;
+
+        Ok(sp)
+    }
+
+    /// Perform system call requested by registers of `cpu`
+    pub fn syscall(&self, cpu: &RV32) -> Result<(), Exception> {
+        let number = cpu.reg.read("a7")?.0;
+        let value = match self.dispatch(cpu, number) {
+            Ok(value) => Word(value),
+            Err(errno) => Word(-errno as u32),
+        };
+
+        cpu.reg.write_gpr(Word(10), value)
+    }
+
+    /// Perform system call `number`, returning its result or error number
+    fn dispatch(&self, cpu: &RV32, number: u32) -> Result<u32, i32> {
+        let arg = |n: u32| cpu.reg.read_gpr(Word(10 + n)).unwrap_or_default();
+
+        match number {
+            SYS_READ => {
+                let (fd, buffer, length) = (arg(0).0, arg(1), arg(2).0);
+                self.read(cpu, fd, buffer, length)
+            }
+            SYS_WRITE => {
+                let (fd, buffer, length) = (arg(0).0, arg(1), arg(2).0);
+                self.write(cpu, fd, buffer, length)
+            }
+            SYS_READV | SYS_WRITEV => {
+                let (fd, iov, count) = (arg(0).0, arg(1), arg(2).0);
+                if count > IOV_MAX {
+                    return Err(EINVAL);
+                }
+                let mut total = 0;
+
+                for i in 0..count {
+                    // struct iovec { void *iov_base, size_t iov_len }
+                    let base = cpu.bus.read(iov + Word(8 * i), AccessWidth::Word).map_err(efault)?;
+                    let length = cpu.bus.read(iov + Word(8 * i + 4), AccessWidth::Word).map_err(efault)?.0;
+
+                    let done = match number {
+                        SYS_READV => self.read(cpu, fd, base, length),
+                        _ => self.write(cpu, fd, base, length),
+                    };
+
+                    match done {
+                        Ok(n) => total += n,
+                        Err(errno) if total == 0 => return Err(errno),
+                        Err(_) => break,
+                    }
+                    if done != Ok(length) {
+                        break;
+                    }
+                }
+
+                Ok(total)
+            }
+            SYS_OPENAT => {
+                let (dirfd, path, flags) = (arg(0).signed(), arg(1), arg(2).0);
+                let path = String::from_utf8_lossy(&cpu.bus.read_cstr(path).map_err(efault)?).into_owned();
+                self.open(dirfd, &path, flags)
+            }
+            SYS_CLOSE => {
+                match self.fds.borrow_mut().get_mut(arg(0).0 as usize).and_then(Option::take) {
+                    Some(_) => Ok(0),
+                    None => Err(EBADF),
+                }
+            }
+            SYS_LLSEEK => {
+                let (fd, offset, result, whence) = (arg(0).0, (arg(1).0 as u64) << 32 | arg(2).0 as u64, arg(3), arg(4).0);
+                let position = match whence {
+                    0 => SeekFrom::Start(offset),
+                    1 => SeekFrom::Current(offset as i64),
+                    2 => SeekFrom::End(offset as i64),
+                    _ => return Err(EINVAL),
+                };
+
+                self.with_fd(fd, |f| match f {
+                    Fd::File(file) => file.seek(position),
+                    _ => Err(io::Error::from_raw_os_error(ESPIPE)),
+                }).and_then(|position| {
+                    cpu.bus.load(result, &position.to_le_bytes()).map_err(efault)?;
+                    Ok(0)
+                })
+            }
+            SYS_IOCTL => {
+                let (fd, request, argp) = (arg(0).0, arg(1).0, arg(2));
+                match self.fds.borrow().get(fd as usize) {
+                    None | Some(None) => Err(EBADF),
+                    Some(Some(Fd::File(_))) => Err(ENOTTY),
+                    // Console is the only terminal and it only reports its size
+                    Some(Some(_)) if request == TIOCGWINSZ => {
+                        // struct winsize { rows, cols, xpixel, ypixel }
+                        let winsize = [24u16, 80, 0, 0];
+                        let bytes: Vec<u8> = winsize.iter().flat_map(|v| v.to_le_bytes()).collect();
+                        cpu.bus.load(argp, &bytes).map_err(efault)?;
+                        Ok(0)
+                    }
+                    Some(Some(_)) => Err(ENOTTY),
+                }
+            }
+            SYS_FACCESSAT => {
+                let path = String::from_utf8_lossy(&cpu.bus.read_cstr(arg(1)).map_err(efault)?).into_owned();
+                match self.sandboxed(arg(0).signed(), &path) {
+                    Some(path) if path.exists() => Ok(0),
+                    Some(_) => Err(ENOENT),
+                    None => Err(EACCES),
+                }
+            }
+            SYS_STATX => {
+                let (dirfd, path, flags, buffer) = (arg(0).signed(), arg(1), arg(2).0, arg(4));
+                let path = String::from_utf8_lossy(&cpu.bus.read_cstr(path).map_err(efault)?).into_owned();
+                self.statx(cpu, dirfd, &path, flags, buffer)
+            }
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                cpu.halt(Halt::Exit(arg(0).signed()));
+                Ok(0)
+            }
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(1),
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => Ok(0),
+            SYS_MPROTECT | SYS_MADVISE => Ok(0),
+            SYS_MUNMAP => {
+                let (address, length) = (arg(0), arg(1));
+                if address.0 % PAGE_SIZE != 0 || length == Word(0) {
+                    return Err(EINVAL);
+                }
+                let end = page_align(length).and_then(|l| address.0.checked_add(l.0)).ok_or(EINVAL)?;
+                self.unmap(address, Word(end));
+                Ok(0)
+            }
+            SYS_PRLIMIT64 => Err(ENOSYS),
+            SYS_READLINKAT => Err(ENOSYS),
+            SYS_UNAME => {
+                // struct utsname has six 65 byte fields
+                let fields = ["Linux", "risc-v", "6.1.0", "#1", "riscv32", "(none)"];
+                for (i, field) in fields.iter().enumerate() {
+                    let mut bytes = field.as_bytes().to_vec();
+                    bytes.resize(65, 0);
+                    cpu.bus.load(arg(0) + Word(65 * i as u32), &bytes).map_err(efault)?;
+                }
+                Ok(0)
+            }
+            SYS_CLOCK_GETTIME | SYS_CLOCK_GETTIME64 | SYS_GETTIMEOFDAY => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                let (seconds, nanos) = (now.as_secs(), now.subsec_nanos());
+
+                let (buffer, bytes) = match number {
+                    // struct timespec64 { i64 tv_sec, i64 tv_nsec }
+                    SYS_CLOCK_GETTIME64 => (arg(1), [seconds.to_le_bytes(), (nanos as u64).to_le_bytes()].concat()),
+                    // struct timespec { i32 tv_sec, i32 tv_nsec }
+                    SYS_CLOCK_GETTIME => (arg(1), [(seconds as u32).to_le_bytes(), nanos.to_le_bytes()].concat()),
+                    // struct timeval { i32 tv_sec, i32 tv_usec }
+                    _ => (arg(0), [(seconds as u32).to_le_bytes(), (nanos / 1000).to_le_bytes()].concat()),
+                };
+
+                if buffer != Word(0) {
+                    cpu.bus.load(buffer, &bytes).map_err(efault)?;
+                }
+                Ok(0)
+            }
+            SYS_GETRANDOM => {
+                let (buffer, length) = (arg(0), arg(1).0.min(MAX_TRANSFER));
+                let bytes: Vec<u8> = (0..length).map(|_| self.next_random() as u8).collect();
+                cpu.bus.load(buffer, &bytes).map_err(efault)?;
+                Ok(length)
+            }
+            SYS_BRK => {
+                let requested = arg(0);
+                if requested >= self.heap.get() && requested <= MMAP_BASE {
+                    // Memory handed out again must be zeroed
+                    let old = self.brk.get();
+                    if requested > old {
+                        cpu.bus.load(old, &vec![0; (requested - old).0 as usize]).map_err(efault)?;
+                    }
+                    self.brk.set(requested);
+                }
+                Ok(self.brk.get().0)
+            }
+            SYS_MMAP2 => {
+                let (length, flags, fd, page_offset) = (arg(1).0, arg(3).0, arg(4).0, arg(5).0);
+                self.mmap(cpu, length, flags, fd, page_offset as u64 * PAGE_SIZE as u64)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+
+    fn read(&self, cpu: &RV32, fd: u32, buffer: Word, length: u32) -> Result<u32, i32> {
+        let mut data = vec![0; length.min(MAX_TRANSFER) as usize];
+        let read = self.with_fd(fd, |f| match f {
+            Fd::Stdin => io::stdin().read(&mut data),
+            Fd::File(file) => file.read(&mut data),
+            _ => Err(io::Error::from_raw_os_error(EBADF)),
+        })?;
+
+        cpu.bus.load(buffer, &data[..read]).map_err(efault)?;
+        Ok(read as u32)
+    }
+
+    fn write(&self, cpu: &RV32, fd: u32, buffer: Word, length: u32) -> Result<u32, i32> {
+        let length = length.min(MAX_TRANSFER);
+        let data = cpu.bus.read_bytes(buffer, length as usize).map_err(efault)?;
+        self.with_fd(fd, |f| match f {
+            Fd::Stdin => Err(io::Error::from_raw_os_error(EBADF)),
+            Fd::Stdout => io::stdout().write_all(&data).and_then(|_| io::stdout().flush()),
+            Fd::Stderr => io::stderr().write_all(&data),
+            Fd::File(file) => file.write_all(&data),
+        })?;
+
+        Ok(length)
+    }
+
+    fn open(&self, dirfd: i32, path: &str, flags: u32) -> Result<u32, i32> {
+        let path = self.sandboxed(dirfd, path).ok_or(EACCES)?;
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            0 => options.read(true),
+            1 => options.write(true),
+            _ => options.read(true).write(true),
+        };
+        options
+            .create(flags & O_CREAT != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .append(flags & O_APPEND != 0);
+
+        let file = options.open(path).map_err(|err| errno(&err))?;
+        Ok(self.allocate_fd(Fd::File(file)))
+    }
+
+    fn statx(&self, cpu: &RV32, dirfd: i32, path: &str, flags: u32, buffer: Word) -> Result<u32, i32> {
+        let (mode, size) = if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+            self.with_fd(dirfd as u32, |f| match f {
+                Fd::File(file) => file.metadata().map(|m| (S_IFREG | 0o644, m.len())),
+                _ => Ok((S_IFCHR | 0o620, 0)),
+            })?
+        } else {
+            let path = self.sandboxed(dirfd, path).ok_or(EACCES)?;
+            let metadata = std::fs::metadata(path).map_err(|err| errno(&err))?;
+            (S_IFREG | 0o644, metadata.len())
+        };
+
+        // struct statx, only the basic fields are filled in
+        let mut statx = vec![0u8; 256];
+        statx[0..4].copy_from_slice(&0x7FFu32.to_le_bytes());
+        statx[4..8].copy_from_slice(&PAGE_SIZE.to_le_bytes());
+        statx[16..20].copy_from_slice(&1u32.to_le_bytes());
+        statx[28..30].copy_from_slice(&mode.to_le_bytes());
+        statx[40..48].copy_from_slice(&size.to_le_bytes());
+        statx[48..56].copy_from_slice(&size.div_ceil(512).to_le_bytes());
+        cpu.bus.load(buffer, &statx).map_err(efault)?;
+
+        Ok(0)
+    }
+
+    fn mmap(&self, cpu: &RV32, length: u32, flags: u32, fd: u32, offset: u64) -> Result<u32, i32> {
+        let length = page_align(Word(length)).ok_or(ENOMEM)?;
+        if length == Word(0) {
+            return Err(ENOMEM);
+        }
+
+        let file_backed = flags & MAP_ANONYMOUS == 0;
+        if file_backed {
+            self.with_fd(fd, |f| match f {
+                Fd::File(file) => file.seek(SeekFrom::Start(offset)),
+                _ => Err(io::Error::from_raw_os_error(EACCES)),
+            })?;
+        }
+
+        let address = self.allocate_mapping(length).ok_or(ENOMEM)?;
+        let filled = self.fill_mapping(cpu, address, length, file_backed.then_some(fd));
+        if filled.is_err() {
+            self.unmap(address, address + length);
+        }
+        filled.map(|_| address.0)
+    }
+
+    /// Find room for `length` bytes, in an unmapped range or above all mappings
+    fn allocate_mapping(&self, length: Word) -> Option<Word> {
+        let mut unmapped = self.unmapped.borrow_mut();
+        if let Some(i) = unmapped.iter().position(|(start, end)| *end - *start >= length) {
+            let start = unmapped[i].0;
+            unmapped[i].0 += length;
+            if unmapped[i].0 == unmapped[i].1 {
+                unmapped.remove(i);
+            }
+            return Some(start);
+        }
+
+        let address = self.mmap.get();
+        if address.0 as u64 + length.0 as u64 > STACK_BASE.0 as u64 {
+            return None;
+        }
+        self.mmap.set(address + length);
+        Some(address)
+    }
+
+    /// Fill mapping a chunk at a time from `fd`, pages past the end of file are zeroed
+    fn fill_mapping(&self, cpu: &RV32, address: Word, length: Word, fd: Option<u32>) -> Result<(), i32> {
+        let mut data = vec![0; MAX_TRANSFER as usize];
+        for chunk in (0..length.0).step_by(MAX_TRANSFER as usize) {
+            let data = &mut data[..(length.0 - chunk).min(MAX_TRANSFER) as usize];
+            data.fill(0);
+            if let Some(fd) = fd {
+                self.with_fd(fd, |f| match f {
+                    Fd::File(file) => read_full(file, data),
+                    _ => Err(io::Error::from_raw_os_error(EACCES)),
+                })?;
+            }
+            cpu.bus.load(address + Word(chunk), data).map_err(efault)?;
+        }
+        Ok(())
+    }
+
+    /// Give back mapped pages from `start` up to `end`, parts outside of
+    /// the mappings are ignored as Linux does
+    fn unmap(&self, start: Word, end: Word) {
+        let top = self.mmap.get();
+        let (start, end) = (start.max(MMAP_BASE), end.min(top));
+        if start >= end {
+            return;
+        }
+
+        let mut unmapped = self.unmapped.borrow_mut();
+        unmapped.push((start, end));
+        unmapped.sort();
+        let mut merged: Vec<(Word, Word)> = vec![];
+        for (start, end) in unmapped.drain(..) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        // Range reaching the top lowers it instead
+        if let Some(&(start, end)) = merged.last() {
+            if end == top {
+                merged.pop();
+                self.mmap.set(start);
+            }
+        }
+        *unmapped = merged;
+    }
+
+    fn allocate_fd(&self, fd: Fd) -> u32 {
+        let mut fds = self.fds.borrow_mut();
+        match fds.iter().position(Option::is_none) {
+            Some(free) => {
+                fds[free] = Some(fd);
+                free as u32
+            }
+            None => {
+                fds.push(Some(fd));
+                fds.len() as u32 - 1
+            }
+        }
+    }
+
+    fn with_fd<T, F>(&self, fd: u32, f: F) -> Result<T, i32>
+    where
+        F: FnOnce(&mut Fd) -> io::Result<T>,
+    {
+        let mut fds = self.fds.borrow_mut();
+        let fd = fds.get_mut(fd as usize).and_then(Option::as_mut).ok_or(EBADF)?;
+        f(fd).map_err(|err| errno(&err))
+    }
+
+    /// Resolve path of the process inside the root directory,
+    /// `None` if it would escape it or is relative to a directory descriptor
+    fn sandboxed(&self, dirfd: i32, name: &str) -> Option<PathBuf> {
+        if dirfd != AT_FDCWD && !name.starts_with('/') {
+            return None;
+        }
+
+        let mut path = self.root.clone();
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::RootDir | Component::CurDir => (),
+                _ => return None,
+            }
+        }
+
+        contained(&self.root, &path)
+    }
+
+    /// xorshift64* generator, deterministic for a given seed
+    fn next_random(&self) -> u64 {
+        let mut x = self.random.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.random.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32
+    }
+}
+
+/// Create machine with memory for a process and load `elf` into it
+pub fn process<P: Into<PathBuf>>(elf: &Elf, args: &[String], env: &[String], root: P) -> Result<RV32, ElfError> {
+    let mut cpu = RV32::new();
+
+    let range = MemoryRange::new(Word(0), MEMORY_SIZE);
+    cpu.bus.connect(Device::new(range, Box::new(Ram::new(MEMORY_SIZE.0 as usize)))).unwrap();
+
+    let linux = LinuxUser::new(root);
+    linux.load(&cpu, elf, args, env)?;
+    cpu.linux = Some(linux);
+
+    Ok(cpu)
+}
+
+/// Guest passed memory that can't be accessed
+fn efault(_: BusError) -> i32 {
+    EFAULT
+}
+
+/// Read into `data` until it is full or the end of file is reached
+fn read_full(file: &mut File, data: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < data.len() {
+        match file.read(&mut data[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// Round `address` up to a page boundary, `None` past the end of address space
+fn page_align(address: Word) -> Option<Word> {
+    let end = address.0.checked_add(PAGE_SIZE - 1)?;
+    Some(Word(end & !(PAGE_SIZE - 1)))
+}
+
+fn errno(err: &io::Error) -> i32 {
+    match err.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        _ => err.raw_os_error().unwrap_or(EINVAL),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Process memory without a program, `root` is a fresh directory
+    fn machine(name: &str) -> (RV32, LinuxUser, PathBuf) {
+        let root = std::env::temp_dir().join(format!("linux-user-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let mut cpu = RV32::new();
+        let range = MemoryRange::new(Word(0), MEMORY_SIZE);
+        cpu.bus.connect(Device::new(range, Box::new(Ram::new(MEMORY_SIZE.0 as usize)))).unwrap();
+        (cpu, LinuxUser::new(&root), root)
+    }
+
+    fn syscall(linux: &LinuxUser, cpu: &RV32, number: u32, args: &[u32]) -> i32 {
+        for (i, arg) in args.iter().enumerate() {
+            cpu.reg.write_gpr(Word(10 + i as u32), Word(*arg)).unwrap();
+        }
+        cpu.reg.write("a7", Word(number)).unwrap();
+        linux.syscall(cpu).unwrap();
+        cpu.reg.read("a0").unwrap().signed()
+    }
+
+    #[test]
+    fn huge_mmap_fails_with_enomem() {
+        let (cpu, linux, root) = machine("mmap-huge");
+
+        let result = syscall(&linux, &cpu, SYS_MMAP2, &[0, u32::MAX, 3, MAP_ANONYMOUS, u32::MAX, 0]);
+        assert_eq!(result, -ENOMEM);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn file_mapping_is_filled_past_one_chunk() {
+        let (cpu, linux, root) = machine("mmap-file");
+        let contents: Vec<u8> = (0..MAX_TRANSFER + 100).map(|i| (i % 251) as u8).collect();
+        std::fs::write(root.join("data"), &contents).unwrap();
+
+        let fd = linux.open(AT_FDCWD, "data", 0).unwrap();
+        let address = syscall(&linux, &cpu, SYS_MMAP2, &[0, 2 * MAX_TRANSFER, 1, 0, fd, 0]) as u32;
+        assert_eq!(address, MMAP_BASE.0);
+
+        let tail = cpu.bus.read_bytes(Word(address + MAX_TRANSFER), 100).unwrap();
+        assert_eq!(tail, &contents[MAX_TRANSFER as usize..]);
+        assert_eq!(cpu.bus.read_byte(Word(address + MAX_TRANSFER + 100)), 0);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn getrandom_returns_short_count() {
+        let (cpu, linux, root) = machine("getrandom");
+
+        assert_eq!(syscall(&linux, &cpu, SYS_GETRANDOM, &[0x1000, u32::MAX, 0]), MAX_TRANSFER as i32);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn iovec_arrays_are_checked() {
+        let (cpu, linux, root) = machine("iovec");
+
+        assert_eq!(syscall(&linux, &cpu, SYS_WRITEV, &[1, 0x1000, IOV_MAX + 1]), -EINVAL);
+        assert_eq!(syscall(&linux, &cpu, SYS_WRITEV, &[1, 0xFFFF_FFF8, 1]), -EFAULT);
+        assert_eq!(syscall(&linux, &cpu, SYS_READV, &[0, u32::MAX, IOV_MAX]), -EFAULT);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn symbolic_links_do_not_escape_the_root() {
+        let (_cpu, linux, root) = machine("symlink");
+        let outside = std::env::temp_dir().join(format!("linux-user-outside-{}", std::process::id()));
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("passwd"), b"root").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("etc")).unwrap();
+
+        assert_eq!(linux.open(AT_FDCWD, "/etc/passwd", 0), Err(EACCES));
+        assert_eq!(linux.open(AT_FDCWD, "/etc/shadow", O_CREAT | 1), Err(EACCES));
+        assert!(!outside.join("shadow").exists());
+
+        std::fs::remove_dir_all(outside).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Static executable with one segment at 0x10000 covering its headers,
+    /// `code` starting at 0x10080 and `data` at 0x100C0
+    fn static_elf(code: &[u32], data: &[u8]) -> Vec<u8> {
+        let mut elf = vec![0u8; 0xC0];
+        let size = (0xC0 + data.len()) as u32;
+
+        elf[0..7].copy_from_slice(b"\x7FELF\x01\x01\x01");
+        let header = [(16, 2u32, 2), (18, 0xF3, 2), (20, 1, 4), (24, 0x10080, 4), (28, 52, 4),
+                      (40, 52, 2), (42, 32, 2), (44, 1, 2)];
+        // Program header: PT_LOAD, offset, vaddr, paddr, filesz, memsz, flags, align
+        let program_header = [1, 0, 0x10000, 0x10000, size, size, 5, 0x1000];
+        let fields = header.into_iter()
+            .chain(program_header.into_iter().enumerate().map(|(i, value)| (52 + 4 * i, value, 4)));
+        for (offset, value, width) in fields {
+            elf[offset..offset + width].copy_from_slice(&value.to_le_bytes()[..width]);
+        }
+
+        for (i, instruction) in code.iter().enumerate() {
+            elf[0x80 + 4 * i..0x84 + 4 * i].copy_from_slice(&instruction.to_le_bytes());
+        }
+        elf.extend_from_slice(data);
+        elf
+    }
+
+    fn strings(strings: &[&str]) -> Vec<String> {
+        strings.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn initial_stack_holds_arguments_environment_and_auxv() {
+        let (cpu, linux, root) = machine("stack");
+        let elf = Elf::parse(&static_elf(&[], &[])).unwrap();
+        linux.load(&cpu, &elf, &strings(&["prog", "-v"]), &strings(&["HOME=/"])).unwrap();
+
+        let sp = cpu.reg.read("sp").unwrap();
+        assert_eq!(sp.0 % 16, 0);
+        assert_eq!(cpu.reg.read("pc").unwrap(), Word(0x10080));
+
+        let word = |n: u32| cpu.bus.read(sp + Word(4 * n), AccessWidth::Word).unwrap();
+        let string = |address: Word| String::from_utf8(cpu.bus.read_cstr(address).unwrap()).unwrap();
+        assert_eq!(word(0), Word(2));
+        assert_eq!(string(word(1)), "prog");
+        assert_eq!(string(word(2)), "-v");
+        assert_eq!(word(3), Word(0));
+        assert_eq!(string(word(4)), "HOME=/");
+        assert_eq!(word(5), Word(0));
+
+        let auxv: Vec<(u32, Word)> = (0..).map(|i| (word(6 + 2 * i).0, word(7 + 2 * i)))
+            .take_while(|(key, _)| *key != AT_NULL)
+            .collect();
+        let aux = |key: u32| auxv.iter().find(|(k, _)| *k == key).unwrap().1;
+        assert_eq!(aux(AT_PHDR), Word(0x10034));
+        assert_eq!(aux(AT_PHNUM), Word(1));
+        assert_eq!(aux(AT_ENTRY), Word(0x10080));
+        assert_eq!(aux(AT_PAGESZ), Word(PAGE_SIZE));
+
+        // Random bytes sit above everything else on the stack
+        let random = aux(AT_RANDOM);
+        assert_eq!(random, STACK_TOP - Word(16));
+        assert_ne!(cpu.bus.read_bytes(random, 16).unwrap(), vec![0; 16]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn files_are_written_and_read_back() {
+        let (cpu, linux, root) = machine("files");
+        cpu.bus.load(Word(0x1000), b"/notes\0hello").unwrap();
+
+        let fd = syscall(&linux, &cpu, SYS_OPENAT, &[AT_FDCWD as u32, 0x1000, O_CREAT | O_TRUNC | 1]);
+        assert_eq!(fd, 3);
+        assert_eq!(syscall(&linux, &cpu, SYS_WRITE, &[fd as u32, 0x1007, 5]), 5);
+        assert_eq!(syscall(&linux, &cpu, SYS_CLOSE, &[fd as u32]), 0);
+        assert_eq!(std::fs::read(root.join("notes")).unwrap(), b"hello");
+
+        let fd = syscall(&linux, &cpu, SYS_OPENAT, &[AT_FDCWD as u32, 0x1000, 0]);
+        assert_eq!(syscall(&linux, &cpu, SYS_READ, &[fd as u32, 0x2000, 100]), 5);
+        assert_eq!(cpu.bus.read_bytes(Word(0x2000), 5).unwrap(), b"hello");
+        assert_eq!(syscall(&linux, &cpu, SYS_READ, &[fd as u32, 0x2000, 100]), 0);
+        assert_eq!(syscall(&linux, &cpu, SYS_WRITE, &[fd as u32, 0x1007, 5]), -EBADF);
+        assert_eq!(syscall(&linux, &cpu, SYS_CLOSE, &[fd as u32]), 0);
+        assert_eq!(syscall(&linux, &cpu, SYS_CLOSE, &[fd as u32]), -EBADF);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn openat_stays_inside_the_root() {
+        let (cpu, linux, root) = machine("openat");
+        cpu.bus.load(Word(0x1000), b"/../escape\0data\0").unwrap();
+
+        assert_eq!(syscall(&linux, &cpu, SYS_OPENAT, &[AT_FDCWD as u32, 0x1000, O_CREAT | 1]), -EACCES);
+        // Relative to a directory descriptor
+        assert_eq!(syscall(&linux, &cpu, SYS_OPENAT, &[0, 0x100B, 0]), -EACCES);
+        assert_eq!(syscall(&linux, &cpu, SYS_OPENAT, &[AT_FDCWD as u32, 0x100B, 0]), -ENOENT);
+        assert!(!root.parent().unwrap().join("escape").exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn brk_grows_the_heap_with_zeroed_memory() {
+        let (cpu, linux, root) = machine("brk");
+        let elf = Elf::parse(&static_elf(&[], &[0xAA; 0x100])).unwrap();
+        linux.load(&cpu, &elf, &[], &[]).unwrap();
+
+        let heap = syscall(&linux, &cpu, SYS_BRK, &[0]) as u32;
+        assert_eq!(heap, 0x11000);
+        assert_eq!(syscall(&linux, &cpu, SYS_BRK, &[heap + 0x2000]) as u32, heap + 0x2000);
+        cpu.bus.load(Word(heap + 0x1000), &[0x55; 16]).unwrap();
+
+        // Shrinking and growing again hands out zeroed memory
+        assert_eq!(syscall(&linux, &cpu, SYS_BRK, &[heap]) as u32, heap);
+        assert_eq!(syscall(&linux, &cpu, SYS_BRK, &[heap + 0x2000]) as u32, heap + 0x2000);
+        assert_eq!(cpu.bus.read_bytes(Word(heap + 0x1000), 16).unwrap(), vec![0; 16]);
+
+        // Requests below the program or into the mappings are refused
+        assert_eq!(syscall(&linux, &cpu, SYS_BRK, &[0x10000]) as u32, heap + 0x2000);
+        assert_eq!(syscall(&linux, &cpu, SYS_BRK, &[MMAP_BASE.0 + 1]) as u32, heap + 0x2000);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn exit_group_halts_with_its_status() {
+        let (cpu, linux, root) = machine("exit");
+
+        syscall(&linux, &cpu, SYS_EXIT_GROUP, &[(-3i32) as u32]);
+        assert_eq!(cpu.halted(), Some(Halt::Exit(-3)));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unmapped_ranges_are_reused() {
+        let (cpu, linux, root) = machine("munmap");
+        let map = |length: u32| syscall(&linux, &cpu, SYS_MMAP2, &[0, length, 3, MAP_ANONYMOUS, u32::MAX, 0]) as u32;
+
+        let (first, second, third) = (map(0x3000), map(0x1000), map(0x1000));
+        assert_eq!((first, second, third), (MMAP_BASE.0, MMAP_BASE.0 + 0x3000, MMAP_BASE.0 + 0x4000));
+        cpu.bus.load(Word(first), &[0x55; 16]).unwrap();
+
+        assert_eq!(syscall(&linux, &cpu, SYS_MUNMAP, &[first, 0x3000]), 0);
+        assert_eq!(map(0x2000), first);
+        assert_eq!(cpu.bus.read_bytes(Word(first), 16).unwrap(), vec![0; 16]);
+        assert_eq!(map(0x2000), MMAP_BASE.0 + 0x5000);
+
+        // Freeing the top mappings lowers the next address
+        assert_eq!(syscall(&linux, &cpu, SYS_MUNMAP, &[third, 0x3000]), 0);
+        assert_eq!(syscall(&linux, &cpu, SYS_MUNMAP, &[second, 1]), 0);
+        assert_eq!(map(0x3000), first + 0x2000);
+
+        assert_eq!(syscall(&linux, &cpu, SYS_MUNMAP, &[first + 1, 0x1000]), -EINVAL);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn static_program_runs_to_exit() {
+        let root = std::env::temp_dir().join(format!("linux-user-process-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let code = [
+            0x00012403, // lw s0, 0(sp)          argc
+            0xf9c00513, // li a0, -100           AT_FDCWD
+            0x000105b7, // lui a1, 0x10
+            0x0c058593, // addi a1, a1, 0xc0     "/out"
+            0x24100613, // li a2, 0x241          O_CREAT | O_TRUNC | O_WRONLY
+            0x03800893, // li a7, 56             openat
+            0x00000073, // ecall
+            0x00412583, // lw a1, 4(sp)          argv[0]
+            0x00400613, // li a2, 4
+            0x04000893, // li a7, 64             write
+            0x00000073, // ecall
+            0x00040513, // mv a0, s0
+            0x05e00893, // li a7, 94             exit_group
+            0x00000073, // ecall
+        ];
+        let elf = Elf::parse(&static_elf(&code, b"/out\0")).unwrap();
+
+        let cpu = process(&elf, &strings(&["prog", "x", "y"]), &[], &root).unwrap();
+        assert_eq!(cpu.run(), Ok(Halt::Exit(3)));
+        assert_eq!(std::fs::read(root.join("out")).unwrap(), b"prog");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
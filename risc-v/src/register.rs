@@ -1,5 +1,5 @@
 use crate::{Word, exception::Exception};
-use std::{cell::Cell, fmt::Debug, ops::Not};
+use std::{cell::{Cell, RefCell}, fmt::Debug, ops::Not};
 #[derive(Debug, Clone)]
 pub struct Register {
     pub aliases: Vec<String>,
@@ -43,6 +43,9 @@ pub struct RV32IRegisters {
 
     /// General purpose RV32I base registers
     base: [Register; 32],
+
+    /// Writes to general purpose registers, collected while recording
+    write_log: RefCell<Option<Vec<(Word, Word)>>>,
 }
 impl RV32IRegisters {
     pub fn new() -> Self {
@@ -59,13 +62,14 @@ impl RV32IRegisters {
                 vec!["x24", "s8"],       vec!["x25", "s9"], vec!["x26", "s10"], vec!["x27", "s11"],
                 vec!["x28", "t3"],       vec!["x29", "t4"], vec!["x30", "t5"],  vec!["x31", "t6"]
             },
+            write_log: RefCell::new(None),
         }
     }
 
     /// Read general purpose integer register; 
     /// Valid indexes are between 0 and 31
     pub fn read_gpr(&self, idx: Word) -> Result<Word, Exception> {
-        if (0..31).contains(&(idx.0 as usize)).not() {
+        if (0..32).contains(&(idx.0 as usize)).not() {
             return Err(Exception::InvalidRegister);
         }
 
@@ -75,7 +79,7 @@ impl RV32IRegisters {
     /// Write to general purpose integer register; 
     /// Valid indexes are between 0 and 31
     pub fn write_gpr(&self, idx: Word, word: Word) -> Result<(), Exception> {
-        if (0..31).contains(&(idx.0 as usize)).not() {
+        if (0..32).contains(&(idx.0 as usize)).not() {
             return Err(Exception::InvalidRegister);
         }
 
@@ -85,9 +89,34 @@ impl RV32IRegisters {
         }
 
         self.base[idx.0 as usize].write(word);
+
+        if let Some(log) = self.write_log.borrow_mut().as_mut() {
+            log.push((idx, word));
+        }
+
         Ok(())
     }
 
+    /// Clear the program counter and every general purpose register
+    pub fn reset(&self) {
+        self.pc.write(Word(0));
+        for register in &self.base {
+            register.write(Word(0));
+        }
+    }
+
+    /// Start collecting writes to general purpose registers,
+    /// previously collected writes are discarded
+    pub fn record_writes(&self) {
+        *self.write_log.borrow_mut() = Some(vec![]);
+    }
+
+    /// Stop collecting writes and return `(index, value)` pairs
+    /// in the order they were written
+    pub fn take_writes(&self) -> Vec<(Word, Word)> {
+        self.write_log.borrow_mut().take().unwrap_or_default()
+    }
+
     /// Read from register identified by name
     pub fn read(&self, name: &str) -> Result<Word, Exception> {
         if name == "pc" {
@@ -141,7 +170,7 @@ impl RV32IRegisters {
 
     /// Get a reference to base register by index
     pub fn get_gpr(&self, idx: Word) -> Option<&Register> {
-        if (0..31).contains(&(idx.0 as usize)).not() {
+        if (0..32).contains(&(idx.0 as usize)).not() {
             return None;
         }
 
@@ -174,4 +203,21 @@ impl Debug for RV32IRegisters {
             self.read("x28").unwrap().0, self.read("x29").unwrap().0, self.read("x30").unwrap().0, self.read("x31").unwrap().0,
         )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RV32IRegisters;
+    use crate::{Word, exception::Exception};
+
+    #[test]
+    fn x31_is_accessible() {
+        let reg = RV32IRegisters::new();
+        reg.write_gpr(Word(31), Word(0x1234)).unwrap();
+
+        assert_eq!(reg.read_gpr(Word(31)), Ok(Word(0x1234)));
+        assert_eq!(reg.read("t6"), Ok(Word(0x1234)));
+        assert!(reg.get_gpr(Word(31)).is_some());
+        assert_eq!(reg.read_gpr(Word(32)), Err(Exception::InvalidRegister));
+    }
+}
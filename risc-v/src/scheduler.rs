@@ -0,0 +1,176 @@
+//! Cycle based event queue
+//!
+//! Time is counted in cycles, every executed instruction takes one. Devices
+//! schedule callbacks at future cycles instead of being polled, and the
+//! machine can skip straight to the next event while it has nothing to do.
+use crate::bus::{Bus, DeviceHandle};
+
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::rc::Rc;
+
+pub type Cycle = u64;
+
+/// Function run when its cycle is reached
+pub type Callback = Box<dyn FnOnce(&Bus)>;
+
+/// Identifies a scheduled event, so it can be cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventId(u64);
+
+/// What a device receives when it is connected to the bus
+#[derive(Clone)]
+pub struct DeviceContext {
+    /// Handle of the device itself, to reach it from event callbacks
+    pub handle: DeviceHandle,
+    pub scheduler: Rc<Scheduler>,
+}
+
+pub struct Scheduler {
+    now: Cell<Cycle>,
+    next_id: Cell<u64>,
+
+    /// Pending events ordered by cycle, then by the order they were scheduled
+    queue: RefCell<BinaryHeap<Reverse<(Cycle, u64)>>>,
+
+    /// Callbacks of pending events, cancelled events are removed from here only
+    callbacks: RefCell<HashMap<u64, Callback>>,
+}
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            now: Cell::new(0),
+            next_id: Cell::new(0),
+            queue: RefCell::new(BinaryHeap::new()),
+            callbacks: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Current cycle
+    pub fn now(&self) -> Cycle {
+        self.now.get()
+    }
+
+    /// Run `callback` `delay` cycles from now
+    pub fn schedule<F: FnOnce(&Bus) + 'static>(&self, delay: Cycle, callback: F) -> EventId {
+        self.schedule_at(self.now() + delay, callback)
+    }
+
+    /// Run `callback` at `cycle`, events in the past run on the next advance
+    pub fn schedule_at<F: FnOnce(&Bus) + 'static>(&self, cycle: Cycle, callback: F) -> EventId {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+
+        self.queue.borrow_mut().push(Reverse((cycle, id)));
+        self.callbacks.borrow_mut().insert(id, Box::new(callback));
+        EventId(id)
+    }
+
+    /// Remove pending event, return `false` if it already ran or was cancelled
+    pub fn cancel(&self, event: EventId) -> bool {
+        self.callbacks.borrow_mut().remove(&event.0).is_some()
+    }
+
+    /// Return `true` if the event is still waiting to run
+    pub fn is_pending(&self, event: EventId) -> bool {
+        self.callbacks.borrow().contains_key(&event.0)
+    }
+
+    /// Cycle of the earliest pending event
+    pub fn next_event(&self) -> Option<Cycle> {
+        let mut queue = self.queue.borrow_mut();
+        let callbacks = self.callbacks.borrow();
+
+        // Drop cancelled events from the top of the queue
+        while let Some(Reverse((cycle, id))) = queue.peek() {
+            if callbacks.contains_key(id) {
+                return Some(*cycle);
+            }
+            queue.pop();
+        }
+        None
+    }
+
+    /// Move time forward to `cycle`, running every event due until then
+    /// in order. Callbacks see `now` at the cycle they were scheduled for.
+    pub fn advance_to(&self, cycle: Cycle, bus: &Bus) {
+        while let Some(due) = self.next_event().filter(|due| *due <= cycle) {
+            let Reverse((_, id)) = self.queue.borrow_mut().pop().unwrap();
+            let callback = self.callbacks.borrow_mut().remove(&id).unwrap();
+
+            self.now.set(self.now().max(due));
+            callback(bus);
+        }
+
+        self.now.set(self.now().max(cycle));
+    }
+}
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Callback appending `(label, now)` to `log`
+    fn record(log: &Rc<RefCell<Vec<(&'static str, Cycle)>>>, label: &'static str) -> impl FnOnce(&Bus) {
+        let log = log.clone();
+        move |bus: &Bus| log.borrow_mut().push((label, bus.now()))
+    }
+
+    #[test]
+    fn events_run_in_order_at_their_cycle() {
+        let bus = Bus::new();
+        let scheduler = bus.scheduler().clone();
+        let log = Rc::new(RefCell::new(vec![]));
+
+        scheduler.schedule(10, record(&log, "late"));
+        scheduler.schedule(5, record(&log, "first"));
+        scheduler.schedule(5, record(&log, "second"));
+
+        bus.advance(4);
+        assert!(log.borrow().is_empty());
+        assert_eq!(scheduler.next_event(), Some(5));
+
+        bus.advance(6);
+        assert_eq!(*log.borrow(), vec![("first", 5), ("second", 5), ("late", 10)]);
+        assert_eq!(bus.now(), 10);
+        assert_eq!(scheduler.next_event(), None);
+    }
+
+    #[test]
+    fn cancelled_events_dont_run() {
+        let bus = Bus::new();
+        let scheduler = bus.scheduler().clone();
+        let log = Rc::new(RefCell::new(vec![]));
+
+        let cancelled = scheduler.schedule(3, record(&log, "cancelled"));
+        scheduler.schedule(7, record(&log, "kept"));
+        assert!(scheduler.cancel(cancelled));
+        assert!(!scheduler.is_pending(cancelled));
+        assert!(!scheduler.cancel(cancelled));
+
+        assert_eq!(scheduler.next_event(), Some(7));
+        assert!(bus.skip_to_next_event());
+        assert_eq!(*log.borrow(), vec![("kept", 7)]);
+        assert!(!bus.skip_to_next_event());
+    }
+
+    #[test]
+    fn callbacks_can_schedule_more_events() {
+        let bus = Bus::new();
+        let log = Rc::new(RefCell::new(vec![]));
+
+        let chained = record(&log, "chained");
+        bus.scheduler().schedule(2, move |bus| {
+            bus.scheduler().schedule(0, chained);
+        });
+
+        bus.advance(2);
+        assert_eq!(*log.borrow(), vec![("chained", 2)]);
+    }
+}
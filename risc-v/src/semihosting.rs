@@ -0,0 +1,489 @@
+//! RISC-V semihosting
+//!
+//! A semihosting call is an `ebreak` surrounded by two marker instructions:
+//!
+//! ```text
+//! slli x0, x0, 0x1f
+//! ebreak
+//! srai x0, x0, 7
+//! ```
+//!
+//! Operation number is passed in `a0` and its parameter, usually a pointer
+//! to a parameter block, in `a1`. Result is returned in `a0`. Files opened
+//! by the guest are resolved inside a sandboxed root directory.
+use crate::{RV32, Word, Halt};
+use crate::bus::{AccessWidth, BusError};
+use crate::exception::Exception;
+
+use std::cell::{Cell, RefCell};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// `slli x0, x0, 0x1f` placed before the `ebreak`
+pub const ENTRY_MARKER: Word = Word(0x01f01013);
+
+/// `srai x0, x0, 7` placed after the `ebreak`
+pub const EXIT_MARKER: Word = Word(0x40705013);
+
+pub const SYS_OPEN: u32 = 0x01;
+pub const SYS_CLOSE: u32 = 0x02;
+pub const SYS_WRITEC: u32 = 0x03;
+pub const SYS_WRITE0: u32 = 0x04;
+pub const SYS_WRITE: u32 = 0x05;
+pub const SYS_READ: u32 = 0x06;
+pub const SYS_READC: u32 = 0x07;
+pub const SYS_ISERROR: u32 = 0x08;
+pub const SYS_ISTTY: u32 = 0x09;
+pub const SYS_SEEK: u32 = 0x0A;
+pub const SYS_FLEN: u32 = 0x0C;
+pub const SYS_CLOCK: u32 = 0x10;
+pub const SYS_TIME: u32 = 0x11;
+pub const SYS_ERRNO: u32 = 0x13;
+pub const SYS_GET_CMDLINE: u32 = 0x15;
+pub const SYS_EXIT: u32 = 0x18;
+pub const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// Reason passed to `SYS_EXIT` when application finished normally
+pub const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+/// Special file name that opens the host console
+const CONSOLE: &str = ":tt";
+
+/// Error numbers returned by `SYS_ERRNO`
+const ENOENT: i32 = 2;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+const ENAMETOOLONG: i32 = 36;
+
+/// Longest file name accepted by `SYS_OPEN`, including terminating null
+const PATH_MAX: usize = 4096;
+
+/// Largest transfer of a single `SYS_READ` or `SYS_WRITE`, the guest is told
+/// how many bytes are left and has to call again for the rest. The count is
+/// clamped to `i32::MAX` so it is never mistaken for an error.
+const MAX_TRANSFER: usize = 64 * 1024;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Host side of semihosting calls
+pub struct Semihosting {
+    root: PathBuf,
+    args: Vec<String>,
+    handles: RefCell<Vec<Option<Handle>>>,
+    errno: Cell<i32>,
+    started: Instant,
+}
+impl Semihosting {
+    /// Files opened by the guest are looked up relative to `root`
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            args: vec![],
+            handles: RefCell::new(vec![]),
+            errno: Cell::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Command line returned by `SYS_GET_CMDLINE`, including program name
+    pub fn with_args(mut self, args: Vec<String>) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Return `true` if `ebreak` at `pc` is surrounded by the semihosting markers
+    pub fn is_call(cpu: &RV32, pc: Word) -> bool {
+        cpu.bus.read_le_word(pc - Word(4)) == ENTRY_MARKER
+            && cpu.bus.read_le_word(pc + Word(4)) == EXIT_MARKER
+    }
+
+    /// Perform call requested by registers of `cpu` and write result to `a0`
+    pub fn call(&self, cpu: &RV32) -> Result<(), Exception> {
+        let operation = cpu.reg.read("a0")?;
+        let parameter = cpu.reg.read("a1")?;
+
+        let result = match self.perform(cpu, operation.0, parameter) {
+            Ok(result) => result,
+            // Guest passed memory that can't be accessed
+            Err(_) => self.fail(EFAULT),
+        };
+
+        cpu.reg.write_gpr(Word(10), Word(result as u32))
+    }
+
+    fn perform(&self, cpu: &RV32, operation: u32, parameter: Word) -> Result<i32, BusError> {
+        // Parameter block fields are 32-bit words
+        let field = |n: u32| cpu.bus.read(parameter + Word(4 * n), AccessWidth::Word);
+
+        let result: i32 = match operation {
+            SYS_OPEN => {
+                let length = field(2)?.0 as usize;
+                if length >= PATH_MAX {
+                    self.fail(ENAMETOOLONG)
+                } else {
+                    let name = cpu.bus.read_bytes(field(0)?, length)?;
+                    self.open(&String::from_utf8_lossy(&name), field(1)?.0)
+                }
+            }
+            SYS_CLOSE => {
+                let handle = field(0)?.0 as usize;
+                match self.handles.borrow_mut().get_mut(handle).and_then(Option::take) {
+                    Some(_) => 0,
+                    None => self.fail(EBADF),
+                }
+            }
+            SYS_WRITEC => {
+                let byte = cpu.bus.read(parameter, AccessWidth::Byte)?.0 as u8;
+                let _ = io::stdout().write_all(&[byte]);
+                0
+            }
+            SYS_WRITE0 => {
+                let string = cpu.bus.read_cstr(parameter)?;
+                let _ = io::stdout().write_all(&string);
+                0
+            }
+            SYS_WRITE => {
+                let (handle, buffer, length) = (field(0)?.0 as usize, field(1)?, field(2)?.0 as usize);
+                let data = cpu.bus.read_bytes(buffer, length.min(MAX_TRANSFER))?;
+
+                match self.with_handle(handle, |h| h.write(&data)) {
+                    // Number of bytes that were not written
+                    Ok(written) => remainder(length, written),
+                    Err(errno) => self.fail(errno),
+                }
+            }
+            SYS_READ => {
+                let (handle, buffer, length) = (field(0)?.0 as usize, field(1)?, field(2)?.0 as usize);
+                let mut data = vec![0; length.min(MAX_TRANSFER)];
+
+                match self.with_handle(handle, |h| h.read(&mut data)) {
+                    Ok(read) => {
+                        cpu.bus.load(buffer, &data[..read])?;
+                        // Number of bytes that were not read
+                        remainder(length, read)
+                    }
+                    Err(errno) => self.fail(errno),
+                }
+            }
+            SYS_READC => {
+                let mut byte = [0];
+                match io::stdin().read_exact(&mut byte) {
+                    Ok(_) => byte[0] as i32,
+                    Err(err) => self.fail(errno(&err)),
+                }
+            }
+            SYS_ISERROR => {
+                (field(0)?.signed() < 0) as i32
+            }
+            SYS_ISTTY => {
+                let handle = field(0)?.0 as usize;
+                match self.handles.borrow().get(handle) {
+                    Some(Some(Handle::File(_))) => 0,
+                    Some(Some(_)) => 1,
+                    _ => self.fail(EBADF),
+                }
+            }
+            SYS_SEEK => {
+                let (handle, position) = (field(0)?.0 as usize, field(1)?.0 as u64);
+                match self.with_handle(handle, |h| h.seek(position)) {
+                    Ok(_) => 0,
+                    Err(errno) => self.fail(errno),
+                }
+            }
+            SYS_FLEN => {
+                let handle = field(0)?.0 as usize;
+                match self.with_handle(handle, |h| h.len()) {
+                    Ok(length) => length as i32,
+                    Err(errno) => self.fail(errno),
+                }
+            }
+            SYS_CLOCK => {
+                // Centiseconds since the machine started
+                (self.started.elapsed().as_millis() / 10) as i32
+            }
+            SYS_TIME => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                now.as_secs() as i32
+            }
+            SYS_ERRNO => {
+                self.errno.get()
+            }
+            SYS_GET_CMDLINE => {
+                let (buffer, length) = (field(0)?, field(1)?.0 as usize);
+                let mut cmdline = self.args.join(" ").into_bytes();
+                cmdline.push(0);
+
+                if cmdline.len() > length {
+                    self.fail(EINVAL)
+                } else {
+                    cpu.bus.load(buffer, &cmdline)?;
+                    // Length of the command line without terminating null
+                    cpu.bus.load(parameter + Word(4), &(cmdline.len() as u32 - 1).to_le_bytes())?;
+                    0
+                }
+            }
+            SYS_EXIT => {
+                // On RV32 the parameter is the reason itself,
+                // there is no room for an exit code
+                let code = match parameter.0 {
+                    ADP_STOPPED_APPLICATION_EXIT => 0,
+                    _ => 1,
+                };
+                cpu.halt(Halt::Exit(code));
+                0
+            }
+            SYS_EXIT_EXTENDED => {
+                let code = match field(0)?.0 {
+                    ADP_STOPPED_APPLICATION_EXIT => field(1)?.signed(),
+                    _ => 1,
+                };
+                cpu.halt(Halt::Exit(code));
+                0
+            }
+            _ => self.fail(EINVAL),
+        };
+
+        Ok(result)
+    }
+
+    /// Open file in `mode` (index into `r`, `rb`, `r+`, `r+b`, `w`, `wb`, ...
+    /// `a+b` as specified by `fopen`) and return its handle
+    fn open(&self, name: &str, mode: u32) -> i32 {
+        let handle = match name {
+            CONSOLE => match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            },
+            _ => {
+                let Some(path) = self.sandboxed(name) else {
+                    return self.fail(EACCES);
+                };
+
+                let mut options = OpenOptions::new();
+                match mode / 4 {
+                    0 => options.read(true).write(mode & 2 != 0),
+                    1 => options.write(true).create(true).truncate(true).read(mode & 2 != 0),
+                    2 => options.append(true).create(true).read(mode & 2 != 0),
+                    _ => return self.fail(EINVAL),
+                };
+
+                match options.open(path) {
+                    Ok(file) => Handle::File(file),
+                    Err(err) => return self.fail(errno(&err)),
+                }
+            }
+        };
+
+        let mut handles = self.handles.borrow_mut();
+        match handles.iter().position(Option::is_none) {
+            Some(free) => {
+                handles[free] = Some(handle);
+                free as i32
+            }
+            None => {
+                handles.push(Some(handle));
+                handles.len() as i32 - 1
+            }
+        }
+    }
+
+    /// Resolve guest path inside the root directory, `None` if it would escape it
+    fn sandboxed(&self, name: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => (),
+                _ => return None,
+            }
+        }
+
+        contained(&self.root, &path)
+    }
+
+    fn with_handle<T, F>(&self, handle: usize, f: F) -> Result<T, i32>
+    where
+        F: FnOnce(&mut Handle) -> io::Result<T>,
+    {
+        let mut handles = self.handles.borrow_mut();
+        let handle = handles.get_mut(handle).and_then(Option::as_mut).ok_or(EBADF)?;
+        f(handle).map_err(|err| errno(&err))
+    }
+
+    /// Remember error number for `SYS_ERRNO` and return `-1`
+    fn fail(&self, errno: i32) -> i32 {
+        self.errno.set(errno);
+        -1
+    }
+}
+
+impl Handle {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Handle::Stdin => Err(io::Error::from_raw_os_error(EBADF)),
+            Handle::Stdout => io::stdout().write_all(data).map(|_| data.len()),
+            Handle::Stderr => io::stderr().write_all(data).map(|_| data.len()),
+            Handle::File(file) => file.write_all(data).map(|_| data.len()),
+        }
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Handle::Stdin => io::stdin().read(data),
+            Handle::File(file) => file.read(data),
+            _ => Err(io::Error::from_raw_os_error(EBADF)),
+        }
+    }
+
+    fn seek(&mut self, position: u64) -> io::Result<u64> {
+        match self {
+            Handle::File(file) => file.seek(SeekFrom::Start(position)),
+            _ => Err(io::Error::from_raw_os_error(EBADF)),
+        }
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        match self {
+            Handle::File(file) => file.metadata().map(|m| m.len()),
+            _ => Err(io::Error::from_raw_os_error(EBADF)),
+        }
+    }
+}
+
+/// Bytes of a `length` transfer that were not done, as a non-negative result
+fn remainder(length: usize, done: usize) -> i32 {
+    (length - done).min(i32::MAX as usize) as i32
+}
+
+/// Check that `path`, built from components of a guest path below `root`,
+/// stays inside `root` once the host follows its symbolic links. A path that
+/// doesn't exist yet is checked through its nearest existing directory.
+pub(crate) fn contained(root: &Path, path: &Path) -> Option<PathBuf> {
+    let root = root.canonicalize().ok()?;
+
+    let mut existing = path;
+    let mut missing = vec![];
+    let mut resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => break resolved,
+            // Dangling link, creating the file would follow it
+            Err(_) if existing.symlink_metadata().is_ok() => return None,
+            Err(_) => {
+                missing.push(existing.file_name()?);
+                existing = existing.parent()?;
+            }
+        }
+    };
+    resolved.extend(missing.into_iter().rev());
+
+    resolved.starts_with(&root).then_some(resolved)
+}
+
+fn errno(err: &io::Error) -> i32 {
+    match err.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        _ => err.raw_os_error().unwrap_or(EINVAL),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRange;
+    use crate::devices::{Device, ram::Ram};
+
+    /// Machine with RAM at 0 and a semihosting root in a fresh directory
+    fn machine(name: &str) -> (RV32, Semihosting, PathBuf) {
+        let root = std::env::temp_dir().join(format!("semihosting-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let mut cpu = RV32::new();
+        let ram = Ram::new(0x1000);
+        cpu.bus.connect(Device::new(MemoryRange::new(Word(0), Word(0x1000)), Box::new(ram))).unwrap();
+        (cpu, Semihosting::new(&root), root)
+    }
+
+    fn call(semihosting: &Semihosting, cpu: &RV32, operation: u32, parameter: u32) -> i32 {
+        cpu.reg.write("a0", Word(operation)).unwrap();
+        cpu.reg.write("a1", Word(parameter)).unwrap();
+        semihosting.call(cpu).unwrap();
+        cpu.reg.read("a0").unwrap().signed()
+    }
+
+    #[test]
+    fn read_is_clamped_and_reports_remaining_bytes() {
+        let (cpu, semihosting, root) = machine("read");
+        std::fs::write(root.join("data"), b"hello").unwrap();
+
+        // Parameter block at 0x100, file name at 0x200, buffer at 0x300
+        cpu.bus.load(Word(0x200), b"data").unwrap();
+        cpu.bus.load(Word(0x100), &[0x200u32, 1, 4].map(u32::to_le_bytes).concat()).unwrap();
+        let handle = call(&semihosting, &cpu, SYS_OPEN, 0x100);
+        assert!(handle >= 0);
+
+        // Guest asks for 4 GiB, host must not allocate it nor report an error
+        cpu.bus.load(Word(0x100), &[handle as u32, 0x300, u32::MAX].map(u32::to_le_bytes).concat()).unwrap();
+        assert_eq!(call(&semihosting, &cpu, SYS_READ, 0x100), i32::MAX);
+        assert_eq!(cpu.bus.read_bytes(Word(0x300), 5), Ok(b"hello".to_vec()));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn bad_parameter_block_fails_with_efault() {
+        let (cpu, semihosting, root) = machine("efault");
+
+        assert_eq!(call(&semihosting, &cpu, SYS_CLOSE, 0x8000_0000), -1);
+        assert_eq!(call(&semihosting, &cpu, SYS_ERRNO, 0), EFAULT);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn long_file_names_are_refused() {
+        let (cpu, semihosting, root) = machine("long-name");
+
+        cpu.bus.load(Word(0x100), &[0x200u32, 1, u32::MAX].map(u32::to_le_bytes).concat()).unwrap();
+        assert_eq!(call(&semihosting, &cpu, SYS_OPEN, 0x100), -1);
+        assert_eq!(call(&semihosting, &cpu, SYS_ERRNO, 0), ENAMETOOLONG);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn symbolic_links_do_not_escape_the_root() {
+        let (_cpu, semihosting, root) = machine("symlink");
+        let outside = std::env::temp_dir().join(format!("semihosting-outside-{}", std::process::id()));
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret"), b"secret").unwrap();
+        std::fs::create_dir(root.join("inner")).unwrap();
+
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(outside.join("new"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(root.join("inner"), root.join("inside")).unwrap();
+
+        assert_eq!(semihosting.sandboxed("escape/secret"), None);
+        assert_eq!(semihosting.sandboxed("escape/new"), None);
+        assert_eq!(semihosting.sandboxed("dangling"), None);
+        assert_eq!(semihosting.sandboxed("../secret"), None);
+        let canonical = root.canonicalize().unwrap();
+        assert_eq!(semihosting.sandboxed("inside/file"), Some(canonical.join("inner/file")));
+        assert_eq!(semihosting.sandboxed("missing/file"), Some(canonical.join("missing/file")));
+        assert!(!outside.join("new").exists());
+
+        std::fs::remove_dir_all(outside).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
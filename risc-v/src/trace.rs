@@ -0,0 +1,405 @@
+//! Instruction commit log compatible with `spike --log-commits`
+//!
+//! Every retired instruction produces one [`Commit`] holding its architectural
+//! effects. Formatted with `Display` a commit looks exactly like a line in
+//! Spike's commit log, so logs of both simulators can be compared with `diff`:
+//!
+//! ```text
+//! core   0: 3 0x00000000 (0x00100093) x1  0x00000001
+//! core   0: 3 0x00000004 (0x00102223) mem 0x00000004 0x01
+//! ```
+//!
+//! Commit logs produced by Spike can be read back with [`CommitReader`].
+use crate::Word;
+use crate::bus::{Access, AccessKind};
+use crate::disassembly::Disassembly;
+
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Privilege level printed for every commit;
+/// the machine only implements machine mode
+pub const MACHINE_MODE: u8 = 3;
+
+/// Memory access performed by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: Word,
+    /// Size of the access in bytes
+    pub size: u8,
+    /// Value read or written, little endian
+    pub value: Word,
+}
+impl MemoryAccess {
+    /// Merge transfers seen on the bus into accesses of the instruction.
+    /// Consecutive transfers of the same kind to adjacent addresses
+    /// are treated as one wider access, up to a word.
+    pub fn coalesce(accesses: &[Access]) -> Vec<MemoryAccess> {
+        let mut merged: Vec<MemoryAccess> = vec![];
+
+        for access in accesses {
+            let size = access.width.bytes() as u8;
+
+            if let Some(last) = merged.last_mut() {
+                let adjacent = last.address + Word(last.size as u32) == access.address;
+
+                if last.kind == access.kind && adjacent && last.size + size <= 4 {
+                    last.value |= access.value << Word(8 * last.size as u32);
+                    last.size += size;
+                    continue;
+                }
+            }
+
+            merged.push(MemoryAccess {
+                kind: access.kind,
+                address: access.address,
+                size,
+                value: access.value,
+            });
+        }
+
+        merged
+    }
+}
+
+/// Architectural effects of a single retired instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub core: u32,
+    pub privilege: u8,
+    pub pc: Word,
+    pub instruction: Word,
+    /// Written general purpose registers as `(index, value)` pairs
+    pub writebacks: Vec<(Word, Word)>,
+    pub memory: Vec<MemoryAccess>,
+}
+impl Commit {
+    pub fn new(pc: Word, instruction: Word, writebacks: Vec<(Word, Word)>, memory: Vec<MemoryAccess>) -> Self {
+        Self { core: 0, privilege: MACHINE_MODE, pc, instruction, writebacks, memory }
+    }
+}
+impl FromStr for Commit {
+    type Err = String;
+
+    /// Parse a commit line, for example
+    /// `core   0: 3 0x80000000 (0x00000297) x5  0x80000000 mem 0x80001000`.
+    /// CSR and floating point writebacks are skipped.
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        fn hex(token: Option<&str>) -> Result<(Word, usize), String> {
+            let token = token.ok_or("unexpected end of line")?;
+            let digits = token.strip_prefix("0x").ok_or(format!("expected hex number, found `{token}`"))?;
+            let value = u64::from_str_radix(digits, 16).map_err(|e| format!("`{token}`: {e}"))?;
+            Ok((Word(value as u32), digits.len()))
+        }
+
+        let mut tokens = line.split_whitespace();
+
+        if tokens.next() != Some("core") {
+            return Err("expected `core`".into());
+        }
+
+        let core = tokens.next()
+            .and_then(|t| t.strip_suffix(':'))
+            .and_then(|t| t.parse().ok())
+            .ok_or("expected core number")?;
+        let privilege = tokens.next()
+            .and_then(|t| t.parse().ok())
+            .ok_or("expected privilege level")?;
+        let (pc, _) = hex(tokens.next())?;
+        let instruction = tokens.next()
+            .and_then(|t| t.strip_prefix('('))
+            .and_then(|t| t.strip_suffix(')'));
+        let (instruction, _) = hex(instruction)?;
+
+        let mut commit = Commit { core, privilege, pc, instruction, writebacks: vec![], memory: vec![] };
+        let mut tokens = tokens.peekable();
+
+        while let Some(token) = tokens.next() {
+            if token == "mem" {
+                let (address, _) = hex(tokens.next())?;
+
+                // Stores are followed by the written value, loads are not
+                match tokens.peek() {
+                    Some(t) if t.starts_with("0x") => {
+                        let (value, digits) = hex(tokens.next())?;
+                        let size = (digits / 2).clamp(1, 4) as u8;
+                        commit.memory.push(MemoryAccess { kind: AccessKind::Write, address, size, value });
+                    }
+                    _ => {
+                        commit.memory.push(MemoryAccess { kind: AccessKind::Read, address, size: 0, value: Word(0) });
+                    }
+                }
+            } else if let Some(idx) = token.strip_prefix('x').and_then(|i| i.parse::<u32>().ok()) {
+                let (value, _) = hex(tokens.next())?;
+                commit.writebacks.push((Word(idx), value));
+            } else {
+                // Other register files, skip the written value
+                hex(tokens.next())?;
+            }
+        }
+
+        Ok(commit)
+    }
+}
+impl Display for Commit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "core {:>3}: {} 0x{:08x} (0x{:08x})",
+            self.core, self.privilege, self.pc.0, self.instruction.0
+        )?;
+
+        for (idx, value) in self.writebacks.iter() {
+            write!(f, " x{:<2} 0x{:08x}", idx.0, value.0)?;
+        }
+
+        for access in self.memory.iter() {
+            match access.kind {
+                AccessKind::Read => write!(f, " mem 0x{:08x}", access.address.0)?,
+                AccessKind::Write => write!(f, " mem 0x{:08x} 0x{:0width$x}",
+                    access.address.0, access.value.0, width = 2 * access.size as usize
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Error while reading a commit log
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    /// Malformed commit on given line (counting from 1)
+    Parse { line: usize, message: String },
+}
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceError::Io(err) => write!(f, "failed to read commit log: {err}"),
+            TraceError::Parse { line, message } => write!(f, "malformed commit on line {line}: {message}"),
+        }
+    }
+}
+impl std::error::Error for TraceError {}
+
+/// Reader of a commit log produced by `spike --log-commits` or [`CommitLog`].
+///
+/// Iterates over commits and skips every other line,
+/// such as disassembly or trap messages.
+pub struct CommitReader<R: BufRead> {
+    lines: io::Lines<R>,
+    line: usize,
+}
+impl<R: BufRead> CommitReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { lines: reader.lines(), line: 0 }
+    }
+}
+impl CommitReader<BufReader<File>> {
+    /// Open log file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+impl<R: BufRead> Iterator for CommitReader<R> {
+    type Item = Result<Commit, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let text = match self.lines.next()? {
+                Ok(text) => text,
+                Err(err) => return Some(Err(TraceError::Io(err))),
+            };
+            self.line += 1;
+
+            // Commit lines carry privilege level right after the core number,
+            // disassembly lines carry program counter there
+            let mut tokens = text.split_whitespace();
+            let is_commit = tokens.next() == Some("core")
+                && tokens.next().is_some_and(|t| t.ends_with(':'))
+                && tokens.next().is_some_and(|t| t.chars().all(|c| c.is_ascii_digit()));
+
+            if !is_commit {
+                continue;
+            }
+
+            return Some(text.parse().map_err(|message| TraceError::Parse { line: self.line, message }));
+        }
+    }
+}
+
+/// Writer of a commit log.
+///
+/// Logging stops at the first I/O error, which is then reported by [`CommitLog::finish`].
+pub struct CommitLog {
+    writer: RefCell<Box<dyn Write>>,
+    disassembly: bool,
+    error: RefCell<Option<io::Error>>,
+}
+impl CommitLog {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self { writer: RefCell::new(writer), disassembly: false, error: RefCell::new(None) }
+    }
+
+    /// Create log file at `path`, truncating it if it exists
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(Box::new(BufWriter::new(file))))
+    }
+
+    /// Precede every commit with a disassembly line,
+    /// like `spike -l --log-commits` does
+    pub fn with_disassembly(mut self, disassembly: bool) -> Self {
+        self.disassembly = disassembly;
+        self
+    }
+
+    /// Append commit to the log
+    pub fn log(&self, commit: &Commit, disassembly: Option<&Disassembly>) {
+        if self.error.borrow().is_some() {
+            return;
+        }
+
+        let mut writer = self.writer.borrow_mut();
+        let mut result = Ok(());
+
+        if self.disassembly {
+            let text = match disassembly {
+                Some(disasm) => disasm.to_string(),
+                None => "unknown".to_string(),
+            };
+            result = writeln!(writer, "core {:>3}: 0x{:08x} (0x{:08x}) {text}",
+                commit.core, commit.pc.0, commit.instruction.0
+            );
+        }
+
+        if let Err(err) = result.and_then(|_| writeln!(writer, "{commit}")) {
+            *self.error.borrow_mut() = Some(err);
+        }
+    }
+
+    /// Flush the log and return the first error that occured while writing it
+    pub fn finish(self) -> io::Result<()> {
+        if let Some(err) = self.error.into_inner() {
+            return Err(err);
+        }
+
+        self.writer.into_inner().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::AccessWidth;
+
+    /// Lines of `spike --log-commits` for an rv32 hart
+    const SPIKE_LOG: &str = "\
+core   0: 0x00001000 (0x00000297) auipc   t0, 0x0
+core   0: 3 0x00001000 (0x00000297) x5  0x00001000
+core   0: 0x80000004 (0x00412703) lw      a4, 4(sp)
+core   0: 3 0x80000004 (0x00412703) x14 0x12345678 mem 0x80001004
+core   0: 3 0x80000008 (0x00e12423) mem 0x80001008 0x12345678
+core   0: 3 0x8000000c (0x00e10623) mem 0x8000100c 0x78
+core   0: 3 0x80000010 (0x00e11723) mem 0x8000100e 0x5678
+core   0: exception trap_illegal_instruction, epc 0x80000014
+core   0: 3 0x80000014 (0x30529073) c773_mtvec 0x80000100
+core   0: 3 0x80000018 (0x34202573) x10 0x00000002 c834_mcause 0x00000002
+";
+
+    #[test]
+    fn spike_lines_round_trip() {
+        for line in SPIKE_LOG.lines().filter(|line| line.contains(": 3 ")).filter(|line| !line.contains(" c")) {
+            let commit: Commit = line.parse().unwrap();
+            assert_eq!(commit.to_string(), line);
+        }
+    }
+
+    #[test]
+    fn spike_lines_are_parsed() {
+        let commits: Vec<Commit> = CommitReader::new(SPIKE_LOG.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(commits.len(), 7);
+
+        assert_eq!(commits[0], Commit::new(Word(0x1000), Word(0x297), vec![(Word(5), Word(0x1000))], vec![]));
+        assert_eq!(commits[1].writebacks, [(Word(14), Word(0x1234_5678))]);
+        assert_eq!(commits[1].memory, [MemoryAccess { kind: AccessKind::Read, address: Word(0x8000_1004), size: 0, value: Word(0) }]);
+        let sizes: Vec<u8> = commits[2..5].iter().map(|commit| commit.memory[0].size).collect();
+        assert_eq!(sizes, [4, 1, 2]);
+        assert_eq!(commits[3].memory[0].value, Word(0x78));
+
+        // CSR writebacks are skipped, register ones next to them are kept
+        assert!(commits[5].writebacks.is_empty());
+        assert_eq!(commits[6].writebacks, [(Word(10), Word(2))]);
+    }
+
+    #[test]
+    fn malformed_commits_name_their_line() {
+        let log = "core   0: 0x00001000 (0x00000297) auipc   t0, 0x0\ncore   0: 3 0x00001000 (0x00000297) x5  12\n";
+        match CommitReader::new(log.as_bytes()).next() {
+            Some(Err(TraceError::Parse { line, message })) => {
+                assert_eq!(line, 2);
+                assert_eq!(message, "expected hex number, found `12`");
+            }
+            other => panic!("expected parse error, got {other:?}"),
+        }
+
+        assert_eq!("core 0: 3 0x0 (0x13) x1".parse::<Commit>(), Err("unexpected end of line".into()));
+        assert_eq!("core 0: 3 0x0 0x13".parse::<Commit>(), Err("unexpected end of line".into()));
+        assert!("hart 0: 3 0x0 (0x13)".parse::<Commit>().is_err());
+        assert!(CommitReader::new("".as_bytes()).next().is_none());
+    }
+
+    #[test]
+    fn adjacent_transfers_coalesce_up_to_a_word() {
+        let byte = |kind, address, value| Access { kind, address: Word(address), width: AccessWidth::Byte, value: Word(value) };
+        let transfers = [
+            byte(AccessKind::Write, 0x100, 0x11),
+            byte(AccessKind::Write, 0x101, 0x22),
+            byte(AccessKind::Write, 0x102, 0x33),
+            byte(AccessKind::Write, 0x103, 0x44),
+            // Word is full, a new access starts
+            byte(AccessKind::Write, 0x104, 0x55),
+            // Not adjacent
+            byte(AccessKind::Write, 0x200, 0x66),
+            // Different kind
+            byte(AccessKind::Read, 0x201, 0x77),
+        ];
+
+        let merged = MemoryAccess::coalesce(&transfers);
+        let summary: Vec<(AccessKind, u32, u8, u32)> = merged.iter()
+            .map(|access| (access.kind, access.address.0, access.size, access.value.0))
+            .collect();
+        assert_eq!(summary, [
+            (AccessKind::Write, 0x100, 4, 0x4433_2211),
+            (AccessKind::Write, 0x104, 1, 0x55),
+            (AccessKind::Write, 0x200, 1, 0x66),
+            (AccessKind::Read, 0x201, 1, 0x77),
+        ]);
+    }
+
+    #[test]
+    fn log_writes_disassembly_and_commit_lines() {
+        struct Shared(std::rc::Rc<RefCell<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(data);
+                Ok(data.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = std::rc::Rc::new(RefCell::new(vec![]));
+        let log = CommitLog::new(Box::new(Shared(output.clone()))).with_disassembly(true);
+        log.log(&Commit::new(Word(0x1000), Word(0x297), vec![(Word(5), Word(0x1000))], vec![]), None);
+        log.finish().unwrap();
+
+        let text = String::from_utf8(output.borrow().clone()).unwrap();
+        assert_eq!(text, "core   0: 0x00001000 (0x00000297) unknown\ncore   0: 3 0x00001000 (0x00000297) x5  0x00001000\n");
+    }
+}
@@ -85,7 +85,7 @@ impl Word {
 
         match sign {
             0 => (),
-            1 => value |= 0b_11111111_11111111_11111000_00000000,
+            1 => value |= 0b_11111111_11111111_11110000_00000000,
             _ => unreachable!("sign can only be 0 or 1"),
         }
 
@@ -107,12 +107,16 @@ impl Word {
 
         match sign {
             0 => (),
-            1 => value |= 0b_11111111_11111000_00000000_00000000,
+            1 => value |= 0b_11111111_11110000_00000000_00000000,
             _ => unreachable!("sign can only be 0 or 1"),
         }
 
         value.into()
     }
+    /// Address of the control and status register of a Zicsr instruction
+    pub fn csr(&self) -> Word {
+        (self.0 >> 20).into()
+    }
     pub fn shift_imm_amount(&self) -> Word {
         let imm = self.0 & 0b_11111111_11110000_00000000_00000000;
         let shamt_i = (imm >> 20) & 0b_11111;
@@ -281,4 +285,21 @@ impl Not for Word {
     fn not(self) -> Self::Output {
         Word(!self.0)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Word;
+
+    #[test]
+    fn branch_offset_is_sign_extended_from_bit_12() {
+        // beq zero, zero, -2400, bit 11 of the offset is clear
+        assert_eq!(Word(0xea000063).b_type_immediate().signed(), -2400);
+    }
+
+    #[test]
+    fn jump_offset_is_sign_extended_from_bit_20() {
+        // jal zero, -600000, bit 19 of the offset is clear
+        assert_eq!(Word(0x8416d06f).j_type_immediate().signed(), -600000);
+    }
+}
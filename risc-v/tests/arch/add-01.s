@@ -0,0 +1,66 @@
+# ADD, SUB, ADDI and LUI results, in riscv-arch-test style: results go to
+# the signature, which is compared with add-01.reference_output
+.include "test_macros.s"
+
+RVTEST_CODE_BEGIN
+    la x1, begin_signature
+
+    li x2, 0
+    li x3, 0
+    add x4, x2, x3
+    sw x4, 0(x1)
+
+    li x2, 1
+    li x3, 1
+    add x4, x2, x3
+    sw x4, 4(x1)
+
+    # Signed overflow wraps around
+    li x2, 0x7fffffff
+    li x3, 1
+    add x4, x2, x3
+    sw x4, 8(x1)
+
+    li x2, -1
+    li x3, 1
+    add x4, x2, x3
+    sw x4, 12(x1)
+
+    li x2, 0x80000000
+    li x3, 0xffff8000
+    add x4, x2, x3
+    sw x4, 16(x1)
+
+    # Last registers are as usable as the others
+    li x30, 0x12345678
+    li x29, 0x11111111
+    add x31, x30, x29
+    sw x31, 20(x1)
+
+    # Writes to x0 are dropped
+    add x0, x2, x3
+    sw x0, 24(x1)
+
+    li x5, -2048
+    addi x6, x5, -1
+    sw x6, 28(x1)
+
+    li x2, 0
+    li x3, 1
+    sub x4, x2, x3
+    sw x4, 32(x1)
+
+    lui x4, 0xfffff
+    sw x4, 36(x1)
+
+    li x7, 0x40000000
+    add x7, x7, x7
+    sw x7, 40(x1)
+
+    j pass
+RVTEST_CODE_END
+
+RVTEST_DATA_BEGIN
+RVTEST_SIG_BEGIN
+    .fill 12, 4, 0xdeadbeef
+RVTEST_SIG_END
@@ -0,0 +1,120 @@
+# Loads, stores and backward jumps, in riscv-tests style: every test case
+# checks its own result and the test reports the first failing case
+.include "test_macros.s"
+
+RVTEST_CODE_BEGIN
+    la x1, tdat
+
+    # Memory is little endian
+test_2:
+    li gp, 2
+    lw x14, 0(x1)
+    li x7, 0x11223344
+    bne x14, x7, fail
+
+test_3:
+    li gp, 3
+    lbu x14, 0(x1)
+    li x7, 0x44
+    bne x14, x7, fail
+
+test_4:
+    li gp, 4
+    lhu x14, 2(x1)
+    li x7, 0x1122
+    bne x14, x7, fail
+
+    # Signed loads extend the sign of the loaded value
+test_5:
+    li gp, 5
+    lb x14, 4(x1)
+    li x7, 0xffffff80
+    bne x14, x7, fail
+
+test_6:
+    li gp, 6
+    lh x14, 6(x1)
+    li x7, 0xffff8000
+    bne x14, x7, fail
+
+test_7:
+    li gp, 7
+    lhu x14, 4(x1)
+    li x7, 0xff80
+    bne x14, x7, fail
+
+    # Stores put the least significant byte first
+test_8:
+    li gp, 8
+    li x2, 0xaabbccdd
+    sw x2, 8(x1)
+    lbu x14, 8(x1)
+    li x7, 0xdd
+    bne x14, x7, fail
+
+test_9:
+    li gp, 9
+    li x2, 0x1234
+    sh x2, 10(x1)
+    lw x14, 8(x1)
+    li x7, 0x1234ccdd
+    bne x14, x7, fail
+
+test_10:
+    li gp, 10
+    li x2, 0x99
+    sb x2, 9(x1)
+    lw x14, 8(x1)
+    li x7, 0x123499dd
+    bne x14, x7, fail
+
+    # Branches and jumps backwards
+test_11:
+    li gp, 11
+    li x5, 3
+    li x6, 0
+1:
+    addi x6, x6, 1
+    addi x5, x5, -1
+    bnez x5, 1b
+    li x7, 3
+    bne x6, x7, fail
+
+test_12:
+    li gp, 12
+    li x8, 0
+    j 2f
+1:
+    li x8, 42
+    j 3f
+2:
+    j 1b
+3:
+    li x7, 42
+    bne x8, x7, fail
+
+    # Offset of more than 2 KiB back, with bit 11 of the offset clear
+test_13:
+    li gp, 13
+    li x5, 2
+1:
+    addi x5, x5, -1
+    .fill 600, 4, 0x00000013
+    bnez x5, 1b
+    bnez x5, fail
+
+test_14:
+    li gp, 14
+    li x31, 0x5a5a5a5a
+    mv x14, x31
+    li x7, 0x5a5a5a5a
+    bne x14, x7, fail
+
+    j pass
+RVTEST_CODE_END
+
+RVTEST_DATA_BEGIN
+tdat:
+    .word 0x11223344
+    .word 0x8000ff80
+    .word 0
@@ -0,0 +1,100 @@
+# Test environment of the self-built compliance tests, after the `p`
+# environment of riscv-tests: everything runs in machine mode, `gp` holds
+# the number of the test case running and the result is written to `tohost`
+# by the trap handler when the test ends with an `ecall`. Tests with a
+# signature store it between `begin_signature` and `end_signature`.
+
+# Boot the hart and jump to the test in machine mode
+.macro RVTEST_CODE_BEGIN
+    .text
+    .globl _start
+_start:
+    # Registers the hart doesn't have trap, skip them through mtvec
+    la t0, 1f
+    csrw mtvec, t0
+    csrwi satp, 0
+    .align 2
+1:
+    la t0, trap_vector
+    csrw mtvec, t0
+    csrwi mie, 0
+    csrwi mstatus, 0
+    li gp, 0
+    la t0, test_start
+    csrw mepc, t0
+    csrr a0, mhartid
+    mret
+
+trap_vector:
+    # Environment call ends the test, anything else fails it
+    csrr t5, mcause
+    li t6, 11
+    beq t5, t6, write_tohost
+    ori gp, gp, 1337
+write_tohost:
+    la t5, tohost
+    sw gp, 0(t5)
+1:
+    j 1b
+
+test_start:
+.endm
+
+.macro RVTEST_PASS
+    fence
+    li gp, 1
+    ecall
+.endm
+
+# Report the test case in gp as failed, `(gp << 1) | 1`
+.macro RVTEST_FAIL
+    fence
+1:
+    beqz gp, 1b
+    sll gp, gp, 1
+    or gp, gp, 1
+    ecall
+.endm
+
+.macro RVTEST_CODE_END
+pass:
+    RVTEST_PASS
+fail:
+    RVTEST_FAIL
+.endm
+
+.macro RVTEST_DATA_BEGIN
+    .align 6
+    .globl tohost
+tohost:
+    .word 0
+    .align 6
+    # Spike's host interface wants both symbols
+    .globl fromhost
+fromhost:
+    .word 0
+    .align 6
+.endm
+
+.macro RVTEST_SIG_BEGIN
+    .align 4
+    .globl begin_signature
+begin_signature:
+.endm
+
+.macro RVTEST_SIG_END
+    .align 4
+    .globl end_signature
+end_signature:
+.endm
+
+# Test case `n`: `inst` of `val1` and `val2` gives `result`
+.macro TEST_RR_OP n, inst, result, val1, val2
+test_\n:
+    li gp, \n
+    li x11, \val1
+    li x12, \val2
+    \inst x14, x11, x12
+    li x7, \result
+    bne x14, x7, fail
+.endm
@@ -0,0 +1,101 @@
+# Machine mode CSRs and traps, in riscv-arch-test style: results go to the
+# signature, which is compared with trap-01.reference_output
+.include "test_macros.s"
+
+RVTEST_CODE_BEGIN
+    la x1, begin_signature
+
+    # RV32I on hart 0
+    csrr x2, misa
+    sw x2, 0(x1)
+    csrr x2, mhartid
+    sw x2, 4(x1)
+
+    # Read-write returns the old value
+    li x3, 0x12345678
+    csrw mscratch, x3
+    li x4, 0xcafef00d
+    csrrw x2, mscratch, x4
+    sw x2, 8(x1)
+    csrr x2, mscratch
+    sw x2, 12(x1)
+
+    # Set and clear bits, with registers and immediates
+    li x3, 0x0000ffff
+    csrrs x2, mscratch, x3
+    sw x2, 16(x1)
+    li x3, 0xff000000
+    csrrc x2, mscratch, x3
+    sw x2, 20(x1)
+    csrr x2, mscratch
+    sw x2, 24(x1)
+    csrrwi x2, mscratch, 0x15
+    sw x2, 28(x1)
+    csrrsi x2, mscratch, 0x0a
+    sw x2, 32(x1)
+    csrrci x2, mscratch, 0x01
+    sw x2, 36(x1)
+    csrr x2, mscratch
+    sw x2, 40(x1)
+
+    # Only MIE and MPIE can be written, MPP always reads machine mode
+    li x3, 0xffffffff
+    csrw mstatus, x3
+    csrr x2, mstatus
+    sw x2, 44(x1)
+
+    # Every trap stores mcause, mepc relative to trap_1, mtval and mstatus
+    la x5, handler
+    csrw mtvec, x5
+    addi x6, x1, 48
+    lui x8, 0x10000
+    csrwi mstatus, 8
+trap_1:
+    ecall
+    ebreak
+    # Register the hart doesn't have
+    csrr x2, 0x7c0
+    # Nothing is mapped at 0x10000000
+    lw x2, 4(x8)
+    sw x2, 8(x8)
+    # Read-only register
+    csrw mhartid, x0
+    # All zeros is not an instruction
+    .word 0
+
+    # Interrupts are enabled again by mret
+    csrr x2, mstatus
+    sw x2, 160(x1)
+
+    # Nothing to wait for
+    fence
+    fence.i
+
+    la x5, trap_vector
+    csrw mtvec, x5
+    j pass
+
+handler:
+    csrr x9, mcause
+    sw x9, 0(x6)
+    csrr x9, mepc
+    la x10, trap_1
+    sub x9, x9, x10
+    sw x9, 4(x6)
+    csrr x9, mtval
+    sw x9, 8(x6)
+    csrr x9, mstatus
+    sw x9, 12(x6)
+    addi x6, x6, 16
+
+    # Continue after the trapping instruction
+    csrr x9, mepc
+    addi x9, x9, 4
+    csrw mepc, x9
+    mret
+RVTEST_CODE_END
+
+RVTEST_DATA_BEGIN
+RVTEST_SIG_BEGIN
+    .fill 44, 4, 0xdeadbeef
+RVTEST_SIG_END
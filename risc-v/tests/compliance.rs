@@ -0,0 +1,59 @@
+//! Run the self-built test binaries in `tests/arch` through the compliance
+//! harness, the way `arch-test` runs riscv-tests and riscv-arch-test ones
+use risc_v::compliance::{run_test, ArchTest, Outcome, SignatureCheck};
+
+use std::path::{Path, PathBuf};
+
+const MAX_STEPS: u64 = 100_000;
+
+fn binary(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/arch").join(format!("{name}.elf"))
+}
+
+/// Run test `name`, checking its signature when it has a reference
+fn arch_test(name: &str) {
+    let elf = binary(name);
+    let reference = elf.with_extension("reference_output");
+    let reference = reference.exists().then_some(reference.as_path());
+
+    let report = run_test(&elf, reference, MAX_STEPS).unwrap();
+    assert!(report.passed(), "{report}");
+    if reference.is_some() {
+        assert_eq!(report.signature, SignatureCheck::Match, "{report}");
+    }
+}
+
+#[test]
+fn add_01() {
+    arch_test("add-01");
+}
+
+#[test]
+fn rv32ui_ldst() {
+    arch_test("rv32ui-ldst");
+}
+
+#[test]
+fn trap_01() {
+    arch_test("trap-01");
+}
+
+#[test]
+fn signature_mismatch_is_reported() {
+    let mut test = ArchTest::load(binary("add-01")).unwrap();
+    assert_eq!(test.run(MAX_STEPS), Outcome::Pass);
+
+    let reference = std::fs::read_to_string(binary("add-01").with_extension("reference_output")).unwrap();
+    let wrong = reference.replacen("00000002", "00000003", 1);
+    assert_eq!(test.check_signature(&wrong), SignatureCheck::Mismatch {
+        line: 2,
+        expected: "00000003".into(),
+        actual: "00000002".into(),
+    });
+}
+
+#[test]
+fn step_limit_times_out() {
+    let mut test = ArchTest::load(binary("rv32ui-ldst")).unwrap();
+    assert_eq!(test.run(100), Outcome::Timeout);
+}
@@ -1,2 +1,4 @@
 pub mod termscreen;
-pub use termscreen::TermScreen;
\ No newline at end of file
+pub mod textview;
+pub use termscreen::TermScreen;
+pub use textview::TextView;
\ No newline at end of file
@@ -1,64 +1,46 @@
-use risc_v::Word;
-use risc_v::bus::DeviceTrait;
+use risc_v::devices::text::TextDisplay;
+use super::TextView;
 
-use std::{io::{stdout, Stdout}, thread, time::Duration};
+use std::io::{self, stdout, Stdout};
 use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     ExecutableCommand,
 };
 use ratatui::{
     prelude::*,
-    widgets::{canvas::*, *},
+    widgets::*,
 };
 
+/// Terminal taken over for the console, given back when dropped
 pub struct TermScreen {
     terminal: Terminal<CrosstermBackend<Stdout>>,
 }
 impl TermScreen {
-    pub fn new() -> Self {
-        enable_raw_mode().unwrap();
-        stdout().execute(EnterAlternateScreen).unwrap();
-        let mut terminal = Terminal::new(CrosstermBackend::new(stdout())).unwrap();
-        
-        terminal.draw(|frame| {
-            frame.render_widget(Canvas::default()
-                .block(Block::default().title("Canvas").borders(Borders::ALL))
-                .x_bounds([-180.0, 180.0])
-                .y_bounds([-90.0, 90.0])
-                .paint(|ctx| {
-                    ctx.draw(&Map {
-                        resolution: MapResolution::High,
-                        color: Color::White,
-                    });
-                    ctx.layer();
-                    ctx.draw(&Rectangle {
-                        x: 10.0,
-                        y: 20.0,
-                        width: 10.0,
-                        height: 10.0,
-                        color: Color::Red,
-                    }), frame.size())
-            }, frame.size()s) 
-        }).unwrap();
-
-        thread::sleep(Duration::from_millis(3000));
-        Self { terminal }
+    /// Switch to raw mode on the alternate screen
+    pub fn new() -> io::Result<Self> {
+        enable_raw_mode()?;
+        stdout().execute(EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+        terminal.hide_cursor()?;
+        Ok(Self { terminal })
     }
-    pub fn boxed(self) -> Box<Self> {
-        Box::new(self)
+
+    /// Draw `display` in a box titled `title`
+    pub fn draw(&mut self, display: &TextDisplay, title: &str) -> io::Result<()> {
+        self.terminal.draw(|frame| {
+            let block = Block::default().title(title).borders(Borders::ALL);
+            let area = block.inner(frame.size());
+            frame.render_widget(block, frame.size());
+            frame.render_widget(TextView::new(display), area);
+        })?;
+        Ok(())
     }
 }
 impl Drop for TermScreen {
     fn drop(&mut self) {
-        disable_raw_mode().unwrap();
-        stdout().execute(LeaveAlternateScreen).unwrap();
+        // Nothing left to report errors to
+        let _ = self.terminal.show_cursor();
+        let _ = disable_raw_mode();
+        let _ = stdout().execute(LeaveAlternateScreen);
     }
 }
-impl DeviceTrait for TermScreen {
-    fn name(&self) -> String {
-        "TermScreen".to_string()
-    }
-    fn read(&self, address: Word) -> Word { Word(0) }
-    fn write(&self, address: Word, word: Word) {}
-    fn tick(&self) {}
-}
\ No newline at end of file
@@ -0,0 +1,78 @@
+use risc_v::devices::text::TextDisplay;
+
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    widgets::Widget,
+};
+
+/// Terminal color closest to each CGA attribute color
+const COLORS: [Color; 16] = [
+    Color::Black, Color::Blue, Color::Green, Color::Cyan,
+    Color::Red, Color::Magenta, Color::Yellow, Color::Gray,
+    Color::DarkGray, Color::LightBlue, Color::LightGreen, Color::LightCyan,
+    Color::LightRed, Color::LightMagenta, Color::LightYellow, Color::White,
+];
+
+/// Draws a text display one terminal cell per character, clipped to the
+/// area. The cursor shows in reverse video.
+pub struct TextView<'a> {
+    display: &'a TextDisplay,
+}
+impl<'a> TextView<'a> {
+    pub fn new(display: &'a TextDisplay) -> Self {
+        Self { display }
+    }
+}
+impl Widget for TextView<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let columns = self.display.columns().min(area.width as u32);
+        let rows = self.display.rows().min(area.height as u32);
+        for row in 0..rows {
+            for column in 0..columns {
+                let (character, attribute) = self.display.cell(column, row);
+                let symbol = match character {
+                    0x20..=0x7e => character as char,
+                    _ => ' ',
+                };
+                let mut style = Style::default()
+                    .fg(COLORS[(attribute & 0xf) as usize])
+                    .bg(COLORS[(attribute >> 4) as usize]);
+                if self.display.cursor() == Some((column, row)) {
+                    style = style.add_modifier(Modifier::REVERSED);
+                }
+                buf.get_mut(area.x + column as u16, area.y + row as u16)
+                    .set_char(symbol)
+                    .set_style(style);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_is_drawn_one_cell_per_character() {
+        let display = TextDisplay::new(6, 2);
+        for (column, character) in b"Hi".iter().enumerate() {
+            display.set_cell(column as u32, 0, *character, 0x1e);
+        }
+        display.set_cell(2, 0, 0x01, 0x07);
+        display.set_cell(0, 1, b'>', 0x07);
+
+        // Clipped to the area, cursor at the top left
+        let area = Rect::new(0, 0, 4, 3);
+        let mut buf = Buffer::empty(area);
+        TextView::new(&display).render(area, &mut buf);
+
+        let mut expected = Buffer::with_lines(vec!["Hi  ", ">   ", "    "]);
+        let text = Style::default().fg(Color::Gray).bg(Color::Black);
+        expected.set_style(Rect::new(0, 0, 4, 2), text);
+        expected.set_style(Rect::new(0, 0, 2, 1), Style::default().fg(Color::LightYellow).bg(Color::Blue));
+        expected.set_style(Rect::new(0, 0, 1, 1), Style::default().add_modifier(Modifier::REVERSED));
+        assert_eq!(buf, expected);
+    }
+}
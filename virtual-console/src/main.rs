@@ -1,14 +1,105 @@
-use risc_v::Word;
-use risc_v::bus::{Device, MemoryRange};
+use risc_v::{Halt, MemoryRange, RV32, Word};
+use risc_v::bus::DeviceHandle;
+use risc_v::devices::{Device, input::Input, ram::Ram, syscon::SystemController, terminal, text::TextDisplay};
+use risc_v::elf::Elf;
+use risc_v::exception::Exception;
 use device::TermScreen;
 mod device;
 
-fn main() {
-    let mut cpu = risc_v::RV32::new();
-    cpu.bus.connect(Device::new(
-        MemoryRange::new(Word(0x8000), Word(0x1000)).unwrap(),
-        TermScreen::new().boxed()
-    ));
-    
-    println!("Hello, world!");
+use std::{env, error::Error, io, process, time::Duration};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+
+const RAM_BASE: Word = Word(0x8000_0000);
+const RAM_SIZE: usize = 4 * 1024 * 1024;
+const SYSCON_BASE: Word = Word(0x1000_0000);
+const INPUT_BASE: Word = Word(0x1001_0000);
+const TEXT_BASE: Word = Word(0x1100_0000);
+
+const COLUMNS: u32 = 80;
+const ROWS: u32 = 25;
+
+/// Instructions run between two frames
+const STEPS_PER_FRAME: usize = 100_000;
+/// Longest wait for a key between two frames
+const FRAME_TIME: Duration = Duration::from_millis(16);
+/// Key that leaves the console, every other one goes to the guest
+const QUIT: KeyCode = KeyCode::F(12);
+const TITLE: &str = " virtual console - F12 to quit ";
+
+/// Why the console stopped
+enum Ending {
+    Halted(Halt),
+    Fault(Exception),
+    Quit,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Some(path) = env::args().nth(1) else {
+        eprintln!("usage: virtual-console <program.elf>");
+        process::exit(2);
+    };
+    let elf = Elf::open(&path)?;
+
+    let mut cpu = RV32::new();
+    cpu.bus.connect(Device::new(MemoryRange::new(RAM_BASE, Word(RAM_SIZE as u32)), Box::new(Ram::new(RAM_SIZE))))?;
+    cpu.bus.connect(Device::new(MemoryRange::new(SYSCON_BASE, Word(0x1000)), Box::new(SystemController::new())))?;
+    let input = cpu.bus.connect(Device::new(MemoryRange::new(INPUT_BASE, Word(0x20)), Box::new(Input::new())))?;
+    let display = TextDisplay::new(COLUMNS, ROWS);
+    let size = Word(display.size());
+    let text = cpu.bus.connect(Device::new(MemoryRange::new(TEXT_BASE, size), Box::new(display)))?;
+    elf.load(&cpu.bus)?;
+    cpu.reset_vector = elf.entry;
+    cpu.reset();
+
+    let mut screen = TermScreen::new()?;
+    let ending = loop {
+        match run(&cpu, &mut screen, input, text) {
+            // Start over with the program as loaded, it may have changed its data
+            Ok(Ending::Halted(Halt::Reset(_) | Halt::WatchdogReset)) => {
+                elf.load(&cpu.bus)?;
+                cpu.reset();
+            }
+            ending => break ending,
+        }
+    };
+    drop(screen);
+
+    match ending? {
+        Ending::Halted(reason) => println!("{path}: {reason:?}"),
+        Ending::Fault(exception) => {
+            eprintln!("{path}: {exception:?} at pc {:#010x}", cpu.reg.read("pc").unwrap().0);
+            process::exit(1);
+        }
+        Ending::Quit => {}
+    }
+    Ok(())
+}
+
+/// Run the program, drawing its screen and passing it keys, until it
+/// stops or `QUIT` is pressed
+fn run(cpu: &RV32, screen: &mut TermScreen, input: DeviceHandle, text: DeviceHandle) -> io::Result<Ending> {
+    loop {
+        for _ in 0..STEPS_PER_FRAME {
+            if let Some(reason) = cpu.halted() {
+                return Ok(Ending::Halted(reason));
+            }
+            if let Err(exception) = cpu.step() {
+                return Ok(Ending::Fault(exception));
+            }
+        }
+        screen.draw(cpu.bus.device::<TextDisplay>(text).unwrap(), TITLE)?;
+
+        // Take every key typed during the frame
+        let mut timeout = FRAME_TIME;
+        while event::poll(timeout)? {
+            timeout = Duration::ZERO;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.code == QUIT && key.kind == KeyEventKind::Press {
+                return Ok(Ending::Quit);
+            }
+            terminal::forward(&key, cpu.bus.device::<Input>(input).unwrap(), false);
+        }
+    }
 }